@@ -9,6 +9,19 @@ include!("src/cli.rs");
 fn main() -> Result<(), Error> {
     println!("cargo:rerun-if-changed=src/cli.rs");
 
+    // Embed the git commit if built from a repository
+    let git_hash = std::process::Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .and_then(|o| String::from_utf8(o.stdout).ok());
+    if let Some(hash) = git_hash {
+        println!("cargo:rustc-env=LIVESTREAM_DL_GIT_HASH={}", hash.trim());
+        println!("cargo:rerun-if-changed=.git/HEAD");
+        println!("cargo:rerun-if-changed=.git/refs");
+    }
+
     let outdir = match env::var_os("OUT_DIR") {
         None => return Ok(()),
         Some(outdir) => outdir,
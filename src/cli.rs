@@ -1,21 +1,100 @@
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 use reqwest::Url;
 
 /// A HLS (m3u8) livestream downloader
 #[derive(Parser, Clone, Debug)]
-#[clap(version, about)]
+#[clap(
+    version,
+    about,
+    subcommand_negates_reqs = true,
+    args_conflicts_with_subcommands = true
+)]
 pub struct Args {
     /// m3u8 playlist URL
-    #[clap(value_parser, value_hint = clap::ValueHint::Url)]
-    pub m3u8_url: Url,
+    #[clap(value_parser, value_hint = clap::ValueHint::Url, required = true)]
+    pub m3u8_url: Option<Url>,
+
+    #[clap(subcommand)]
+    pub command: Option<Command>,
 
     #[clap(flatten)]
     pub download_options: DownloadOptions,
 
     #[clap(flatten)]
     pub network_options: NetworkOptions,
+
+    #[cfg(feature = "s3")]
+    #[clap(flatten)]
+    pub upload_options: UploadOptions,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum Command {
+    /// Merge segments directories of earlier downloads into one video. Segments found in more
+    /// than one directory are taken from the first directory they appear in
+    Merge(MergeArgs),
+    /// Measure the download throughput of segments of a playlist at different numbers of
+    /// concurrent downloads, to help choose --max-concurrent-downloads. Data is discarded
+    Bench(Box<BenchArgs>),
+}
+
+#[derive(Parser, Clone, Debug)]
+pub struct MergeArgs {
+    /// segments directories to merge
+    #[clap(value_parser, required = true, value_hint = clap::ValueHint::DirPath)]
+    pub segments_directories: Vec<PathBuf>,
+
+    /// Output directory of the merged video
+    #[clap(short, long, value_parser)]
+    pub output: PathBuf,
+
+    /// Overwrite the merged video file if it already exists
+    #[clap(long, value_parser)]
+    pub overwrite: bool,
+
+    /// Container format of the merged video: mp4, mkv or ts
+    #[clap(long, value_parser, value_name = "CONTAINER", default_value = "mp4")]
+    pub container: Container,
+}
+
+#[derive(Parser, Clone, Debug)]
+pub struct BenchArgs {
+    /// m3u8 playlist URL
+    #[clap(value_parser, value_hint = clap::ValueHint::Url)]
+    pub m3u8_url: Url,
+
+    /// Number of segments of the main stream to download at each level
+    #[clap(long, value_parser, default_value_t = 20)]
+    pub segments: usize,
+
+    /// Numbers of concurrent downloads to measure
+    #[clap(
+        long,
+        value_parser,
+        value_name = "N",
+        use_value_delimiter = true,
+        default_values = &["1", "2", "4", "8", "16", "32"]
+    )]
+    pub concurrency: Vec<NonZeroUsize>,
+
+    #[clap(flatten)]
+    pub network_options: NetworkOptions,
+}
+
+impl BenchArgs {
+    /// Arguments to open the playlist with
+    pub fn download_args(&self) -> Args {
+        let mut args = Args::parse_from(["livestream-dl", self.m3u8_url.as_str()]);
+        args.network_options = self.network_options.clone();
+        args
+    }
 }
 
 #[derive(Parser, Clone, Debug)]
@@ -25,37 +104,454 @@ pub struct DownloadOptions {
     #[clap(short, long, value_parser)]
     pub output: Option<PathBuf>,
 
+    /// Resume an interrupted download into an existing output directory, with the variant it
+    /// selected
+    #[clap(long, value_parser, requires = "output")]
+    pub resume: bool,
+
+    /// Overwrite the remuxed output file if it already exists
+    #[clap(long, value_parser, overrides_with = "no_overwrite")]
+    pub overwrite: bool,
+
+    /// Fail if the remuxed output file already exists. This is the default
+    #[clap(long, value_parser, overrides_with = "overwrite")]
+    pub no_overwrite: bool,
+
     /// Don't remux streams to mp4 after download
     #[clap(long, value_parser)]
     pub no_remux: bool,
 
+    /// Write an ffmpeg concat list of the saved segments of each stream with their durations,
+    /// e.g. main.ffconcat, into the output directory, to remux them later with custom ffmpeg
+    /// options
+    #[clap(long, value_parser, conflicts_with = "preallocate")]
+    pub write_concat_lists: bool,
+
+    /// Also append the segments of the main stream in order to the MPEG-TS file at PATH as they
+    /// are saved, so that other programs can read it while it grows, e.g. with tail -f. Segments
+    /// missing for too long are skipped
+    #[clap(
+        long,
+        value_parser,
+        value_name = "PATH",
+        value_hint = clap::ValueHint::FilePath,
+        conflicts_with = "preallocate"
+    )]
+    pub append_ts: Option<PathBuf>,
+
+    /// Convert segments that aren't MPEG-TS, e.g. fMP4, with ffmpeg before appending them to
+    /// --append-ts. Without it, appending stops at the first such segment
+    #[clap(long, value_parser, requires = "append-ts")]
+    pub append_ts_transcode: bool,
+
     /// Show interactive stream picker. If not specified, automatically choose highest bitrate
     /// stream
     #[clap(long, value_parser)]
     pub choose_stream: bool,
+
+    /// Select a variant matching the one saved in the output directory by an earlier run into
+    /// the same directory
+    #[clap(long, value_parser, requires = "output")]
+    pub pin_variant: bool,
+
+    /// Only download the stream with this identifier, e.g. main or audio_stereo_English
+    #[clap(long, value_parser, value_name = "ID")]
+    pub stream: Option<String>,
+
+    /// Maximum number of alternative renditions to download. Renditions marked as default are
+    /// preferred, then renditions in preferred languages, then earlier renditions in the playlist
+    #[clap(long, alias = "max-alternatives", value_parser, value_name = "COUNT")]
+    pub max_renditions: Option<usize>,
+
+    /// Maximum number of alternative renditions of each type (audio, video and subtitles) to
+    /// download, preferred like with --max-renditions
+    #[clap(
+        long,
+        alias = "max-alternatives-per-type",
+        value_parser,
+        value_name = "COUNT"
+    )]
+    pub max_renditions_per_type: Option<usize>,
+
+    /// Preferred languages of alternative renditions, e.g. en,fr
+    #[clap(long, value_parser, value_name = "LANG", use_value_delimiter = true)]
+    pub prefer_lang: Vec<String>,
+
+    /// Only download the audio renditions with the most channels, or with exactly COUNT channels,
+    /// e.g. max or 6
+    #[clap(long, value_parser, value_name = "max|COUNT")]
+    pub audio_channels: Option<AudioChannels>,
+
+    /// Download audio renditions of this codec if the master playlist has them, e.g. ac-3, ec-3
+    /// or mp4a
+    #[clap(long, value_parser, value_name = "CODEC")]
+    pub audio_codec: Option<String>,
+
+    /// Also download audio renditions describing the video for the visually impaired, which are
+    /// skipped otherwise. Forced subtitles are always kept with renditions of their language
+    #[clap(long, value_parser)]
+    pub include_audio_description: bool,
+
+    /// Re-fetch the master playlist every DURATION to download renditions added during the
+    /// download and stop downloading removed renditions, e.g. 30 or 1m
+    #[clap(long, value_parser = parse_duration, value_name = "DURATION")]
+    pub refresh_master: Option<Duration>,
+
+    /// Stop starting new segment downloads after DURATION, then remux the downloaded segments,
+    /// e.g. 90m or 1h30m
+    #[clap(long, value_parser = parse_duration, value_name = "DURATION")]
+    pub max_runtime: Option<Duration>,
+
+    /// Only download segments up to DURATION of media per stream, e.g. 600 or 10m
+    #[clap(long, value_parser = parse_duration, value_name = "DURATION")]
+    pub max_duration: Option<Duration>,
+
+    /// Stop starting new segment downloads once SIZE bytes have been downloaded, e.g. 500M or 2GiB
+    #[clap(long, value_parser = parse_size, value_name = "SIZE")]
+    pub max_filesize: Option<u64>,
+
+    /// Only download segments in these discontinuity windows, given as discontinuity sequence
+    /// numbers N, ranges N-M or open ranges N-, e.g. 1,3-4. Output is cut at discontinuities
+    #[clap(long, value_parser, value_name = "WINDOW", use_value_delimiter = true)]
+    pub discon_window: Vec<DisconRange>,
+
+    /// Only download the newest LIMIT of the segments listed when the playlist is first fetched,
+    /// given as a duration or a number of segments, e.g. 30m or 20seg. Segments added later are
+    /// all downloaded
+    #[clap(long, value_parser, value_name = "LIMIT")]
+    pub backlog_limit: Option<BacklogLimit>,
+
+    /// When segments of the main and video streams wait more than 3 seconds for a download
+    /// worker, defer downloads of subtitles and audio renditions other than the default one
+    /// until they caught up. Deferred segments that leave the playlist in the meantime become
+    /// gaps
+    #[clap(long, value_parser)]
+    pub prioritize_main: bool,
+
+    /// Skip ad breaks inserted as discontinuities, e.g. by Twitch. Only discontinuities that
+    /// start with a stitched ad EXT-X-DATERANGE or EXT-X-CUE-OUT are skipped, for at most 3
+    /// minutes each
+    #[clap(long, value_parser)]
+    pub skip_ads: bool,
+
+    /// Check media playlists for violations of the HLS specification that are tolerated, like
+    /// a missing EXT-X-ENDLIST in VODs, EXTINF longer than EXT-X-TARGETDURATION, media sequence
+    /// gaps or a too low EXT-X-VERSION, and report them once the download ends
+    #[clap(long, value_parser)]
+    pub lint_playlist: bool,
+
+    /// Once the download ends or is stopped, download segments that failed once more, one at a
+    /// time with three times the timeout. Useful when origins keep segments after they left the
+    /// live window
+    #[clap(long, value_parser)]
+    pub repair_pass: bool,
+
+    /// Skip segments whose URL and content match a recently saved segment. Segments whose URL
+    /// was reused for different content are kept and recorded in the journal
+    #[clap(long, value_parser)]
+    pub dedupe_by_content: bool,
+
+    /// Name segment files descriptively, e.g. segment_main_d0000000000s0000000042.ts, or
+    /// sequentially by a counter per stream in playlist order, e.g. main_000001.ts. Sequentially
+    /// named segments can't be resumed
+    #[clap(
+        long,
+        value_parser,
+        value_name = "NAMING",
+        default_value = "descriptive",
+        conflicts_with = "resume"
+    )]
+    pub segment_naming: SegmentNaming,
+
+    /// Name of the segments directory in the output directory: url names it after a hash of the
+    /// playlist URL without its query, e.g. segments-5f3a9c0e1d2b4a67, so that downloads of
+    /// different playlists into the same output directory don't mix their segments, run names it
+    /// after a random ID of this run, and shared uses segments/ for every download
+    #[clap(long, value_parser, value_name = "NAMING", default_value = "url")]
+    pub segments_dir_naming: SegmentsDirNaming,
+
+    /// Also cut the remuxed output where codecs or resolution change at a new initialization
+    /// section. Output is always cut at discontinuities
+    #[clap(long, value_parser)]
+    pub split_on_codec_change: bool,
+
+    /// Container format of the remuxed output: mp4, mkv or ts. Subtitles that can't be embedded
+    /// into the container are written next to it as .srt files, e.g. video.en.srt
+    #[clap(
+        long,
+        value_parser,
+        value_name = "CONTAINER",
+        default_value = "mp4",
+        conflicts_with = "no-remux"
+    )]
+    pub container: Container,
+
+    /// Start the remuxed output of each discontinuity at the first video keyframe, so that it
+    /// plays cleanly when the first segment doesn't start with one. Audio and video before that
+    /// keyframe are dropped, so the output starts slightly later than the downloaded media
+    #[clap(long, value_parser, conflicts_with = "no-remux")]
+    pub trim_to_keyframe: bool,
+
+    /// Re-encode video to H.264 while remuxing instead of copying it, to save space. Much slower
+    /// than remuxing and lossy. Audio and subtitles are still copied
+    #[clap(long, value_parser, conflicts_with = "no-remux")]
+    pub transcode: bool,
+
+    /// Scale video down to this resolution when transcoding, a height like 720p, keeping the
+    /// aspect ratio, or WIDTHxHEIGHT to fit within. Smaller videos aren't scaled up
+    #[clap(long, value_parser, value_name = "RESOLUTION", requires = "transcode")]
+    pub target_resolution: Option<TargetResolution>,
+
+    /// Average video bitrate when transcoding in bits per second, e.g. 2M or 800k. If not
+    /// specified, use constant quality instead
+    #[clap(
+        long,
+        value_parser = parse_bitrate,
+        value_name = "BITRATE",
+        requires = "transcode"
+    )]
+    pub target_bitrate: Option<u64>,
+
+    /// Add a subtitle track to the remuxed output announcing each gap left by segments that
+    /// failed to download, with the time and amount of lost content and the reason. Outputs that
+    /// can't hold subtitles get a .gaps.srt sidecar instead
+    #[clap(long, value_parser, conflicts_with_all = &["no-remux", "preallocate"])]
+    pub embed_gap_report: bool,
+
+    /// Extract the CEA-608 closed captions of the remuxed output into .srt sidecars named
+    /// `{stem}.{lang}.CC1.srt`, from the caption channels the selected variant declares with its
+    /// CLOSED-CAPTIONS attribute. Only CC1 and CC3 can be extracted
+    #[clap(long, value_parser, conflicts_with = "no-remux")]
+    pub extract_captions: bool,
+
+    /// Mark the audio rendition in this language as the default audio track of the remuxed
+    /// output, e.g. fr. All audio renditions are kept as separate tracks either way
+    #[clap(long, value_parser, value_name = "LANG", conflicts_with = "no-remux")]
+    pub default_audio: Option<String>,
+
+    /// Move the remuxed output to `TITLE (YEAR)/TITLE.mp4` in the output directory with a .nfo
+    /// file describing it, the layout media servers like Jellyfin and Kodi expect. YEAR is taken
+    /// from the first program date time, or the current date without one
+    #[clap(long, value_name = "TITLE", value_parser, conflicts_with = "no-remux")]
+    pub library_title: Option<String>,
+
+    /// Name of the remuxed output relative to the output directory, without extension. May
+    /// contain subdirectories and the placeholders {date}, {host}, {title} (name of the playlist
+    /// file) and {quality}. Existing files are not overwritten unless --overwrite is given,
+    /// _1, _2, ... is appended to the name instead
+    #[clap(
+        long,
+        value_name = "TEMPLATE",
+        value_parser,
+        conflicts_with_all = &["no-remux", "library-title"]
+    )]
+    pub remux_name: Option<NameTemplate>,
+
+    /// Write subtitles to .srt sidecars named by this template instead of embedding them, relative
+    /// to the directory of the remuxed output and without extension, e.g. `subs/{stem}.{lang}`.
+    /// {stem} is the name of the remuxed output and {lang} the language of the subtitles, or their
+    /// name without one. Subtitles that can't be embedded into the container are written to
+    /// `{stem}.{lang}.srt` without it. Forced subtitles get .forced before the extension
+    #[clap(
+        long,
+        value_name = "TEMPLATE",
+        value_parser,
+        conflicts_with_all = &["no-remux", "library-title"]
+    )]
+    pub subtitle_sidecar_template: Option<SidecarTemplate>,
+
+    /// Use the EXT-X-RENDITION-REPORT tags of low-latency playlists to fetch the playlists of
+    /// streams that lag behind the others again sooner, keeping them in sync at the live edge
+    #[clap(long, value_parser)]
+    pub follow_rendition_reports: bool,
+
+    /// Fail the download if the playlist of an alternative rendition can't be fetched or parsed,
+    /// instead of dropping the rendition and downloading the other streams
+    #[clap(long, value_parser)]
+    pub strict_renditions: bool,
+
+    /// Check continuity counters of saved MPEG-TS segments and report packets that were likely
+    /// dropped or corrupted
+    #[clap(long, value_parser)]
+    pub validate_ts: bool,
+
+    /// Save the AES-128 keys and IVs that decrypted segments to keys.json in the output
+    /// directory, with the URI each key was fetched from, to decrypt the stream again with other
+    /// tools. Keys and IVs are hex encoded
+    #[clap(long, value_parser)]
+    pub save_keys: bool,
+
+    /// Write segment files in place instead of to a .part file that is renamed once complete.
+    /// Faster on some disks, but a crash can leave a truncated segment that --resume keeps
+    #[clap(long, value_parser)]
+    pub no_part_files: bool,
+
+    /// Keep segments in memory for up to DURATION if the segments directory disappears, e.g.
+    /// while a USB disk or network mount reconnects, and write them once it's back. At most
+    /// --max-in-flight-bytes are kept before downloads wait for the directory. Without it, segments
+    /// fail as soon as they can't be written
+    #[clap(long, value_parser = parse_duration, value_name = "DURATION")]
+    pub output_grace: Option<Duration>,
+
+    /// For finished VODs, write segments directly into one preallocated file per stream instead
+    /// of separate segment files. Falls back to segment files if the size of a segment is unknown
+    #[clap(
+        long,
+        value_parser,
+        conflicts_with_all = &[
+            "resume",
+            "refresh-master",
+            "max-runtime",
+            "max-duration",
+            "max-filesize",
+            "backlog-limit",
+            "split-on-codec-change",
+            "validate-ts",
+            "discon-window",
+            "dedupe-by-content",
+        ]
+    )]
+    pub preallocate: bool,
+
+    /// Measure the stream for DURATION instead of recording it, e.g. 10m. Segments are
+    /// downloaded and discarded, nothing is written to disk. Prints bitrates, segment durations,
+    /// playlist refresh cadence, discontinuities and errors of each stream
+    #[clap(
+        long,
+        value_parser = parse_duration,
+        value_name = "DURATION",
+        conflicts_with_all = &[
+            "output",
+            "resume",
+            "preallocate",
+            "library-title",
+            "remux-name",
+        ]
+    )]
+    pub measure: Option<Duration>,
+
+    /// Also write the measurement of --measure to FILE as JSON
+    #[clap(long, value_parser, value_name = "FILE", requires = "measure")]
+    pub measure_json: Option<PathBuf>,
+
+    /// Append download events (segments requested, downloaded and failed, retries, keys
+    /// fetched, playlist refreshes, ended streams) to FILE as JSON lines
+    #[clap(long, value_parser, value_name = "FILE")]
+    pub event_log: Option<PathBuf>,
+
+    /// Print the versions and options in effect as JSON, with secrets redacted, and exit
+    /// without downloading
+    #[clap(long, value_parser)]
+    pub print_effective_options: bool,
+
+    /// Print the media playlist URL of each selected stream as it would be polled, and exit
+    /// without downloading. FORMAT is text for one URL per line, or json for the URLs with their
+    /// streams, e.g. --print-selected-urls=json
+    #[clap(
+        long,
+        value_parser,
+        value_name = "FORMAT",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "text"
+    )]
+    pub print_selected_urls: Option<PrintFormat>,
+
+    /// Check that the segments currently listed by the playlists of the selected streams are
+    /// available with HEAD requests, print their number and size and exit without downloading.
+    /// Servers that don't support HEAD are asked for the first byte of each segment instead
+    #[clap(long, value_parser)]
+    pub check_segments: bool,
 }
 
 #[derive(Parser, Clone, Debug)]
 #[clap(help_heading = "NETWORK OPTIONS")]
 pub struct NetworkOptions {
-    /// Maximum number of times to retry network requests before giving up
+    /// Maximum number of times to retry network requests before giving up, 0 disables retries
     #[clap(long, value_parser, default_value_t = 10)]
     pub max_retries: u32,
 
-    /// Network requests timeout in seconds
+    /// Maximum number of attempts to download a segment. Failed segments are downloaded again
+    /// while playlist refreshes still list them, each attempt retries up to max-retries times
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u32).range(1..),
+        value_name = "ATTEMPTS",
+        default_value_t = 3
+    )]
+    pub max_attempts_per_segment: u32,
+
+    /// Number of times a stream whose playlist fetching failed is restarted before giving up on
+    /// it, waiting between restarts like between retries of a network request. Restarts continue
+    /// after the segments already downloaded
+    #[clap(long, value_parser, value_name = "RESTARTS", default_value_t = 0)]
+    pub retry_streams: u32,
+
+    /// Minimum delay between retries of a failed network request
+    #[clap(
+        long,
+        alias = "retry-min",
+        value_parser = parse_duration,
+        value_name = "DURATION",
+        default_value = "1"
+    )]
+    pub retry_min_delay: Duration,
+
+    /// Maximum delay between retries of a failed network request
+    #[clap(
+        long,
+        alias = "retry-max",
+        value_parser = parse_duration,
+        value_name = "DURATION",
+        default_value = "10"
+    )]
+    pub retry_max_delay: Duration,
+
+    /// Growth factor of the delay between consecutive retries
+    #[clap(long, alias = "retry-exponent", value_parser, default_value_t = 2)]
+    pub retry_backoff_exponent: u32,
+
+    /// Network requests timeout, e.g. 300, 30s or 5m
     #[clap(
         short,
         long,
-        value_parser,
-        value_name = "SECONDS",
-        default_value_t = 300
+        value_parser = parse_duration,
+        value_name = "DURATION",
+        default_value = "300"
     )]
-    pub timeout: u64,
+    pub timeout: Duration,
 
-    /// Maximum number of concurrent downloads
+    /// Maximum number of concurrent downloads, at most 64 unless --i-know-what-im-doing is given
     #[clap(short = 'j', long, value_parser, default_value_t = 20)]
     pub max_concurrent_downloads: usize,
 
+    /// Allow more than 64 concurrent downloads. So many connections can get the client banned by
+    /// CDNs and exhaust file descriptors
+    #[clap(long, value_parser)]
+    pub i_know_what_im_doing: bool,
+
+    /// Maximum size of segments held in memory at once, e.g. 64M. If not specified, unlimited
+    #[clap(long, value_parser = parse_size, value_name = "SIZE")]
+    pub max_in_flight_bytes: Option<u64>,
+
+    /// Abort downloads of segments larger than SIZE, e.g. 512M. Protects memory from servers
+    /// sending an unbounded body, such as a live stream mislabeled as a single segment
+    #[clap(long, value_parser = parse_size, value_name = "SIZE", default_value = "1GiB")]
+    pub max_segment_size: u64,
+
+    /// Follow the redirect of every segment URL. By default, once consecutive segments of a
+    /// directory were redirected to the same directory and query, e.g. a signed CDN URL, later
+    /// segments are requested from there directly. A directory is no longer rewritten after such
+    /// a request fails
+    #[clap(long, value_parser)]
+    pub no_redirect_cache: bool,
+
+    /// Request only the first few KiB of the playlist with a Range header to tell master from
+    /// media playlists. Saves downloading a large media playlist twice at startup, the whole
+    /// playlist is fetched if it's a master playlist or the server ignores the Range header
+    #[clap(long, value_parser)]
+    pub probe_playlist_range: bool,
+
     /// Use cookies, path to cookies file in Netscape format
     #[clap(short, long, value_parser, value_hint = clap::ValueHint::FilePath)]
     pub cookies: Option<PathBuf>,
@@ -64,8 +560,1574 @@ pub struct NetworkOptions {
     #[clap(short = 'q', long, value_parser)]
     pub copy_query: bool,
 
+    /// Send the origin of m3u8_url as Referer with segment and key requests, or the origin of
+    /// PAGE_URL if given, e.g. --referer-from-url=https://example.com/watch
+    #[clap(
+        long,
+        value_parser,
+        value_name = "PAGE_URL",
+        min_values = 0,
+        require_equals = true
+    )]
+    pub referer_from_url: Option<Option<Url>>,
+
+    /// Connect to CONNECT_HOST instead of HOST for requests to HOST on any port. TLS and the Host
+    /// header still use HOST, and CONNECT_HOST is resolved again for each new connection. Unlike
+    /// curl's --connect-to, ports can't be given. Can be given multiple times
+    #[clap(long, value_parser, value_name = "HOST:CONNECT_HOST")]
+    pub connect_host: Vec<ConnectHost>,
+
+    /// Use IP for all requests to HOST instead of resolving it, like curl's --resolve.
+    /// IPv6 addresses may be given in brackets. Can be given multiple times
+    #[clap(long, value_parser, value_name = "HOST:IP")]
+    pub resolve: Vec<Resolve>,
+
+    /// Record these response headers of each segment in the journal and event log,
+    /// e.g. x-cache,cf-ray,age,server
+    #[clap(long, value_parser, value_name = "HEADER", use_value_delimiter = true)]
+    pub capture_response_headers: Vec<HeaderName>,
+
+    /// Send headers claiming that requests are forwarded for a client in COUNTRY, one of us, gb,
+    /// de, fr, jp or kr: X-Forwarded-For and X-Real-IP with an address there, and CF-IPCountry.
+    /// This only gets past servers that trust these headers, it doesn't change the location of
+    /// your IP address
+    #[clap(long, value_parser, value_name = "COUNTRY")]
+    pub geo_bypass_headers: Option<GeoBypass>,
+
     /// By default, every TLS connection is verified to be secure.
     /// This option allows livestream-dl to skip verification and proceed without checking.
     #[clap(short = 'k', long, value_parser)]
     pub insecure: bool,
 }
+
+#[cfg(feature = "s3")]
+#[derive(Parser, Clone, Debug)]
+#[clap(help_heading = "UPLOAD OPTIONS")]
+pub struct UploadOptions {
+    /// Upload segments and remuxed output to an S3 bucket, e.g. s3://bucket/prefix/
+    #[clap(
+        long,
+        value_parser,
+        value_name = "S3_URL",
+        conflicts_with = "preallocate"
+    )]
+    pub upload_s3: Option<String>,
+
+    /// Endpoint of an S3-compatible service. If not specified, use AWS_ENDPOINT_URL or AWS
+    #[clap(long, value_parser, value_hint = clap::ValueHint::Url)]
+    pub s3_endpoint: Option<Url>,
+
+    /// Maximum number of concurrent uploads
+    #[clap(long, value_parser, default_value_t = 4)]
+    pub max_concurrent_uploads: usize,
+
+    /// Delete local files after they are uploaded
+    #[clap(long, value_parser, requires = "upload-s3")]
+    pub upload_then_delete: bool,
+}
+
+/// Mapping of a host to the host that should be connected to instead, on any port
+#[derive(Clone, Debug)]
+pub struct ConnectHost {
+    pub host: String,
+    pub connect_host: String,
+}
+
+impl FromStr for ConnectHost {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = |reason: &str| format!("invalid connect-host mapping {:?}: {}", s, reason);
+
+        // Split on colons outside of IPv6 brackets
+        let mut parts = Vec::new();
+        let mut depth = 0;
+        let mut start = 0;
+        for (i, c) in s.char_indices() {
+            match c {
+                '[' => depth += 1,
+                ']' => depth -= 1,
+                ':' if depth == 0 => {
+                    parts.push(&s[start..i]);
+                    start = i + 1;
+                }
+                _ => (),
+            }
+        }
+        parts.push(&s[start..]);
+
+        let (host, connect_host) =
+            match parts.as_slice() {
+                [h, ch] => (*h, *ch),
+                [_, _, _, _] => return Err(err(
+                    "expected HOST:CONNECT_HOST, ports can't be given as the mapping applies to \
+                     all ports",
+                )),
+                _ => return Err(err("expected HOST:CONNECT_HOST")),
+            };
+
+        let unbracket = |h: &str| h.trim_start_matches('[').trim_end_matches(']').to_owned();
+        let mapping = Self {
+            host: unbracket(host),
+            connect_host: unbracket(connect_host),
+        };
+        if mapping.host.is_empty() || mapping.connect_host.is_empty() {
+            return Err(err("HOST and CONNECT_HOST must not be empty"));
+        }
+
+        Ok(mapping)
+    }
+}
+
+/// Hostname that should resolve to a fixed address
+#[derive(Clone, Debug)]
+pub struct Resolve {
+    pub host: String,
+    pub addr: IpAddr,
+}
+
+impl FromStr for Resolve {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = |reason: &str| format!("invalid resolve mapping {:?}: {}", s, reason);
+
+        // Hostnames can't contain colons, so the address starts after the first one
+        let (host, addr) = s.split_once(':').ok_or_else(|| err("expected HOST:IP"))?;
+        if host.is_empty() {
+            return Err(err("HOST must not be empty"));
+        }
+        let addr = addr.trim_start_matches('[').trim_end_matches(']');
+        let addr = addr
+            .parse()
+            .map_err(|_| err(&format!("invalid IP address {:?}", addr)))?;
+
+        Ok(Self {
+            host: host.to_owned(),
+            addr,
+        })
+    }
+}
+
+/// Inclusive range of discontinuity sequence numbers, open ended if end is None
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DisconRange {
+    pub start: u64,
+    pub end: Option<u64>,
+}
+
+impl DisconRange {
+    pub fn contains(&self, discon_seq: u64) -> bool {
+        discon_seq >= self.start && self.end.is_none_or(|e| discon_seq <= e)
+    }
+}
+
+impl FromStr for DisconRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = |reason: &str| format!("invalid discontinuity window {:?}: {}", s, reason);
+        let parse = |n: &str| {
+            n.trim()
+                .parse()
+                .map_err(|_| err(&format!("invalid discontinuity sequence {:?}", n)))
+        };
+
+        let range = match s.split_once('-') {
+            Some((start, "")) => Self {
+                start: parse(start)?,
+                end: None,
+            },
+            Some((start, end)) => Self {
+                start: parse(start)?,
+                end: Some(parse(end)?),
+            },
+            None => {
+                let n = parse(s)?;
+                Self {
+                    start: n,
+                    end: Some(n),
+                }
+            }
+        };
+        if range.end.is_some_and(|e| e < range.start) {
+            return Err(err("end is before start"));
+        }
+
+        Ok(range)
+    }
+}
+
+/// Limit on the segments listed by the first fetch of a playlist that are downloaded, either a
+/// duration or a number of segments
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use livestream_dl::cli::BacklogLimit;
+///
+/// assert_eq!("30m".parse(), Ok(BacklogLimit::Duration(Duration::from_secs(1800))));
+/// assert_eq!("20seg".parse(), Ok(BacklogLimit::Segments(20)));
+/// assert_eq!("20 segments".parse(), Ok(BacklogLimit::Segments(20)));
+/// assert!("20x".parse::<BacklogLimit>().is_err());
+///
+/// // The newest segments are kept
+/// let durations = [6.0, 6.0, 6.0, 6.0, 4.0];
+/// assert_eq!(BacklogLimit::Duration(Duration::from_secs(10)).first_kept(&durations), 3);
+/// assert_eq!(BacklogLimit::Duration(Duration::from_secs(9)).first_kept(&durations), 4);
+/// assert_eq!(BacklogLimit::Duration(Duration::from_secs(0)).first_kept(&durations), 5);
+/// assert_eq!(BacklogLimit::Duration(Duration::from_secs(60)).first_kept(&durations), 0);
+/// assert_eq!(BacklogLimit::Segments(2).first_kept(&durations), 3);
+/// assert_eq!(BacklogLimit::Segments(0).first_kept(&durations), 5);
+/// assert_eq!(BacklogLimit::Segments(10).first_kept(&durations), 0);
+/// ```
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BacklogLimit {
+    Duration(Duration),
+    Segments(u64),
+}
+
+impl BacklogLimit {
+    /// Index of the first of the newest segments with these durations that fit in the limit
+    pub fn first_kept(&self, durations: &[f32]) -> usize {
+        match *self {
+            Self::Duration(limit) => {
+                let mut total = 0.0;
+                let kept = durations
+                    .iter()
+                    .rev()
+                    .take_while(|d| {
+                        total += **d as f64;
+                        total <= limit.as_secs_f64()
+                    })
+                    .count();
+                durations.len() - kept
+            }
+            Self::Segments(n) => durations.len().saturating_sub(n as usize),
+        }
+    }
+}
+
+impl FromStr for BacklogLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let count = s
+            .strip_suffix("segments")
+            .or_else(|| s.strip_suffix("seg"))
+            .map(str::trim);
+        match count {
+            Some(n) => n
+                .parse()
+                .map(Self::Segments)
+                .map_err(|_| format!("invalid segment count {:?}", n)),
+            None => parse_duration(s).map(Self::Duration),
+        }
+    }
+}
+
+/// Template of the name of remuxed outputs
+///
+/// ```
+/// use livestream_dl::cli::{NameTemplate, NameValues};
+///
+/// let values = NameValues {
+///     date: "20220101",
+///     host: "cdn.example.com",
+///     title: "live",
+///     quality: "720p",
+/// };
+/// let template: NameTemplate = "{host}/{date}_{title}_{quality}".parse().unwrap();
+/// assert_eq!(
+///     template.render(&values).unwrap(),
+///     std::path::Path::new("cdn.example.com/20220101_live_720p")
+/// );
+///
+/// assert!("{resolution}".parse::<NameTemplate>().is_err());
+/// assert!("{title".parse::<NameTemplate>().is_err());
+/// assert!("what?".parse::<NameTemplate>().is_err());
+/// assert!("../{title}".parse::<NameTemplate>().is_err());
+///
+/// // Substituted values must be usable in file names too
+/// let template: NameTemplate = "{title}".parse().unwrap();
+/// let values = NameValues { title: "a: b", ..values };
+/// assert!(template.render(&values).is_err());
+/// ```
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct NameTemplate(String);
+
+/// Values of the placeholders of a NameTemplate
+#[derive(Clone, Copy, Debug)]
+pub struct NameValues<'a> {
+    pub date: &'a str,
+    pub host: &'a str,
+    pub title: &'a str,
+    pub quality: &'a str,
+}
+
+impl NameTemplate {
+    /// Names of placeholders, written as {name}
+    pub const PLACEHOLDERS: &'static [&'static str] = &["date", "host", "title", "quality"];
+
+    /// Substitute values for placeholders, errors if the result is not a relative path of valid
+    /// file names
+    pub fn render(&self, values: &NameValues) -> Result<PathBuf, String> {
+        let mut name = String::new();
+        parse_template(&self.0, Self::PLACEHOLDERS, |part| match part {
+            TemplatePart::Literal(l) => name.push_str(l),
+            TemplatePart::Placeholder("date") => name.push_str(values.date),
+            TemplatePart::Placeholder("host") => name.push_str(values.host),
+            TemplatePart::Placeholder("title") => name.push_str(values.title),
+            TemplatePart::Placeholder(_) => name.push_str(values.quality),
+        })?;
+        check_relative_path(&name)?;
+        Ok(PathBuf::from(name))
+    }
+}
+
+impl FromStr for NameTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        check_template(s, Self::PLACEHOLDERS)?;
+        Ok(Self(s.to_string()))
+    }
+}
+
+/// Template of the path of subtitle sidecars relative to the directory of the remuxed output,
+/// without extension
+///
+/// ```
+/// use livestream_dl::cli::{SidecarTemplate, SidecarValues};
+///
+/// let values = SidecarValues {
+///     stem: "Show.S01E01",
+///     lang: "en",
+/// };
+/// let template = SidecarTemplate::default();
+/// assert_eq!(
+///     template.render(&values).unwrap(),
+///     std::path::Path::new("Show.S01E01.en")
+/// );
+/// let template: SidecarTemplate = "subs/{lang}/{stem}".parse().unwrap();
+/// assert_eq!(
+///     template.render(&values).unwrap(),
+///     std::path::Path::new("subs/en/Show.S01E01")
+/// );
+///
+/// assert!("{title}.{lang}".parse::<SidecarTemplate>().is_err());
+/// assert!("../{stem}.{lang}".parse::<SidecarTemplate>().is_err());
+/// ```
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SidecarTemplate(String);
+
+/// Values of the placeholders of a SidecarTemplate
+#[derive(Clone, Copy, Debug)]
+pub struct SidecarValues<'a> {
+    pub stem: &'a str,
+    pub lang: &'a str,
+}
+
+impl SidecarTemplate {
+    /// Names of placeholders, written as {name}
+    pub const PLACEHOLDERS: &'static [&'static str] = &["stem", "lang"];
+
+    /// Substitute values for placeholders, errors if the result is not a relative path of valid
+    /// file names
+    pub fn render(&self, values: &SidecarValues) -> Result<PathBuf, String> {
+        let mut name = String::new();
+        parse_template(&self.0, Self::PLACEHOLDERS, |part| match part {
+            TemplatePart::Literal(l) => name.push_str(l),
+            TemplatePart::Placeholder("stem") => name.push_str(values.stem),
+            TemplatePart::Placeholder(_) => name.push_str(values.lang),
+        })?;
+        check_relative_path(&name)?;
+        Ok(PathBuf::from(name))
+    }
+}
+
+/// Sidecars are named after the video they belong to, e.g. video.en
+impl Default for SidecarTemplate {
+    fn default() -> Self {
+        Self("{stem}.{lang}".into())
+    }
+}
+
+impl FromStr for SidecarTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        check_template(s, Self::PLACEHOLDERS)?;
+        Ok(Self(s.to_string()))
+    }
+}
+
+/// Literal text or placeholder name of a template
+enum TemplatePart<'a> {
+    Literal(&'a str),
+    Placeholder(&'a str),
+}
+
+/// Split template into literals and placeholder names, errors on unclosed placeholders and
+/// placeholders not in placeholders
+fn parse_template<'a>(
+    template: &'a str,
+    placeholders: &[&str],
+    mut f: impl FnMut(TemplatePart<'a>),
+) -> Result<(), String> {
+    let mut rest = template;
+    while let Some(start) = rest.find(['{', '}']) {
+        if rest[start..].starts_with('}') {
+            return Err(format!("unmatched }} in {:?}", template));
+        }
+        f(TemplatePart::Literal(&rest[..start]));
+        let len = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("unclosed {{ in {:?}", template))?;
+        let placeholder = &rest[start + 1..start + len];
+        if !placeholders.contains(&placeholder) {
+            return Err(format!(
+                "unknown placeholder {{{}}}, expected one of {}",
+                placeholder,
+                placeholders
+                    .iter()
+                    .map(|p| format!("{{{}}}", p))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+        f(TemplatePart::Placeholder(placeholder));
+        rest = &rest[start + len + 1..];
+    }
+    f(TemplatePart::Literal(rest));
+    Ok(())
+}
+
+/// Check the literal parts of template, placeholders may still expand to invalid names
+fn check_template(template: &str, placeholders: &[&str]) -> Result<(), String> {
+    let mut literal = String::new();
+    parse_template(template, placeholders, |part| match part {
+        TemplatePart::Literal(l) => literal.push_str(l),
+        TemplatePart::Placeholder(p) => literal.push_str(&format!("{{{}}}", p)),
+    })?;
+    check_relative_path(&literal)
+}
+
+/// Check that path is relative and its components are valid file names on common file systems
+fn check_relative_path(path: &str) -> Result<(), String> {
+    const RESERVED_CHARS: &[char] = &['<', '>', ':', '"', '\\', '|', '?', '*'];
+
+    if path.starts_with('/') {
+        return Err(format!(
+            "{:?} must be relative to the output directory",
+            path
+        ));
+    }
+    for name in path.split('/') {
+        if name.is_empty() || name == "." || name == ".." {
+            return Err(format!(
+                "{:?} contains an invalid file name {:?}",
+                path, name
+            ));
+        }
+        if let Some(c) = name
+            .chars()
+            .find(|c| c.is_control() || RESERVED_CHARS.contains(c))
+        {
+            return Err(format!("{:?} contains invalid character {:?}", path, c));
+        }
+        if name.ends_with(['.', ' ']) {
+            return Err(format!(
+                "{:?} contains file name {:?} ending in a dot or space",
+                path, name
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Channel count of audio renditions to download
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AudioChannels {
+    Max,
+    Count(u32),
+}
+
+impl FromStr for AudioChannels {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "max" => Ok(Self::Max),
+            _ => s
+                .parse()
+                .map(Self::Count)
+                .map_err(|_| format!("invalid channel count {:?}, expected max or a number", s)),
+        }
+    }
+}
+
+/// Naming of saved segment files
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SegmentNaming {
+    Descriptive,
+    Sequential,
+}
+
+impl FromStr for SegmentNaming {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "descriptive" => Ok(Self::Descriptive),
+            "sequential" => Ok(Self::Sequential),
+            _ => Err(format!(
+                "invalid segment naming {:?}, expected descriptive or sequential",
+                s
+            )),
+        }
+    }
+}
+
+/// Naming of the segments directory in the output directory
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SegmentsDirNaming {
+    Shared,
+    Url,
+    Run,
+}
+
+impl FromStr for SegmentsDirNaming {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "shared" => Ok(Self::Shared),
+            "url" => Ok(Self::Url),
+            "run" => Ok(Self::Run),
+            _ => Err(format!(
+                "invalid segments directory naming {:?}, expected shared, url or run",
+                s
+            )),
+        }
+    }
+}
+
+/// Format of information printed instead of downloading
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PrintFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for PrintFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("invalid format {:?}, expected text or json", s)),
+        }
+    }
+}
+
+/// Container format of remuxed output
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Container {
+    Mp4,
+    Mkv,
+    Ts,
+}
+
+impl Container {
+    /// File extension of the container, without dot
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Mp4 => "mp4",
+            Self::Mkv => "mkv",
+            Self::Ts => "ts",
+        }
+    }
+}
+
+impl FromStr for Container {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mp4" => Ok(Self::Mp4),
+            "mkv" => Ok(Self::Mkv),
+            "ts" => Ok(Self::Ts),
+            _ => Err(format!(
+                "invalid container {:?}, expected mp4, mkv or ts",
+                s
+            )),
+        }
+    }
+}
+
+/// Country to claim in headers with --geo-bypass-headers
+///
+/// ```
+/// use livestream_dl::cli::GeoBypass;
+///
+/// let headers = "gb".parse::<GeoBypass>().unwrap().headers();
+/// assert_eq!(headers["x-forwarded-for"], "86.0.0.1");
+/// assert_eq!(headers["x-real-ip"], "86.0.0.1");
+/// assert_eq!(headers["cf-ipcountry"], "GB");
+/// assert!("uk".parse::<GeoBypass>().is_ok());
+/// assert!("nl".parse::<GeoBypass>().is_err());
+/// ```
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GeoBypass {
+    Us,
+    Gb,
+    De,
+    Fr,
+    Jp,
+    Kr,
+}
+
+impl GeoBypass {
+    /// ISO 3166-1 code of the country and an address of a large residential ISP there
+    fn country(&self) -> (&'static str, &'static str) {
+        match self {
+            Self::Us => ("US", "24.0.0.1"),
+            Self::Gb => ("GB", "86.0.0.1"),
+            Self::De => ("DE", "84.128.0.1"),
+            Self::Fr => ("FR", "90.0.0.1"),
+            Self::Jp => ("JP", "126.0.0.1"),
+            Self::Kr => ("KR", "121.128.0.1"),
+        }
+    }
+
+    /// Headers sent with every request
+    pub fn headers(&self) -> HeaderMap {
+        let (code, addr) = self.country();
+        let mut headers = HeaderMap::new();
+        for (name, value) in [
+            ("x-forwarded-for", addr),
+            ("x-real-ip", addr),
+            ("cf-ipcountry", code),
+        ] {
+            headers.insert(name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+}
+
+impl FromStr for GeoBypass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "us" => Ok(Self::Us),
+            "gb" | "uk" => Ok(Self::Gb),
+            "de" => Ok(Self::De),
+            "fr" => Ok(Self::Fr),
+            "jp" => Ok(Self::Jp),
+            "kr" => Ok(Self::Kr),
+            _ => Err(format!(
+                "invalid country {:?}, expected us, gb, de, fr, jp or kr",
+                s
+            )),
+        }
+    }
+}
+
+/// Parse a duration of at most [`MAX_DURATION`] from a number of seconds or a sequence of numbers
+/// with h, m, s or ms units, numbers may be fractional
+///
+/// ```
+/// use livestream_dl::cli::parse_duration;
+///
+/// let secs = |s: &str| parse_duration(s).map(|d| d.as_secs_f64());
+/// assert_eq!(secs("90"), Ok(90.0));
+/// assert_eq!(secs("90s"), Ok(90.0));
+/// assert_eq!(secs("5m"), Ok(300.0));
+/// assert_eq!(secs("1h30m"), Ok(5400.0));
+/// assert_eq!(secs("2.5h"), Ok(9000.0));
+/// assert_eq!(secs("1m 0.5s"), Ok(60.5));
+/// assert_eq!(secs("250ms"), Ok(0.25));
+/// assert!(parse_duration("-5s").is_err());
+/// assert!(parse_duration("1e30").is_err());
+/// assert!(parse_duration("400d").is_err());
+/// assert!(parse_duration("5x").is_err());
+/// assert!(parse_duration("1h30").is_err());
+/// assert!(parse_duration("").is_err());
+/// ```
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let err = |reason: &str| format!("invalid duration {:?}: {}", s, reason);
+
+    // Bare numbers are seconds
+    if let Ok(seconds) = s.trim().parse::<f64>() {
+        return duration_from_secs(seconds).map_err(err);
+    }
+
+    let mut rest = s.trim();
+    let mut seconds = 0.0;
+    if rest.is_empty() {
+        return Err(err("expected e.g. 90, 90s, 5m or 1h30m"));
+    }
+    while !rest.is_empty() {
+        let (value, unit, r) = split_number(rest).ok_or_else(|| err("expected a number"))?;
+        let factor = match unit {
+            "h" => 3600.0,
+            "m" => 60.0,
+            "s" => 1.0,
+            "ms" => 0.001,
+            "" => return Err(err("every number except a single one needs a unit")),
+            u => {
+                return Err(err(&format!(
+                    "unknown unit {:?}, expected h, m, s or ms",
+                    u
+                )))
+            }
+        };
+        seconds += non_negative(value).map_err(err)? * factor;
+        rest = r.trim_start();
+    }
+
+    duration_from_secs(seconds).map_err(err)
+}
+
+/// Longest duration accepted by [`parse_duration`], so that deadlines and intervals computed
+/// from durations can't overflow
+pub const MAX_DURATION: Duration = Duration::from_secs(365 * 24 * 3600);
+
+/// Duration of seconds if it is at most MAX_DURATION
+fn duration_from_secs(seconds: f64) -> Result<Duration, &'static str> {
+    match Duration::try_from_secs_f64(seconds) {
+        Ok(d) if d <= MAX_DURATION => Ok(d),
+        Ok(_) => Err("must be at most 365 days"),
+        Err(_) if seconds < 0.0 => Err("must not be negative"),
+        Err(_) if seconds.is_nan() => Err("must be a number"),
+        Err(_) => Err("must be at most 365 days"),
+    }
+}
+
+/// Parse a size in bytes from a number of bytes or a number with a decimal (k, M, G, T) or binary
+/// (Ki, Mi, Gi, Ti) suffix, optionally followed by B. Numbers with a suffix may be fractional
+///
+/// ```
+/// use livestream_dl::cli::parse_size;
+///
+/// assert_eq!(parse_size("1024"), Ok(1024));
+/// assert_eq!(parse_size("500k"), Ok(500_000));
+/// assert_eq!(parse_size("2M"), Ok(2_000_000));
+/// assert_eq!(parse_size("1.5G"), Ok(1_500_000_000));
+/// assert_eq!(parse_size("2MiB"), Ok(2 * 1024 * 1024));
+/// assert_eq!(parse_size("1.5Ki"), Ok(1536));
+/// assert_eq!(parse_size("10 MB"), Ok(10_000_000));
+/// assert!(parse_size("-1M").is_err());
+/// assert!(parse_size("-1").is_err());
+/// assert!(parse_size("1.5").is_err());
+/// assert!(parse_size("2X").is_err());
+/// ```
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let err = |reason: &str| format!("invalid size {:?}: {}", s, reason);
+
+    // Bare numbers are bytes
+    if let Ok(bytes) = s.trim().parse::<u64>() {
+        return Ok(bytes);
+    }
+
+    let (value, suffix, rest) = split_number(s.trim()).ok_or_else(|| err("expected a number"))?;
+    if !rest.trim().is_empty() {
+        return Err(err("unexpected text after the size"));
+    }
+    let value = non_negative(value).map_err(err)?;
+    let suffix = suffix.strip_suffix('B').unwrap_or(suffix);
+    let factor: u64 = match suffix {
+        "" => return Err(err("fractional sizes need a suffix, e.g. 1.5M")),
+        "k" | "K" => 1000,
+        "M" => 1000_u64.pow(2),
+        "G" => 1000_u64.pow(3),
+        "T" => 1000_u64.pow(4),
+        "Ki" => 1 << 10,
+        "Mi" => 1 << 20,
+        "Gi" => 1 << 30,
+        "Ti" => 1 << 40,
+        u => {
+            return Err(err(&format!(
+                "unknown suffix {:?}, expected k, M, G, T, Ki, Mi, Gi or Ti",
+                u
+            )))
+        }
+    };
+    let bytes = (value * factor as f64).round();
+    if bytes > u64::MAX as f64 {
+        return Err(err("too large"));
+    }
+
+    Ok(bytes as u64)
+}
+
+/// Parse a bitrate in bits per second from a number or a number with a k, M or G suffix, which
+/// may be fractional
+///
+/// ```
+/// use livestream_dl::cli::parse_bitrate;
+///
+/// assert_eq!(parse_bitrate("2M"), Ok(2_000_000));
+/// assert_eq!(parse_bitrate("800k"), Ok(800_000));
+/// assert_eq!(parse_bitrate("1.5M"), Ok(1_500_000));
+/// assert_eq!(parse_bitrate("2500000"), Ok(2_500_000));
+/// assert!(parse_bitrate("0").is_err());
+/// assert!(parse_bitrate("500").is_err());
+/// assert!(parse_bitrate("2MiB").is_err());
+/// ```
+pub fn parse_bitrate(s: &str) -> Result<u64, String> {
+    let err = |reason: &str| format!("invalid bitrate {:?}: {}", s, reason);
+
+    let (value, suffix, rest) = split_number(s.trim()).ok_or_else(|| err("expected a number"))?;
+    if !rest.trim().is_empty() {
+        return Err(err("unexpected text after the bitrate"));
+    }
+    let value = non_negative(value).map_err(err)?;
+    let factor = match suffix {
+        "" => 1.0,
+        "k" | "K" => 1e3,
+        "M" => 1e6,
+        "G" => 1e9,
+        u => return Err(err(&format!("unknown suffix {:?}, expected k, M or G", u))),
+    };
+    let bitrate = (value * factor).round();
+    if bitrate < MIN_TARGET_BITRATE as f64 {
+        return Err(err(&format!(
+            "must be at least {}k",
+            MIN_TARGET_BITRATE / 1000
+        )));
+    }
+    if bitrate > u64::MAX as f64 {
+        return Err(err("too large"));
+    }
+
+    Ok(bitrate as u64)
+}
+
+/// Lowest --target-bitrate, lower bitrates are most likely missing a suffix
+const MIN_TARGET_BITRATE: u64 = 10_000;
+
+/// Resolution to scale video down to when transcoding
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TargetResolution {
+    /// Height, keeping the aspect ratio
+    Height(u32),
+    /// Width and height to fit within, keeping the aspect ratio
+    Size(u32, u32),
+}
+
+impl TargetResolution {
+    /// Largest width or height accepted
+    const MAX: u32 = 16384;
+
+    /// ffmpeg video filter scaling video down to this resolution, to even dimensions as needed by
+    /// H.264
+    ///
+    /// ```
+    /// use livestream_dl::cli::TargetResolution;
+    ///
+    /// let height: TargetResolution = "720p".parse().unwrap();
+    /// assert_eq!(height, TargetResolution::Height(720));
+    /// assert_eq!(height.scale_filter(), "scale=-2:min(720\\,ih)");
+    ///
+    /// let size: TargetResolution = "1280x720".parse().unwrap();
+    /// assert_eq!(
+    ///     size.scale_filter(),
+    ///     "scale=min(1280\\,iw):min(720\\,ih):force_original_aspect_ratio=decrease:\
+    ///      force_divisible_by=2"
+    /// );
+    ///
+    /// assert!("721p".parse::<TargetResolution>().is_err());
+    /// assert!("0x720".parse::<TargetResolution>().is_err());
+    /// assert!("720".parse::<TargetResolution>().is_err());
+    /// ```
+    pub fn scale_filter(&self) -> String {
+        match self {
+            Self::Height(h) => format!("scale=-2:min({}\\,ih)", h),
+            Self::Size(w, h) => format!(
+                "scale=min({}\\,iw):min({}\\,ih):force_original_aspect_ratio=decrease:\
+                 force_divisible_by=2",
+                w, h
+            ),
+        }
+    }
+}
+
+impl FromStr for TargetResolution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = |reason: &str| format!("invalid resolution {:?}: {}", s, reason);
+        let dimension = |d: &str| match d.parse::<u32>() {
+            Ok(d) if d == 0 || d > Self::MAX => Err(err(&format!(
+                "dimensions must be between 1 and {}",
+                Self::MAX
+            ))),
+            Ok(d) if d % 2 != 0 => Err(err("dimensions must be even")),
+            Ok(d) => Ok(d),
+            Err(_) => Err(err("expected a height like 720p or WIDTHxHEIGHT")),
+        };
+
+        if let Some(h) = s.strip_suffix('p') {
+            Ok(Self::Height(dimension(h)?))
+        } else if let Some((w, h)) = s.split_once('x') {
+            Ok(Self::Size(dimension(w)?, dimension(h)?))
+        } else {
+            Err(err("expected a height like 720p or WIDTHxHEIGHT"))
+        }
+    }
+}
+
+/// Split s into a leading unsigned number, the unit following it and the rest
+fn split_number(s: &str) -> Option<(f64, &str, &str)> {
+    let number_end = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-' || c == '+'))
+        .unwrap_or(s.len());
+    let value = s[..number_end].parse().ok()?;
+    let rest = s[number_end..].trim_start();
+    let unit_end = rest
+        .find(|c: char| !c.is_ascii_alphabetic())
+        .unwrap_or(rest.len());
+    Some((value, &rest[..unit_end], &rest[unit_end..]))
+}
+
+fn non_negative(value: f64) -> Result<f64, &'static str> {
+    if !value.is_finite() {
+        Err("must be finite")
+    } else if value < 0.0 {
+        Err("must not be negative")
+    } else {
+        Ok(value)
+    }
+}
+
+/// Highest --max-concurrent-downloads allowed without --i-know-what-im-doing
+pub const MAX_CONCURRENT_DOWNLOADS: usize = 64;
+
+/// Shortest allowed --timeout
+pub const MIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Highest allowed --max-retries
+pub const MAX_RETRIES: u32 = 100;
+
+/// All problems found in options, reported together instead of stopping at the first
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use livestream_dl::cli::{DownloadOptions, NetworkOptions};
+///
+/// let rejected = [
+///     NetworkOptions::builder().max_concurrent_downloads(0).build(),
+///     NetworkOptions::builder().max_concurrent_downloads(500).build(),
+///     NetworkOptions::builder().timeout(Duration::ZERO).build(),
+///     NetworkOptions::builder().timeout(Duration::from_millis(500)).build(),
+///     NetworkOptions::builder().max_retries(1000).build(),
+///     NetworkOptions::builder().max_attempts_per_segment(0).build(),
+///     NetworkOptions::builder().retry_min_delay(Duration::from_secs(20)).build(),
+///     NetworkOptions::builder()
+///         .connect_host(vec!["a.com:b.com".parse().unwrap()])
+///         .resolve(vec!["a.com:127.0.0.1".parse().unwrap()])
+///         .build(),
+/// ];
+/// for options in rejected {
+///     assert_eq!(options.unwrap_err().problems().len(), 1);
+/// }
+///
+/// // Every problem is listed
+/// let err = NetworkOptions::builder()
+///     .max_concurrent_downloads(500)
+///     .timeout(Duration::ZERO)
+///     .build()
+///     .unwrap_err();
+/// assert_eq!(err.problems().len(), 2);
+///
+/// assert!(NetworkOptions::builder()
+///     .max_concurrent_downloads(500)
+///     .i_know_what_im_doing(true)
+///     .build()
+///     .is_ok());
+///
+/// let minute = Duration::from_secs(60);
+/// let rejected = [
+///     DownloadOptions::builder().overwrite(true).no_overwrite(true).build(),
+///     DownloadOptions::builder().resume(true).build(),
+///     DownloadOptions::builder().pin_variant(true).build(),
+///     DownloadOptions::builder().no_remux(true).trim_to_keyframe(true).build(),
+///     DownloadOptions::builder().no_remux(true).library_title("A".into()).build(),
+///     DownloadOptions::builder().refresh_master(Duration::ZERO).build(),
+///     DownloadOptions::builder().measure(Duration::ZERO).build(),
+///     DownloadOptions::builder().measure(minute).output("out".into()).build(),
+///     DownloadOptions::builder().preallocate(true).max_runtime(minute).build(),
+/// ];
+/// for options in rejected {
+///     assert_eq!(options.unwrap_err().problems().len(), 1);
+/// }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OptionsError(Vec<String>);
+
+impl OptionsError {
+    /// Ok if problems is empty
+    fn check(problems: Vec<String>) -> Result<(), Self> {
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(Self(problems))
+        }
+    }
+
+    pub fn problems(&self) -> &[String] {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for OptionsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0.as_slice() {
+            [problem] => write!(f, "{}", problem),
+            problems => {
+                write!(f, "{} problems with options:", problems.len())?;
+                for problem in problems {
+                    write!(f, "\n  - {}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for OptionsError {}
+
+impl DownloadOptions {
+    /// Problems with values and combinations of options, including conflicts clap checks on the
+    /// command line
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        // Durations are non-negative, but these must be positive
+        for (name, duration) in [
+            ("--refresh-master", self.refresh_master),
+            ("--measure", self.measure),
+        ] {
+            if duration == Some(Duration::ZERO) {
+                problems.push(format!("{} must be a positive duration", name));
+            }
+        }
+
+        for (name, given) in [
+            ("--resume", self.resume),
+            ("--pin-variant", self.pin_variant),
+        ] {
+            if given && self.output.is_none() {
+                problems.push(format!("{} requires --output", name));
+            }
+        }
+        for (name, given) in [
+            ("--target-resolution", self.target_resolution.is_some()),
+            ("--target-bitrate", self.target_bitrate.is_some()),
+        ] {
+            if given && !self.transcode {
+                problems.push(format!("{} requires --transcode", name));
+            }
+        }
+        if self.append_ts_transcode && self.append_ts.is_none() {
+            problems.push("--append-ts-transcode requires --append-ts".to_string());
+        }
+
+        let limited = self.max_runtime.is_some()
+            || self.max_duration.is_some()
+            || self.max_filesize.is_some()
+            || self.backlog_limit.is_some();
+        let conflicts = [
+            (
+                "--overwrite",
+                self.overwrite,
+                "--no-overwrite",
+                self.no_overwrite,
+            ),
+            (
+                "--no-remux",
+                self.no_remux,
+                "--trim-to-keyframe",
+                self.trim_to_keyframe,
+            ),
+            (
+                "--no-remux",
+                self.no_remux,
+                "--library-title",
+                self.library_title.is_some(),
+            ),
+            (
+                "--no-remux",
+                self.no_remux,
+                "--remux-name",
+                self.remux_name.is_some(),
+            ),
+            (
+                "--no-remux",
+                self.no_remux,
+                "--default-audio",
+                self.default_audio.is_some(),
+            ),
+            ("--no-remux", self.no_remux, "--transcode", self.transcode),
+            (
+                "--no-remux",
+                self.no_remux,
+                "--embed-gap-report",
+                self.embed_gap_report,
+            ),
+            (
+                "--preallocate",
+                self.preallocate,
+                "--embed-gap-report",
+                self.embed_gap_report,
+            ),
+            (
+                "--no-remux",
+                self.no_remux,
+                "--extract-captions",
+                self.extract_captions,
+            ),
+            (
+                "--preallocate",
+                self.preallocate,
+                "--write-concat-lists",
+                self.write_concat_lists,
+            ),
+            (
+                "--preallocate",
+                self.preallocate,
+                "--append-ts",
+                self.append_ts.is_some(),
+            ),
+            (
+                "--library-title",
+                self.library_title.is_some(),
+                "--remux-name",
+                self.remux_name.is_some(),
+            ),
+            (
+                "--no-remux",
+                self.no_remux,
+                "--subtitle-sidecar-template",
+                self.subtitle_sidecar_template.is_some(),
+            ),
+            (
+                "--library-title",
+                self.library_title.is_some(),
+                "--subtitle-sidecar-template",
+                self.subtitle_sidecar_template.is_some(),
+            ),
+            (
+                "--segments-dir-naming run",
+                self.segments_dir_naming == SegmentsDirNaming::Run,
+                "--resume",
+                self.resume,
+            ),
+            (
+                "--segment-naming sequential",
+                self.segment_naming == SegmentNaming::Sequential,
+                "--resume",
+                self.resume,
+            ),
+            ("--preallocate", self.preallocate, "--resume", self.resume),
+            (
+                "--preallocate",
+                self.preallocate,
+                "download limits",
+                limited,
+            ),
+            (
+                "--preallocate",
+                self.preallocate,
+                "--refresh-master",
+                self.refresh_master.is_some(),
+            ),
+            (
+                "--measure",
+                self.measure.is_some(),
+                "--output",
+                self.output.is_some(),
+            ),
+            (
+                "--measure",
+                self.measure.is_some(),
+                "--preallocate",
+                self.preallocate,
+            ),
+        ];
+        for (a, a_given, b, b_given) in conflicts {
+            if a_given && b_given {
+                problems.push(format!("{} can't be used with {}", a, b));
+            }
+        }
+
+        problems
+    }
+}
+
+impl NetworkOptions {
+    /// Problems with values and combinations of options
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if self.max_concurrent_downloads == 0 {
+            problems.push("--max-concurrent-downloads must be at least 1".to_string());
+        } else if self.max_concurrent_downloads > MAX_CONCURRENT_DOWNLOADS
+            && !self.i_know_what_im_doing
+        {
+            problems.push(format!(
+                "--max-concurrent-downloads {} is above {}, so many connections can get you \
+                 banned by CDNs and exhaust file descriptors. Add --i-know-what-im-doing to use it \
+                 anyway",
+                self.max_concurrent_downloads, MAX_CONCURRENT_DOWNLOADS
+            ));
+        }
+        if self.timeout < MIN_TIMEOUT {
+            problems.push(format!("--timeout must be at least {:?}", MIN_TIMEOUT));
+        }
+        if self.max_retries > MAX_RETRIES {
+            problems.push(format!("--max-retries must be at most {}", MAX_RETRIES));
+        }
+        if self.max_attempts_per_segment == 0 {
+            problems.push("--max-attempts-per-segment must be at least 1".to_string());
+        }
+        if self.max_segment_size == 0 {
+            problems.push("--max-segment-size must be greater than 0".to_string());
+        }
+        if self.retry_min_delay > self.retry_max_delay {
+            problems
+                .push("--retry-min-delay must not be greater than --retry-max-delay".to_string());
+        }
+        for r in &self.resolve {
+            if self
+                .connect_host
+                .iter()
+                .any(|c| c.host.eq_ignore_ascii_case(&r.host))
+            {
+                problems.push(format!(
+                    "--connect-host and --resolve both redirect host {}",
+                    r.host
+                ));
+            }
+        }
+
+        problems
+    }
+}
+
+#[cfg(feature = "s3")]
+impl UploadOptions {
+    /// Problems with values and combinations of options
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.max_concurrent_uploads == 0 {
+            problems.push("--max-concurrent-uploads must be at least 1".to_string());
+        }
+        if self.upload_then_delete && self.upload_s3.is_none() {
+            problems.push("--upload-then-delete requires --upload-s3".to_string());
+        }
+        problems
+    }
+}
+
+impl Args {
+    /// Options of a download of url, for use as a library without parsing command line
+    /// arguments
+    ///
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// use livestream_dl::cli::{Args, DownloadOptions, NetworkOptions};
+    ///
+    /// let args = Args::new(
+    ///     "https://example.com/live.m3u8".parse().unwrap(),
+    ///     DownloadOptions::builder().no_remux(true).build()?,
+    ///     NetworkOptions::builder()
+    ///         .timeout(Duration::from_secs(30))
+    ///         .max_retries(5)
+    ///         .build()?,
+    /// );
+    /// assert!(args.download_options.no_remux);
+    /// assert_eq!(args.network_options.timeout, Duration::from_secs(30));
+    /// assert_eq!(args.network_options.max_retries, 5);
+    /// // Other options keep the defaults of the command line
+    /// assert_eq!(args.network_options.max_concurrent_downloads, 20);
+    /// assert!(args.validate().is_ok());
+    /// # Ok::<(), livestream_dl::cli::OptionsError>(())
+    /// ```
+    pub fn new(
+        m3u8_url: Url,
+        download_options: DownloadOptions,
+        network_options: NetworkOptions,
+    ) -> Self {
+        Self {
+            m3u8_url: Some(m3u8_url),
+            command: None,
+            download_options,
+            network_options,
+            #[cfg(feature = "s3")]
+            upload_options: UploadOptions::default(),
+        }
+    }
+
+    /// Check if the download may stop before all segments are downloaded
+    pub fn has_download_limits(&self) -> bool {
+        let d = &self.download_options;
+        d.max_runtime.is_some() || d.max_duration.is_some() || d.max_filesize.is_some()
+    }
+
+    /// Problems with values and combinations of all options
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = self.download_options.problems();
+        problems.extend(self.network_options.problems());
+        if let Some(Command::Bench(bench)) = &self.command {
+            problems.extend(bench.network_options.problems());
+        }
+        #[cfg(feature = "s3")]
+        {
+            problems.extend(self.upload_options.problems());
+            if self.upload_options.upload_s3.is_some() && self.download_options.preallocate {
+                problems.push("--upload-s3 can't be used with --preallocate".to_string());
+            }
+        }
+        problems
+    }
+
+    /// Check values and combinations of arguments that can't be checked by clap, reporting all
+    /// problems at once
+    pub fn validate(&self) -> Result<(), clap::Error> {
+        use clap::{CommandFactory, ErrorKind};
+
+        OptionsError::check(self.problems())
+            .map_err(|e| Self::command().error(ErrorKind::ValueValidation, e))
+    }
+
+    /// Args with the defaults of the command line, without a URL
+    ///
+    /// Options refer to each other, so their defaults are taken from the matches of the whole
+    /// command.
+    fn defaults() -> Self {
+        use clap::{CommandFactory, FromArgMatches};
+
+        let matches = Self::command()
+            .mut_arg("m3u8-url", |a| a.required(false))
+            .try_get_matches_from(["livestream-dl"])
+            .expect("options have valid defaults");
+        Self::from_arg_matches(&matches).expect("options have valid defaults")
+    }
+}
+
+/// Implement Default with the defaults of the command line, and a builder with a setter for each
+/// listed field. Setters of optional fields set them to Some
+macro_rules! options_builder {
+    (
+        $(#[$meta:meta])*
+        $options:ident ($args_field:ident) => $builder:ident {
+            $($field:ident: $ty:ty,)*
+        }
+        optional {
+            $($opt_field:ident: $opt_ty:ty,)*
+        }
+    ) => {
+        impl Default for $options {
+            fn default() -> Self {
+                Args::defaults().$args_field
+            }
+        }
+
+        impl $options {
+            /// Builder starting from the defaults of the command line
+            pub fn builder() -> $builder {
+                $builder(Self::default())
+            }
+        }
+
+        $(#[$meta])*
+        #[derive(Clone, Default, Debug)]
+        pub struct $builder($options);
+
+        impl $builder {
+            $(
+                pub fn $field(mut self, $field: $ty) -> Self {
+                    self.0.$field = $field;
+                    self
+                }
+            )*
+            $(
+                pub fn $opt_field(mut self, $opt_field: $opt_ty) -> Self {
+                    self.0.$opt_field = Some($opt_field);
+                    self
+                }
+            )*
+
+            /// Options set on the builder, or all problems with them
+            pub fn build(self) -> Result<$options, OptionsError> {
+                OptionsError::check(self.0.problems())?;
+                Ok(self.0)
+            }
+        }
+    };
+}
+
+options_builder! {
+    /// Builder of DownloadOptions
+    DownloadOptions (download_options) => DownloadOptionsBuilder {
+        resume: bool,
+        overwrite: bool,
+        no_overwrite: bool,
+        no_remux: bool,
+        choose_stream: bool,
+        pin_variant: bool,
+        prefer_lang: Vec<String>,
+        include_audio_description: bool,
+        discon_window: Vec<DisconRange>,
+        prioritize_main: bool,
+        skip_ads: bool,
+        lint_playlist: bool,
+        repair_pass: bool,
+        dedupe_by_content: bool,
+        segment_naming: SegmentNaming,
+        segments_dir_naming: SegmentsDirNaming,
+        split_on_codec_change: bool,
+        container: Container,
+        trim_to_keyframe: bool,
+        transcode: bool,
+        embed_gap_report: bool,
+        extract_captions: bool,
+        strict_renditions: bool,
+        follow_rendition_reports: bool,
+        check_segments: bool,
+        write_concat_lists: bool,
+        append_ts_transcode: bool,
+        validate_ts: bool,
+        save_keys: bool,
+        no_part_files: bool,
+        preallocate: bool,
+        print_effective_options: bool,
+    }
+    optional {
+        output: PathBuf,
+        stream: String,
+        max_renditions: usize,
+        max_renditions_per_type: usize,
+        audio_channels: AudioChannels,
+        audio_codec: String,
+        refresh_master: Duration,
+        max_runtime: Duration,
+        max_duration: Duration,
+        max_filesize: u64,
+        output_grace: Duration,
+        backlog_limit: BacklogLimit,
+        library_title: String,
+        remux_name: NameTemplate,
+        subtitle_sidecar_template: SidecarTemplate,
+        default_audio: String,
+        measure: Duration,
+        measure_json: PathBuf,
+        event_log: PathBuf,
+        print_selected_urls: PrintFormat,
+        target_resolution: TargetResolution,
+        target_bitrate: u64,
+        append_ts: PathBuf,
+    }
+}
+
+options_builder! {
+    /// Builder of NetworkOptions
+    NetworkOptions (network_options) => NetworkOptionsBuilder {
+        max_retries: u32,
+        max_attempts_per_segment: u32,
+        retry_streams: u32,
+        retry_min_delay: Duration,
+        retry_max_delay: Duration,
+        retry_backoff_exponent: u32,
+        timeout: Duration,
+        max_concurrent_downloads: usize,
+        max_segment_size: u64,
+        i_know_what_im_doing: bool,
+        no_redirect_cache: bool,
+        probe_playlist_range: bool,
+        copy_query: bool,
+        connect_host: Vec<ConnectHost>,
+        resolve: Vec<Resolve>,
+        capture_response_headers: Vec<HeaderName>,
+        insecure: bool,
+    }
+    optional {
+        max_in_flight_bytes: u64,
+        cookies: PathBuf,
+        referer_from_url: Option<Url>,
+        geo_bypass_headers: GeoBypass,
+    }
+}
+
+#[cfg(feature = "s3")]
+options_builder! {
+    /// Builder of UploadOptions
+    UploadOptions (upload_options) => UploadOptionsBuilder {
+        max_concurrent_uploads: usize,
+        upload_then_delete: bool,
+    }
+    optional {
+        upload_s3: String,
+        s3_endpoint: Url,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(options: &[&str]) -> Result<Args, clap::Error> {
+        let args = ["livestream-dl", "https://example.com/live.m3u8"];
+        let args = Args::try_parse_from(args.iter().chain(options))?;
+        args.validate()?;
+        Ok(args)
+    }
+
+    #[test]
+    fn retry_delays_out_of_range_are_rejected() {
+        for delay in ["1e30", "inf", "-1s"] {
+            for option in ["--retry-min-delay", "--retry-max-delay"] {
+                let err = parse(&[&format!("{}={}", option, delay)]).unwrap_err();
+                assert_eq!(err.kind(), clap::ErrorKind::ValueValidation, "{}", err);
+            }
+        }
+    }
+
+    #[test]
+    fn retry_delays_are_ordered() {
+        let args = parse(&["--retry-min-delay", "2s", "--retry-max-delay", "1m"]).unwrap();
+        assert_eq!(args.network_options.retry_min_delay, Duration::from_secs(2));
+        assert_eq!(
+            args.network_options.retry_max_delay,
+            Duration::from_secs(60)
+        );
+
+        let err = parse(&["--retry-min-delay", "1m", "--retry-max-delay", "2s"]).unwrap_err();
+        assert_eq!(err.kind(), clap::ErrorKind::ValueValidation);
+    }
+
+    #[test]
+    fn connect_host_mappings_have_no_ports() {
+        let args = parse(&["--connect-host", "a.com:[::1]"]).unwrap();
+        let mapping = &args.network_options.connect_host[0];
+        assert_eq!(
+            (mapping.host.as_str(), mapping.connect_host.as_str()),
+            ("a.com", "::1")
+        );
+
+        let err = parse(&["--connect-host", "a.com:443:b.com:443"]).unwrap_err();
+        assert!(
+            err.to_string().contains("\"a.com:443:b.com:443\""),
+            "{}",
+            err
+        );
+        assert!(err.to_string().contains("ports can't be given"));
+    }
+}
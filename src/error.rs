@@ -2,12 +2,19 @@ use std::fmt::Display;
 
 use reqwest::Response;
 
-#[allow(dead_code)]
+#[allow(dead_code, clippy::large_enum_variant)]
 #[derive(Debug)]
 pub enum LivestreamDLError {
     NetworkRequest(Response),
     ParseCookie(String),
     ParseM3u8(String),
+    Decrypt(String),
+    /// A playlist expected to be a media playlist is a master playlist
+    NotMediaPlaylist(String),
+    /// Why each stream didn't download any segment
+    NoSegments(Vec<String>),
+    /// URL and size limit of a segment whose body exceeded the limit
+    SegmentTooLarge(String, u64),
 }
 
 impl Display for LivestreamDLError {
@@ -27,6 +34,30 @@ impl Display for LivestreamDLError {
             Self::ParseM3u8(s) => {
                 write!(f, "failed to parse m3u8 playlist from url: {}", s)
             }
+            Self::Decrypt(s) => {
+                write!(f, "failed to decrypt segment: {}", s)
+            }
+            Self::NotMediaPlaylist(s) => {
+                write!(
+                    f,
+                    "expected a media playlist, got a master playlist from url: {}",
+                    s
+                )
+            }
+            Self::NoSegments(problems) => {
+                write!(f, "no segments were downloaded")?;
+                for problem in problems {
+                    write!(f, "\n  - {}", problem)?;
+                }
+                Ok(())
+            }
+            Self::SegmentTooLarge(s, limit) => {
+                write!(
+                    f,
+                    "segment is larger than the maximum segment size of {} bytes from url: {}",
+                    limit, s
+                )
+            }
         }
     }
 }
@@ -0,0 +1,9 @@
+pub mod cli;
+pub mod effective_options;
+pub mod error;
+pub mod livestream;
+pub mod mux;
+#[cfg(feature = "progress")]
+pub mod progress;
+#[cfg(feature = "s3")]
+mod s3;
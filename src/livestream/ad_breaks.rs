@@ -0,0 +1,140 @@
+use m3u8_rs::MediaSegment;
+use tracing::{event, Level};
+
+/// Ad breaks lasting longer than this many seconds are assumed to be marked wrongly
+const MAX_AD_BREAK: f64 = 180.0;
+
+/// Detection of ad breaks inserted as discontinuity windows, e.g. by Twitch
+///
+/// A discontinuity window is an ad break if its first segment carries an ad marker, an
+/// EXT-X-DATERANGE of a stitched ad or EXT-X-CUE-OUT. A segment with EXT-X-CUE-IN ends the ad
+/// break early. To avoid dropping real content, windows without markers are never skipped, and
+/// skipping stops once an ad break lasts longer than 3 minutes.
+#[derive(Default, Debug)]
+pub(crate) struct AdBreaks {
+    /// Discontinuity sequence of the last checked segment
+    last_discon_seq: Option<u64>,
+    /// Discontinuity sequence of the current ad break and its duration so far
+    current: Option<(u64, f64)>,
+    breaks: usize,
+    skipped_segments: usize,
+    skipped_duration: f64,
+}
+
+impl AdBreaks {
+    /// Check if a new segment in discontinuity window discon_seq belongs to an ad break, segments
+    /// must be checked in playlist order
+    pub fn is_ad(&mut self, discon_seq: u64, segment: &MediaSegment) -> bool {
+        let window_start = self.last_discon_seq != Some(discon_seq);
+        self.last_discon_seq = Some(discon_seq);
+
+        if window_start {
+            self.current = None;
+            if is_ad_marker(segment) {
+                event!(
+                    Level::INFO,
+                    "Skipping ad break in discontinuity {}",
+                    discon_seq
+                );
+                self.current = Some((discon_seq, 0.0));
+                self.breaks += 1;
+            }
+        }
+        if has_tag(segment, "X-CUE-IN") {
+            self.current = None;
+        }
+
+        let (_, duration) = match &mut self.current {
+            Some(c) => c,
+            None => return false,
+        };
+        *duration += segment.duration as f64;
+        if *duration > MAX_AD_BREAK {
+            event!(
+                Level::WARN,
+                "Ad break in discontinuity {} lasts longer than {}s, downloading the rest of it",
+                discon_seq,
+                MAX_AD_BREAK
+            );
+            self.current = None;
+            return false;
+        }
+        self.skipped_segments += 1;
+        self.skipped_duration += segment.duration as f64;
+
+        true
+    }
+
+    /// Number of detected ad breaks
+    pub fn breaks(&self) -> usize {
+        self.breaks
+    }
+
+    pub fn skipped_segments(&self) -> usize {
+        self.skipped_segments
+    }
+
+    /// Duration of skipped segments in seconds
+    pub fn skipped_duration(&self) -> f64 {
+        self.skipped_duration
+    }
+}
+
+/// Check if segment starts an ad, by a DATERANGE of a Twitch stitched ad or a SCTE-35 cue
+fn is_ad_marker(segment: &MediaSegment) -> bool {
+    // m3u8-rs only parses the misspelled EXT-X-DATE-RANGE, EXT-X-DATERANGE ends up as unknown tag
+    let stitched_ad = segment
+        .unknown_tags
+        .iter()
+        .filter(|t| t.tag == "X-DATERANGE")
+        .filter_map(|t| t.rest.as_deref())
+        .chain(segment.daterange.as_deref())
+        .any(|d| d.contains("stitched-ad"));
+    stitched_ad || has_tag(segment, "X-CUE-OUT")
+}
+
+fn has_tag(segment: &MediaSegment, tag: &str) -> bool {
+    segment.unknown_tags.iter().any(|t| t.tag == tag)
+}
+
+#[cfg(test)]
+mod tests {
+    use m3u8_rs::Playlist;
+
+    use super::*;
+
+    #[test]
+    fn stitched_ad_window_is_skipped() {
+        let playlist = b"#EXTM3U
+#EXT-X-TARGETDURATION:2
+#EXTINF:2.000,live
+live0.ts
+#EXT-X-DISCONTINUITY
+#EXT-X-DATERANGE:ID=\"stitched-ad-1\",CLASS=\"twitch-stitched-ad\",START-DATE=\"2022-01-01T00:00:00Z\"
+#EXTINF:2.000,Amazon
+ad0.ts
+#EXTINF:2.000,Amazon
+ad1.ts
+#EXT-X-DISCONTINUITY
+#EXTINF:2.000,live
+live1.ts
+";
+        let playlist = match m3u8_rs::parse_playlist_res(playlist).unwrap() {
+            Playlist::MediaPlaylist(p) => p,
+            _ => unreachable!(),
+        };
+
+        let mut ad_breaks = AdBreaks::default();
+        let discon_seqs = [0, 1, 1, 2];
+        let skipped: Vec<_> = playlist
+            .segments
+            .iter()
+            .zip(discon_seqs)
+            .map(|(s, d)| ad_breaks.is_ad(d, s))
+            .collect();
+        assert_eq!(skipped, [false, true, true, false]);
+        assert_eq!(ad_breaks.breaks(), 1);
+        assert_eq!(ad_breaks.skipped_segments(), 2);
+        assert_eq!(ad_breaks.skipped_duration(), 4.0);
+    }
+}
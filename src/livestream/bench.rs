@@ -0,0 +1,112 @@
+use std::num::NonZeroUsize;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use futures::{stream, StreamExt};
+use tracing::{event, Level};
+
+use super::playlist_fetcher::{fetch_media_playlist, playlist_segments};
+use super::{Encryption, InitCache, Livestream, Stream};
+
+/// Throughput measured at one number of concurrent downloads
+#[derive(Clone, Debug)]
+pub struct BenchResult {
+    pub concurrency: usize,
+    pub segments: usize,
+    pub failed: usize,
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl BenchResult {
+    /// Throughput in bytes per second
+    pub fn throughput(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+impl Livestream {
+    /// Download up to segments segments of the main stream once for each number of concurrent
+    /// downloads and measure the throughput, discarding the downloaded data
+    ///
+    /// The same segments are downloaded at every level, so later levels may be served from
+    /// caches of the origin.
+    pub async fn bench(
+        &self,
+        segments: usize,
+        concurrency: &[NonZeroUsize],
+    ) -> Result<Vec<BenchResult>> {
+        let (stream, url) = self
+            .streams
+            .get_key_value(&Stream::Main)
+            .or_else(|| self.streams.iter().next())
+            .ok_or_else(|| anyhow::anyhow!("No streams to benchmark"))?;
+        let playlist = fetch_media_playlist(&self.client, stream, url, &self.variables).await?;
+        let segments: Vec<_> = playlist_segments(&playlist, url, None)?
+            .into_iter()
+            .take(segments)
+            .collect();
+        if segments.is_empty() {
+            return Err(anyhow::anyhow!("Stream {} has no segments", stream));
+        }
+        let encrypted = playlist
+            .segments
+            .iter()
+            .filter_map(|s| s.key.as_ref())
+            .any(|k| k.method != "NONE");
+        if encrypted {
+            event!(
+                Level::INFO,
+                "Stream {} is encrypted, segments are downloaded without decrypting them",
+                stream
+            );
+        }
+
+        let mut results = Vec::with_capacity(concurrency.len());
+        for &c in concurrency {
+            event!(
+                Level::INFO,
+                "Downloading {} segments of {} with {} concurrent downloads",
+                segments.len(),
+                stream,
+                c
+            );
+            // Initializations are downloaded again at every level
+            let inits = InitCache::new(1);
+            let start = Instant::now();
+            let (bytes, failed) = stream::iter(&segments)
+                .map(|segment| {
+                    self.fetch_segment(
+                        &inits,
+                        stream.clone(),
+                        segment.clone(),
+                        Encryption::None,
+                        false,
+                    )
+                })
+                .buffer_unordered(c.get())
+                .fold((0, 0), |(bytes, failed), res| async move {
+                    match res {
+                        Ok(((_, _, init, data), _, _, _)) => {
+                            (bytes + (init.len() + data.len()) as u64, failed)
+                        }
+                        Err(e) => {
+                            event!(Level::WARN, "{:?}", e);
+                            (bytes, failed + 1)
+                        }
+                    }
+                })
+                .await;
+
+            results.push(BenchResult {
+                concurrency: c.get(),
+                segments: segments.len(),
+                failed,
+                bytes,
+                elapsed: start.elapsed(),
+            });
+        }
+
+        Ok(results)
+    }
+}
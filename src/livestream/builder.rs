@@ -0,0 +1,672 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::Result;
+use itertools::Itertools;
+use m3u8_rs::Playlist;
+use reqwest::header::RANGE;
+use reqwest::{Client, StatusCode, Url};
+use reqwest_middleware::ClientBuilder;
+use reqwest_retry::{policies, RetryTransientMiddleware};
+use time::format_description;
+use time::OffsetDateTime;
+use tracing::{event, Level};
+
+use super::byte_budget::ByteBudget;
+use super::connect_host::ConnectHostResolver;
+use super::content_encoding::playlist_body;
+use super::cookies::CookieJar;
+use super::displayable_variant::DisplayableVariant;
+use super::encryption::KeyRecovery;
+use super::gap_report::GapReport;
+use super::http_client::HttpClient;
+use super::local_url::is_local;
+use super::redirect_cache::RedirectCache;
+use super::segment_attempts::SegmentAttempts;
+use super::segment_numbers::SegmentNumbers;
+use super::source::{HttpPlaylistSource, HttpSegmentFetcher, PlaylistSource, SegmentFetcher};
+use super::stream_status::StreamStatuses;
+use super::traffic::{RequestCategory, TrafficStats};
+use super::trick_play::TrickPlayStreams;
+use super::utils::{best_variant, make_absolute_url, segments_dir_name};
+use super::{
+    audio_group, group_has_codec, referer, request_error, select_renditions, CaptionChannel,
+    Coverage, FdEstimate, Livestream, Master, OpenFiles, Stopper, Stream, Variables,
+    VariantSelection,
+};
+use crate::cli::{Args, NameTemplate, NameValues, SegmentNaming};
+use crate::error::LivestreamDLError;
+
+/// Factor by which the timeout of fetching failed segments again is longer
+const REFETCH_TIMEOUT_FACTOR: u32 = 3;
+
+/// Bytes of the start of a playlist requested with --probe-playlist-range
+const PLAYLIST_PROBE_SIZE: u64 = 8 * 1024;
+
+/// Builder of a Livestream, by default discovering streams from the playlist at its URL and
+/// downloading them over HTTP
+///
+/// Custom sources replace how playlists and segments are fetched, everything downstream like
+/// decryption, saving and remuxing stays the same:
+///
+/// ```
+/// use std::collections::HashMap;
+/// use std::sync::{Arc, Mutex};
+///
+/// use anyhow::Result;
+/// use async_trait::async_trait;
+/// use clap::Parser;
+/// use futures::stream;
+/// use livestream_dl::cli::Args;
+/// use livestream_dl::livestream::{
+///     FetchedData, Livestream, PlaylistSource, PlaylistStream, RemoteData, Segment,
+///     SegmentFetcher, SegmentSink, Stream,
+/// };
+/// use reqwest::Url;
+///
+/// /// Source announcing a fixed playlist
+/// #[derive(Debug)]
+/// struct MemorySource(String);
+///
+/// impl PlaylistSource for MemorySource {
+///     fn playlists(&self, _: &Stream, url: &Url) -> PlaylistStream {
+///         let playlist = m3u8_rs::parse_media_playlist_res(self.0.as_bytes()).unwrap();
+///         Box::pin(stream::iter([Ok((playlist, url.clone()))]))
+///     }
+/// }
+///
+/// /// Fetcher serving segments from memory and recording requested URLs
+/// #[derive(Debug, Default)]
+/// struct MemoryFetcher(Arc<Mutex<Vec<String>>>);
+///
+/// #[async_trait]
+/// impl SegmentFetcher for MemoryFetcher {
+///     async fn fetch(&self, data: &RemoteData) -> Result<FetchedData> {
+///         self.0.lock().unwrap().push(data.url().to_string());
+///         Ok(FetchedData::new(vec![0x47; 188], data.url().clone()))
+///     }
+/// }
+///
+/// /// Sink discarding segments
+/// struct NullSink;
+///
+/// #[async_trait]
+/// impl SegmentSink for NullSink {
+///     async fn on_init(&mut self, _: &Stream, _: &RemoteData, _: &[u8]) -> Result<()> {
+///         Ok(())
+///     }
+///
+///     async fn on_segment(&mut self, _: &Stream, _: &Segment, _: &[u8]) -> Result<()> {
+///         Ok(())
+///     }
+/// }
+///
+/// let url: Url = "memory://live/media.m3u8".parse().unwrap();
+/// let playlist = "#EXTM3U\n#EXT-X-TARGETDURATION:6\n#EXTINF:6.0,\n0.ts\n#EXTINF:6.0,\n1.ts\n#EXT-X-ENDLIST\n";
+/// let requested = Arc::new(Mutex::new(Vec::new()));
+/// let args = Args::parse_from(["livestream-dl", url.as_str()]);
+/// let runtime = tokio::runtime::Runtime::new().unwrap();
+/// runtime.block_on(async {
+///     let (livestream, _) = Livestream::builder(&url, &args)
+///         .streams([(Stream::Main, url.clone())])
+///         .playlist_source(MemorySource(playlist.into()))
+///         .segment_fetcher(MemoryFetcher(requested.clone()))
+///         .build()
+///         .await
+///         .unwrap();
+///     livestream.download_with_sink(&mut NullSink).await.unwrap();
+/// });
+/// assert_eq!(
+///     *requested.lock().unwrap(),
+///     ["memory://live/0.ts", "memory://live/1.ts"]
+/// );
+/// ```
+#[derive(Debug)]
+pub struct LivestreamBuilder {
+    url: Url,
+    options: Args,
+    streams: Option<HashMap<Stream, Url>>,
+    source: Option<Arc<dyn PlaylistSource>>,
+    fetcher: Option<Arc<dyn SegmentFetcher>>,
+}
+
+/// Streams discovered from a playlist
+struct Discovery {
+    /// Playlist URL after redirects
+    url: Url,
+    streams: HashMap<Stream, Url>,
+    variant_selection: Option<VariantSelection>,
+    variables: Variables,
+    master: Option<Master>,
+}
+
+impl LivestreamBuilder {
+    pub fn new(url: &Url, options: &Args) -> Self {
+        Self {
+            url: url.clone(),
+            options: options.clone(),
+            streams: None,
+            source: None,
+            fetcher: None,
+        }
+    }
+
+    /// Download these streams and their media playlist URLs instead of discovering them from the
+    /// playlist at the URL
+    pub fn streams(mut self, streams: impl IntoIterator<Item = (Stream, Url)>) -> Self {
+        self.streams = Some(streams.into_iter().collect());
+        self
+    }
+
+    /// Get media playlists from source instead of polling them over HTTP
+    pub fn playlist_source(mut self, source: impl PlaylistSource + 'static) -> Self {
+        self.source = Some(Arc::new(source));
+        self
+    }
+
+    /// Get segments from fetcher instead of downloading them over HTTP
+    pub fn segment_fetcher(mut self, fetcher: impl SegmentFetcher + 'static) -> Self {
+        self.fetcher = Some(Arc::new(fetcher));
+        self
+    }
+
+    /// Create the Livestream and the stopper that stops its download
+    pub async fn build(self) -> Result<(Livestream, Stopper)> {
+        let url = &self.url;
+        let options = &self.options;
+
+        // Create reqwest client, playlists are decoded by playlist_body to tolerate mislabeled
+        // encodings
+        let client = Client::builder()
+            .no_gzip()
+            .no_brotli()
+            .no_deflate()
+            .timeout(options.network_options.timeout)
+            .danger_accept_invalid_certs(options.network_options.insecure);
+
+        // Add cookie provider if needed
+        let mut client = if let Some(cookies_path) = &options.network_options.cookies {
+            let jar = CookieJar::parse_from_file(cookies_path)?;
+            client.cookie_provider(Arc::new(jar))
+        } else {
+            client
+        };
+
+        // Claim to forward requests from another country
+        if let Some(geo) = &options.network_options.geo_bypass_headers {
+            let headers = geo.headers();
+            event!(
+                Level::INFO,
+                "Sending {}",
+                headers
+                    .iter()
+                    .map(|(k, v)| format!("{}: {}", k, v.to_str().unwrap_or_default()))
+                    .join(", ")
+            );
+            client = client.default_headers(headers);
+        }
+
+        // Connect elsewhere for hosts given by --connect-host
+        let connect_host = &options.network_options.connect_host;
+        if !connect_host.is_empty() {
+            for mapping in connect_host {
+                event!(
+                    Level::INFO,
+                    "Connecting to {} for requests to {}",
+                    mapping.connect_host,
+                    mapping.host
+                );
+            }
+            client = client.dns_resolver(Arc::new(ConnectHostResolver::new(connect_host)));
+        }
+        // Force hosts to fixed addresses, the port is ignored by reqwest
+        for mapping in &options.network_options.resolve {
+            event!(
+                Level::INFO,
+                "Resolving {} to {}",
+                mapping.host,
+                mapping.addr
+            );
+            client = client.resolve(&mapping.host, SocketAddr::new(mapping.addr, 0));
+        }
+        let client = client.build()?;
+
+        // Set client retry on failure
+        let traffic = TrafficStats::default();
+        let network_options = &options.network_options;
+        let client = if network_options.max_retries > 0 {
+            let min_delay = network_options.retry_min_delay;
+            let max_delay = network_options.retry_max_delay;
+            let retry_policy = policies::ExponentialBackoff::builder()
+                .retry_bounds(min_delay, max_delay)
+                .backoff_exponent(network_options.retry_backoff_exponent)
+                .build_with_max_retries(network_options.max_retries);
+            event!(
+                Level::INFO,
+                "Retry policy: up to {} retries, delay {:?} to {:?}, backoff exponent {}",
+                network_options.max_retries,
+                min_delay,
+                max_delay,
+                network_options.retry_backoff_exponent
+            );
+
+            // Build client with middleware, traffic is counted for every attempt
+            ClientBuilder::new(client)
+                .with(RetryTransientMiddleware::new_with_policy(retry_policy))
+                .with(traffic.middleware())
+                .build()
+        } else {
+            event!(Level::INFO, "Retry policy: retries disabled");
+            ClientBuilder::new(client)
+                .with(traffic.middleware())
+                .build()
+        };
+
+        // Build HttpClient
+        let query_pairs = if options.network_options.copy_query {
+            Some(url.query_pairs().collect::<Vec<_>>())
+        } else {
+            None
+        };
+        let mut client = HttpClient::new(client, query_pairs).with_traffic(traffic);
+        if is_local(url) {
+            client = client.with_local_urls();
+        }
+        if let Some(page_url) = &options.network_options.referer_from_url {
+            let referer = referer(page_url.as_ref().unwrap_or(url))?;
+            event!(Level::INFO, "Using Referer {:?}", referer);
+            client = client.with_referer(referer);
+        }
+
+        // Discover streams unless given
+        let Discovery {
+            url: final_url,
+            mut streams,
+            variant_selection,
+            variables,
+            master,
+        } = match self.streams {
+            Some(streams) => Discovery {
+                url: url.clone(),
+                streams,
+                variant_selection: None,
+                variables: Variables::default(),
+                master: None,
+            },
+            None => Self::discover(&client, url, options).await?,
+        };
+
+        // Only keep the requested stream
+        if let Some(id) = &options.download_options.stream {
+            let available = streams.keys().map(|s| s.to_string()).sorted().join(", ");
+            streams.retain(|s, _| &s.to_string() == id);
+            if streams.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "Stream {:?} not found, available streams: {}",
+                    id,
+                    available
+                ));
+            }
+        }
+
+        // Fetch playlists and segments over HTTP unless custom sources are given
+        let source = self.source.unwrap_or_else(|| {
+            let source = HttpPlaylistSource::new(client.clone(), variables.clone());
+            match options.download_options.follow_rendition_reports {
+                true => Arc::new(source.following_rendition_reports()),
+                false => Arc::new(source),
+            }
+        });
+        let fetcher = self.fetcher.unwrap_or_else(|| {
+            Arc::new(HttpSegmentFetcher::new(
+                client.clone(),
+                ByteBudget::new(options.network_options.max_in_flight_bytes),
+                (!options.network_options.no_redirect_cache).then(RedirectCache::default),
+                options.network_options.capture_response_headers.clone(),
+                options.network_options.timeout * REFETCH_TIMEOUT_FACTOR,
+                options.network_options.max_segment_size,
+            ))
+        });
+
+        // Name the segments directory after the playlist URL as given, which stays the same
+        // between runs unlike the redirect target
+        let segments_dir_name =
+            segments_dir_name(options.download_options.segments_dir_naming, url);
+        event!(Level::DEBUG, "Saving segments to {}", segments_dir_name);
+
+        // Resolve name of the remuxed output to fail early if it is invalid
+        let remux_name = match &options.download_options.remux_name {
+            Some(template) => {
+                let name = resolve_remux_name(template, url, master.as_ref())?;
+                event!(
+                    Level::INFO,
+                    "Remuxing to {:?} in the output directory",
+                    name
+                );
+                Some(name)
+            }
+            None => None,
+        };
+
+        let stopper = Stopper::new();
+
+        Ok((
+            Livestream {
+                url: final_url,
+                streams,
+                variant_selection,
+                variables,
+                master,
+                client,
+                source,
+                fetcher,
+                attempts: SegmentAttempts::new(options.network_options.max_attempts_per_segment),
+                key_recovery: if options.download_options.save_keys {
+                    KeyRecovery::saving_keys()
+                } else {
+                    KeyRecovery::default()
+                },
+                gaps: GapReport::default(),
+                coverage: Coverage::default(),
+                statuses: StreamStatuses::default(),
+                trick_play: TrickPlayStreams::default(),
+                numbers: (options.download_options.segment_naming == SegmentNaming::Sequential)
+                    .then(SegmentNumbers::default),
+                remux_name,
+                segments_dir_name,
+                open_files: OpenFiles::new(&FdEstimate::new(options)),
+                stopper: stopper.clone(),
+                options: options.clone(),
+            },
+            stopper,
+        ))
+    }
+
+    /// Fetch the playlist at url and select its streams
+    async fn discover(client: &HttpClient, url: &Url, options: &Args) -> Result<Discovery> {
+        // Media playlists are fetched again by the playlist fetcher, so their start is enough
+        if options.network_options.probe_playlist_range {
+            if let Some(final_url) = probe_media_playlist(client, url).await {
+                event!(
+                    Level::DEBUG,
+                    "Start of {} is a media playlist, not fetching it in full",
+                    final_url
+                );
+                return Ok(Discovery {
+                    streams: HashMap::from([(Stream::Main, final_url.clone())]),
+                    url: final_url,
+                    variant_selection: None,
+                    variables: Variables::default(),
+                    master: None,
+                });
+            }
+        }
+
+        // Get m3u8 playlist, the client retries transient failures
+        let (bytes, final_url) = match client.read_local(url).await {
+            Some(bytes) => (bytes?, url.clone()),
+            None => {
+                let resp = client
+                    .get_playlist(url.clone())
+                    .send()
+                    .await
+                    .map_err(|e| request_error(e, url))?;
+                if !resp.status().is_success() {
+                    return Err(anyhow::Error::new(LivestreamDLError::NetworkRequest(resp))
+                        .context(format!("Unable to fetch playlist {}", url)));
+                }
+                let final_url = resp.url().clone();
+                let counter = client.traffic().body(RequestCategory::Playlist);
+                (playlist_body(resp, counter).await?, final_url)
+            }
+        };
+
+        // Check if m3u8 is master or media
+        let (bytes, variables) = Variables::substitute(&bytes, url, &Variables::default())?;
+
+        // Parse m3u8 playlist and add streams
+        let mut streams = HashMap::new();
+        // With --pin-variant and when resuming, the selected variant is saved in the output
+        // directory and reused by later runs so that the output doesn't mix qualities
+        let download_options = &options.download_options;
+        let pin_path = download_options
+            .output
+            .as_ref()
+            .map(|o| o.join(VariantSelection::FILE_NAME));
+        let reuse_pinned = download_options.pin_variant || download_options.resume;
+        let mut variant_selection = None;
+        let mut master = None;
+        match m3u8_rs::parse_playlist(&bytes) {
+            Ok((_, Playlist::MasterPlaylist(p))) => {
+                // Find variant matching a previous selection if pinned
+                let pinned = match &pin_path {
+                    Some(path) if reuse_pinned => VariantSelection::load(path)?,
+                    _ => None,
+                };
+                let pinned_variant = pinned.as_ref().and_then(|s| {
+                    let v = s.find(&p.variants);
+                    match v {
+                        None if download_options.resume => event!(
+                            Level::WARN,
+                            "Variant {} of the resumed download no longer exists, selecting \
+                             another variant, the output may mix qualities",
+                            s.quality()
+                        ),
+                        None => event!(
+                            Level::WARN,
+                            "Pinned variant {:?} no longer exists, selecting another variant",
+                            s
+                        ),
+                        Some(_) => (),
+                    }
+                    v
+                });
+
+                let stream = if let Some(v) = pinned_variant {
+                    event!(
+                        Level::INFO,
+                        "Using pinned variant {}",
+                        DisplayableVariant::from(v)
+                    );
+                    v
+                } else if !options.download_options.choose_stream {
+                    // Pick highest bitrate stream
+                    best_variant(&p).ok_or_else(|| anyhow::anyhow!("No streams found"))?
+                } else {
+                    // Show stream chooser
+                    let options: Vec<_> = p
+                        .variants
+                        .iter()
+                        .filter(|v| !v.is_i_frame)
+                        .filter_map(|v| Some((v.bandwidth.parse::<u64>().ok()?, v)))
+                        .sorted_by_key(|(b, _)| *b)
+                        .map(|(_, v)| v)
+                        .rev()
+                        .map(DisplayableVariant::from)
+                        .collect();
+                    let response = inquire::Select::new("Choose stream", options).prompt()?;
+                    response.into()
+                };
+
+                // Remember selection for later pinned or resumed runs unless one is reused
+                if pin_path.is_some() && reuse_pinned && pinned.is_none() {
+                    variant_selection = Some(VariantSelection::new(stream));
+                }
+
+                // Add main stream
+                streams.insert(Stream::Main, make_absolute_url(url, &stream.uri)?);
+
+                // Add alternative renditions
+                let (included, excluded) =
+                    select_renditions(&p, stream, url, &options.download_options)?;
+                if let (Some(codec), Some(group)) =
+                    (&options.download_options.audio_codec, &stream.audio)
+                {
+                    match audio_group(&p, stream, Some(codec)) {
+                        Some(g) if !group_has_codec(&p, g, codec) => event!(
+                            Level::WARN,
+                            "No audio renditions with codec {} found, using audio group {}",
+                            codec,
+                            group
+                        ),
+                        Some(g) if g != group => {
+                            event!(Level::INFO, "Using audio group {} for codec {}", g, codec)
+                        }
+                        _ => (),
+                    }
+                }
+                let d = &options.download_options;
+                if d.max_renditions.is_some()
+                    || d.max_renditions_per_type.is_some()
+                    || d.audio_channels.is_some()
+                {
+                    for (s, _) in &included {
+                        event!(Level::INFO, "Including rendition {}", s);
+                    }
+                    for s in &excluded {
+                        event!(Level::WARN, "Dropping rendition {}", s);
+                    }
+                }
+                // Default audio rendition, or the first one without a default
+                let primary_audio = included
+                    .iter()
+                    .map(|(s, _)| s)
+                    .filter(|s| matches!(s, Stream::Audio { .. }))
+                    .min_by_key(|s| {
+                        !p.alternatives.iter().any(|a| {
+                            a.default
+                                && Some(a.group_id.as_str()) == s.group_id()
+                                && Some(&a.name) == s.name().as_ref()
+                        })
+                    })
+                    .cloned();
+                streams.extend(included);
+
+                let captions = CaptionChannel::declared(&p, stream);
+                if options.download_options.extract_captions {
+                    for c in &captions {
+                        event!(Level::INFO, "Variant declares closed captions {}", c);
+                    }
+                }
+
+                // Remember selected variant to find it again when refreshing
+                master = Some(Master {
+                    url: url.clone(),
+                    selection: VariantSelection::new(stream),
+                    ladder: p.variants.iter().map(VariantSelection::new).collect(),
+                    primary_audio,
+                    captions,
+                });
+            }
+            Ok((_, Playlist::MediaPlaylist(_))) => {
+                streams.insert(Stream::Main, final_url.clone());
+            }
+            Err(_) => {
+                let reason = if bytes.trim_ascii_start().starts_with(b"#EXTM3U") {
+                    "Playlist is invalid"
+                } else {
+                    "Response is not an m3u8 playlist"
+                };
+                return Err(anyhow::Error::new(LivestreamDLError::ParseM3u8(
+                    final_url.to_string(),
+                ))
+                .context(reason));
+            }
+        }
+
+        Ok(Discovery {
+            url: final_url,
+            streams,
+            variant_selection,
+            variables,
+            master,
+        })
+    }
+}
+
+/// Name of the remuxed output from template, with the current date, and the host and file name of
+/// url
+fn resolve_remux_name(
+    template: &NameTemplate,
+    url: &Url,
+    master: Option<&Master>,
+) -> Result<PathBuf> {
+    let format = format_description::parse("[year][month][day]")?;
+    let date = OffsetDateTime::now_utc().format(&format)?;
+    let title = url
+        .path_segments()
+        .and_then(|mut s| s.next_back())
+        .map(|n| n.rsplit_once('.').map_or(n, |(stem, _)| stem))
+        .filter(|n| !n.is_empty())
+        .unwrap_or("stream");
+    let quality = master.map_or_else(|| "source".to_string(), |m| m.selection.quality());
+    template
+        .render(&NameValues {
+            date: &date,
+            host: url.host_str().unwrap_or_default(),
+            title,
+            quality: &quality,
+        })
+        .map_err(|e| anyhow::anyhow!("Invalid --remux-name: {}", e))
+}
+
+/// Fetch the first PLAYLIST_PROBE_SIZE bytes of the playlist at url with a Range header, returns
+/// the playlist URL after redirects if they are the header of a media playlist
+///
+/// Returns None if the playlist may be a master playlist, the server ignored the Range header or
+/// the request failed, then the playlist must be fetched in full.
+async fn probe_media_playlist(client: &HttpClient, url: &Url) -> Option<Url> {
+    // Plain get instead of get_playlist, which asks for a compressed body by hand as the client
+    // doesn't decompress, so that the range is taken from the playlist itself
+    let resp = client
+        .get(url.clone())
+        .header(RANGE, format!("bytes=0-{}", PLAYLIST_PROBE_SIZE - 1))
+        .send()
+        .await
+        .ok()?;
+    if resp.status() != StatusCode::PARTIAL_CONTENT {
+        event!(
+            Level::DEBUG,
+            "Range request for {} returned {}, fetching the whole playlist",
+            url,
+            resp.status()
+        );
+        return None;
+    }
+    let final_url = resp.url().clone();
+    let head = resp.bytes().await.ok()?;
+    client
+        .traffic()
+        .received(RequestCategory::Playlist, head.len());
+    is_media_playlist_head(&head).then_some(final_url)
+}
+
+/// Check if the start of a playlist is the header of a media playlist, i.e. it has a tag only
+/// media playlists have and none that only master playlists have
+fn is_media_playlist_head(head: &[u8]) -> bool {
+    const MASTER_TAGS: &[&str] = &[
+        "#EXT-X-STREAM-INF",
+        "#EXT-X-I-FRAME-STREAM-INF",
+        "#EXT-X-MEDIA:",
+        "#EXT-X-SESSION-DATA",
+        "#EXT-X-SESSION-KEY",
+        "#EXT-X-CONTENT-STEERING",
+    ];
+    const MEDIA_TAGS: &[&str] = &[
+        "#EXTINF",
+        "#EXT-X-TARGETDURATION",
+        "#EXT-X-MEDIA-SEQUENCE",
+        "#EXT-X-PLAYLIST-TYPE",
+    ];
+    let head = String::from_utf8_lossy(head);
+    if !head.trim_start().starts_with("#EXTM3U") {
+        return false;
+    }
+    let has = |tags: &[&str]| {
+        head.lines()
+            .any(|l| tags.iter().any(|t| l.trim().starts_with(t)))
+    };
+    has(MEDIA_TAGS) && !has(MASTER_TAGS)
+}
@@ -0,0 +1,53 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Granularity of reservations, so that budgets larger than u32::MAX bytes are possible
+const UNIT: u64 = 1024;
+
+/// Limit on the total size of segments held in memory at once
+#[derive(Clone, Debug)]
+pub struct ByteBudget {
+    semaphore: Option<Arc<Semaphore>>,
+    capacity: u32,
+}
+
+/// Reserved part of a byte budget, released when dropped
+#[derive(Debug)]
+pub struct Reservation {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl ByteBudget {
+    /// Budget of max_bytes, or unlimited if None
+    pub fn new(max_bytes: Option<u64>) -> Self {
+        let capacity = max_bytes
+            .map(|b| u32::try_from(b.div_ceil(UNIT).max(1)).unwrap_or(u32::MAX))
+            .unwrap_or(0);
+        Self {
+            semaphore: max_bytes.map(|_| Arc::new(Semaphore::new(capacity as usize))),
+            capacity,
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(None)
+    }
+
+    /// Wait until bytes fit in the budget and reserve them
+    ///
+    /// Reservations larger than the whole budget wait for the whole budget instead.
+    pub async fn reserve(&self, bytes: u64) -> Result<Reservation> {
+        let semaphore = match &self.semaphore {
+            Some(s) => s.clone(),
+            None => return Ok(Reservation { _permit: None }),
+        };
+        let units = u32::try_from(bytes.div_ceil(UNIT))
+            .unwrap_or(u32::MAX)
+            .min(self.capacity);
+        Ok(Reservation {
+            _permit: Some(semaphore.acquire_many_owned(units).await?),
+        })
+    }
+}
@@ -0,0 +1,81 @@
+use std::fmt;
+
+use m3u8_rs::{AlternativeMediaType, MasterPlaylist, VariantStream};
+
+/// In-band closed caption channel of a variant, declared by an EXT-X-MEDIA tag of
+/// TYPE=CLOSED-CAPTIONS in the group named by the CLOSED-CAPTIONS attribute of the variant
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct CaptionChannel {
+    /// CEA-608 channel CC1 to CC4, or CEA-708 service SERVICE1 to SERVICE63
+    pub instream_id: String,
+    pub name: String,
+    pub lang: Option<String>,
+}
+
+impl CaptionChannel {
+    /// Caption channels declared for variant, empty if it has none or CLOSED-CAPTIONS=NONE
+    ///
+    /// ```
+    /// use livestream_dl::livestream::CaptionChannel;
+    /// use m3u8_rs::Playlist;
+    ///
+    /// let playlist = b"#EXTM3U\n\
+    ///     #EXT-X-MEDIA:TYPE=CLOSED-CAPTIONS,GROUP-ID=\"cc\",NAME=\"English\",LANGUAGE=\"en\",INSTREAM-ID=\"CC1\"\n\
+    ///     #EXT-X-MEDIA:TYPE=CLOSED-CAPTIONS,GROUP-ID=\"cc\",NAME=\"Spanish\",LANGUAGE=\"es\",INSTREAM-ID=\"CC3\"\n\
+    ///     #EXT-X-STREAM-INF:BANDWIDTH=2000000,CLOSED-CAPTIONS=\"cc\"\n\
+    ///     720p.m3u8\n\
+    ///     #EXT-X-STREAM-INF:BANDWIDTH=800000,CLOSED-CAPTIONS=NONE\n\
+    ///     360p.m3u8\n";
+    /// let master = match m3u8_rs::parse_playlist_res(playlist) {
+    ///     Ok(Playlist::MasterPlaylist(m)) => m,
+    ///     _ => unreachable!(),
+    /// };
+    ///
+    /// let channels = CaptionChannel::declared(&master, &master.variants[0]);
+    /// assert_eq!(channels.len(), 2);
+    /// assert_eq!(channels[1].instream_id, "CC3");
+    /// assert_eq!(channels[1].lang.as_deref(), Some("es"));
+    /// assert_eq!(channels[1].data_field(), Some("second"));
+    /// assert!(CaptionChannel::declared(&master, &master.variants[1]).is_empty());
+    /// ```
+    pub fn declared(master: &MasterPlaylist, variant: &VariantStream) -> Vec<Self> {
+        let group = match variant.closed_captions.as_deref() {
+            None | Some("NONE") => return Vec::new(),
+            Some(g) => g,
+        };
+        master
+            .alternatives
+            .iter()
+            .filter(|a| a.media_type == AlternativeMediaType::ClosedCaptions && a.group_id == group)
+            .filter_map(|a| {
+                Some(Self {
+                    instream_id: a.instream_id.clone()?,
+                    name: a.name.clone(),
+                    lang: a.language.clone(),
+                })
+            })
+            .collect()
+    }
+
+    /// Field of the CEA-608 data carrying the channel, as the data_field option of the ffmpeg
+    /// caption decoder, or None for channels ffmpeg can't decode
+    ///
+    /// ffmpeg only decodes the first data channel of each field, CC1 and CC3, and no CEA-708
+    /// services.
+    pub fn data_field(&self) -> Option<&'static str> {
+        match self.instream_id.as_str() {
+            "CC1" => Some("first"),
+            "CC3" => Some("second"),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for CaptionChannel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.lang {
+            Some(lang) => write!(f, "{} ({}, {})", self.instream_id, self.name, lang),
+            None => write!(f, "{} ({})", self.instream_id, self.name),
+        }
+    }
+}
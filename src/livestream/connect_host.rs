@@ -0,0 +1,53 @@
+use std::collections::HashMap;
+
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use tokio::net;
+use tracing::{event, Level};
+
+use crate::cli::ConnectHost;
+
+/// DNS resolver that resolves other hosts for some hosts, given by --connect-host
+///
+/// The other host is resolved for every new connection, so that a change of its addresses is
+/// picked up like that of any other host.
+#[derive(Debug)]
+pub(crate) struct ConnectHostResolver {
+    /// Hosts to connect to by lowercase hostname
+    hosts: HashMap<String, String>,
+}
+
+impl ConnectHostResolver {
+    pub fn new(mappings: &[ConnectHost]) -> Self {
+        Self {
+            hosts: mappings
+                .iter()
+                .map(|m| (m.host.to_ascii_lowercase(), m.connect_host.clone()))
+                .collect(),
+        }
+    }
+}
+
+impl Resolve for ConnectHostResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let connect_host = self.hosts.get(&name.as_str().to_ascii_lowercase()).cloned();
+        Box::pin(async move {
+            let host = connect_host.as_deref().unwrap_or(name.as_str());
+            let addrs: Vec<_> = net::lookup_host((host, 0)).await?.collect();
+            if connect_host.is_some() {
+                event!(
+                    Level::DEBUG,
+                    "Connecting to {} ({}) for {}",
+                    host,
+                    addrs
+                        .iter()
+                        .map(|a| a.ip().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    name.as_str()
+                );
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
@@ -0,0 +1,83 @@
+use lru::LruCache;
+use xxhash_rust::xxh3::xxh3_64;
+
+use super::{RemoteData, Stream};
+
+/// Number of recently saved segments remembered for deduplication
+const DEFAULT_WINDOW: usize = 4096;
+
+/// Outcome of checking a downloaded segment against recently saved segments
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum ContentCheck {
+    /// No recent segment has the same identity
+    New,
+    /// A recent segment has the same identity and content
+    Duplicate,
+    /// A recent segment has the same identity but different content, e.g. because the origin
+    /// reused its URL after an encoder restart
+    Changed { previous: u64, current: u64 },
+}
+
+/// Deduplication of segments by their identity (stream, URL and byte range) and a hash of their
+/// content, remembering a bounded number of recent segments
+///
+/// Segments are only duplicates if both identity and content match.
+#[derive(Debug)]
+pub(crate) struct ContentDedupe {
+    recent: LruCache<(Stream, RemoteData), u64>,
+}
+
+impl Default for ContentDedupe {
+    fn default() -> Self {
+        Self::new(DEFAULT_WINDOW)
+    }
+}
+
+impl ContentDedupe {
+    /// Deduplication remembering up to window segments
+    pub fn new(window: usize) -> Self {
+        Self {
+            recent: LruCache::new(window),
+        }
+    }
+
+    /// Check a downloaded segment against recent segments and remember its content
+    pub fn check(&mut self, stream: &Stream, data_id: &RemoteData, data: &[u8]) -> ContentCheck {
+        let current = xxh3_64(data);
+        match self.recent.put((stream.clone(), data_id.clone()), current) {
+            None => ContentCheck::New,
+            Some(previous) if previous == current => ContentCheck::Duplicate,
+            Some(previous) => ContentCheck::Changed { previous, current },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(u: &str) -> RemoteData {
+        RemoteData::new(u.parse().unwrap(), None)
+    }
+
+    #[test]
+    fn duplicates_match_identity_and_content() {
+        let mut dedupe = ContentDedupe::new(16);
+        let mut check = |u, data| dedupe.check(&Stream::Main, &url(u), data);
+        assert_eq!(check("http://a/1.ts", b"one"), ContentCheck::New);
+
+        // Same URL, same content
+        assert_eq!(check("http://a/1.ts", b"one"), ContentCheck::Duplicate);
+        // Same URL, different content
+        assert!(matches!(
+            check("http://a/1.ts", b"two"),
+            ContentCheck::Changed { .. }
+        ));
+        // The changed content is remembered
+        assert_eq!(check("http://a/1.ts", b"two"), ContentCheck::Duplicate);
+        // Different URL, same content
+        assert_eq!(check("http://a/2.ts", b"two"), ContentCheck::New);
+        // Different URL, different content
+        assert_eq!(check("http://a/3.ts", b"three"), ContentCheck::New);
+    }
+}
@@ -0,0 +1,247 @@
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::{Context, Result};
+use flate2::read::{DeflateDecoder, MultiGzDecoder, ZlibDecoder};
+use reqwest::header::{CONTENT_ENCODING, CONTENT_TYPE};
+use reqwest::{Response, Url};
+use tracing::{event, Level};
+
+use super::traffic::BodyCounter;
+
+/// Largest playlist body read, before and after decompression
+const MAX_PLAYLIST_SIZE: usize = 8 * 1024 * 1024;
+
+/// Number of bytes read before checking if a body looks like media
+const SNIFF_SIZE: usize = 512;
+
+/// Set once a mislabeled playlist was logged as a warning, later ones are logged at debug level
+static WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Read the body of a playlist response, decoding it according to its Content-Encoding header
+///
+/// Responses that are media files by their Content-Type or first bytes, or larger than
+/// MAX_PLAYLIST_SIZE, are rejected without reading the rest of the body. Bytes read are counted
+/// by counter.
+pub async fn playlist_body(mut resp: Response, mut counter: BodyCounter) -> Result<Vec<u8>> {
+    let url = resp.url().clone();
+    let header = |name| {
+        resp.headers()
+            .get(&name)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_owned)
+    };
+    let encoding = header(CONTENT_ENCODING);
+    let content_type = header(CONTENT_TYPE);
+
+    if let Some(t) = content_type.as_deref().filter(|t| is_media_type(t)) {
+        return Err(anyhow::anyhow!(
+            "{} is a media file ({}), not a playlist",
+            url,
+            t
+        ));
+    }
+
+    let mut body = Vec::new();
+    let mut sniffed = false;
+    while let Some(chunk) = resp.chunk().await? {
+        counter.add(chunk.len());
+        if body.len() + chunk.len() > MAX_PLAYLIST_SIZE {
+            return Err(anyhow::anyhow!(
+                "{} is larger than {} MiB, not a playlist",
+                url,
+                MAX_PLAYLIST_SIZE / 1024 / 1024
+            ));
+        }
+        body.extend_from_slice(&chunk);
+        if !sniffed && body.len() >= SNIFF_SIZE {
+            check_not_media(&body, &url)?;
+            sniffed = true;
+        }
+    }
+    if !sniffed {
+        check_not_media(&body, &url)?;
+    }
+    counter.finish();
+
+    decode_playlist(&body, encoding.as_deref())
+        .with_context(|| format!("error decoding playlist {}", url))
+}
+
+/// Check if a Content-Type is that of media rather than a playlist
+fn is_media_type(content_type: &str) -> bool {
+    let t = content_type.trim().to_lowercase();
+    (t.starts_with("video/") || t.starts_with("audio/") || t.starts_with("application/mp4"))
+        && !t.contains("mpegurl")
+}
+
+/// Fail if the start of a body looks like a media file
+fn check_not_media(body: &[u8], url: &Url) -> Result<()> {
+    let kind = match body {
+        _ if is_playlist(body) => None,
+        [0x47, ..] if body.get(188).is_none_or(|b| *b == 0x47) => Some("MPEG-TS"),
+        [_, _, _, _, b'f', b't', b'y', b'p', ..]
+        | [_, _, _, _, b's', b't', b'y', b'p', ..]
+        | [_, _, _, _, b'm', b'o', b'o', b'f', ..] => Some("MP4"),
+        [0x1a, 0x45, 0xdf, 0xa3, ..] => Some("Matroska"),
+        [b'I', b'D', b'3', ..] => Some("ID3 tagged audio"),
+        [0xff, b, ..] if b & 0xf6 == 0xf0 => Some("AAC"),
+        _ => None,
+    };
+    match kind {
+        Some(k) => Err(anyhow::anyhow!(
+            "{} is a media file ({}), not a playlist",
+            url,
+            k
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Decode a playlist body with the given Content-Encoding, tolerating origins that mislabel it
+///
+/// Bodies that already are playlists are returned as is whatever the encoding claims, and
+/// bodies compressed without a matching label are decompressed if they look like gzip or zlib.
+fn decode_playlist(body: &[u8], content_encoding: Option<&str>) -> Result<Vec<u8>> {
+    let encoding = content_encoding
+        .map(|e| e.trim().to_lowercase())
+        .filter(|e| !e.is_empty() && e != "identity");
+
+    // Playlist isn't compressed, whatever the header says
+    if is_playlist(body) {
+        if let Some(e) = &encoding {
+            log_correction(&format!(
+                "Playlist is labeled as {} but not compressed, ignoring its Content-Encoding",
+                e
+            ));
+        }
+        return Ok(body.to_vec());
+    }
+
+    // Decode as labeled
+    if let Some(e) = &encoding {
+        match decompress(body, e) {
+            Ok(d) if is_playlist(&d) => return Ok(d),
+            Ok(_) => event!(Level::DEBUG, "Playlist decoded as {} is not a playlist", e),
+            Err(err) => event!(
+                Level::DEBUG,
+                "Unable to decode playlist as {}: {:?}",
+                e,
+                err
+            ),
+        }
+    }
+
+    // Detect compression by magic bytes
+    let detected = if body.starts_with(&[0x1f, 0x8b]) {
+        Some("gzip")
+    } else if is_zlib(body) {
+        Some("deflate")
+    } else {
+        None
+    };
+    if let Some(d) = detected.filter(|d| encoding.as_deref() != Some(*d)) {
+        if let Ok(decoded) = decompress(body, d) {
+            log_correction(&format!(
+                "Playlist is {} compressed but labeled as {}, decompressing it",
+                d,
+                encoding.as_deref().unwrap_or("uncompressed")
+            ));
+            return Ok(decoded);
+        }
+    }
+
+    // Leave errors to the playlist parser
+    Ok(body.to_vec())
+}
+
+/// Decompress data with a Content-Encoding, up to MAX_PLAYLIST_SIZE bytes
+fn decompress(data: &[u8], encoding: &str) -> Result<Vec<u8>> {
+    let limit = MAX_PLAYLIST_SIZE as u64 + 1;
+    let mut decoded = Vec::new();
+    match encoding {
+        "gzip" | "x-gzip" => MultiGzDecoder::new(data)
+            .take(limit)
+            .read_to_end(&mut decoded)?,
+        // Deflate is often sent without the zlib wrapper it should have
+        "deflate" => match ZlibDecoder::new(data).take(limit).read_to_end(&mut decoded) {
+            Ok(n) => n,
+            Err(_) => {
+                decoded.clear();
+                DeflateDecoder::new(data)
+                    .take(limit)
+                    .read_to_end(&mut decoded)?
+            }
+        },
+        "br" => brotli_decompressor::Decompressor::new(data, 4096)
+            .take(limit)
+            .read_to_end(&mut decoded)?,
+        _ => return Err(anyhow::anyhow!("Unsupported Content-Encoding {}", encoding)),
+    };
+    if decoded.len() > MAX_PLAYLIST_SIZE {
+        return Err(anyhow::anyhow!(
+            "Decompressed playlist is larger than {} MiB",
+            MAX_PLAYLIST_SIZE / 1024 / 1024
+        ));
+    }
+    Ok(decoded)
+}
+
+/// Check if data starts like a playlist, allowing a byte order mark and leading whitespace
+fn is_playlist(data: &[u8]) -> bool {
+    data.strip_prefix(b"\xef\xbb\xbf")
+        .unwrap_or(data)
+        .trim_ascii_start()
+        .starts_with(b"#EXTM3U")
+}
+
+/// Check if data starts with a valid zlib header
+fn is_zlib(data: &[u8]) -> bool {
+    match data {
+        [cmf, flg, ..] => cmf & 0x0f == 8 && (u16::from(*cmf) << 8 | u16::from(*flg)) % 31 == 0,
+        _ => false,
+    }
+}
+
+/// Log a corrected encoding, as a warning the first time
+fn log_correction(message: &str) {
+    if WARNED.swap(true, Ordering::Relaxed) {
+        event!(Level::DEBUG, "{}", message);
+    } else {
+        event!(Level::WARN, "{}", message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    use super::*;
+
+    const PLAYLIST: &[u8] = b"#EXTM3U\n#EXT-X-TARGETDURATION:6\n";
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn correctly_labeled_playlist_is_decompressed() {
+        assert_eq!(
+            decode_playlist(&gzip(PLAYLIST), Some("gzip")).unwrap(),
+            PLAYLIST
+        );
+    }
+
+    #[test]
+    fn mislabeled_playlists_are_corrected() {
+        // Labeled gzip, but already decompressed
+        assert_eq!(decode_playlist(PLAYLIST, Some("gzip")).unwrap(), PLAYLIST);
+        // Compressed, but not labeled
+        assert_eq!(decode_playlist(&gzip(PLAYLIST), None).unwrap(), PLAYLIST);
+    }
+}
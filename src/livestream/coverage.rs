@@ -0,0 +1,508 @@
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::fmt::{self, Display};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use tracing::{event, Level};
+
+use super::{Segment, Stream};
+
+/// Interval between saves of observed segments while playlists are fetched
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Percentage of promised segments above which missing segments are minor gaps
+const MINOR_GAPS_MIN_COVERAGE: f64 = 99.0;
+
+/// Set of media sequence numbers, stored as ranges so that it stays small for long recordings
+///
+/// ```
+/// use livestream_dl::livestream::SeqRanges;
+///
+/// let mut seqs = SeqRanges::default();
+/// assert!(seqs.insert(10, 12));
+/// assert!(seqs.insert(15, 15));
+/// assert!(!seqs.insert(11, 12));
+/// assert_eq!(seqs.ranges().collect::<Vec<_>>(), [(10, 12), (15, 15)]);
+///
+/// // Adjacent and overlapping ranges are merged
+/// seqs.insert(13, 14);
+/// seqs.insert(8, 10);
+/// assert_eq!(seqs.ranges().collect::<Vec<_>>(), [(8, 15)]);
+/// assert_eq!(seqs.len(), 8);
+/// assert!(seqs.contains(15) && !seqs.contains(16));
+/// ```
+#[derive(Clone, Default, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(from = "Vec<(u64, u64)>", into = "Vec<(u64, u64)>")]
+pub struct SeqRanges(BTreeMap<u64, u64>);
+
+impl SeqRanges {
+    /// Add first to last inclusive, returns whether any of them were new
+    pub fn insert(&mut self, first: u64, last: u64) -> bool {
+        if self.range_before(first).is_some_and(|(_, e)| last <= e) {
+            return false;
+        }
+
+        // Merge with overlapping or adjacent ranges
+        let (mut start, mut end) = (first, last);
+        if let Some((s, e)) = self.range_before(first) {
+            if e.saturating_add(1) >= first {
+                start = s;
+                end = end.max(e);
+            }
+        }
+        let merged: Vec<_> = self
+            .0
+            .range(start..=end.saturating_add(1))
+            .map(|(&s, &e)| (s, e))
+            .collect();
+        for (s, e) in merged {
+            self.0.remove(&s);
+            end = end.max(e);
+        }
+        self.0.insert(start, end);
+        true
+    }
+
+    pub fn contains(&self, seq: u64) -> bool {
+        self.range_before(seq).is_some_and(|(_, e)| seq <= e)
+    }
+
+    /// Number of sequence numbers
+    pub fn len(&self) -> u64 {
+        self.0.iter().map(|(s, e)| e - s + 1).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn first(&self) -> Option<u64> {
+        self.0.keys().next().copied()
+    }
+
+    pub fn last(&self) -> Option<u64> {
+        self.0.values().next_back().copied()
+    }
+
+    /// Ranges of consecutive sequence numbers in order, first and last inclusive
+    pub fn ranges(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.0.iter().map(|(&s, &e)| (s, e))
+    }
+
+    /// Range starting at or before seq
+    fn range_before(&self, seq: u64) -> Option<(u64, u64)> {
+        self.0.range(..=seq).next_back().map(|(&s, &e)| (s, e))
+    }
+}
+
+impl From<Vec<(u64, u64)>> for SeqRanges {
+    fn from(ranges: Vec<(u64, u64)>) -> Self {
+        let mut seqs = Self::default();
+        for (first, last) in ranges {
+            seqs.insert(first, last.max(first));
+        }
+        seqs
+    }
+}
+
+impl From<SeqRanges> for Vec<(u64, u64)> {
+    fn from(seqs: SeqRanges) -> Self {
+        seqs.ranges().collect()
+    }
+}
+
+/// Segments of each stream that playlists listed, that were skipped and whose downloads failed,
+/// to reconcile them with the saved segments once the download ends
+///
+/// Clones share the same state, which is saved to a file if persisted, so that resumed downloads
+/// know what earlier runs observed.
+#[derive(Clone, Default, Debug)]
+pub struct Coverage(Arc<Mutex<CoverageState>>);
+
+#[derive(Default, Debug)]
+struct CoverageState {
+    streams: HashMap<Stream, StreamCoverage>,
+    path: Option<PathBuf>,
+    saved_at: Option<Instant>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct StreamCoverage {
+    stream: Stream,
+    /// Segments listed by any playlist
+    observed: SeqRanges,
+    /// Segments skipped by filters, e.g. ad breaks or a backlog limit
+    skipped: SeqRanges,
+    /// Reason of the last failed download of segments
+    failed: BTreeMap<u64, String>,
+}
+
+/// Reconciliation of the segments promised by the playlists of each stream with the saved
+/// segments
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct CoverageReport {
+    /// Worst verdict of all streams, or failed if the download failed
+    pub verdict: Verdict,
+    /// Why the download failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub streams: Vec<StreamCoverageReport>,
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct StreamCoverageReport {
+    pub stream: Stream,
+    pub verdict: Verdict,
+    /// Percentage of promised segments that were saved
+    pub coverage: f64,
+    /// Segments from the first to the last observed or saved media sequence number
+    pub promised: u64,
+    pub saved: u64,
+    pub missing: Vec<MissingRange>,
+}
+
+/// Whether a stream was captured completely, worst last
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Verdict {
+    Complete,
+    /// Less than 1% of the segments are missing
+    MinorGaps,
+    MajorGaps,
+    /// Remuxing, uploading or a stream failed after the segments were saved
+    Failed,
+}
+
+/// Consecutive missing segments with the same reason
+#[derive(Clone, PartialEq, Eq, Debug, Serialize)]
+pub struct MissingRange {
+    pub first_seq: u64,
+    pub last_seq: u64,
+    pub reason: MissingReason,
+    /// Why the downloads failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MissingReason {
+    /// Left the playlist before it was fetched
+    Expired,
+    /// Download failed
+    Failed,
+    /// Skipped by a filter
+    Skipped,
+    /// Listed, but not downloaded before the download stopped
+    NotDownloaded,
+}
+
+impl Coverage {
+    pub const FILE_NAME: &'static str = "coverage.json";
+
+    /// Save the state to path from now on, continuing from the state saved there if resume is set
+    pub fn persist_to(&self, path: impl AsRef<Path>, resume: bool) -> Result<()> {
+        let path = path.as_ref();
+        let mut state = self.0.lock().unwrap();
+        if resume && path.exists() {
+            let saved: Vec<StreamCoverage> = serde_json::from_slice(&std::fs::read(path)?)
+                .with_context(|| format!("Invalid {:?}", path))?;
+            for s in saved {
+                let c = state.stream(&s.stream);
+                for (first, last) in s.observed.ranges() {
+                    c.observed.insert(first, last);
+                }
+                for (first, last) in s.skipped.ranges() {
+                    c.skipped.insert(first, last);
+                }
+                for (seq, reason) in s.failed {
+                    c.failed.entry(seq).or_insert(reason);
+                }
+            }
+        }
+        state.path = Some(path.to_path_buf());
+        state.save()
+    }
+
+    /// Save the state if persisted
+    pub fn save(&self) -> Result<()> {
+        self.0.lock().unwrap().save()
+    }
+
+    /// Record the media sequence numbers first to last listed by a playlist of stream
+    pub fn observe(&self, stream: &Stream, first: u64, last: u64) {
+        let mut state = self.0.lock().unwrap();
+        let new = state.stream(stream).observed.insert(first, last);
+        if new && state.saved_at.is_none_or(|s| s.elapsed() >= SAVE_INTERVAL) {
+            if let Err(e) = state.save() {
+                event!(Level::WARN, "Unable to save observed segments: {:#}", e);
+            }
+        }
+    }
+
+    /// Record a segment skipped by a filter
+    pub fn skip(&self, stream: &Stream, seq: u64) {
+        self.0
+            .lock()
+            .unwrap()
+            .stream(stream)
+            .skipped
+            .insert(seq, seq);
+    }
+
+    /// Record a failed download of a segment
+    pub fn fail(&self, stream: &Stream, seq: u64, reason: String) {
+        self.0
+            .lock()
+            .unwrap()
+            .stream(stream)
+            .failed
+            .insert(seq, reason);
+    }
+
+    /// Compare the observed segments of each stream with the saved ones
+    ///
+    /// Segments between the first and last observed or saved one are promised, since media
+    /// sequence numbers have no holes. Saved segments count regardless of failures recorded for
+    /// them. Unsaved segments are missing because their download failed, because they were
+    /// skipped, because the download stopped before they were downloaded, or, if no playlist
+    /// listed them, because they expired.
+    ///
+    /// ```
+    /// use std::collections::{BinaryHeap, HashMap};
+    /// use std::path::PathBuf;
+    ///
+    /// use livestream_dl::livestream::{
+    ///     Coverage, MediaFormat, MissingReason, RemoteData, Segment, Stream, Verdict,
+    /// };
+    ///
+    /// let segment = |seq| Segment {
+    ///     data: RemoteData::new(format!("https://example.com/{}.ts", seq).parse().unwrap(), None),
+    ///     discon_seq: 0,
+    ///     seq,
+    ///     format: MediaFormat::MpegTs,
+    ///     initialization: None,
+    ///     program_date_time: None,
+    ///     duration: None,
+    /// };
+    /// let saved = |seqs: &[u64]| {
+    ///     let heap: BinaryHeap<_> = seqs.iter().map(|&s| (segment(s), PathBuf::new())).collect();
+    ///     HashMap::from([(Stream::Main, heap)])
+    /// };
+    ///
+    /// // Playlists listed 0 to 4, then 8 to 9 after 5 to 7 expired
+    /// let coverage = Coverage::default();
+    /// coverage.observe(&Stream::Main, 0, 4);
+    /// coverage.observe(&Stream::Main, 8, 9);
+    /// coverage.fail(&Stream::Main, 3, "HTTP 404".into());
+    /// coverage.fail(&Stream::Main, 4, "HTTP 404".into());
+    ///
+    /// let report = coverage.report(&saved(&[0, 1, 2, 8, 9]));
+    /// let main = &report.streams[0];
+    /// assert_eq!((main.promised, main.saved, main.coverage), (10, 5, 50.0));
+    /// assert_eq!(report.verdict, Verdict::MajorGaps);
+    /// let missing: Vec<_> = main
+    ///     .missing
+    ///     .iter()
+    ///     .map(|m| (m.first_seq, m.last_seq, m.reason, m.error.as_deref()))
+    ///     .collect();
+    /// assert_eq!(
+    ///     missing,
+    ///     [
+    ///         (3, 4, MissingReason::Failed, Some("HTTP 404")),
+    ///         (5, 7, MissingReason::Expired, None),
+    ///     ]
+    /// );
+    ///
+    /// // All promised segments were saved, including failed ones that were retried
+    /// let report = coverage.report(&saved(&(0..10).collect::<Vec<_>>()));
+    /// assert_eq!(report.verdict, Verdict::Complete);
+    /// assert_eq!(report.streams[0].coverage, 100.0);
+    /// ```
+    pub fn report(
+        &self,
+        downloaded: &HashMap<Stream, BinaryHeap<(Segment, PathBuf)>>,
+    ) -> CoverageReport {
+        let state = self.0.lock().unwrap();
+        let mut streams: Vec<_> = state.streams.keys().chain(downloaded.keys()).collect();
+        streams.sort_by_key(|s| s.to_string());
+        streams.dedup();
+
+        let streams: Vec<_> = streams
+            .into_iter()
+            .map(|stream| {
+                let mut saved = SeqRanges::default();
+                for (segment, _) in downloaded.get(stream).into_iter().flatten() {
+                    saved.insert(segment.seq, segment.seq);
+                }
+                match state.streams.get(stream) {
+                    Some(c) => c.report(&saved),
+                    None => StreamCoverage::new(stream).report(&saved),
+                }
+            })
+            .collect();
+        CoverageReport {
+            verdict: streams
+                .iter()
+                .map(|s| s.verdict)
+                .max()
+                .unwrap_or(Verdict::Complete),
+            error: None,
+            streams,
+        }
+    }
+}
+
+impl CoverageState {
+    fn stream(&mut self, stream: &Stream) -> &mut StreamCoverage {
+        self.streams
+            .entry(stream.clone())
+            .or_insert_with(|| StreamCoverage::new(stream))
+    }
+
+    /// Write the state to its path if any, replacing the file at once
+    fn save(&mut self) -> Result<()> {
+        let path = match &self.path {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        let streams: Vec<_> = self.streams.values().collect();
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        std::fs::write(&tmp_path, serde_json::to_vec(&streams)?)?;
+        std::fs::rename(&tmp_path, path)?;
+        self.saved_at = Some(Instant::now());
+        Ok(())
+    }
+}
+
+impl StreamCoverage {
+    fn new(stream: &Stream) -> Self {
+        Self {
+            stream: stream.clone(),
+            observed: SeqRanges::default(),
+            skipped: SeqRanges::default(),
+            failed: BTreeMap::new(),
+        }
+    }
+
+    fn report(&self, saved: &SeqRanges) -> StreamCoverageReport {
+        let first = self.observed.first().into_iter().chain(saved.first()).min();
+        let last = self.observed.last().into_iter().chain(saved.last()).max();
+        let mut missing: Vec<MissingRange> = Vec::new();
+        let mut promised = 0;
+        if let (Some(first), Some(last)) = (first, last) {
+            promised = last - first + 1;
+            for seq in (first..=last).filter(|s| !saved.contains(*s)) {
+                let (reason, error) = match self.failed.get(&seq) {
+                    Some(e) => (MissingReason::Failed, Some(e)),
+                    None if self.skipped.contains(seq) => (MissingReason::Skipped, None),
+                    None if self.observed.contains(seq) => (MissingReason::NotDownloaded, None),
+                    None => (MissingReason::Expired, None),
+                };
+                match missing.last_mut() {
+                    Some(m)
+                        if m.last_seq + 1 == seq
+                            && m.reason == reason
+                            && m.error.as_ref() == error =>
+                    {
+                        m.last_seq = seq;
+                    }
+                    _ => missing.push(MissingRange {
+                        first_seq: seq,
+                        last_seq: seq,
+                        reason,
+                        error: error.cloned(),
+                    }),
+                }
+            }
+        }
+
+        let saved = saved.len();
+        let coverage = match promised {
+            0 => 100.0,
+            p => saved as f64 * 100.0 / p as f64,
+        };
+        let verdict = match coverage {
+            _ if saved >= promised => Verdict::Complete,
+            c if c >= MINOR_GAPS_MIN_COVERAGE => Verdict::MinorGaps,
+            _ => Verdict::MajorGaps,
+        };
+        StreamCoverageReport {
+            stream: self.stream.clone(),
+            verdict,
+            coverage,
+            promised,
+            saved,
+            missing,
+        }
+    }
+}
+
+impl CoverageReport {
+    pub const FILE_NAME: &'static str = "capture-report.json";
+
+    /// Log the verdict and missing segments of each stream
+    pub fn log(&self) {
+        for s in &self.streams {
+            let summary = format!(
+                "Stream {}: {}, saved {} of {} segments ({:.2}%)",
+                s.stream, s.verdict, s.saved, s.promised, s.coverage
+            );
+            if s.missing.is_empty() {
+                event!(Level::INFO, "{}", summary);
+            } else {
+                event!(
+                    Level::WARN,
+                    "{}, missing {}",
+                    summary,
+                    s.missing.iter().join(", ")
+                );
+            }
+        }
+    }
+
+    /// Mark the download as failed by error, whatever the coverage of its streams
+    pub fn failed(mut self, error: &anyhow::Error) -> Self {
+        self.verdict = Verdict::Failed;
+        self.error = Some(format!("{:#}", error));
+        self
+    }
+
+    /// Write the report as JSON to path
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        std::fs::write(path, serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+impl Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Complete => write!(f, "complete"),
+            Self::MinorGaps => write!(f, "minor gaps"),
+            Self::MajorGaps => write!(f, "major gaps"),
+            Self::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+impl Display for MissingRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.first_seq == self.last_seq {
+            true => write!(f, "{} ", self.first_seq)?,
+            false => write!(f, "{}-{} ", self.first_seq, self.last_seq)?,
+        }
+        match (self.reason, &self.error) {
+            (_, Some(e)) => write!(f, "(failed: {})", e),
+            (MissingReason::Expired, _) => write!(f, "(expired)"),
+            (MissingReason::Failed, _) => write!(f, "(failed)"),
+            (MissingReason::Skipped, _) => write!(f, "(skipped)"),
+            (MissingReason::NotDownloaded, _) => write!(f, "(not downloaded)"),
+        }
+    }
+}
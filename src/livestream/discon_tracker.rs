@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use m3u8_rs::MediaPlaylist;
+use reqwest::Url;
+use tracing::{event, Level};
+
+use super::playlist_fetcher::playlist_segments;
+use super::{RemoteData, Segment};
+
+/// Discontinuity sequence numbers of the segments of a stream, kept monotonic across refreshes of
+/// its playlist
+///
+/// Some packagers omit EXT-X-DISCONTINUITY-SEQUENCE while emitting EXT-X-DISCONTINUITY tags, or
+/// reset it on every refresh, so the numbers declared by a playlist collide with or go back
+/// behind earlier ones. The numbering is anchored on the first playlist and continued from the
+/// segments seen before, the declared sequence is only followed when it agrees or jumps ahead
+/// across segments that were never listed.
+#[derive(Default, Debug)]
+pub(crate) struct DisconTracker {
+    /// Discontinuity sequence of the segments of the last playlist by media sequence
+    known: HashMap<u64, u64>,
+    /// Media sequence and discontinuity sequence of the last segment of the last playlist
+    last: Option<(u64, u64)>,
+    /// Set once a playlist disagreed with the anchored numbering
+    disagreed: bool,
+}
+
+impl DisconTracker {
+    /// Segments of a media playlist fetched from url, numbered consistently with the playlists
+    /// seen before, init is the initialization section in effect before its first segment
+    pub fn segments(
+        &mut self,
+        media_playlist: &MediaPlaylist,
+        url: &Url,
+        init: Option<RemoteData>,
+    ) -> Result<Vec<Segment>> {
+        let mut segments = playlist_segments(media_playlist, url, init)?;
+        let (first_seq, declared) = match segments.first() {
+            Some(s) => (s.seq, s.discon_seq),
+            None => return Ok(segments),
+        };
+
+        // Declared numbers are trusted relative to the first segment within a playlist
+        let anchored = match self.last {
+            None => declared,
+            Some((last_seq, last_discon)) => {
+                let overlap = segments
+                    .iter()
+                    .find_map(|s| Some((*self.known.get(&s.seq)?, s.discon_seq - declared)));
+                match overlap {
+                    // Continue from a segment that was listed before
+                    Some((known, offset)) => known.saturating_sub(offset),
+                    // Continue from the last segment
+                    None if first_seq == last_seq + 1 => {
+                        let tagged = media_playlist
+                            .segments
+                            .first()
+                            .is_some_and(|s| s.discontinuity);
+                        last_discon + u64::from(tagged)
+                    }
+                    // Follow the declared sequence across unlisted segments unless it goes back
+                    None if declared >= last_discon => declared,
+                    None => last_discon + 1,
+                }
+            }
+        };
+
+        // Warn once, packagers resetting the sequence disagree on every refresh
+        if anchored != declared {
+            let message = format!(
+                "Playlist declares discontinuity sequence {} for segment {}, numbering it {} to \
+                 keep discontinuities monotonic",
+                declared, first_seq, anchored
+            );
+            if self.disagreed {
+                event!(Level::DEBUG, "{}", message);
+            } else {
+                event!(Level::WARN, "{}", message);
+            }
+            self.disagreed = true;
+            for s in &mut segments {
+                s.discon_seq = anchored + (s.discon_seq - declared);
+            }
+        }
+
+        self.known = segments.iter().map(|s| (s.seq, s.discon_seq)).collect();
+        self.last = segments.last().map(|s| (s.seq, s.discon_seq));
+        Ok(segments)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Discontinuity and media sequence of the segments of a playlist
+    fn ids(discons: &mut DisconTracker, playlist: &str) -> Vec<(u64, u64)> {
+        let url = "https://example.com/live.m3u8".parse().unwrap();
+        let playlist = format!("#EXTM3U\n#EXT-X-TARGETDURATION:4\n{}", playlist);
+        let playlist = m3u8_rs::parse_media_playlist_res(playlist.as_bytes()).unwrap();
+        let segments = discons.segments(&playlist, &url, None).unwrap();
+        segments.iter().map(|s| (s.discon_seq, s.seq)).collect()
+    }
+
+    #[test]
+    fn omitted_sequence_continues_numbering() {
+        // The tag of 11 left the playlist
+        let mut discons = DisconTracker::default();
+        let first = "#EXT-X-MEDIA-SEQUENCE:10\n#EXTINF:4,\n10.ts\n\
+            #EXT-X-DISCONTINUITY\n#EXTINF:4,\n11.ts\n";
+        assert_eq!(ids(&mut discons, first), [(0, 10), (1, 11)]);
+        let next = "#EXT-X-MEDIA-SEQUENCE:12\n#EXTINF:4,\n12.ts\n\
+            #EXT-X-DISCONTINUITY\n#EXTINF:4,\n13.ts\n";
+        assert_eq!(ids(&mut discons, next), [(1, 12), (2, 13)]);
+    }
+
+    #[test]
+    fn reset_sequence_is_ignored() {
+        // Reset to 0 on refresh, and after segments that were never listed
+        let mut discons = DisconTracker::default();
+        let first = "#EXT-X-MEDIA-SEQUENCE:20\n#EXT-X-DISCONTINUITY-SEQUENCE:5\n\
+            #EXTINF:4,\n20.ts\n#EXT-X-DISCONTINUITY\n#EXTINF:4,\n21.ts\n";
+        assert_eq!(ids(&mut discons, first), [(5, 20), (6, 21)]);
+        let reset = "#EXT-X-MEDIA-SEQUENCE:21\n#EXT-X-DISCONTINUITY-SEQUENCE:0\n\
+            #EXT-X-DISCONTINUITY\n#EXTINF:4,\n21.ts\n#EXTINF:4,\n22.ts\n";
+        assert_eq!(ids(&mut discons, reset), [(6, 21), (6, 22)]);
+        let after_gap = "#EXT-X-MEDIA-SEQUENCE:30\n#EXT-X-DISCONTINUITY-SEQUENCE:0\n\
+            #EXTINF:4,\n30.ts\n";
+        assert_eq!(ids(&mut discons, after_gap), [(7, 30)]);
+    }
+
+    #[test]
+    fn correct_sequence_is_kept() {
+        // Including jumps across segments that were never listed
+        let mut discons = DisconTracker::default();
+        let first = "#EXT-X-MEDIA-SEQUENCE:100\n#EXT-X-DISCONTINUITY-SEQUENCE:3\n\
+            #EXTINF:4,\n100.ts\n#EXT-X-DISCONTINUITY\n#EXTINF:4,\n101.ts\n";
+        assert_eq!(ids(&mut discons, first), [(3, 100), (4, 101)]);
+        let next = "#EXT-X-MEDIA-SEQUENCE:101\n#EXT-X-DISCONTINUITY-SEQUENCE:3\n\
+            #EXT-X-DISCONTINUITY\n#EXTINF:4,\n101.ts\n#EXTINF:4,\n102.ts\n";
+        assert_eq!(ids(&mut discons, next), [(4, 101), (4, 102)]);
+        let jumped = "#EXT-X-MEDIA-SEQUENCE:200\n#EXT-X-DISCONTINUITY-SEQUENCE:9\n\
+            #EXTINF:4,\n200.ts\n";
+        assert_eq!(ids(&mut discons, jumped), [(9, 200)]);
+    }
+}
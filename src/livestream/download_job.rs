@@ -0,0 +1,76 @@
+use std::cmp::Ordering;
+use std::sync::atomic::{self, AtomicU64};
+use std::time::Instant;
+
+use super::{Encryption, Segment, Stream};
+
+/// Source of ordering indexes, shared by all playlist fetchers
+static NEXT_INDEX: AtomicU64 = AtomicU64::new(0);
+
+/// Priority of a download job, earlier variants are downloaded first
+///
+/// Playlist fetchers queue every segment as Backlog so far, which keeps downloads in FIFO order.
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub(crate) enum Priority {
+    /// Initialization sections needed by other segments
+    Init,
+    /// Newest segments of a livestream
+    LiveEdge,
+    /// Everything else
+    Backlog,
+    /// Segments that can wait, e.g. of less important streams
+    Low,
+}
+
+/// Segment queued by a playlist fetcher for download
+#[derive(Clone, Debug)]
+pub(crate) struct DownloadJob {
+    pub stream: Stream,
+    pub segment: Segment,
+    pub encryption: Encryption,
+    pub priority: Priority,
+    /// Time the job was queued
+    pub enqueued: Instant,
+    /// Order in which jobs were queued
+    pub index: u64,
+}
+
+impl DownloadJob {
+    pub fn new(
+        stream: Stream,
+        segment: Segment,
+        encryption: Encryption,
+        priority: Priority,
+    ) -> Self {
+        Self {
+            stream,
+            segment,
+            encryption,
+            priority,
+            enqueued: Instant::now(),
+            index: NEXT_INDEX.fetch_add(1, atomic::Ordering::Relaxed),
+        }
+    }
+}
+
+impl PartialEq for DownloadJob {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for DownloadJob {}
+
+impl PartialOrd for DownloadJob {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Jobs that should be downloaded first are greater, so that they are popped first from a max-heap
+impl Ord for DownloadJob {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.priority, other.index).cmp(&(self.priority, self.index))
+    }
+}
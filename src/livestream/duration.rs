@@ -0,0 +1,152 @@
+use std::time::Duration;
+
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tracing::{event, Level};
+
+/// Segments longer than this multiple of the target duration are considered bogus
+const MAX_TARGET_RATIO: f64 = 2.0;
+
+/// Cumulative duration of a stream
+///
+/// Each segment's duration is taken from the difference of consecutive EXT-X-PROGRAM-DATE-TIME
+/// tags when available, otherwise from its EXTINF. Durations that are not positive or much longer
+/// than the target duration are replaced with the target duration.
+#[derive(Clone, Debug)]
+pub(crate) struct DurationTracker {
+    target_duration: f64,
+    claimed: f64,
+    checked: f64,
+    /// Last segment, whose duration may still be refined by the next program date time
+    pending: Option<(f64, Option<OffsetDateTime>)>,
+}
+
+impl DurationTracker {
+    pub fn new(target_duration: f32) -> Self {
+        Self {
+            target_duration: target_duration.into(),
+            claimed: 0.0,
+            checked: 0.0,
+            pending: None,
+        }
+    }
+
+    /// Update target duration after a playlist refresh
+    pub fn set_target_duration(&mut self, target_duration: f32) {
+        self.target_duration = target_duration.into();
+    }
+
+    /// Add the next segment of the stream
+    pub fn push(&mut self, extinf: f32, program_date_time: Option<&str>, discontinuity: bool) {
+        let extinf = f64::from(extinf);
+        let pdt = program_date_time.and_then(parse_program_date_time);
+
+        // Finish previous segment, preferring the program date time delta
+        if let Some((duration, prev_pdt)) = self.pending.take() {
+            let delta = match (prev_pdt, pdt) {
+                (Some(a), Some(b)) if !discontinuity => Some((b - a).as_seconds_f64()),
+                _ => None,
+            };
+            self.checked += delta.filter(|d| self.is_sane(*d)).unwrap_or(duration);
+        }
+
+        let duration = if self.is_sane(extinf) {
+            extinf
+        } else {
+            event!(
+                Level::WARN,
+                "Segment duration {}s is implausible for target duration {}s, using target duration",
+                extinf,
+                self.target_duration
+            );
+            self.target_duration
+        };
+
+        self.claimed += extinf;
+        self.pending = Some((duration, pdt));
+    }
+
+    /// Total duration according to EXTINF tags
+    pub fn claimed(&self) -> f64 {
+        self.claimed
+    }
+
+    /// Total duration after sanity checks
+    pub fn checked(&self) -> f64 {
+        self.checked + self.pending.map(|(d, _)| d).unwrap_or_default()
+    }
+
+    fn is_sane(&self, duration: f64) -> bool {
+        is_plausible(duration, self.target_duration)
+    }
+}
+
+/// Duration of a segment from its EXTINF, or the target duration if the EXTINF is implausible as
+/// checked by [`DurationTracker`]
+pub fn segment_duration(extinf: f32, target_duration: f32) -> Option<Duration> {
+    let (extinf, target_duration) = (f64::from(extinf), f64::from(target_duration));
+    let seconds = if is_plausible(extinf, target_duration) {
+        extinf
+    } else {
+        target_duration
+    };
+    Duration::try_from_secs_f64(seconds)
+        .ok()
+        .filter(|d| !d.is_zero())
+}
+
+/// Check if a segment duration is positive and not much longer than the target duration
+fn is_plausible(duration: f64, target_duration: f64) -> bool {
+    duration.is_finite()
+        && duration > 0.0
+        && (target_duration <= 0.0 || duration <= target_duration * MAX_TARGET_RATIO)
+}
+
+/// Parse a program date time, which is often missing the colon in its UTC offset
+pub fn parse_program_date_time(s: &str) -> Option<OffsetDateTime> {
+    OffsetDateTime::parse(s, &Rfc3339).ok().or_else(|| {
+        let i = s.len().checked_sub(5)?;
+        let (datetime, offset) = (s.get(..i)?, s.get(i..)?);
+        let (hours, minutes) = (offset.get(..3)?, offset.get(3..)?);
+        OffsetDateTime::parse(&format!("{}{}:{}", datetime, hours, minutes), &Rfc3339).ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outliers_are_clamped_to_target_duration() {
+        let mut tracker = DurationTracker::new(6.0);
+        for extinf in [6.0, 6.0, 6000.0, 6.0] {
+            tracker.push(extinf, None, false);
+        }
+        assert_eq!(tracker.claimed(), 6018.0);
+        assert_eq!(tracker.checked(), 24.0);
+    }
+
+    #[test]
+    fn program_date_times_take_precedence() {
+        let mut tracker = DurationTracker::new(6.0);
+        tracker.push(6.0, Some("2022-01-01T00:00:00.000Z"), false);
+        tracker.push(6.0, Some("2022-01-01T00:00:05.500Z"), false);
+        assert_eq!(tracker.claimed(), 12.0);
+        assert_eq!(tracker.checked(), 11.5);
+    }
+
+    #[test]
+    fn segment_duration_clamps_outliers() {
+        assert_eq!(
+            segment_duration(5.5, 6.0),
+            Some(Duration::from_secs_f64(5.5))
+        );
+        assert_eq!(segment_duration(6000.0, 6.0), Some(Duration::from_secs(6)));
+        assert_eq!(segment_duration(-1.0, 6.0), Some(Duration::from_secs(6)));
+        assert_eq!(
+            segment_duration(f32::NAN, 6.0),
+            Some(Duration::from_secs(6))
+        );
+        assert_eq!(segment_duration(0.0, 0.0), None);
+    }
+}
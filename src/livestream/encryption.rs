@@ -1,15 +1,35 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
 use aes::cipher::block_padding::Pkcs7;
 use aes::cipher::{BlockDecryptMut, KeyIvInit};
 use anyhow::Result;
 use m3u8_rs::Key;
 use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+use tokio::task;
 use tracing::{event, Level};
 
 use super::http_client::HttpClient;
+use super::media_format::MediaFormat;
+use super::stream::Stream;
+use super::traffic::RequestCategory;
 use super::utils::make_absolute_url;
+use super::EVENT_TARGET;
+use crate::error::LivestreamDLError;
 
 type Aes128CbcDec = cbc::Decryptor<aes::Aes128>;
 
+/// Permits to decrypt segments, one per CPU, so that decryption can't take over the blocking
+/// thread pool
+static DECRYPT_PERMITS: LazyLock<Semaphore> =
+    LazyLock::new(|| Semaphore::new(thread::available_parallelism().map_or(1, |n| n.get())));
+
 /// HLS encryption methods
 #[allow(dead_code)]
 #[derive(Clone, Debug)]
@@ -67,26 +87,236 @@ impl Encryption {
         Ok(encryption)
     }
 
-    /// Decrypt the given data
-    pub async fn decrypt(&self, client: &HttpClient, data: &[u8]) -> Result<Vec<u8>> {
-        let r = match self {
-            Self::None => Vec::from(data),
-            Self::Aes128 { key_uri, iv } => {
-                event!(
-                    Level::TRACE,
-                    "Fetching encryption key from {}",
-                    key_uri.as_str()
-                );
-                let body = client.get(key_uri.clone()).send().await?.bytes().await?;
-                let mut key = [0_u8; 16];
-                key.copy_from_slice(&body[..16]);
-
-                event!(Level::TRACE, "Decrypting segment");
-                Aes128CbcDec::new(&key.into(), iv.into()).decrypt_padded_vec_mut::<Pkcs7>(data)?
-            }
+    /// Decrypt data and return it with the time decryption took and whether the key had to be
+    /// fetched again
+    ///
+    /// Unencrypted data is returned as is. AES-128 segments are decrypted on the blocking thread
+    /// pool, at most one per CPU at once, to keep the async worker threads free for downloads.
+    ///
+    /// Keys may expire and rotate server-side, so if decryption is clearly wrong, with invalid
+    /// padding or, if expect_media is set, output that isn't recognized as media, the key is
+    /// fetched once more and decryption retried before failing with LivestreamDLError::Decrypt.
+    ///
+    /// The key and IV that decrypted the data are recorded to saved_keys if given.
+    pub async fn decrypt(
+        &self,
+        client: &HttpClient,
+        data: Vec<u8>,
+        expect_media: bool,
+        saved_keys: Option<&SavedKeys>,
+    ) -> Result<(Vec<u8>, Option<Duration>, bool)> {
+        let (key_uri, iv) = match self {
+            Self::None => return Ok((data, None, false)),
+            Self::Aes128 { key_uri, iv } => (key_uri, *iv),
             Self::SampleAes => unimplemented!(),
         };
+        let plausible = |d: &[u8]| !expect_media || MediaFormat::sniff(d).is_some();
+
+        let key = fetch_key(client, key_uri).await?;
+        let (data, decrypted, time) = decrypt_aes128(key, iv, data).await?;
+        let record = |key: &[u8; 16]| {
+            if let Some(saved_keys) = saved_keys {
+                saved_keys.record(key_uri, key, &iv);
+            }
+        };
+        let problem = match decrypted {
+            Some(d) if plausible(&d) => {
+                record(&key);
+                return Ok((d, Some(time), false));
+            }
+            Some(_) => "output is not a recognized media format",
+            None => "invalid padding",
+        };
 
-        Ok(r)
+        event!(
+            Level::WARN,
+            "Decrypted segment has {}, fetching key {} again",
+            problem,
+            key_uri.as_str()
+        );
+        let key = fetch_key(client, key_uri).await?;
+        let (_, decrypted, retry_time) = decrypt_aes128(key, iv, data).await?;
+        match decrypted {
+            Some(d) if plausible(&d) => {
+                record(&key);
+                Ok((d, Some(time + retry_time), true))
+            }
+            _ => Err(LivestreamDLError::Decrypt(format!(
+                "{} with key {}, also after fetching the key again",
+                problem, key_uri
+            ))
+            .into()),
+        }
     }
 }
+
+/// Tracks streams whose decrypted segments were recognized as media, so that unrecognized output
+/// of their later segments is taken for a wrong key, and counts segments that only decrypted after
+/// fetching their key again
+#[derive(Default, Debug)]
+pub struct KeyRecovery {
+    recognized: Mutex<HashSet<Stream>>,
+    recovered: AtomicUsize,
+    /// Keys that decrypted segments, if they are to be saved
+    saved_keys: Option<SavedKeys>,
+}
+
+impl KeyRecovery {
+    /// Also record the keys that decrypt segments, see KeyRecovery::save_keys
+    pub fn saving_keys() -> Self {
+        Self {
+            saved_keys: Some(SavedKeys::default()),
+            ..Default::default()
+        }
+    }
+
+    /// Decrypt data of a segment of stream, see Encryption::decrypt
+    pub async fn decrypt(
+        &self,
+        client: &HttpClient,
+        stream: &Stream,
+        encryption: &Encryption,
+        data: Vec<u8>,
+    ) -> Result<(Vec<u8>, Option<Duration>)> {
+        let expect_media = self.recognized.lock().unwrap().contains(stream);
+        let (data, time, refetched) = encryption
+            .decrypt(client, data, expect_media, self.saved_keys.as_ref())
+            .await?;
+        if time.is_some() && !expect_media && MediaFormat::sniff(&data).is_some() {
+            self.recognized.lock().unwrap().insert(stream.clone());
+        }
+        if refetched {
+            self.recovered.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok((data, time))
+    }
+
+    /// Log the number of recovered segments
+    pub fn log(&self) {
+        let recovered = self.recovered.load(Ordering::Relaxed);
+        if recovered > 0 {
+            event!(
+                Level::INFO,
+                "Recovered {} segments by fetching their key again",
+                recovered
+            );
+        }
+    }
+
+    /// Save recorded keys to SavedKeys::FILE_NAME in output, if keys are recorded
+    pub fn save_keys(&self, output: &Path) -> Result<()> {
+        match &self.saved_keys {
+            Some(saved_keys) => saved_keys.save(output.join(SavedKeys::FILE_NAME)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// AES-128 keys and IVs that decrypted segments, saved so that raw segments can be decrypted
+/// again with other tools
+#[derive(Default, Debug)]
+pub struct SavedKeys {
+    /// IVs by key URI and key, all hex encoded
+    keys: Mutex<BTreeMap<(String, String), BTreeSet<String>>>,
+}
+
+/// Entry of the saved keys file
+#[derive(Serialize, Deserialize)]
+struct SavedKey {
+    uri: String,
+    key: String,
+    ivs: BTreeSet<String>,
+}
+
+impl SavedKeys {
+    pub const FILE_NAME: &'static str = "keys.json";
+
+    /// Record that key fetched from key_uri decrypted a segment with iv
+    fn record(&self, key_uri: &Url, key: &[u8; 16], iv: &[u8; 16]) {
+        self.keys
+            .lock()
+            .unwrap()
+            .entry((key_uri.to_string(), hex::encode(key)))
+            .or_default()
+            .insert(hex::encode(iv));
+    }
+
+    /// Write the recorded keys to path, keeping keys saved there by earlier runs
+    fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let mut keys = self.keys.lock().unwrap().clone();
+        if keys.is_empty() {
+            return Ok(());
+        }
+        if path.exists() {
+            let saved: Vec<SavedKey> = serde_json::from_slice(&std::fs::read(path)?)?;
+            for k in saved {
+                keys.entry((k.uri, k.key)).or_default().extend(k.ivs);
+            }
+        }
+
+        let saved: Vec<_> = keys
+            .into_iter()
+            .map(|((uri, key), ivs)| SavedKey { uri, key, ivs })
+            .collect();
+        std::fs::write(path, serde_json::to_vec_pretty(&saved)?)?;
+        event!(
+            Level::INFO,
+            "Saved {} encryption keys to {:?}",
+            saved.len(),
+            path
+        );
+        Ok(())
+    }
+}
+
+/// Fetch an AES-128 key
+async fn fetch_key(client: &HttpClient, key_uri: &Url) -> Result<[u8; 16]> {
+    event!(
+        Level::TRACE,
+        "Fetching encryption key from {}",
+        key_uri.as_str()
+    );
+    let body = match client.read_local(key_uri).await {
+        Some(body) => body?,
+        None => {
+            // Fail with the status, so that an expired key isn't taken for a wrong one
+            let resp = client.get_media(key_uri.clone()).send().await?;
+            if !resp.status().is_success() {
+                return Err(LivestreamDLError::NetworkRequest(resp).into());
+            }
+            let body = resp.bytes().await?;
+            client.traffic().received(RequestCategory::Key, body.len());
+            body.to_vec()
+        }
+    };
+    event!(
+        target: EVENT_TARGET,
+        Level::DEBUG,
+        url = %key_uri,
+        "key_fetched"
+    );
+    body.get(..16)
+        .and_then(|k| k.try_into().ok())
+        .ok_or_else(|| anyhow::anyhow!("Key {} is {} bytes long, not 16", key_uri, body.len()))
+}
+
+/// Decrypt data on the blocking thread pool, returns data, the decrypted data or None if its
+/// padding is invalid, and the time decryption took
+async fn decrypt_aes128(
+    key: [u8; 16],
+    iv: [u8; 16],
+    data: Vec<u8>,
+) -> Result<(Vec<u8>, Option<Vec<u8>>, Duration)> {
+    let _permit = DECRYPT_PERMITS.acquire().await?;
+    event!(Level::TRACE, "Decrypting segment");
+    let res = task::spawn_blocking(move || {
+        let start = Instant::now();
+        let decrypted = Aes128CbcDec::new(&key.into(), &iv.into())
+            .decrypt_padded_vec_mut::<Pkcs7>(&data)
+            .ok();
+        (data, decrypted, start.elapsed())
+    })
+    .await?;
+    Ok(res)
+}
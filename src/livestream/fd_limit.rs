@@ -0,0 +1,165 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::cli::Args;
+
+/// File descriptors used regardless of concurrency: stdio, the async runtime, DNS resolution and
+/// playlist requests
+const BASE_FDS: u64 = 64;
+
+/// File descriptors of a concurrent download: its connection and the segment file being saved
+const FDS_PER_DOWNLOAD: u64 = 2;
+
+/// File descriptors of a concurrent upload: its connection and the file being read
+const FDS_PER_UPLOAD: u64 = 2;
+
+/// Child processes (ffprobe, ffmpeg) that can run at once, each with three pipes
+const CHILD_PROCESSES: u64 = 4;
+const FDS_PER_CHILD: u64 = 6;
+
+/// Files opened outside of downloads and uploads at once, e.g. while concatenating segments
+const EXTRA_OPEN_FILES: usize = 4;
+
+/// Number of file descriptors a download needs, estimated from its configured concurrency
+///
+/// ```
+/// use livestream_dl::livestream::FdEstimate;
+///
+/// let estimate = FdEstimate {
+///     downloads: 20,
+///     uploads: 4,
+///     log_files: 2,
+/// };
+/// assert_eq!(estimate.required(), 138);
+///
+/// // Fits as configured
+/// assert_eq!(estimate.fit(1024), Some(estimate));
+///
+/// // Concurrency is reduced, the larger of downloads and uploads first
+/// let fitted = estimate.fit(120).unwrap();
+/// assert_eq!((fitted.downloads, fitted.uploads), (11, 4));
+/// assert!(fitted.required() <= 120);
+/// let fitted = estimate.fit(108).unwrap();
+/// assert_eq!((fitted.downloads, fitted.uploads), (5, 4));
+///
+/// // At least one download and one upload must fit
+/// assert_eq!(estimate.fit(94).map(|e| (e.downloads, e.uploads)), Some((1, 1)));
+/// assert_eq!(estimate.fit(93), None);
+/// ```
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct FdEstimate {
+    pub downloads: usize,
+    pub uploads: usize,
+    /// Files kept open for the whole download, like the journal and the event log
+    pub log_files: usize,
+}
+
+impl FdEstimate {
+    /// Estimate for the concurrency configured by options
+    pub fn new(options: &Args) -> Self {
+        #[cfg(feature = "s3")]
+        let uploads = match options.upload_options.upload_s3 {
+            Some(_) => options.upload_options.max_concurrent_uploads,
+            None => 0,
+        };
+        #[cfg(not(feature = "s3"))]
+        let uploads = 0;
+
+        Self {
+            downloads: options.network_options.max_concurrent_downloads,
+            uploads,
+            // Journal and event log
+            log_files: usize::from(options.download_options.measure.is_none())
+                + usize::from(options.download_options.event_log.is_some()),
+        }
+    }
+
+    /// Estimated number of file descriptors needed
+    pub fn required(&self) -> u64 {
+        BASE_FDS
+            + CHILD_PROCESSES * FDS_PER_CHILD
+            + self.log_files as u64
+            + self.downloads as u64 * FDS_PER_DOWNLOAD
+            + self.uploads as u64 * FDS_PER_UPLOAD
+    }
+
+    /// Highest concurrency that fits in limit file descriptors, reducing whichever of downloads
+    /// and uploads is larger first, or None if not even one of each fits
+    pub fn fit(&self, limit: u64) -> Option<Self> {
+        let mut fitted = *self;
+        while fitted.required() > limit {
+            if fitted.downloads > 1 && fitted.downloads >= fitted.uploads {
+                fitted.downloads -= 1;
+            } else if fitted.uploads > 1 {
+                fitted.uploads -= 1;
+            } else {
+                return None;
+            }
+        }
+        Some(fitted)
+    }
+}
+
+/// Bound on the number of files a download opens at once, so that it stays within the open file
+/// limit its concurrency was fitted into, unbounded by default
+#[derive(Clone, Debug, Default)]
+pub struct OpenFiles(Option<Arc<Semaphore>>);
+
+impl OpenFiles {
+    /// Bound for the downloads and uploads of estimate, with a few files to spare
+    pub fn new(estimate: &FdEstimate) -> Self {
+        Self(Some(Arc::new(Semaphore::new(
+            estimate.downloads + estimate.uploads + EXTRA_OPEN_FILES,
+        ))))
+    }
+
+    /// Wait until another file may be opened
+    pub async fn acquire(&self) -> Result<FilePermit> {
+        self.acquire_many(1).await
+    }
+
+    /// Wait until n more files may be opened at once
+    ///
+    /// Taking the permits in one step avoids deadlocks between tasks each holding some of the
+    /// permits they need.
+    pub async fn acquire_many(&self, n: u32) -> Result<FilePermit> {
+        let permit = match &self.0 {
+            Some(s) => Some(s.clone().acquire_many_owned(n).await?),
+            None => None,
+        };
+        Ok(FilePermit { _permit: permit })
+    }
+}
+
+/// Permit to open a file, held for as long as the file is open
+#[derive(Debug)]
+pub struct FilePermit {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn downloads_bound_their_own_open_files() {
+        let estimate = FdEstimate {
+            downloads: 1,
+            uploads: 0,
+            log_files: 0,
+        };
+        let first = OpenFiles::new(&estimate);
+        let _held = first
+            .acquire_many(1 + EXTRA_OPEN_FILES as u32)
+            .await
+            .unwrap();
+        assert_eq!(first.0.as_ref().unwrap().available_permits(), 0);
+
+        // Another download, e.g. in the same process as a library, isn't held up
+        let second = OpenFiles::new(&estimate);
+        second.acquire().await.unwrap();
+        OpenFiles::default().acquire_many(100).await.unwrap();
+    }
+}
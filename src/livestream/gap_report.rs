@@ -0,0 +1,241 @@
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use itertools::Itertools;
+
+use super::{Segment, Stream};
+use crate::error::LivestreamDLError;
+
+/// Seconds a cue announcing a gap is shown for
+const CUE_SECONDS: f64 = 5.0;
+
+/// Discontinuity sequence and media sequence of a segment
+type SegmentKey = (u64, u64);
+
+/// Duration in seconds and reason of a failed segment
+type Failure = (f64, String);
+
+/// Segments missing from the output because their last download failed, by stream and
+/// discontinuity and media sequence
+#[derive(Default, Debug)]
+pub struct GapReport {
+    failed: Mutex<HashMap<Stream, BTreeMap<SegmentKey, Failure>>>,
+}
+
+/// Consecutive missing segments of a stream, positioned in the timeline of the output they are
+/// missing from
+#[derive(Clone, PartialEq, Debug)]
+pub struct GapCue {
+    pub stream: Stream,
+    /// Seconds into the output where the segments before and after the gap are joined
+    pub start: f64,
+    /// Seconds into the recording where the gap starts, counting the lost content of earlier
+    /// gaps
+    pub recorded_start: f64,
+    /// Seconds of lost content
+    pub duration: f64,
+    pub segments: usize,
+    /// Distinct reasons the segments failed
+    pub reasons: Vec<String>,
+}
+
+impl GapReport {
+    /// Record a failed download of segment, replacing earlier failures
+    pub fn failed(&self, stream: &Stream, segment: &Segment, reason: String) {
+        let duration = segment.duration.unwrap_or_default().as_secs_f64();
+        self.failed
+            .lock()
+            .unwrap()
+            .entry(stream.clone())
+            .or_default()
+            .insert((segment.discon_seq, segment.seq), (duration, reason));
+    }
+
+    /// Forget failures of a segment that was saved after all
+    pub fn saved(&self, stream: &Stream, segment: &Segment) {
+        if let Some(failed) = self.failed.lock().unwrap().get_mut(stream) {
+            failed.remove(&(segment.discon_seq, segment.seq));
+        }
+    }
+
+    /// Check if no segment is missing
+    pub fn is_empty(&self) -> bool {
+        self.failed.lock().unwrap().values().all(|f| f.is_empty())
+    }
+
+    /// Gaps of the output of a discontinuity, positioned by the durations of the segments saved
+    /// before them, sorted by start
+    ///
+    /// Gaps after the last saved segment start early enough for their cue to be shown.
+    ///
+    /// ```
+    /// use std::collections::{BinaryHeap, HashMap};
+    /// use std::path::PathBuf;
+    /// use std::time::Duration;
+    ///
+    /// use livestream_dl::livestream::{GapReport, MediaFormat, RemoteData, Segment, Stream};
+    ///
+    /// let segment = |seq| Segment {
+    ///     data: RemoteData::new(format!("https://example.com/{}.ts", seq).parse().unwrap(), None),
+    ///     discon_seq: 0,
+    ///     seq,
+    ///     format: MediaFormat::MpegTs,
+    ///     initialization: None,
+    ///     program_date_time: None,
+    ///     duration: Some(Duration::from_secs(6)),
+    /// };
+    ///
+    /// // Segments 2 and 3 failed, 0, 1 and 4 were saved
+    /// let report = GapReport::default();
+    /// report.failed(&Stream::Main, &segment(2), "HTTP 404".into());
+    /// report.failed(&Stream::Main, &segment(3), "HTTP 404".into());
+    /// let saved: BinaryHeap<_> = [0, 1, 4]
+    ///     .into_iter()
+    ///     .map(|seq| (segment(seq), PathBuf::from(format!("{}.ts", seq))))
+    ///     .collect();
+    /// let downloaded = HashMap::from([(Stream::Main, saved)]);
+    ///
+    /// // The gap is at the join of segments 1 and 4, 12s into the output
+    /// let cues = report.cues(&downloaded, 0);
+    /// assert_eq!(cues.len(), 1);
+    /// assert_eq!((cues[0].start, cues[0].recorded_start, cues[0].duration), (12.0, 12.0, 12.0));
+    /// assert_eq!(
+    ///     cues[0].text(),
+    ///     "[RECORDING GAP 00:00:12\u{2013}00:00:24 \u{2014} 2 segments lost, HTTP 404]"
+    /// );
+    ///
+    /// // Without segment 4 the gap is at the end, its cue starts early enough to be shown
+    /// let mut downloaded = downloaded;
+    /// downloaded.get_mut(&Stream::Main).unwrap().retain(|(s, _)| s.seq < 4);
+    /// let cues = report.cues(&downloaded, 0);
+    /// assert_eq!((cues[0].start, cues[0].recorded_start), (7.0, 12.0));
+    ///
+    /// // Segment 2 was saved after all
+    /// report.saved(&Stream::Main, &segment(2));
+    /// assert_eq!(report.cues(&downloaded, 0)[0].segments, 1);
+    /// ```
+    pub fn cues(
+        &self,
+        downloaded: &HashMap<Stream, BinaryHeap<(Segment, PathBuf)>>,
+        discon_seq: u64,
+    ) -> Vec<GapCue> {
+        let failed = self.failed.lock().unwrap();
+        let mut cues = Vec::new();
+        for (stream, failed) in failed.iter() {
+            let saved: BTreeMap<_, _> = downloaded
+                .get(stream)
+                .into_iter()
+                .flatten()
+                .filter(|(s, _)| s.discon_seq == discon_seq)
+                .map(|(s, _)| (s.seq, s.duration.unwrap_or_default().as_secs_f64()))
+                .collect();
+            let failed = failed
+                .range((discon_seq, 0)..=(discon_seq, u64::MAX))
+                .map(|((_, seq), f)| (*seq, f));
+            let end: f64 = saved.values().sum();
+
+            // Walk saved and failed segments in order, grouping consecutive failures
+            let mut position = 0.0;
+            let mut lost = 0.0;
+            let mut gap: Option<GapCue> = None;
+            let segments = saved
+                .iter()
+                .map(|(seq, duration)| (*seq, Err(*duration)))
+                .merge_by(failed.map(|(seq, f)| (seq, Ok(f))), |a, b| a.0 <= b.0);
+            for (_, segment) in segments {
+                match segment {
+                    Ok((duration, reason)) => {
+                        let g = gap.get_or_insert_with(|| GapCue {
+                            stream: stream.clone(),
+                            start: position,
+                            recorded_start: position + lost,
+                            duration: 0.0,
+                            segments: 0,
+                            reasons: Vec::new(),
+                        });
+                        g.duration += duration;
+                        g.segments += 1;
+                        if !g.reasons.contains(reason) {
+                            g.reasons.push(reason.clone());
+                        }
+                        lost += duration;
+                    }
+                    Err(duration) => {
+                        cues.extend(gap.take());
+                        position += duration;
+                    }
+                }
+            }
+            if let Some(mut g) = gap {
+                g.start = g.start.min((end - CUE_SECONDS).max(0.0));
+                cues.push(g);
+            }
+        }
+        cues.sort_by(|a, b| a.start.total_cmp(&b.start));
+        cues
+    }
+}
+
+impl GapCue {
+    /// Text of the cue announcing the gap
+    pub fn text(&self) -> String {
+        let stream = match self.stream {
+            Stream::Main => String::new(),
+            _ => format!("{} ", self.stream),
+        };
+        format!(
+            "[RECORDING GAP {}{}\u{2013}{} \u{2014} {} segment{} lost, {}]",
+            stream,
+            timestamp(self.recorded_start),
+            timestamp(self.recorded_start + self.duration),
+            self.segments,
+            if self.segments == 1 { "" } else { "s" },
+            self.reasons.join("; ")
+        )
+    }
+}
+
+/// SubRip subtitles showing each cue from its start for a few seconds
+pub fn gaps_srt(cues: &[GapCue]) -> String {
+    let mut srt = String::new();
+    for (i, cue) in cues.iter().enumerate() {
+        let _ = write!(
+            srt,
+            "{}\n{} --> {}\n{}\n\n",
+            i + 1,
+            srt_timestamp(cue.start),
+            srt_timestamp(cue.start + CUE_SECONDS),
+            cue.text()
+        );
+    }
+    srt
+}
+
+/// Short reason a segment failed for cues, e.g. HTTP 404
+pub fn gap_reason(e: &anyhow::Error) -> String {
+    match e.downcast_ref::<LivestreamDLError>() {
+        Some(LivestreamDLError::NetworkRequest(r)) => format!("HTTP {}", r.status().as_u16()),
+        Some(LivestreamDLError::Decrypt(_)) => "decryption failed".to_string(),
+        _ => e.root_cause().to_string(),
+    }
+}
+
+/// Seconds formatted as HH:MM:SS
+fn timestamp(seconds: f64) -> String {
+    let s = seconds.round() as u64;
+    format!("{:02}:{:02}:{:02}", s / 3600, s / 60 % 60, s % 60)
+}
+
+/// Seconds formatted as a SubRip timestamp, HH:MM:SS,mmm
+fn srt_timestamp(seconds: f64) -> String {
+    let ms = (seconds * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02},{:03}",
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
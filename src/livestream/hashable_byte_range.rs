@@ -1,8 +1,24 @@
+use std::cmp::Ordering;
 use std::hash::Hash;
 use std::ops::Deref;
 
 use m3u8_rs::ByteRange;
 
+/// Byte range that can be hashed and ordered, ordered by offset then length
+///
+/// ```
+/// use livestream_dl::livestream::HashableByteRange;
+///
+/// let first = HashableByteRange::from_offset_len(0, 100);
+/// let second = HashableByteRange::following(&first, 50);
+/// assert_eq!(second.start(), 100);
+/// assert_eq!(second.end(), 150);
+/// assert_eq!(second.to_http_range(), "bytes=100-149");
+/// assert!(first.is_contiguous_with(&second));
+/// assert!(!second.is_contiguous_with(&first));
+/// assert!(first < second);
+/// assert!(HashableByteRange::from_offset_len(0, 10) < first);
+/// ```
 #[derive(Clone, Eq, Debug)]
 pub struct HashableByteRange(ByteRange);
 
@@ -10,6 +26,48 @@ impl HashableByteRange {
     pub fn new(b: ByteRange) -> Self {
         Self(b)
     }
+
+    /// Byte range of length bytes starting at offset
+    pub fn from_offset_len(offset: u64, length: u64) -> Self {
+        Self(ByteRange {
+            length,
+            offset: Some(offset),
+        })
+    }
+
+    /// Byte range of length bytes starting directly after prev
+    pub fn following(prev: &HashableByteRange, length: u64) -> Self {
+        Self::from_offset_len(prev.end(), length)
+    }
+
+    pub fn into_inner(self) -> ByteRange {
+        self.0
+    }
+
+    /// Offset of the first byte, a missing offset means the start of the resource
+    pub fn start(&self) -> u64 {
+        self.0.offset.unwrap_or(0)
+    }
+
+    /// Offset one past the last byte
+    pub fn end(&self) -> u64 {
+        self.start() + self.0.length
+    }
+
+    /// Check if next starts directly after this range
+    pub fn is_contiguous_with(&self, next: &HashableByteRange) -> bool {
+        self.end() == next.start()
+    }
+
+    /// Value of an HTTP Range header requesting this range
+    pub fn to_http_range(&self) -> String {
+        let start = self.start();
+        format!(
+            "bytes={}-{}",
+            start,
+            start + self.0.length.saturating_sub(1)
+        )
+    }
 }
 
 impl Deref for HashableByteRange {
@@ -32,3 +90,15 @@ impl Hash for HashableByteRange {
         self.0.offset.hash(state);
     }
 }
+
+impl PartialOrd for HashableByteRange {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for HashableByteRange {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.0.offset, self.0.length).cmp(&(other.0.offset, other.0.length))
+    }
+}
@@ -1,14 +1,25 @@
 use std::fmt::Display;
 
-use reqwest::IntoUrl;
+use anyhow::Result;
+use reqwest::header::{HeaderValue, ACCEPT_ENCODING, REFERER};
+use reqwest::Url;
 use reqwest_middleware::{ClientWithMiddleware, RequestBuilder};
 
+use super::content_encoding::playlist_body;
+use super::local_url::{is_local, read_local};
+use super::traffic::{RequestCategory, TrafficStats};
+use crate::error::LivestreamDLError;
+
 /// Wrapper around ClientWithMiddleware to optionally add additional GET query parameters to every
-/// GET request
+/// GET request, and a Referer to requests for segments and keys
 #[derive(Clone, Debug)]
 pub struct HttpClient {
     client: ClientWithMiddleware,
     query_pairs: Option<Vec<(String, String)>>,
+    referer: Option<HeaderValue>,
+    traffic: TrafficStats,
+    /// Read file: and data: URLs, only set if the playlist being downloaded is local itself
+    local_urls: bool,
 }
 
 impl HttpClient {
@@ -25,13 +36,108 @@ impl HttpClient {
                     .map(|(s1, s2)| (s1.to_string(), s2.to_string()))
                     .collect()
             }),
+            referer: None,
+            traffic: TrafficStats::default(),
+            local_urls: false,
+        }
+    }
+
+    /// Count bytes of response bodies in traffic, which should be shared with the
+    /// [`TrafficMiddleware`](super::TrafficMiddleware) of the client
+    pub fn with_traffic(mut self, traffic: TrafficStats) -> Self {
+        self.traffic = traffic;
+        self
+    }
+
+    /// Requests and bytes received by this client
+    pub fn traffic(&self) -> &TrafficStats {
+        &self.traffic
+    }
+
+    /// Send referer with requests for segments and keys
+    pub fn with_referer(mut self, referer: HeaderValue) -> Self {
+        self.referer = Some(referer);
+        self
+    }
+
+    /// Read file: and data: URLs listed by playlists, which are refused otherwise so that a remote
+    /// playlist can't have local files downloaded
+    pub fn with_local_urls(mut self) -> Self {
+        self.local_urls = true;
+        self
+    }
+
+    /// Content of a file: or data: URL, or None for other URLs, which are fetched over HTTP
+    ///
+    /// Fails for file: and data: URLs unless they were allowed by
+    /// [`with_local_urls`](Self::with_local_urls).
+    pub async fn read_local(&self, url: &Url) -> Option<Result<Vec<u8>>> {
+        if is_local(url) && !self.local_urls {
+            return Some(Err(anyhow::anyhow!(
+                "Refusing to read {} listed by a remote playlist, file: and data: URLs are only \
+                 read for local playlists",
+                url
+            )));
+        }
+        read_local(url).await
+    }
+
+    /// GET request for a playlist, whose body is decoded with
+    /// [`playlist_body`](super::content_encoding::playlist_body)
+    pub fn get_playlist(&self, url: Url) -> RequestBuilder {
+        self.get(url).header(
+            ACCEPT_ENCODING,
+            HeaderValue::from_static("gzip, deflate, br"),
+        )
+    }
+
+    /// Fetch the playlist at url, returns its body and its URL after redirects
+    ///
+    /// file: and data: URLs are read locally if allowed.
+    pub async fn fetch_playlist(&self, url: &Url) -> Result<(Vec<u8>, Url)> {
+        if let Some(body) = self.read_local(url).await {
+            return Ok((body?, url.clone()));
+        }
+
+        let resp = self.get_playlist(url.clone()).send().await?;
+        if !resp.status().is_success() {
+            return Err(LivestreamDLError::NetworkRequest(resp).into());
+        }
+        let final_url = resp.url().clone();
+        let body = playlist_body(resp, self.traffic.body(RequestCategory::Playlist)).await?;
+        Ok((body, final_url))
+    }
+
+    pub fn get(&self, url: Url) -> RequestBuilder {
+        self.client.get(self.request_url(url))
+    }
+
+    /// GET request for a segment or key
+    pub fn get_media(&self, url: Url) -> RequestBuilder {
+        self.with_media_headers(self.get(url))
+    }
+
+    /// HEAD request for a segment
+    pub fn head_media(&self, url: Url) -> RequestBuilder {
+        self.with_media_headers(self.client.head(self.request_url(url)))
+    }
+
+    /// URL that requests for url are sent to, with the additional query parameters
+    pub fn request_url(&self, mut url: Url) -> Url {
+        if let Some(q) = &self.query_pairs {
+            url.query_pairs_mut().extend_pairs(q);
+            // Like reqwest, drop the query if it stayed empty
+            if url.query() == Some("") {
+                url.set_query(None);
+            }
         }
+        url
     }
 
-    pub fn get<T: IntoUrl>(&self, url: T) -> RequestBuilder {
-        match &self.query_pairs {
-            Some(q) => self.client.get(url).query(q),
-            None => self.client.get(url),
+    fn with_media_headers(&self, req: RequestBuilder) -> RequestBuilder {
+        match &self.referer {
+            Some(r) => req.header(REFERER, r.clone()),
+            None => req,
         }
     }
 }
@@ -0,0 +1,155 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use lru::LruCache;
+use tokio::sync::OnceCell;
+use tracing::{event, Level};
+
+use super::{RemoteData, SegmentFetcher, Stream};
+
+/// Initialization sections of all streams by URL and byte range, so streams referencing the same
+/// EXT-X-MAP download it once
+#[derive(Debug)]
+pub(crate) struct InitCache {
+    entries: Mutex<LruCache<RemoteData, InitEntry>>,
+    /// Downloads avoided by sharing an initialization with another stream
+    shared: AtomicUsize,
+}
+
+#[derive(Debug)]
+struct InitEntry {
+    data: Arc<OnceCell<Vec<u8>>>,
+    /// Streams that used the initialization
+    streams: HashSet<Stream>,
+}
+
+impl InitCache {
+    /// Cache of the last capacity initializations
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Mutex::new(LruCache::new(capacity)),
+            shared: AtomicUsize::new(0),
+        }
+    }
+
+    /// Initialization init of stream, fetched from fetcher unless it is cached
+    ///
+    /// Concurrent calls for the same initialization wait for a single download.
+    pub async fn get(
+        &self,
+        stream: &Stream,
+        init: &RemoteData,
+        fetcher: &dyn SegmentFetcher,
+    ) -> Result<Vec<u8>> {
+        let (data, shared_with) = {
+            let mut entries = self.entries.lock().unwrap();
+            if !entries.contains(init) {
+                entries.put(
+                    init.clone(),
+                    InitEntry {
+                        data: Default::default(),
+                        streams: HashSet::new(),
+                    },
+                );
+            }
+            let entry = entries.get_mut(init).unwrap();
+            let others: Vec<_> = entry.streams.iter().map(ToString::to_string).collect();
+            let new_stream = entry.streams.insert(stream.clone());
+            (
+                entry.data.clone(),
+                (new_stream && !others.is_empty()).then_some(others),
+            )
+        };
+
+        let mut fetched = false;
+        let bytes = data
+            .get_or_try_init(|| {
+                fetched = true;
+                fetcher.fetch_init(init)
+            })
+            .await?
+            .clone();
+
+        if let (Some(others), false) = (shared_with, fetched) {
+            self.shared.fetch_add(1, Ordering::Relaxed);
+            event!(
+                Level::INFO,
+                "Sharing initialization {} of {} with {}",
+                init.url(),
+                others.join(", "),
+                stream
+            );
+        }
+        Ok(bytes)
+    }
+
+    /// Number of initialization downloads avoided by sharing them between streams
+    pub fn shared(&self) -> usize {
+        self.shared.load(Ordering::Relaxed)
+    }
+
+    /// Log the number of downloads avoided by sharing initializations
+    pub fn log(&self) {
+        let shared = self.shared();
+        if shared > 0 {
+            event!(
+                Level::INFO,
+                "Saved {} initialization requests by sharing them between streams",
+                shared
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use m3u8_rs::ByteRange;
+
+    use super::*;
+    use crate::livestream::FetchedData;
+
+    #[derive(Debug, Default)]
+    struct MemoryFetcher(Mutex<Vec<String>>);
+
+    #[async_trait]
+    impl SegmentFetcher for MemoryFetcher {
+        async fn fetch(&self, data: &RemoteData) -> Result<FetchedData> {
+            self.0.lock().unwrap().push(data.url().to_string());
+            Ok(FetchedData::new(b"moov".to_vec(), data.url().clone()))
+        }
+    }
+
+    #[tokio::test]
+    async fn streams_share_one_download() {
+        let fetcher = MemoryFetcher::default();
+        let audio = Stream::Audio {
+            group_id: "aac".into(),
+            name: "English".into(),
+            lang: None,
+            channels: None,
+            describes_video: false,
+        };
+        let init = RemoteData::new("https://example.com/init.mp4".parse().unwrap(), None);
+        let range = ByteRange {
+            length: 4,
+            offset: Some(0),
+        };
+        let ranged = RemoteData::new("https://example.com/init.mp4".parse().unwrap(), Some(range));
+
+        let cache = InitCache::new(4);
+        let (main, audio) = futures::join!(
+            cache.get(&Stream::Main, &init, &fetcher),
+            cache.get(&audio, &init, &fetcher),
+        );
+        assert_eq!(main.unwrap(), b"moov");
+        assert_eq!(audio.unwrap(), b"moov");
+
+        // A different byte range of the same URL is another initialization
+        cache.get(&Stream::Main, &ranged, &fetcher).await.unwrap();
+        assert_eq!(fetcher.0.lock().unwrap().len(), 2);
+        assert_eq!(cache.shared(), 1);
+    }
+}
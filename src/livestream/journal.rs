@@ -0,0 +1,433 @@
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fs;
+use std::io::{prelude::*, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use m3u8_rs::ByteRange;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tracing::{event, Level};
+
+use super::remote_data::{RemoteData, ResponseHeaders};
+use super::stream_params::StreamParams;
+use super::{MediaFormat, Segment, Stream};
+
+/// Location of a piece of remote data as recorded in the journal
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DataRecord {
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub length: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset: Option<u64>,
+}
+
+impl From<&RemoteData> for DataRecord {
+    fn from(d: &RemoteData) -> Self {
+        let byte_range = d.byte_range();
+        Self {
+            url: d.url().to_string(),
+            length: byte_range.map(|b| b.length),
+            offset: byte_range.and_then(|b| b.offset),
+        }
+    }
+}
+
+impl DataRecord {
+    fn to_remote_data(&self) -> Result<RemoteData> {
+        let byte_range = self.length.map(|length| ByteRange {
+            length,
+            offset: self.offset,
+        });
+        Ok(RemoteData::new(Url::parse(&self.url)?, byte_range))
+    }
+}
+
+/// Events recorded in the journal
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum JournalEntry {
+    /// Segment was queued for download
+    Enqueue {
+        stream: Stream,
+        discon_seq: u64,
+        seq: u64,
+        data: DataRecord,
+    },
+    /// Segment was saved to disk, file name is relative to the segments directory
+    Save {
+        stream: Stream,
+        discon_seq: u64,
+        seq: u64,
+        data: DataRecord,
+        format: MediaFormat,
+        file_name: String,
+        /// RFC 3339 program date time of the segment
+        #[serde(default)]
+        program_date_time: Option<String>,
+        /// EXTINF duration of the segment in seconds
+        #[serde(default)]
+        duration: Option<f64>,
+    },
+    /// Captured response headers of a downloaded segment
+    ResponseHeaders {
+        stream: Stream,
+        discon_seq: u64,
+        seq: u64,
+        headers: ResponseHeaders,
+    },
+    /// Segment has the identity of a recently saved segment, but different content. Hashes are
+    /// hexadecimal XXH3 hashes of the segment data
+    ContentChanged {
+        stream: Stream,
+        discon_seq: u64,
+        seq: u64,
+        data: DataRecord,
+        previous_hash: String,
+        hash: String,
+    },
+    /// Segment was skipped because it was already saved
+    Skip {
+        stream: Stream,
+        discon_seq: u64,
+        seq: u64,
+    },
+    /// Segment failed to download or save
+    Gap {
+        stream: Stream,
+        discon_seq: u64,
+        seq: u64,
+        reason: String,
+    },
+    /// Initialization section of a stream changed
+    InitChange {
+        stream: Stream,
+        data: Option<DataRecord>,
+    },
+    /// Codecs or resolution of a stream changed, starting at a segment
+    ParamsChange {
+        stream: Stream,
+        discon_seq: u64,
+        seq: u64,
+        from: StreamParams,
+        to: StreamParams,
+    },
+}
+
+impl JournalEntry {
+    /// Entry for a segment saved at path
+    pub fn saved(stream: &Stream, segment: &Segment, path: &Path) -> Self {
+        Self::Save {
+            stream: stream.clone(),
+            discon_seq: segment.discon_seq,
+            seq: segment.seq,
+            data: DataRecord::from(&segment.data),
+            format: segment.format.clone(),
+            file_name: path
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            program_date_time: segment
+                .program_date_time
+                .and_then(|t| t.format(&Rfc3339).ok()),
+            duration: segment.duration.map(|d| d.as_secs_f64()),
+        }
+    }
+}
+
+/// Append-only journal of download events, used to resume interrupted downloads
+#[derive(Debug)]
+pub struct Journal(Mutex<BufWriter<fs::File>>);
+
+/// Download state rebuilt from a previous run
+#[derive(Default, Debug)]
+pub struct ResumeState {
+    pub downloaded_segments: HashMap<Stream, BinaryHeap<(Segment, PathBuf)>>,
+    pub gaps: HashMap<Stream, HashSet<(u64, u64)>>,
+    saved: HashSet<(Stream, u64, u64)>,
+}
+
+impl ResumeState {
+    /// Check if a segment was already saved
+    pub fn contains(&self, stream: &Stream, segment: &Segment) -> bool {
+        self.saved
+            .contains(&(stream.clone(), segment.discon_seq, segment.seq))
+    }
+
+    fn insert(&mut self, stream: Stream, segment: Segment, path: PathBuf) {
+        if let Some(gaps) = self.gaps.get_mut(&stream) {
+            gaps.remove(&(segment.discon_seq, segment.seq));
+        }
+        self.saved
+            .insert((stream.clone(), segment.discon_seq, segment.seq));
+        self.downloaded_segments
+            .entry(stream)
+            .or_default()
+            .push((segment, path));
+    }
+}
+
+impl Journal {
+    pub const FILE_NAME: &'static str = "journal.ndjson";
+
+    /// Open journal for appending, creating it if needed and discarding old entries if truncate
+    pub fn open(path: impl AsRef<Path>, truncate: bool) -> Result<Self> {
+        if truncate {
+            fs::File::create(path.as_ref())
+                .with_context(|| format!("error creating journal {:?}", path.as_ref()))?;
+        }
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path.as_ref())
+            .with_context(|| format!("error opening journal {:?}", path.as_ref()))?;
+
+        // Terminate a torn final line so new entries start on their own line
+        let len = file.metadata()?.len();
+        if len > 0 {
+            let mut last = [0_u8];
+            file.seek(std::io::SeekFrom::Start(len - 1))?;
+            file.read_exact(&mut last)?;
+            if last[0] != b'\n' {
+                file.write_all(b"\n")?;
+            }
+        }
+
+        Ok(Self(Mutex::new(BufWriter::new(file))))
+    }
+
+    /// Append an entry to the journal
+    pub fn record(&self, entry: &JournalEntry) {
+        let mut writer = self.0.lock().unwrap();
+        let res = serde_json::to_writer(&mut *writer, entry)
+            .map_err(anyhow::Error::from)
+            .and_then(|_| Ok(writer.write_all(b"\n")?));
+        if let Err(e) = res {
+            event!(Level::WARN, "Failed to write journal entry: {}", e);
+        }
+    }
+
+    /// Flush buffered entries to disk
+    pub fn flush(&self) {
+        if let Err(e) = self.0.lock().unwrap().flush() {
+            event!(Level::WARN, "Failed to flush journal: {}", e);
+        }
+    }
+
+    /// Rebuild download state from the journal at path, returns None if there is no journal
+    ///
+    /// Lines that can't be parsed, e.g. the last line torn by a crash, are skipped.
+    pub fn replay(
+        path: impl AsRef<Path>,
+        segments_directory: impl AsRef<Path>,
+    ) -> Result<Option<ResumeState>> {
+        let path = path.as_ref();
+        let bytes = match fs::read(path) {
+            Ok(b) => b,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("error reading journal {:?}", path)),
+        };
+
+        // Lines are split as bytes, a torn line may end in the middle of a character
+        let mut state = ResumeState::default();
+        let mut inits: HashMap<Stream, Option<RemoteData>> = HashMap::new();
+        for (i, line) in bytes.split(|b| *b == b'\n').enumerate() {
+            if line.trim_ascii().is_empty() {
+                continue;
+            }
+
+            let res = serde_json::from_slice(line)
+                .map_err(anyhow::Error::from)
+                .and_then(|entry| {
+                    state.replay_entry(entry, &mut inits, segments_directory.as_ref())
+                });
+            if let Err(e) = res {
+                // A torn line is expected if the previous run crashed mid-write
+                event!(
+                    Level::WARN,
+                    "Ignoring malformed journal line {} of {:?}: {:#}",
+                    i + 1,
+                    path,
+                    e
+                );
+            }
+        }
+
+        Ok(Some(state))
+    }
+}
+
+impl ResumeState {
+    /// Apply a journal entry, inits are the initialization sections in effect by stream
+    fn replay_entry(
+        &mut self,
+        entry: JournalEntry,
+        inits: &mut HashMap<Stream, Option<RemoteData>>,
+        segments_directory: &Path,
+    ) -> Result<()> {
+        match entry {
+            JournalEntry::InitChange { stream, data } => {
+                let data = data.map(|d| d.to_remote_data()).transpose()?;
+                inits.insert(stream, data);
+            }
+            JournalEntry::Save {
+                stream,
+                discon_seq,
+                seq,
+                data,
+                format,
+                file_name,
+                program_date_time,
+                duration,
+            } => {
+                let segment = Segment {
+                    data: data.to_remote_data()?,
+                    discon_seq,
+                    seq,
+                    format,
+                    initialization: inits.get(&stream).cloned().flatten(),
+                    program_date_time: program_date_time
+                        .and_then(|t| OffsetDateTime::parse(&t, &Rfc3339).ok()),
+                    duration: duration.and_then(|d| Duration::try_from_secs_f64(d).ok()),
+                };
+                let path = segments_directory.join(file_name);
+                self.insert(stream, segment, path);
+            }
+            JournalEntry::Gap {
+                stream,
+                discon_seq,
+                seq,
+                ..
+            } => {
+                self.gaps
+                    .entry(stream)
+                    .or_default()
+                    .insert((discon_seq, seq));
+            }
+            JournalEntry::Enqueue { .. }
+            | JournalEntry::Skip { .. }
+            | JournalEntry::ParamsChange { .. }
+            | JournalEntry::ResponseHeaders { .. }
+            | JournalEntry::ContentChanged { .. } => (),
+        }
+        Ok(())
+    }
+}
+
+/// Rebuild download state by scanning segment file names, used when no journal exists
+pub fn scan_segments<'a>(
+    segments_directory: impl AsRef<Path>,
+    streams: impl IntoIterator<Item = &'a Stream>,
+) -> Result<ResumeState> {
+    let mut state = ResumeState::default();
+    let entries = match fs::read_dir(segments_directory.as_ref()) {
+        Ok(e) => e,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(state),
+        Err(e) => return Err(e.into()),
+    };
+    let streams: Vec<_> = streams.into_iter().collect();
+
+    for entry in entries {
+        let path = entry?.path();
+        let (stream_name, segment) = match segment_from_file(&path)? {
+            Some(s) => s,
+            None => continue,
+        };
+        let stream = match streams.iter().find(|s| s.file_id() == stream_name) {
+            Some(s) => (*s).clone(),
+            None => continue,
+        };
+        state.insert(stream, segment, path);
+    }
+
+    Ok(state)
+}
+
+/// Parse the stream name and segment of a saved segment file, returns None if the file name
+/// isn't a segment file name
+pub fn segment_from_file(path: &Path) -> Result<Option<(String, Segment)>> {
+    let parsed = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .and_then(Segment::parse_file_name);
+    let (stream_name, discon_seq, seq, format) = match parsed {
+        Some(p) => p,
+        None => return Ok(None),
+    };
+
+    let url = Url::from_file_path(fs::canonicalize(path)?)
+        .map_err(|_| anyhow::anyhow!("Invalid segment path {:?}", path))?;
+    let segment = Segment {
+        data: RemoteData::new(url, None),
+        discon_seq,
+        seq,
+        format,
+        initialization: None,
+        program_date_time: None,
+        duration: None,
+    };
+    Ok(Some((stream_name.to_owned(), segment)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn save(name: &str, seq: u64) -> JournalEntry {
+        JournalEntry::Save {
+            stream: Stream::Audio {
+                group_id: "aac".into(),
+                name: name.into(),
+                lang: Some("es".into()),
+                channels: None,
+                describes_video: false,
+            },
+            discon_seq: 0,
+            seq,
+            data: DataRecord {
+                url: format!("https://example.com/{}.aac", seq),
+                length: None,
+                offset: None,
+            },
+            format: MediaFormat::Aac,
+            file_name: format!("{}.aac", seq),
+            program_date_time: None,
+            duration: Some(4.0),
+        }
+    }
+
+    #[test]
+    fn replay_skips_line_torn_inside_character() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(Journal::FILE_NAME);
+        let mut bytes = Vec::new();
+        for seq in [0, 1] {
+            bytes.extend(serde_json::to_vec(&save("Español", seq)).unwrap());
+            bytes.push(b'\n');
+        }
+
+        // Cut the last line between the two bytes of ñ
+        let torn = serde_json::to_vec(&save("Español", 2)).unwrap();
+        let cut = torn.windows(2).position(|w| w == "ñ".as_bytes()).unwrap() + 1;
+        bytes.extend(&torn[..cut]);
+        fs::write(&path, bytes).unwrap();
+
+        let state = Journal::replay(&path, dir.path()).unwrap().unwrap();
+        let saved: Vec<_> = state.downloaded_segments.values().flatten().collect();
+        assert_eq!(saved.len(), 2);
+        assert!(saved.iter().all(|(s, _)| s.seq < 2));
+    }
+
+    #[test]
+    fn replay_without_journal() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(Journal::FILE_NAME);
+        assert!(Journal::replay(path, dir.path()).unwrap().is_none());
+    }
+}
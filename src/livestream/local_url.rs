@@ -0,0 +1,61 @@
+use anyhow::{Context, Result};
+use percent_encoding::percent_decode_str;
+use reqwest::Url;
+use tokio::fs;
+
+/// Check if url is a file: or data: URL, which is read without a request
+pub fn is_local(url: &Url) -> bool {
+    matches!(url.scheme(), "file" | "data")
+}
+
+/// Content of a file: or data: URL, or None for other URLs, which are fetched over HTTP
+pub async fn read_local(url: &Url) -> Option<Result<Vec<u8>>> {
+    match url.scheme() {
+        "file" => Some(read_file(url).await),
+        "data" => Some(decode_data_url(url)),
+        _ => None,
+    }
+}
+
+async fn read_file(url: &Url) -> Result<Vec<u8>> {
+    let path = url
+        .to_file_path()
+        .map_err(|_| anyhow::anyhow!("Invalid file URL {}", url))?;
+    fs::read(&path)
+        .await
+        .with_context(|| format!("Unable to read {:?}", path))
+}
+
+/// Data of a data: URL, percent-encoded or base64 encoded if its media type ends with ;base64
+///
+/// ```
+/// use livestream_dl::livestream::decode_data_url;
+///
+/// let url = "data:application/vnd.apple.mpegurl,%23EXTM3U%0A".parse().unwrap();
+/// assert_eq!(decode_data_url(&url).unwrap(), b"#EXTM3U\n");
+/// let url = "data:application/vnd.apple.mpegurl;base64,I0VYVE0zVQo=".parse().unwrap();
+/// assert_eq!(decode_data_url(&url).unwrap(), b"#EXTM3U\n");
+/// let url = "data:;base64,%%%".parse().unwrap();
+/// assert!(decode_data_url(&url).is_err());
+/// ```
+pub fn decode_data_url(url: &Url) -> Result<Vec<u8>> {
+    let content = url
+        .as_str()
+        .strip_prefix("data:")
+        .ok_or_else(|| anyhow::anyhow!("Not a data URL: {}", url))?;
+    let content = content.split('#').next().unwrap_or_default();
+    let (media_type, data) = content
+        .split_once(',')
+        .ok_or_else(|| anyhow::anyhow!("Data URL without data: {}", url))?;
+
+    let data: Vec<u8> = percent_decode_str(data).collect();
+    if media_type.ends_with(";base64") {
+        let data: Vec<u8> = data
+            .into_iter()
+            .filter(|b| !b.is_ascii_whitespace())
+            .collect();
+        base64::decode(data).context("Invalid base64 in data URL")
+    } else {
+        Ok(data)
+    }
+}
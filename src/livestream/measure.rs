@@ -0,0 +1,220 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::StreamExt;
+use reqwest::Url;
+use serde::Serialize;
+use tokio::time;
+use tracing::{event, Level};
+
+use super::remote_data::RemoteData;
+use super::source::{PlaylistSource, PlaylistStream};
+use super::{Livestream, Segment, SegmentSink, Stream};
+use crate::effective_options::redact_url;
+
+/// Minimum, mean and maximum of a series of values
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Distribution {
+    pub min: f64,
+    pub mean: f64,
+    pub max: f64,
+}
+
+impl Distribution {
+    fn new(values: &[f64]) -> Option<Self> {
+        if values.is_empty() {
+            return None;
+        }
+        Some(Self {
+            min: values.iter().copied().fold(f64::INFINITY, f64::min),
+            mean: values.iter().sum::<f64>() / values.len() as f64,
+            max: values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+        })
+    }
+}
+
+/// Statistics of one stream over the measurement window
+#[derive(Clone, Debug, Serialize)]
+pub struct StreamMeasurement {
+    pub stream: String,
+    pub segments: usize,
+    pub bytes: u64,
+    /// Sum of the EXTINF durations of downloaded segments in seconds
+    pub media_duration: f64,
+    /// Average bitrate over media_duration in bit/s
+    pub average_bitrate: Option<f64>,
+    /// Highest bitrate of a single segment in bit/s
+    pub peak_bitrate: Option<f64>,
+    /// EXTINF durations of downloaded segments in seconds
+    pub segment_duration: Option<Distribution>,
+    /// Number of fetched versions of the media playlist
+    pub playlist_refreshes: usize,
+    /// Time between fetched versions of the media playlist in seconds
+    pub refresh_interval: Option<Distribution>,
+    /// Number of discontinuities between downloaded segments
+    pub discontinuities: usize,
+    /// Discontinuities per hour of media
+    pub discontinuity_rate: Option<f64>,
+    /// Segments that failed permanently
+    pub failed_segments: usize,
+}
+
+/// Statistics of a stream measured with --measure
+#[derive(Clone, Debug, Serialize)]
+pub struct Measurement {
+    /// Playlist URL, with credentials redacted
+    pub url: String,
+    /// Wall time the measurement ran for in seconds
+    pub elapsed: f64,
+    pub streams: Vec<StreamMeasurement>,
+}
+
+impl Livestream {
+    /// Run the download pipeline for up to duration without saving anything, and measure
+    /// bitrates, segment durations, playlist refreshes, discontinuities and errors of each stream
+    pub async fn measure(mut self, duration: Duration) -> Result<Measurement> {
+        let refreshes = Arc::new(Mutex::new(HashMap::new()));
+        self.source = Arc::new(TimedSource {
+            inner: self.source.clone(),
+            refreshes: refreshes.clone(),
+        });
+
+        let stopper = self.stopper.clone();
+        let timer = tokio::spawn(async move {
+            time::sleep(duration).await;
+            event!(Level::INFO, "Measurement window ended, stopping");
+            stopper.stop().await;
+        });
+
+        let start = Instant::now();
+        let mut sink = MeasureSink::default();
+        let res = self.download_with_sink(&mut sink).await;
+        let elapsed = start.elapsed();
+        timer.abort();
+        if let Err(e) = res {
+            event!(Level::WARN, "{:?}", e);
+        }
+
+        let refreshes = std::mem::take(&mut *refreshes.lock().unwrap());
+        let mut streams: Vec<_> = self
+            .streams
+            .keys()
+            .map(|stream| {
+                let stats = sink.streams.remove(stream).unwrap_or_default();
+                let refreshes = refreshes.get(stream).map_or(&[][..], Vec::as_slice);
+                stats.measurement(
+                    stream,
+                    refreshes,
+                    self.attempts.permanently_failed_in(stream),
+                )
+            })
+            .collect();
+        streams.sort_by(|a, b| a.stream.cmp(&b.stream));
+
+        Ok(Measurement {
+            url: redact_url(&self.url),
+            elapsed: elapsed.as_secs_f64(),
+            streams,
+        })
+    }
+}
+
+/// Sizes and durations of the downloaded segments of a stream
+#[derive(Default, Debug)]
+struct StreamStats {
+    segments: usize,
+    bytes: u64,
+    durations: Vec<f64>,
+    bitrates: Vec<f64>,
+    discon_seqs: BTreeSet<u64>,
+}
+
+impl StreamStats {
+    fn measurement(
+        &self,
+        stream: &Stream,
+        refreshes: &[Instant],
+        failed_segments: usize,
+    ) -> StreamMeasurement {
+        let media_duration: f64 = self.durations.iter().sum();
+        let intervals: Vec<_> = refreshes
+            .windows(2)
+            .map(|w| (w[1] - w[0]).as_secs_f64())
+            .collect();
+        let discontinuities = self.discon_seqs.len().saturating_sub(1);
+        let per_media_second = |v: f64| (media_duration > 0.0).then(|| v / media_duration);
+
+        StreamMeasurement {
+            stream: stream.to_string(),
+            segments: self.segments,
+            bytes: self.bytes,
+            media_duration,
+            average_bitrate: per_media_second(self.bytes as f64 * 8.0),
+            peak_bitrate: self.bitrates.iter().copied().reduce(f64::max),
+            segment_duration: Distribution::new(&self.durations),
+            playlist_refreshes: refreshes.len(),
+            refresh_interval: Distribution::new(&intervals),
+            discontinuities,
+            discontinuity_rate: per_media_second(discontinuities as f64 * 3600.0),
+            failed_segments,
+        }
+    }
+}
+
+/// Sink that only counts downloaded data and discards it
+#[derive(Default, Debug)]
+struct MeasureSink {
+    streams: HashMap<Stream, StreamStats>,
+}
+
+#[async_trait]
+impl SegmentSink for MeasureSink {
+    async fn on_init(&mut self, stream: &Stream, _: &RemoteData, data: &[u8]) -> Result<()> {
+        self.streams.entry(stream.clone()).or_default().bytes += data.len() as u64;
+        Ok(())
+    }
+
+    async fn on_segment(&mut self, stream: &Stream, segment: &Segment, data: &[u8]) -> Result<()> {
+        let stats = self.streams.entry(stream.clone()).or_default();
+        stats.segments += 1;
+        stats.bytes += data.len() as u64;
+        stats.discon_seqs.insert(segment.discon_seq);
+        if let Some(duration) = segment.duration.filter(|d| !d.is_zero()) {
+            stats.durations.push(duration.as_secs_f64());
+            stats
+                .bitrates
+                .push(data.len() as f64 * 8.0 / duration.as_secs_f64());
+        }
+        Ok(())
+    }
+}
+
+/// Playlist source that records when each version of a media playlist was fetched
+#[derive(Debug)]
+struct TimedSource {
+    inner: Arc<dyn PlaylistSource>,
+    refreshes: Arc<Mutex<HashMap<Stream, Vec<Instant>>>>,
+}
+
+impl PlaylistSource for TimedSource {
+    fn playlists(&self, stream: &Stream, url: &Url) -> PlaylistStream {
+        let refreshes = self.refreshes.clone();
+        let stream = stream.clone();
+        self.inner
+            .playlists(&stream, url)
+            .inspect(move |p| {
+                if p.is_ok() {
+                    refreshes
+                        .lock()
+                        .unwrap()
+                        .entry(stream.clone())
+                        .or_default()
+                        .push(Instant::now());
+                }
+            })
+            .boxed()
+    }
+}
@@ -1,14 +1,14 @@
 use std::process::Stdio;
 
 use anyhow::Result;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tokio::io::AsyncWriteExt;
 use tokio::process;
 use tracing::{event, Level};
 
 #[non_exhaustive]
 #[allow(dead_code)]
-#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
 pub enum MediaFormat {
     // Containers
     MpegTs, // MPEG-2 transport stream
@@ -92,6 +92,9 @@ impl MediaFormat {
                 let format = match o.format.format_name.as_str().trim() {
                     "mpegts" => Self::MpegTs,
                     "mp3" => Self::Mp3,
+                    "aac" => Self::Adts,
+                    "ac3" => Self::Ac3,
+                    "eac3" => Self::EAc3,
                     "mov,mp4,m4a,3gp,3g2,mj2" => Self::FMp4,
                     "webvtt" => Self::WebVtt,
                     _ => Self::Unknown,
@@ -102,6 +105,43 @@ impl MediaFormat {
         }
     }
 
+    /// Recognize the format of a media segment from its first bytes, without running ffprobe
+    ///
+    /// Used to tell correctly decrypted segments from garbage, so only formats whose segments
+    /// start with a recognizable signature are detected.
+    ///
+    /// ```
+    /// use livestream_dl::livestream::MediaFormat;
+    ///
+    /// let mut ts = vec![0xff; 376];
+    /// ts[0] = 0x47;
+    /// ts[188] = 0x47;
+    /// assert_eq!(MediaFormat::sniff(&ts), Some(MediaFormat::MpegTs));
+    /// assert_eq!(MediaFormat::sniff(b"\0\0\0\x18stypmsdh"), Some(MediaFormat::FMp4));
+    /// assert_eq!(MediaFormat::sniff(b"ID3\x04\0"), Some(MediaFormat::Adts));
+    /// assert_eq!(MediaFormat::sniff(&[0x3a, 0x9c, 0x01, 0xe7]), None);
+    /// ```
+    pub fn sniff(data: &[u8]) -> Option<Self> {
+        match data {
+            [0x47, ..] if data.get(188).is_none_or(|b| *b == 0x47) => Some(Self::MpegTs),
+            [_, _, _, _, b'f', b't', b'y', b'p', ..]
+            | [_, _, _, _, b's', b't', b'y', b'p', ..]
+            | [_, _, _, _, b's', b'i', b'd', b'x', ..]
+            | [_, _, _, _, b'e', b'm', b's', b'g', ..]
+            | [_, _, _, _, b'p', b'r', b'f', b't', ..]
+            | [_, _, _, _, b'm', b'o', b'o', b'f', ..] => Some(Self::FMp4),
+            // Packed audio starts with an ID3 timestamp tag
+            [b'I', b'D', b'3', ..] => Some(Self::Adts),
+            [0xff, b, ..] if b & 0xf6 == 0xf0 => Some(Self::Adts),
+            [0xff, b, ..] if b & 0xe0 == 0xe0 => Some(Self::Mp3),
+            [0x0b, 0x77, ..] => Some(Self::Ac3),
+            [b'W', b'E', b'B', b'V', b'T', b'T', ..] | [0xef, 0xbb, 0xbf, b'W', ..] => {
+                Some(Self::WebVtt)
+            }
+            _ => None,
+        }
+    }
+
     pub fn extension(&self) -> String {
         match self {
             Self::MpegTs => "ts",
@@ -118,4 +158,55 @@ impl MediaFormat {
         }
         .into()
     }
+
+    /// ffmpeg demuxer to force for files of raw audio formats, which ffmpeg may otherwise probe as
+    /// the wrong format
+    ///
+    /// ```
+    /// use livestream_dl::livestream::MediaFormat;
+    ///
+    /// // A recording of TS video and a raw ADTS audio rendition
+    /// assert_eq!(MediaFormat::MpegTs.demuxer(), None);
+    /// assert_eq!(MediaFormat::Adts.demuxer(), Some("aac"));
+    /// assert_eq!(MediaFormat::Mp3.demuxer(), Some("mp3"));
+    /// ```
+    pub fn demuxer(&self) -> Option<&'static str> {
+        match self {
+            Self::Adts => Some("aac"),
+            Self::Mp3 => Some("mp3"),
+            Self::Ac3 => Some("ac3"),
+            Self::EAc3 => Some("eac3"),
+            _ => None,
+        }
+    }
+
+    /// ffmpeg bitstream filter needed to copy audio of this format into MP4
+    ///
+    /// ```
+    /// use livestream_dl::livestream::MediaFormat;
+    ///
+    /// assert_eq!(MediaFormat::MpegTs.mp4_bitstream_filter(), None);
+    /// assert_eq!(MediaFormat::Adts.mp4_bitstream_filter(), Some("aac_adtstoasc"));
+    /// ```
+    pub fn mp4_bitstream_filter(&self) -> Option<&'static str> {
+        match self {
+            Self::Adts => Some("aac_adtstoasc"),
+            _ => None,
+        }
+    }
+
+    /// Guess format from a file extension produced by [`MediaFormat::extension`]
+    pub fn from_extension(ext: &str) -> Self {
+        match ext {
+            "ts" => Self::MpegTs,
+            "mp4" => Self::FMp4,
+            "m4a" => Self::Aac,
+            "aac" => Self::Adts,
+            "mp3" => Self::Mp3,
+            "ac3" => Self::Ac3,
+            "eac3" => Self::EAc3,
+            "vtt" => Self::WebVtt,
+            _ => Self::Unknown,
+        }
+    }
 }
@@ -0,0 +1,85 @@
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use tracing::{event, Level};
+
+use super::journal::segment_from_file;
+use super::{OpenFiles, Segment, Stream};
+use crate::cli::Container;
+use crate::mux::{remux, MuxOptions};
+
+/// Remux segments saved by earlier downloads into a file of container in output, in sequence
+/// order
+///
+/// Segments are identified by their file names, so a segment saved into more than one directory
+/// is only used once, taken from the first directory it was found in.
+pub async fn merge(
+    segments_directories: &[PathBuf],
+    output: &Path,
+    container: Container,
+    overwrite: bool,
+) -> Result<Vec<PathBuf>> {
+    let mut segments: HashMap<Stream, BinaryHeap<(Segment, PathBuf)>> = HashMap::new();
+    let mut seen = HashSet::new();
+
+    for directory in segments_directories {
+        let entries = fs::read_dir(directory)
+            .with_context(|| format!("error reading segments directory {:?}", directory))?;
+
+        let (mut found, mut duplicates) = (0, 0);
+        for entry in entries {
+            let path = entry?.path();
+            let (stream_id, segment) = match segment_from_file(&path)? {
+                Some(s) => s,
+                None => continue,
+            };
+            let stream = match Stream::from_id(&stream_id) {
+                Some(s) => s,
+                None => {
+                    event!(Level::WARN, "Skipping {:?} of unknown stream", path);
+                    continue;
+                }
+            };
+
+            found += 1;
+            if !seen.insert((stream.clone(), segment.discon_seq, segment.seq)) {
+                duplicates += 1;
+                continue;
+            }
+            segments.entry(stream).or_default().push((segment, path));
+        }
+
+        event!(
+            Level::INFO,
+            "Found {} segments in {:?}, {} already found in earlier directories",
+            found,
+            directory,
+            duplicates
+        );
+    }
+
+    if segments.is_empty() {
+        return Err(anyhow::anyhow!("No segments found to merge"));
+    }
+
+    for (stream, segments) in &segments {
+        event!(
+            Level::INFO,
+            "Merging {} segments of {}",
+            segments.len(),
+            stream
+        );
+    }
+
+    fs::create_dir_all(output)?;
+    remux(
+        segments,
+        output,
+        &MuxOptions::new(container, overwrite),
+        None,
+        &OpenFiles::default(),
+    )
+    .await
+}
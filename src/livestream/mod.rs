@@ -1,61 +1,254 @@
+mod ad_breaks;
+mod bench;
+mod builder;
+mod byte_budget;
+mod caption_channel;
+mod connect_host;
+mod content_dedupe;
+mod content_encoding;
 mod cookies;
+mod coverage;
+mod discon_tracker;
 mod displayable_variant;
+mod download_job;
+mod duration;
 mod encryption;
+mod fd_limit;
+mod gap_report;
 mod hashable_byte_range;
 mod http_client;
+mod init_cache;
+mod journal;
+mod local_url;
+mod measure;
 mod media_format;
+mod merge;
 mod playlist_fetcher;
+mod playlist_lint;
+mod preallocate;
+mod redirect_cache;
 mod remote_data;
+mod rendition_report;
+mod response_timing;
+mod scheduler;
 mod segment;
+mod segment_attempts;
+mod segment_check;
+mod segment_numbers;
+mod sink;
+mod source;
 mod stopper;
 mod stream;
+mod stream_params;
+mod stream_status;
+mod traffic;
+mod trick_play;
+mod ts_append;
+mod ts_continuity;
 mod utils;
+mod variables;
+mod variant_selection;
 
-use std::collections::{BinaryHeap, HashMap};
+use std::collections::{BTreeSet, BinaryHeap, HashMap, HashSet};
 use std::fmt::Display;
+use std::future::Future;
+use std::mem;
 use std::path::{Path, PathBuf};
+use std::pin::Pin;
 use std::sync::Arc;
+use std::task::Poll;
 use std::time::Duration;
 
+use ::time::OffsetDateTime;
 use anyhow::{Context, Result};
 use futures::channel::mpsc;
-use futures::StreamExt;
+use futures::{future, FutureExt, StreamExt};
 use itertools::Itertools;
-use lru::LruCache;
-use m3u8_rs::Playlist;
-use reqwest::{Client, Url};
-use reqwest_middleware::ClientBuilder;
-use reqwest_retry::{policies, RetryTransientMiddleware};
-use tokio::fs;
-use tokio::io::AsyncWriteExt;
-use tokio::sync::Mutex;
-use tracing::{event, Level};
-
-use self::cookies::CookieJar;
+use m3u8_rs::{AlternativeMedia, AlternativeMediaType, MasterPlaylist, Playlist, VariantStream};
+use reqwest::header::HeaderValue;
+use reqwest::Url;
+use tokio::task::{JoinError, JoinHandle};
+use tokio::{fs, time};
+use tracing::{event, instrument, Level};
+use url::Origin;
+
+pub use self::bench::BenchResult;
+pub use self::builder::LivestreamBuilder;
+use self::byte_budget::Reservation;
+pub use self::caption_channel::CaptionChannel;
+use self::content_dedupe::{ContentCheck, ContentDedupe};
+pub use self::coverage::{
+    Coverage, CoverageReport, MissingRange, MissingReason, SeqRanges, StreamCoverageReport, Verdict,
+};
 use self::displayable_variant::DisplayableVariant;
+use self::download_job::{DownloadJob, Priority};
+use self::duration::DurationTracker;
 pub use self::encryption::Encryption;
+use self::encryption::KeyRecovery;
+pub use self::fd_limit::{FdEstimate, FilePermit, OpenFiles};
+use self::gap_report::gap_reason;
+pub use self::gap_report::{gaps_srt, GapCue, GapReport};
 pub use self::hashable_byte_range::HashableByteRange;
 use self::http_client::HttpClient;
+use self::init_cache::InitCache;
+use self::journal::{scan_segments, DataRecord, Journal, JournalEntry, ResumeState};
+pub use self::local_url::decode_data_url;
+pub use self::measure::{Distribution, Measurement, StreamMeasurement};
 pub use self::media_format::MediaFormat;
-use self::playlist_fetcher::m3u8_fetcher;
-use self::remote_data::RemoteData;
+pub use self::merge::merge;
+use self::playlist_fetcher::{send_segments, FetcherContext, FetcherState, SentSegments};
+use self::preallocate::{Layout, PreallocatedSink};
+pub use self::remote_data::{RemoteData, ResponseHeaders};
+pub use self::rendition_report::RenditionReport;
+pub use self::response_timing::{ResponseTiming, TimingStats};
+use self::scheduler::Scheduler;
 pub use self::segment::Segment;
-pub use self::stopper::Stopper;
+use self::segment_attempts::SegmentAttempts;
+use self::segment_check::check_segments;
+pub use self::segment_check::SegmentCheck;
+pub use self::segment_numbers::SegmentNumbers;
+pub use self::sink::{FileSink, SegmentSink};
+pub use self::source::{
+    refresh_interval, FetchedData, HttpPlaylistSource, HttpSegmentFetcher, PlaylistSource,
+    PlaylistStream, SegmentFetcher,
+};
+pub use self::stopper::{ControlState, Stopper};
 pub use self::stream::Stream;
-use self::utils::make_absolute_url;
-use crate::cli::Args;
+use self::stream_params::{ParamsChange, ParamsTracker, StreamParams};
+pub use self::stream_status::{StreamEnd, StreamStatus, StreamStatuses};
+pub use self::traffic::{
+    BodyCounter, RequestCategory, TrafficMiddleware, TrafficStats, TrafficSummary,
+};
+use self::trick_play::TrickPlayStreams;
+use self::ts_append::TsAppender;
+use self::ts_continuity::{ContinuityChecker, ContinuityError};
+use self::utils::{make_absolute_url, prepare_output_dir, SHARED_SEGMENTS_DIR};
+use self::variables::Variables;
+pub use self::variant_selection::VariantSelection;
+use crate::cli::{Args, AudioChannels, DownloadOptions};
 use crate::error::LivestreamDLError;
-use crate::mux::remux;
+use crate::mux::{
+    extract_captions, organize_for_library, remux, remux_concatenated, write_concat_lists,
+    MuxOptions, Transcode,
+};
+#[cfg(feature = "s3")]
+use crate::s3::Uploader;
 
 #[derive(Debug)]
 pub struct Livestream {
+    /// Playlist URL after redirects
+    url: Url,
     streams: HashMap<Stream, Url>,
+    /// Newly selected variant to pin
+    variant_selection: Option<VariantSelection>,
+    /// Variables of the master playlist, imported by media playlists
+    variables: Variables,
+    /// Master playlist, used to refresh renditions
+    master: Option<Master>,
     client: HttpClient,
+    /// Source of media playlists
+    source: Arc<dyn PlaylistSource>,
+    /// Source of segment data
+    fetcher: Arc<dyn SegmentFetcher>,
+    /// Download attempts of listed segments
+    attempts: SegmentAttempts,
+    /// Keys fetched again after failing to decrypt segments, and keys to save
+    key_recovery: KeyRecovery,
+    /// Segments whose last download failed, embedded into outputs with --embed-gap-report
+    gaps: GapReport,
+    /// Segments listed, skipped and failed, reconciled with the saved segments at the end
+    coverage: Coverage,
+    /// Last saved segment and end of each stream
+    statuses: StreamStatuses,
+    /// Streams whose playlists are I-frames only, left out of remuxing
+    trick_play: TrickPlayStreams,
+    /// Numbers of listed segments if segment files are named sequentially
+    numbers: Option<SegmentNumbers>,
+    /// Name of remuxed outputs relative to the output directory, without extension
+    remux_name: Option<PathBuf>,
+    /// Name of the segments directory in the output directory
+    segments_dir_name: String,
+    /// Bound on files opened at once by saving, concatenating and uploading
+    open_files: OpenFiles,
     stopper: Stopper,
     options: Args,
 }
 
-type SegmentIdData = (Stream, Segment, Vec<u8>);
+/// Target of events written to the event log
+pub const EVENT_TARGET: &str = "livestream_dl::events";
+
+/// CHARACTERISTICS of audio renditions describing the video for the visually impaired
+const DESCRIBES_VIDEO: &str = "public.accessibility.describes-video";
+
+/// Number of streams above which first playlist fetches are spread out
+const STAGGER_MIN_STREAMS: usize = 8;
+
+/// Time over which first playlist fetches are spread out
+const STAGGER_DURATION: Duration = Duration::from_secs(2);
+
+/// Interval at which traffic statistics are recorded in the event log
+const TRAFFIC_RECORD_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Time segments of primary streams may wait for a download worker before secondary streams are
+/// deferred with --prioritize-main
+const PRIMARY_LAG_THRESHOLD: Duration = Duration::from_secs(3);
+
+/// Playlist fetcher task of a stream
+type FetcherHandle = (Stream, FetcherTask);
+
+/// Join handle of a playlist fetcher task that aborts the task when dropped, so that playlists
+/// stop being polled once the download they belong to is dropped
+struct FetcherTask(JoinHandle<Result<SentSegments>>);
+
+impl FetcherTask {
+    fn is_finished(&self) -> bool {
+        self.0.is_finished()
+    }
+}
+
+impl Future for FetcherTask {
+    type Output = Result<Result<SentSegments>, JoinError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0).poll(cx)
+    }
+}
+
+impl Drop for FetcherTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Downloaded segment with its initialization and decrypted data
+type SegmentIdData = (Stream, Segment, Vec<u8>, Vec<u8>);
+
+/// Included renditions with their URLs, and excluded renditions
+type RenditionSelection = (Vec<(Stream, Url)>, Vec<Stream>);
+
+/// Master playlist the streams were selected from
+#[derive(Debug)]
+struct Master {
+    url: Url,
+    /// Selected variant
+    selection: VariantSelection,
+    /// All variants when the master playlist was first fetched
+    ladder: Vec<VariantSelection>,
+    /// Default audio rendition of the selected variant
+    primary_audio: Option<Stream>,
+    /// Closed caption channels declared for the selected variant
+    captions: Vec<CaptionChannel>,
+}
+
+/// State of periodic master playlist refreshes
+struct MasterRefresh {
+    interval: time::Interval,
+    /// Sender for fetchers of new renditions, dropped once all fetchers finished
+    tx: mpsc::UnboundedSender<DownloadJob>,
+    /// Stoppers of the fetcher of each stream
+    fetchers: HashMap<Stream, Stopper>,
+    ladder: Vec<VariantSelection>,
+}
 
 impl Stream {
     /// Name of stream if available
@@ -67,15 +260,32 @@ impl Stream {
             Self::Subtitle { name: n, .. } => Some(n.clone()),
         }
     }
+
+    /// Group id of an alternative rendition
+    pub fn group_id(&self) -> Option<&str> {
+        match self {
+            Self::Main => None,
+            Self::Video { group_id: g, .. }
+            | Self::Audio { group_id: g, .. }
+            | Self::Subtitle { group_id: g, .. } => Some(g),
+        }
+    }
 }
 
 impl Display for Stream {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::Main => write!(f, "main"),
-            Self::Video { name: n, .. } => write!(f, "video_{}", n),
-            Self::Audio { name: n, .. } => write!(f, "audio_{}", n),
-            Self::Subtitle { name: n, .. } => write!(f, "subtitle_{}", n),
+        let (kind, group_id, name) = match self {
+            Self::Main => return write!(f, "main"),
+            Self::Video { group_id, name, .. } => ("video", group_id, name),
+            Self::Audio { group_id, name, .. } => ("audio", group_id, name),
+            Self::Subtitle { group_id, name, .. } => ("subtitle", group_id, name),
+        };
+
+        // Include group id to tell apart renditions of the same name in different groups
+        if group_id.is_empty() {
+            write!(f, "{}_{}", kind, name)
+        } else {
+            write!(f, "{}_{}_{}", kind, group_id, name)
         }
     }
 }
@@ -85,321 +295,1924 @@ impl Livestream {
     ///
     /// If a master playlist is given, choose the highest bitrate variant and download its stream
     /// and all of its alternative media streams
+    ///
+    /// Transient failures of the playlist request are retried according to the retry options.
+    /// Playlists are read up to 8 MiB and responses that are media files are rejected, so that
+    /// wrong URLs fail fast.
+    ///
+    /// ```no_run
+    /// # async fn download() -> anyhow::Result<()> {
+    /// use clap::Parser;
+    /// use livestream_dl::cli::Args;
+    /// use livestream_dl::livestream::Livestream;
+    ///
+    /// let url = "https://example.com/master.m3u8";
+    /// let args = Args::parse_from(["livestream-dl", url]);
+    /// let (livestream, _) = Livestream::new(&url.parse()?, &args).await?;
+    /// livestream.download("output".as_ref()).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
     pub async fn new(url: &Url, options: &Args) -> Result<(Self, Stopper)> {
-        // Create reqwest client
-        let client = Client::builder()
-            .timeout(Duration::from_secs(options.network_options.timeout))
-            .danger_accept_invalid_certs(options.network_options.insecure);
-
-        // Add cookie provider if needed
-        let client = if let Some(cookies_path) = &options.network_options.cookies {
-            let jar = CookieJar::parse_from_file(cookies_path)?;
-            client.cookie_provider(Arc::new(jar))
+        Self::builder(url, options).build().await
+    }
+
+    /// Builder of a Livestream with a custom playlist source or segment fetcher
+    pub fn builder(url: &Url, options: &Args) -> LivestreamBuilder {
+        LivestreamBuilder::new(url, options)
+    }
+
+    /// Streams that will be downloaded and their resolved media playlist URLs
+    ///
+    /// Audio renditions describing the video are only selected with --include-audio-description,
+    /// forced subtitles are selected with the renditions of their language.
+    pub fn streams(&self) -> impl Iterator<Item = (&Stream, &Url)> {
+        self.streams.iter()
+    }
+
+    /// Media playlist URL of each stream as requested by its fetcher, with the query parameters
+    /// of --copy-query
+    pub fn playlist_urls(&self) -> impl Iterator<Item = (&Stream, Url)> {
+        self.streams
+            .iter()
+            .map(|(s, u)| (s, self.client.request_url(u.clone())))
+    }
+
+    /// Check the availability and size of the segments currently listed by the playlist of each
+    /// stream without downloading them, see [`RemoteData::check`]
+    ///
+    /// Like downloads, this works with playlists and segments in local files:
+    ///
+    /// ```
+    /// use clap::Parser;
+    /// use livestream_dl::cli::Args;
+    /// use livestream_dl::livestream::Livestream;
+    /// use reqwest::Url;
+    ///
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let master = "#EXTM3U\n#EXT-X-STREAM-INF:BANDWIDTH=1000\nvideo/media.m3u8\n";
+    /// let media = "#EXTM3U\n#EXT-X-TARGETDURATION:6\n#EXTINF:6.0,\n0.ts\n#EXTINF:6.0,\n1.ts\n\
+    ///     #EXT-X-ENDLIST\n";
+    /// std::fs::create_dir(dir.path().join("video")).unwrap();
+    /// std::fs::write(dir.path().join("master.m3u8"), master).unwrap();
+    /// std::fs::write(dir.path().join("video/media.m3u8"), media).unwrap();
+    /// std::fs::write(dir.path().join("video/0.ts"), [0x47; 188]).unwrap();
+    ///
+    /// let url = Url::from_file_path(dir.path().join("master.m3u8")).unwrap();
+    /// let args = Args::parse_from(["livestream-dl", url.as_str()]);
+    /// let runtime = tokio::runtime::Runtime::new().unwrap();
+    /// let checks = runtime.block_on(async {
+    ///     let (livestream, _) = Livestream::new(&url, &args).await.unwrap();
+    ///     livestream.check_segments().await.unwrap()
+    /// });
+    /// let video = Url::from_file_path(dir.path().join("video")).unwrap();
+    /// assert_eq!(checks.len(), 2);
+    /// assert_eq!(checks[0].url, format!("{}/0.ts", video));
+    /// assert_eq!(checks[0].size, Some(188));
+    /// assert!(checks[1].url.ends_with("/1.ts") && !checks[1].available());
+    /// ```
+    pub async fn check_segments(&self) -> Result<Vec<SegmentCheck>> {
+        check_segments(
+            &self.client,
+            &self.streams,
+            &self.variables,
+            self.options.network_options.max_concurrent_downloads,
+        )
+        .await
+    }
+
+    /// URL of the playlist after redirects
+    pub fn playlist_url(&self) -> &Url {
+        &self.url
+    }
+
+    /// Selected variant if the playlist is a master playlist
+    pub fn variant(&self) -> Option<&VariantSelection> {
+        self.master.as_ref().map(|m| &m.selection)
+    }
+
+    /// Final status of each stream whose playlist fetcher ended, see
+    /// [`download_with_sink`](Self::download_with_sink) for an example
+    pub fn stream_statuses(&self) -> Vec<StreamStatus> {
+        self.statuses.statuses()
+    }
+
+    /// Download the livestream to disk
+    ///
+    /// Downloads of segments larger than --max-segment-size are aborted, so a server sending an
+    /// endless body fails the download instead of exhausting memory.
+    pub async fn download(&self, output: &Path) -> Result<()> {
+        // Fail early if the output can't be written
+        let output = &prepare_output_dir(output)?;
+        event!(Level::INFO, "Saving to {:?}", output);
+
+        // Write into preallocated files instead of segment files if possible
+        if self.options.download_options.preallocate {
+            let layout = Layout::plan(
+                &self.client,
+                &self.streams,
+                &self.variables,
+                self.options.network_options.max_concurrent_downloads,
+            )
+            .await;
+            match layout {
+                Ok(l) => return self.download_preallocated(output, l).await,
+                Err(e) => event!(
+                    Level::WARN,
+                    "Unable to preallocate output, saving segments to separate files: {:#}",
+                    e
+                ),
+            }
+        }
+
+        // Segments of other downloads into the same output are kept in other directories
+        let resume = self.options.download_options.resume;
+        let mut segments_directory = output.join(&self.segments_dir_name);
+        let shared_directory = output.join(SHARED_SEGMENTS_DIR);
+        if resume && !segments_directory.exists() && shared_directory.exists() {
+            event!(
+                Level::INFO,
+                "Resuming with segments in {:?} of an earlier download",
+                shared_directory
+            );
+            segments_directory = shared_directory;
+        }
+
+        // Rebuild state of a previous download if resuming
+        let journal_path = output.join(Journal::FILE_NAME);
+        let (resume_state, scanned) = if resume {
+            let (state, scanned) = match Journal::replay(&journal_path, &segments_directory)? {
+                Some(s) => (s, false),
+                None => {
+                    event!(
+                        Level::INFO,
+                        "No journal found, scanning {:?} for existing segments",
+                        &segments_directory
+                    );
+                    (
+                        scan_segments(&segments_directory, self.streams.keys())?,
+                        true,
+                    )
+                }
+            };
+            for (stream, segments) in &state.downloaded_segments {
+                event!(
+                    Level::INFO,
+                    "Resuming {} with {} existing segments",
+                    stream,
+                    segments.len()
+                );
+            }
+            (state, scanned)
         } else {
-            client
+            (ResumeState::default(), false)
+        };
+
+        fs::create_dir_all(output).await?;
+
+        // Record listed segments for the capture report, continuing from those of earlier runs
+        self.coverage
+            .persist_to(output.join(Coverage::FILE_NAME), resume)?;
+
+        // Save selected variant for later pinned or resumed runs
+        if let Some(selection) = &self.variant_selection {
+            selection.save(output.join(VariantSelection::FILE_NAME))?;
+        }
+
+        // Record download events, starting a new journal unless resuming
+        let journal = Arc::new(Journal::open(&journal_path, !resume)?);
+        if scanned {
+            // Seed the new journal with segments found on disk
+            for (stream, segments) in &resume_state.downloaded_segments {
+                for (segment, path) in segments {
+                    journal.record(&JournalEntry::saved(stream, segment, path));
+                }
+            }
+            journal.flush();
+        }
+
+        // Upload files in the background if requested
+        #[cfg(feature = "s3")]
+        let uploader = match self.options.upload_options.upload_s3 {
+            Some(_) => Some(Uploader::new(
+                &self.options.upload_options,
+                output,
+                self.open_files.clone(),
+            )?),
+            None => None,
+        };
+
+        // Download segments to disk
+        let mut sink = FileSink::new(&segments_directory)
+            .resume_from(resume_state.downloaded_segments.clone())
+            .with_journal(journal.clone())
+            .with_open_files(self.open_files.clone());
+        if let Some(numbers) = &self.numbers {
+            sink = sink.with_sequential_names(numbers.clone());
+        }
+        if self.options.download_options.no_part_files {
+            sink = sink.write_in_place();
+        }
+        if let Some(path) = &self.options.download_options.append_ts {
+            let appender = TsAppender::create(
+                path,
+                self.options.download_options.append_ts_transcode,
+                self.options.network_options.max_concurrent_downloads,
+            )
+            .await?;
+            sink = sink.append_ts(appender);
+        }
+        if let Some(grace) = self.options.download_options.output_grace {
+            sink = sink.with_output_grace(grace, self.options.network_options.max_in_flight_bytes);
+        }
+        #[cfg(feature = "s3")]
+        if let Some(u) = &uploader {
+            sink = sink.notify_saved(u.sender());
+        }
+        let (handles, params) = self
+            .download_segments(&mut sink, Some(&journal), &resume_state)
+            .await;
+        if let Err(e) = self.key_recovery.save_keys(output) {
+            event!(Level::WARN, "Unable to save encryption keys: {:#}", e);
+        }
+        if let Err(e) = sink.finish().await {
+            event!(Level::WARN, "{:#}", e);
+        }
+        journal.flush();
+        let mut downloaded_segments = sink.into_downloaded_segments();
+
+        // Explain failures of all streams instead of failing to remux nothing
+        if downloaded_segments.values().all(|s| s.is_empty()) {
+            let stopped = self.stopper.stopped().await;
+            return Err(no_segments_error(handles, &self.attempts, stopped).await);
         }
-        .build()?;
 
-        // Set client retry on failure
-        let retry_policy = policies::ExponentialBackoff::builder()
-            .retry_bounds(Duration::from_secs(1), Duration::from_secs(10))
-            .backoff_exponent(2)
-            .build_with_max_retries(options.network_options.max_retries);
+        // Partial downloads must not have holes to remux cleanly
+        if self.options.has_download_limits() {
+            trim_to_contiguous(&mut downloaded_segments);
+        }
+
+        // Cut output where the encoding changes without a discontinuity
+        if self.options.download_options.split_on_codec_change {
+            split_on_changes(&mut downloaded_segments, &params.changes());
+        }
+
+        // Look for dropped packets in the saved segments
+        if self.options.download_options.validate_ts {
+            validate_ts(&downloaded_segments).await?;
+        }
+
+        // List segments for remuxing them later before they are consumed by remuxing
+        if self.options.download_options.write_concat_lists {
+            write_concat_lists(&downloaded_segments, output).await?;
+        }
 
-        // Build client with middleware
-        let client = ClientBuilder::new(client)
-            .with(RetryTransientMiddleware::new_with_policy(retry_policy))
-            .build();
+        // Reconcile listed and saved segments before they are consumed by remuxing, the report
+        // is written once the download succeeded
+        let report = self.coverage.report(&downloaded_segments);
+        self.coverage.save()?;
 
-        // Build HttpClient
-        let query_pairs = if options.network_options.copy_query {
-            Some(url.query_pairs().collect::<Vec<_>>())
+        // Record date of the capture before segments are consumed by remuxing
+        let recorded = downloaded_segments
+            .values()
+            .flat_map(|segments| segments.iter().filter_map(|(s, _)| s.program_date_time))
+            .min()
+            .unwrap_or_else(OffsetDateTime::now_utc);
+
+        // Trick play segments aren't playable video
+        downloaded_segments.retain(|s, _| !self.trick_play.contains(s));
+        let playable = downloaded_segments.values().any(|s| !s.is_empty());
+        self.trick_play.log_excluded();
+
+        // A failed download isn't reported as complete, even if all segments were saved
+        let finished = async {
+            // Remux if necessary
+            let muxed_paths = if !self.options.download_options.no_remux && playable {
+                let muxed_paths = remux(
+                    downloaded_segments,
+                    output,
+                    &self.mux_options(),
+                    self.options
+                        .download_options
+                        .embed_gap_report
+                        .then_some(&self.gaps),
+                    &self.open_files,
+                )
+                .await?;
+                let muxed_paths = self.extract_captions(muxed_paths).await?;
+                self.organize_for_library(muxed_paths, output, recorded)
+                    .await?
+            } else {
+                Vec::new()
+            };
+
+            // Upload muxed files and wait for remaining uploads
+            #[cfg(feature = "s3")]
+            if let Some(uploader) = uploader {
+                let tx = uploader.sender();
+                for path in &muxed_paths {
+                    let _ = tx.unbounded_send(path.clone());
+                }
+                drop(tx);
+                uploader.finish().await?;
+            }
+
+            join_fetchers(
+                handles,
+                self.options.download_options.strict_renditions,
+                &self.statuses,
+            )
+            .await?;
+            Ok(muxed_paths)
+        }
+        .await;
+        let report_path = output.join(CoverageReport::FILE_NAME);
+        let muxed_paths = match finished {
+            Ok(muxed_paths) => {
+                report.save(report_path)?;
+                muxed_paths
+            }
+            Err(e) => {
+                if let Err(save_error) = report.failed(&e).save(report_path) {
+                    event!(
+                        Level::WARN,
+                        "Unable to save capture report: {:#}",
+                        save_error
+                    );
+                }
+                return Err(e);
+            }
+        };
+        event!(Level::INFO, "Finished download into {:?}", output);
+        for path in &muxed_paths {
+            event!(Level::INFO, "Remuxed output: {:?}", path);
+        }
+        report.log();
+
+        Ok(())
+    }
+
+    /// How to remux downloaded streams, video is re-encoded with --transcode
+    fn mux_options(&self) -> MuxOptions<'_> {
+        let options = &self.options.download_options;
+        MuxOptions {
+            name: self.remux_name.as_deref(),
+            container: options.container,
+            default_audio: options.default_audio.as_deref(),
+            sidecar_template: options.subtitle_sidecar_template.as_ref(),
+            overwrite: options.overwrite,
+            trim_to_keyframe: options.trim_to_keyframe,
+            transcode: options.transcode.then_some(Transcode {
+                resolution: options.target_resolution,
+                bitrate: options.target_bitrate,
+            }),
+        }
+    }
+
+    /// Download a VOD into preallocated files laid out by layout, then remux them
+    async fn download_preallocated(&self, output: &Path, layout: Layout) -> Result<()> {
+        event!(
+            Level::INFO,
+            "Preallocating {} bytes of output",
+            layout.total_size()
+        );
+        let mut sink = PreallocatedSink::create(layout, output).await?;
+        let (handles, _) = self
+            .download_segments(&mut sink, None, &ResumeState::default())
+            .await;
+        if let Err(e) = self.key_recovery.save_keys(output) {
+            event!(Level::WARN, "Unable to save encryption keys: {:#}", e);
+        }
+        let files = sink.finish().await?;
+
+        // Trick play segments aren't playable video
+        let discons: HashMap<_, Vec<_>> = files
+            .iter()
+            .map(|(d, streams)| {
+                let streams = streams
+                    .iter()
+                    .filter(|(s, _, _)| !self.trick_play.contains(s))
+                    .map(|(s, p, f)| (s, p.clone(), f.clone()))
+                    .collect::<Vec<_>>();
+                (*d, streams)
+            })
+            .filter(|(_, streams)| !streams.is_empty())
+            .collect();
+        self.trick_play.log_excluded();
+
+        // Remux if necessary
+        let muxed_paths = if !self.options.download_options.no_remux && !discons.is_empty() {
+            let muxed_paths = remux_concatenated(&discons, output, &self.mux_options()).await?;
+            let muxed_paths = self.extract_captions(muxed_paths).await?;
+            self.organize_for_library(muxed_paths, output, OffsetDateTime::now_utc())
+                .await?
         } else {
-            None
+            Vec::new()
         };
-        let client = HttpClient::new(client, query_pairs);
-
-        // Get m3u8 playlist
-        let resp = client.get(url.clone()).send().await?;
-        if !resp.status().is_success() {
-            return Err(LivestreamDLError::NetworkRequest(resp).into());
-        }
-
-        // Check if m3u8 is master or media
-        let final_url = resp.url().clone();
-        let bytes = resp.bytes().await?;
-
-        // Parse m3u8 playlist and add streams
-        let mut streams = HashMap::new();
-        match m3u8_rs::parse_playlist(&bytes) {
-            Ok((_, Playlist::MasterPlaylist(p))) => {
-                let stream = if !options.download_options.choose_stream {
-                    // Pick highest bitrate stream
-                    p.variants
-                        .iter()
-                        .filter_map(|v| Some((v.bandwidth.parse::<u64>().ok()?, v)))
-                        .max_by_key(|(x, _)| *x)
-                        .ok_or_else(|| anyhow::anyhow!("No streams found"))?
-                        .1
-                } else {
-                    // Show stream chooser
-                    let options: Vec<_> = p
-                        .variants
-                        .iter()
-                        .filter_map(|v| Some((v.bandwidth.parse::<u64>().ok()?, v)))
-                        .sorted_by_key(|(b, _)| *b)
-                        .map(|(_, v)| v)
-                        .rev()
-                        .map(DisplayableVariant::from)
-                        .collect();
-                    let response = inquire::Select::new("Choose stream", options).prompt()?;
-                    response.into()
-                };
 
-                // Add main stream
-                streams.insert(Stream::Main, make_absolute_url(url, &stream.uri)?);
-
-                // Closure to find alternative media with matching group id and add them to streams
-                let mut add_alternative =
-                    |group, f: fn(String, Option<String>) -> Stream| -> Result<()> {
-                        for a in p.alternatives.iter().filter(|a| &a.group_id == group) {
-                            if let Some(a_url) = &a.uri {
-                                streams.insert(
-                                    f(a.name.clone(), a.language.clone()),
-                                    make_absolute_url(url, a_url)?,
-                                );
-                            }
-                        }
-                        Ok(())
+        join_fetchers(
+            handles,
+            self.options.download_options.strict_renditions,
+            &self.statuses,
+        )
+        .await?;
+        event!(Level::INFO, "Finished download into {:?}", output);
+        for path in &muxed_paths {
+            event!(Level::INFO, "Remuxed output: {:?}", path);
+        }
+
+        Ok(())
+    }
+
+    /// Subtitle streams and audio renditions other than the default one, whose downloads may wait
+    /// while other streams lag
+    fn secondary_streams(&self) -> HashSet<Stream> {
+        let primary_audio = self.master.as_ref().and_then(|m| m.primary_audio.as_ref());
+        self.streams
+            .keys()
+            .filter(|s| match s {
+                Stream::Subtitle { .. } => true,
+                Stream::Audio { .. } => primary_audio.is_some_and(|p| p != *s),
+                Stream::Main | Stream::Video { .. } => false,
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Extract the closed captions declared for the selected variant from muxed videos if
+    /// requested, returns muxed_paths with the caption sidecars
+    async fn extract_captions(&self, mut muxed_paths: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+        if !self.options.download_options.extract_captions {
+            return Ok(muxed_paths);
+        }
+        let channels = match &self.master {
+            Some(m) if !m.captions.is_empty() => &m.captions,
+            _ => {
+                event!(
+                    Level::WARN,
+                    "The selected variant declares no closed captions, not extracting any"
+                );
+                return Ok(muxed_paths);
+            }
+        };
+
+        let videos: Vec<_> = muxed_paths
+            .iter()
+            .filter(|p| p.extension() != Some("srt".as_ref()))
+            .cloned()
+            .collect();
+        for video in videos {
+            let sidecars =
+                extract_captions(&video, channels, self.options.download_options.overwrite).await?;
+            muxed_paths.extend(sidecars);
+        }
+        Ok(muxed_paths)
+    }
+
+    /// Move muxed files into a media server library layout if a library title is set, returns
+    /// their new paths
+    async fn organize_for_library(
+        &self,
+        muxed_paths: Vec<PathBuf>,
+        output: &Path,
+        recorded: OffsetDateTime,
+    ) -> Result<Vec<PathBuf>> {
+        match &self.options.download_options.library_title {
+            Some(title) if !muxed_paths.is_empty() => {
+                organize_for_library(
+                    muxed_paths,
+                    output,
+                    title,
+                    recorded,
+                    &self.url,
+                    self.options.download_options.overwrite,
+                )
+                .await
+            }
+            _ => Ok(muxed_paths),
+        }
+    }
+
+    /// Download the livestream into a custom sink instead of to disk
+    ///
+    /// Nothing is written to disk and no remuxing is done. Dropping the returned future cancels
+    /// the download, playlists aren't fetched anymore. Alternative renditions whose playlist
+    /// can't be parsed or fetched are dropped while the other streams complete, unless
+    /// `--strict-renditions` is set. Streams whose playlist fetching failed are restarted up to
+    /// `--retry-streams` times.
+    ///
+    /// ```no_run
+    /// # use anyhow::Result;
+    /// # use livestream_dl::livestream::{Livestream, SegmentSink};
+    /// # async fn download(livestream: Livestream, mut sink: impl SegmentSink) -> Result<()> {
+    /// livestream.download_with_sink(&mut sink).await?;
+    /// for status in livestream.stream_statuses() {
+    ///     println!("{}", status);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn download_with_sink<S: SegmentSink + ?Sized>(&self, sink: &mut S) -> Result<()> {
+        let (handles, _) = self
+            .download_segments(sink, None, &ResumeState::default())
+            .await;
+
+        join_fetchers(
+            handles,
+            self.options.download_options.strict_renditions,
+            &self.statuses,
+        )
+        .await
+    }
+
+    /// Spawn the playlist fetcher of a stream, starting after delay
+    fn spawn_fetcher(
+        &self,
+        stream: &Stream,
+        url: &Url,
+        stopper: Stopper,
+        tx: &mpsc::UnboundedSender<DownloadJob>,
+        delay: Duration,
+    ) -> FetcherHandle {
+        let context = FetcherContext {
+            tx: tx.clone(),
+            attempts: self.attempts.clone(),
+            coverage: self.coverage.clone(),
+            numbers: self.numbers.clone(),
+            trick_play: self.trick_play.clone(),
+            max_duration: self.options.download_options.max_duration,
+            discon_windows: self.options.download_options.discon_window.clone(),
+            backlog_limit: self.options.download_options.backlog_limit,
+        };
+        let source = self.source.clone();
+        let url = url.clone();
+        let skip_ads = self.options.download_options.skip_ads;
+        let lint_playlist = self.options.download_options.lint_playlist;
+        let strict_renditions = self.options.download_options.strict_renditions;
+        let network_options = &self.options.network_options;
+        let retry_streams = network_options.retry_streams;
+        let min_delay = network_options.retry_min_delay;
+        let max_delay = network_options.retry_max_delay;
+        let backoff_exponent = network_options.retry_backoff_exponent;
+        let root_stopper = self.stopper.clone();
+        let stream = stream.clone();
+
+        (
+            stream.clone(),
+            FetcherTask(tokio::spawn(async move {
+                time::sleep(delay).await;
+
+                // Restarts continue after the segments sent before the failure
+                let mut state = FetcherState::new(skip_ads, lint_playlist);
+                let mut restarts = 0;
+                let res = loop {
+                    let res = send_segments(
+                        &mut state,
+                        &context,
+                        stopper.clone(),
+                        stream.clone(),
+                        source.playlists(&stream, &url),
+                    )
+                    .await;
+                    let e = match res {
+                        Err(e) if restarts < retry_streams && !stopper.stopped().await => e,
+                        res => break res,
                     };
+                    restarts += 1;
+                    let growth = f64::from(backoff_exponent).powi(restarts as i32 - 1);
+                    let wait = Duration::try_from_secs_f64(min_delay.as_secs_f64() * growth)
+                        .map_or(max_delay, |d| d.min(max_delay));
+                    event!(
+                        Level::WARN,
+                        "Stream {} failed, restarting in {:?} ({}/{}): {:#}",
+                        stream,
+                        wait,
+                        restarts,
+                        retry_streams,
+                        e
+                    );
+                    tokio::select! {
+                        _ = time::sleep(wait) => {}
+                        _ = stopper.wait() => break Ok(()),
+                    }
+                };
+                let res = res.map(|()| state.sent);
+                context.attempts.end(&stream);
+                event!(
+                    target: EVENT_TARGET,
+                    Level::DEBUG,
+                    stream = %stream,
+                    error = res.as_ref().err().map(|e| format!("{:#}", e)).as_deref(),
+                    "stream_ended"
+                );
 
-                // Add audio streams
-                if let Some(group) = &stream.audio {
-                    add_alternative(group, |n, l| Stream::Audio { name: n, lang: l })?;
+                // Stop the whole download unless only an alternative rendition failed
+                if let Err(e) = &res {
+                    if strict_renditions || !is_rendition_problem(&stream, e) {
+                        event!(
+                            Level::ERROR,
+                            "Stream {} failed, stopping download: {:#}",
+                            stream,
+                            e
+                        );
+                        root_stopper.stop().await;
+                    } else {
+                        event!(Level::WARN, "Dropping rendition {}: {:#}", stream, e);
+                    }
                 }
+                res
+            })),
+        )
+    }
 
-                // Add video streams
-                if let Some(group) = &stream.video {
-                    add_alternative(group, |n, l| Stream::Video { name: n, lang: l })?;
-                }
+    /// Re-fetch the master playlist, start fetchers of new renditions and stop fetchers of removed
+    /// renditions
+    async fn refresh_master(
+        &self,
+        refresh: &mut MasterRefresh,
+        handles: &mut Vec<FetcherHandle>,
+    ) -> Result<()> {
+        let master = match &self.master {
+            Some(m) => m,
+            None => return Ok(()),
+        };
 
-                // Add subtitle streams
-                if let Some(group) = &stream.subtitles {
-                    add_alternative(group, |n, l| Stream::Subtitle { name: n, lang: l })?;
-                }
+        let (bytes, _) = self.client.fetch_playlist(&master.url).await?;
+        let (bytes, _) = Variables::substitute(&bytes, &master.url, &Variables::default())?;
+        let playlist = match m3u8_rs::parse_playlist(&bytes) {
+            Ok((_, Playlist::MasterPlaylist(p))) => p,
+            _ => return Err(LivestreamDLError::ParseM3u8(master.url.to_string()).into()),
+        };
+        event!(
+            target: EVENT_TARGET,
+            Level::DEBUG,
+            url = %master.url,
+            "master_playlist_refreshed"
+        );
+
+        // Variants are only logged, the selected variant is kept
+        let ladder: Vec<_> = playlist
+            .variants
+            .iter()
+            .map(VariantSelection::new)
+            .collect();
+        if ladder != refresh.ladder {
+            event!(
+                Level::INFO,
+                "Variants of the master playlist changed, keeping the selected variant: {}",
+                playlist
+                    .variants
+                    .iter()
+                    .map(DisplayableVariant::from)
+                    .join(", ")
+            );
+            refresh.ladder = ladder;
+        }
+
+        let variant = match master.selection.find(&playlist.variants) {
+            Some(v) => v,
+            None => {
+                event!(
+                    Level::WARN,
+                    "Selected variant is no longer in the master playlist, keeping renditions"
+                );
+                return Ok(());
             }
-            Ok((_, Playlist::MediaPlaylist(_))) => {
-                streams.insert(Stream::Main, final_url);
+        };
+        let (renditions, _) = select_renditions(
+            &playlist,
+            variant,
+            &master.url,
+            &self.options.download_options,
+        )?;
+        let renditions: HashMap<_, _> = renditions
+            .into_iter()
+            .filter(|(s, _)| self.is_requested(s))
+            .collect();
+
+        // Start fetchers of new renditions
+        for (stream, url) in &renditions {
+            if !refresh.fetchers.contains_key(stream) {
+                event!(Level::INFO, "Found new rendition {}: {}", stream, url);
+                let stopper = self.stopper.child();
+                handles.push(self.spawn_fetcher(
+                    stream,
+                    url,
+                    stopper.clone(),
+                    &refresh.tx,
+                    Duration::ZERO,
+                ));
+                refresh.fetchers.insert(stream.clone(), stopper);
             }
-            Err(_) => {
-                return Err(LivestreamDLError::ParseM3u8(final_url.to_string()).into());
+        }
+
+        // Stop fetchers of removed renditions
+        let removed: Vec<_> = refresh
+            .fetchers
+            .keys()
+            .filter(|s| **s != Stream::Main && !renditions.contains_key(*s))
+            .cloned()
+            .collect();
+        for stream in removed {
+            event!(
+                Level::INFO,
+                "Rendition {} was removed, stopping its download",
+                stream
+            );
+            if let Some(stopper) = refresh.fetchers.remove(&stream) {
+                stopper.stop().await;
             }
         }
 
-        let stopper = Stopper::new();
+        Ok(())
+    }
 
-        Ok((
-            Self {
-                streams,
-                client,
-                stopper: stopper.clone(),
-                options: options.clone(),
-            },
-            stopper,
-        ))
+    /// Check if a stream should be downloaded according to --stream
+    fn is_requested(&self, stream: &Stream) -> bool {
+        self.options
+            .download_options
+            .stream
+            .as_ref()
+            .is_none_or(|id| &stream.to_string() == id)
     }
 
-    /// Download the livestream to disk
-    pub async fn download(&self, output: &Path) -> Result<()> {
+    /// Fetch playlists and pass downloaded segments to sink until stopped or all playlists end,
+    /// returns the playlist fetcher task join handles and the parameters of saved segments
+    async fn download_segments<S: SegmentSink + ?Sized>(
+        &self,
+        sink: &mut S,
+        journal: Option<&Journal>,
+        resume_state: &ResumeState,
+    ) -> (Vec<FetcherHandle>, ParamsTracker) {
+        let record = |entry| {
+            if let Some(j) = journal {
+                j.record(&entry);
+            }
+        };
+
         // m3u8 reader task handles
         let mut handles = Vec::new();
 
-        let rx = {
-            // Create channel for m3u8 fetcher <-> segment downloader tasks
-            let (tx, rx) = mpsc::unbounded();
-
-            // Spawn m3u8 reader task
-            for (stream, url) in &self.streams {
-                let client = self.client.clone();
-                let stopper = self.stopper.clone();
-                let tx = tx.clone();
-                let stream = stream.clone();
-                let url = url.clone();
-
-                handles.push(tokio::spawn(async move {
-                    m3u8_fetcher(client, stopper.clone(), tx, stream, url).await
-                }));
-            }
+        // Create channel for m3u8 fetcher <-> segment downloader tasks
+        let (tx, rx) = mpsc::unbounded();
 
-            rx
+        // Spread out first playlist fetches when there are many streams
+        let stagger = if self.streams.len() > STAGGER_MIN_STREAMS {
+            STAGGER_DURATION / self.streams.len() as u32
+        } else {
+            Duration::ZERO
         };
 
-        // Create segments directory if needed
-        let segments_directory = output.join("segments");
+        // Spawn m3u8 reader task
+        let mut fetchers = HashMap::new();
+        for (i, (stream, url)) in self.streams.iter().enumerate() {
+            let stopper = self.stopper.child();
+            handles.push(self.spawn_fetcher(stream, url, stopper.clone(), &tx, stagger * i as u32));
+            fetchers.insert(stream.clone(), stopper);
+        }
 
-        // Cache initializations for each stream
-        let init_lrus: HashMap<_, _> = self
-            .streams
-            .keys()
-            .map(|k| {
-                (
-                    k,
-                    Arc::new(Mutex::new(LruCache::new(
-                        self.options.network_options.max_concurrent_downloads,
-                    ))),
-                )
-            })
-            .collect();
+        // Keep the sender to start fetchers of new renditions if refreshing the master playlist,
+        // otherwise drop it so that the channel closes once all fetchers finished
+        let mut refresh = match (&self.master, self.options.download_options.refresh_master) {
+            (Some(master), Some(period)) => {
+                let mut interval = time::interval_at(time::Instant::now() + period, period);
+                interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+                Some(MasterRefresh {
+                    interval,
+                    tx,
+                    fetchers,
+                    ladder: master.ladder.clone(),
+                })
+            }
+            _ => {
+                drop(tx);
+                None
+            }
+        };
+
+        // Cache initializations, shared by streams referencing the same ones
+        let inits = InitCache::new(
+            self.options.network_options.max_concurrent_downloads * self.streams.len().max(1),
+        );
+
+        // Current initialization of each stream, used to record changes
+        let mut cur_inits: HashMap<Stream, Option<RemoteData>> = HashMap::new();
+
+        // Initializations already passed to the sink
+        let mut sent_inits = HashSet::new();
+
+        // Codecs and resolutions of saved segments, used to detect encoding changes
+        let mut params = ParamsTracker::default();
+
+        // Number of saved segments of each format
+        let mut formats = HashMap::new();
+
+        // Segments whose last download failed
+        let mut failed_segments = HashMap::new();
 
-        // Save paths for each downloaded segment
-        let mut downloaded_segments = HashMap::new();
+        // Response timings of downloaded segments
+        let mut timings = TimingStats::default();
 
-        // Download segments
-        let mut buffered = rx
-            .map(|(stream, seg, encryption)| {
-                fetch_segment(
-                    &self.client,
-                    init_lrus[&stream].clone(),
+        // Recently saved segments if deduplicating by content
+        let mut dedupe = self
+            .options
+            .download_options
+            .dedupe_by_content
+            .then(ContentDedupe::default);
+
+        // Skip segments that were saved by a previous run
+        let jobs = rx.filter(|job| {
+            let saved = resume_state.contains(&job.stream, &job.segment);
+            if saved {
+                event!(Level::TRACE, "Skipping saved segment {}", job.segment.url());
+                record(JournalEntry::Skip {
+                    stream: job.stream.clone(),
+                    discon_seq: job.segment.discon_seq,
+                    seq: job.segment.seq,
+                });
+            }
+            future::ready(!saved)
+        });
+
+        // Download segments, deferring secondary streams while primary streams lag if requested
+        let mut buffered = Scheduler::new(
+            jobs,
+            self.options.network_options.max_concurrent_downloads,
+            |job| {
+                let DownloadJob {
                     stream,
-                    seg,
+                    segment: seg,
                     encryption,
-                )
-            })
-            .buffer_unordered(self.options.network_options.max_concurrent_downloads);
+                    enqueued,
+                    ..
+                } = job;
+                event!(
+                    Level::TRACE,
+                    "Starting {} after {:?} in queue",
+                    seg.url(),
+                    enqueued.elapsed()
+                );
+                event!(
+                    target: EVENT_TARGET,
+                    Level::DEBUG,
+                    stream = %stream,
+                    url = %seg.url(),
+                    seq = seg.seq,
+                    queued_secs = enqueued.elapsed().as_secs_f64(),
+                    "segment_requested"
+                );
+
+                if cur_inits.get(&stream) != Some(&seg.initialization) {
+                    record(JournalEntry::InitChange {
+                        stream: stream.clone(),
+                        data: seg.initialization.as_ref().map(DataRecord::from),
+                    });
+                    cur_inits.insert(stream.clone(), seg.initialization.clone());
+                }
+                record(JournalEntry::Enqueue {
+                    stream: stream.clone(),
+                    discon_seq: seg.discon_seq,
+                    seq: seg.seq,
+                    data: DataRecord::from(&seg.data),
+                });
+
+                let id = (stream.clone(), seg.clone(), encryption.clone());
+                self.fetch_segment(&inits, stream, seg, encryption, false)
+                    .map(|r| (id, r))
+            },
+        );
+        if self.options.download_options.prioritize_main {
+            buffered = buffered.defer_secondary(self.secondary_streams(), PRIMARY_LAG_THRESHOLD);
+        }
+
+        // Pass segments to sink, break if stopped
+        // Limits after which no new downloads are started
+        let mut deadline = self
+            .options
+            .download_options
+            .max_runtime
+            .map(|d| time::Instant::now() + d);
+        let max_filesize = self.options.download_options.max_filesize;
+        let mut downloaded_bytes = 0;
+        let mut traffic_interval = time::interval_at(
+            time::Instant::now() + TRAFFIC_RECORD_INTERVAL,
+            TRAFFIC_RECORD_INTERVAL,
+        );
+
+        loop {
+            let ((stream, segment, encryption), x) = match tokio::select! {
+                y = buffered.next() => { y },
+                _ = self.stopper.wait() => { None },
+                _ = time::sleep_until(deadline.unwrap_or_else(time::Instant::now)), if deadline.is_some() => {
+                    event!(Level::INFO, "Reached maximum runtime, finishing running downloads");
+                    buffered.close();
+                    deadline = None;
+                    continue;
+                }
+                _ = traffic_interval.tick() => {
+                    self.client.traffic().record();
+                    continue;
+                }
+                _ = async {
+                    match refresh.as_mut() {
+                        Some(r) => r.interval.tick().await,
+                        None => future::pending().await,
+                    }
+                } => {
+                    // Stop refreshing once all fetchers finished, so that the download can finish
+                    if handles.iter().all(|(_, h)| h.is_finished()) {
+                        refresh = None;
+                    } else if let Some(r) = refresh.as_mut() {
+                        if let Err(e) = self.refresh_master(r, &mut handles).await {
+                            event!(Level::WARN, "Failed to refresh master playlist: {:?}", e);
+                        }
+                    }
+                    continue;
+                }
+            } {
+                Some(y) => y,
+                None => break,
+            };
 
-        // Save segments to disk in order, break if stopped
-        while let Some(x) = tokio::select! {
-            y = buffered.next() => { y },
-            _ = self.stopper.wait() => { None }
-        } {
             // Quit immediately if stopped
             if self.stopper.stopped().await {
                 break;
             }
 
-            // Save the segment
-            match x {
-                Ok(id_data) => {
-                    let segment = id_data.1.clone();
-                    let res =
-                        save_segment(id_data, &mut downloaded_segments, &segments_directory).await;
+            // Stop starting downloads once enough data was downloaded
+            if let (Some(max), Ok(((_, _, init, data), _, _, _))) = (max_filesize, &x) {
+                downloaded_bytes += (init.len() + data.len()) as u64;
+                if downloaded_bytes >= max && !buffered.is_closed() {
+                    event!(
+                        Level::INFO,
+                        "Reached maximum filesize, finishing running downloads"
+                    );
+                    buffered.close();
+                }
+            }
 
-                    // Log warning if segment failed to download
-                    if let Err(e) = res {
-                        event!(
-                            Level::WARN,
-                            "Failed to save {}, reason: {}",
-                            segment.url(),
-                            e
+            // Save the segment, then release its reservation
+            let res = match x {
+                Ok((id_data, headers, timing, _reservation)) => {
+                    if let Some(t) = timing {
+                        timings.push(t);
+                    }
+                    if !headers.is_empty() {
+                        record(JournalEntry::ResponseHeaders {
+                            stream: stream.clone(),
+                            discon_seq: segment.discon_seq,
+                            seq: segment.seq,
+                            headers,
+                        });
+                    }
+                    for change in check_params(&id_data, &mut params).await {
+                        warn_params_change(
+                            &change,
+                            self.options.download_options.split_on_codec_change,
                         );
+                        record(JournalEntry::ParamsChange {
+                            stream: change.stream,
+                            discon_seq: change.discon_seq,
+                            seq: change.seq,
+                            from: change.from,
+                            to: change.to,
+                        });
+                    }
+
+                    // Drop segments whose identity and content match a recently saved segment
+                    let (_, _, _, data) = &id_data;
+                    match dedupe
+                        .as_mut()
+                        .map(|d| d.check(&stream, &segment.data, data))
+                    {
+                        Some(ContentCheck::Duplicate) => {
+                            event!(Level::INFO, "Skipping duplicate segment {}", segment.url());
+                            record(JournalEntry::Skip {
+                                stream: stream.clone(),
+                                discon_seq: segment.discon_seq,
+                                seq: segment.seq,
+                            });
+                            self.attempts.succeeded(&stream, &segment);
+                            continue;
+                        }
+                        Some(ContentCheck::Changed { previous, current }) => {
+                            event!(
+                                Level::WARN,
+                                "Content of {} changed since it was last downloaded, keeping both",
+                                segment.url()
+                            );
+                            record(JournalEntry::ContentChanged {
+                                stream: stream.clone(),
+                                discon_seq: segment.discon_seq,
+                                seq: segment.seq,
+                                data: DataRecord::from(&segment.data),
+                                previous_hash: format!("{:016x}", previous),
+                                hash: format!("{:016x}", current),
+                            });
+                        }
+                        Some(ContentCheck::New) | None => (),
                     }
+
+                    save_segment(
+                        id_data,
+                        sink,
+                        &mut sent_inits,
+                        &mut formats,
+                        self.client.traffic(),
+                    )
+                    .await
+                    .with_context(|| format!("Failed to save {}", segment.url()))
                 }
-                Err(e) => {
-                    event!(Level::WARN, "{:?}", e);
+                Err(e) => Err(e),
+            };
+
+            // Log warning and record gap if segment failed, it's retried while attempts are left
+            let key = (stream.clone(), segment.discon_seq, segment.seq);
+            if res.is_ok() {
+                self.attempts.succeeded(&stream, &segment);
+                self.gaps.saved(&stream, &segment);
+                self.statuses.saved(&stream, &segment);
+                failed_segments.remove(&key);
+            }
+            if let Err(e) = res {
+                failed_segments.insert(key, (segment.clone(), encryption));
+                self.gaps.failed(&stream, &segment, gap_reason(&e));
+                self.coverage.fail(&stream, segment.seq, gap_reason(&e));
+                event!(Level::WARN, "{:?}", e);
+                match self.attempts.failed(&stream, &segment) {
+                    Some(n) => event!(
+                        Level::INFO,
+                        "Retrying segment {} if it's still listed, {} of {} attempts failed",
+                        segment.url(),
+                        n,
+                        self.attempts.max_attempts()
+                    ),
+                    None if self.attempts.max_attempts() > 1 => {
+                        event!(Level::WARN, "Giving up on segment {}", segment.url())
+                    }
+                    None => (),
                 }
+                event!(
+                    target: EVENT_TARGET,
+                    Level::DEBUG,
+                    stream = %stream,
+                    url = %segment.url(),
+                    seq = segment.seq,
+                    error = %format!("{:#}", e),
+                    decrypt_failed = matches!(
+                        e.downcast_ref::<LivestreamDLError>(),
+                        Some(LivestreamDLError::Decrypt(_))
+                    ),
+                    "segment_failed"
+                );
+                record(JournalEntry::Gap {
+                    stream,
+                    discon_seq: segment.discon_seq,
+                    seq: segment.seq,
+                    reason: format!("{:#}", e),
+                });
             }
         }
 
-        // Remux if necessary
-        if !self.options.download_options.no_remux {
-            remux(downloaded_segments, output).await?;
+        timings.log();
+        inits.log();
+        self.client.traffic().log();
+        self.key_recovery.log();
+        let failed = self.attempts.permanently_failed();
+        if failed > 0 {
+            event!(Level::WARN, "{} segments failed permanently", failed);
+        }
+        if buffered.deferred() > 0 {
+            let lost = failed_segments
+                .iter()
+                .filter(|((stream, _, _), (segment, _))| buffered.was_deferred(stream, segment))
+                .count();
+            event!(
+                Level::INFO,
+                "Deferred {} segments of secondary streams while primary streams lagged, {} of them failed",
+                buffered.deferred(),
+                lost
+            );
         }
 
-        // Check playlist fetcher task join handles
-        for handle in handles {
-            handle.await?.context("m3u8 fetcher failed")?;
+        // Download failed segments once more if requested
+        if self.options.download_options.repair_pass && !failed_segments.is_empty() {
+            self.repair(failed_segments, sink, &mut sent_inits, &mut formats)
+                .await;
         }
+        report_formats(&formats, &params);
 
-        Ok(())
+        (handles, params)
     }
-}
 
-/// Download segment and save to disk if necessary
-async fn fetch_segment(
-    client: &HttpClient,
-    lru: Arc<Mutex<LruCache<RemoteData, Vec<u8>>>>,
-    stream: Stream,
-    segment: Segment,
-    encryption: Encryption,
-) -> Result<SegmentIdData> {
-    // Get initialization
-    let init_bytes = if let Some(ref i) = segment.initialization {
-        // Get cached initialization, otherwise fetch from network
-        let mut guard = lru.lock().await;
-        let data = guard.get(i).cloned();
-        match data {
-            Some(d) => d,
-            None => {
-                let d = i
-                    .fetch(client)
-                    .await
-                    .context("error fetching segment initialization")?
-                    .0;
-                guard.put(i.clone(), d.clone());
-                d
+    /// Download failed segments once more, one at a time and with a longer timeout, and pass
+    /// them to sink
+    async fn repair<S: SegmentSink + ?Sized>(
+        &self,
+        failed_segments: HashMap<(Stream, u64, u64), (Segment, Encryption)>,
+        sink: &mut S,
+        sent_inits: &mut HashSet<(Stream, RemoteData)>,
+        formats: &mut HashMap<Stream, HashMap<MediaFormat, usize>>,
+    ) {
+        let total = failed_segments.len();
+        event!(Level::INFO, "Downloading {} failed segments again", total);
+        let inits = InitCache::new(1);
+        let mut repaired = 0;
+        for ((stream, _, _), (segment, encryption)) in failed_segments
+            .into_iter()
+            .sorted_by_key(|((_, d, s), _)| (*d, *s))
+        {
+            let res = match self
+                .fetch_segment(&inits, stream.clone(), segment.clone(), encryption, true)
+                .await
+            {
+                Ok((id_data, _, _, _reservation)) => {
+                    save_segment(id_data, sink, sent_inits, formats, self.client.traffic()).await
+                }
+                Err(e) => Err(e),
+            };
+            match res {
+                Ok(()) => {
+                    self.gaps.saved(&stream, &segment);
+                    self.statuses.saved(&stream, &segment);
+                    repaired += 1;
+                }
+                Err(e) => event!(
+                    Level::WARN,
+                    "Segment {} is still missing: {:#}",
+                    segment.url(),
+                    e
+                ),
             }
         }
+        event!(Level::INFO, "Repaired {} of {} gaps", repaired, total);
+    }
+
+    /// Download segment and its initialization with the segment fetcher, holding a reservation of
+    /// its size until dropped
+    ///
+    /// Initializations are cached in inits. If refetch is set, the segment failed before and is
+    /// fetched with SegmentFetcher::refetch.
+    #[instrument(skip_all, fields(stream = %stream))]
+    async fn fetch_segment(
+        &self,
+        inits: &InitCache,
+        stream: Stream,
+        segment: Segment,
+        encryption: Encryption,
+        refetch: bool,
+    ) -> Result<(
+        SegmentIdData,
+        ResponseHeaders,
+        Option<ResponseTiming>,
+        Option<Reservation>,
+    )> {
+        // Get initialization
+        let init_bytes = if let Some(ref i) = segment.initialization {
+            // Get cached initialization, otherwise fetch from network
+            inits
+                .get(&stream, i, self.fetcher.as_ref())
+                .await
+                .context("error fetching segment initialization")?
+        } else {
+            Vec::new()
+        };
+
+        // Fetch segment
+        let fetched = if refetch {
+            self.fetcher.refetch(&segment.data).await
+        } else {
+            self.fetcher.fetch(&segment.data).await
+        };
+        let (data_bytes, final_url, headers, timing, reservation) =
+            fetched.context("error fetching segment")?.into_parts();
+        let bytes = data_bytes.len();
+        let (decrypt_data_bytes, decrypt_time) = self
+            .key_recovery
+            .decrypt(&self.client, &stream, &encryption, data_bytes)
+            .await?;
+        let timing = timing.map(|t| ResponseTiming {
+            decrypt: decrypt_time,
+            ..t
+        });
+
+        event!(
+            Level::INFO,
+            "Downloaded {} {}",
+            final_url,
+            segment
+                .data
+                .byte_range_string()
+                .unwrap_or_else(|| "".into())
+        );
+        event!(
+            target: EVENT_TARGET,
+            Level::DEBUG,
+            stream = %stream,
+            url = %final_url,
+            seq = segment.seq,
+            bytes,
+            headers = ?headers,
+            ttfb_secs = timing.map(|t| t.ttfb.as_secs_f64()),
+            total_secs = timing.map(|t| t.total.as_secs_f64()),
+            decrypt_secs = decrypt_time.map(|d| d.as_secs_f64()),
+            "segment_downloaded"
+        );
+
+        Ok((
+            (stream, segment, init_bytes, decrypt_data_bytes),
+            headers,
+            timing,
+            reservation,
+        ))
+    }
+}
+
+/// Detect segment format and pass segment to sink, preceded by its initialization if the sink
+/// hasn't seen it yet
+async fn save_segment<S: SegmentSink + ?Sized>(
+    (stream, mut segment, init_bytes, data_bytes): SegmentIdData,
+    sink: &mut S,
+    sent_inits: &mut HashSet<(Stream, RemoteData)>,
+    formats: &mut HashMap<Stream, HashMap<MediaFormat, usize>>,
+    traffic: &TrafficStats,
+) -> Result<()> {
+    // Detect segment format
+    let bytes = init_bytes.iter().chain(&data_bytes).copied().collect();
+    segment.format = MediaFormat::detect(bytes).await?;
+    *formats
+        .entry(stream.clone())
+        .or_default()
+        .entry(segment.format.clone())
+        .or_default() += 1;
+
+    // Pass initialization to sink if needed
+    if let Some(ref init) = segment.initialization {
+        let key = (stream.clone(), init.clone());
+        if !sent_inits.contains(&key) {
+            sink.on_init(&stream, init, &init_bytes).await?;
+            traffic.saved(init_bytes.len() as u64);
+            sent_inits.insert(key);
+        }
+    }
+
+    sink.on_segment(&stream, &segment, &data_bytes).await?;
+    traffic.saved(data_bytes.len() as u64);
+    Ok(())
+}
+
+/// Probe the first segment of each discontinuity and initialization section, returns detected
+/// changes of codecs or resolution
+async fn check_params(
+    (stream, segment, init_bytes, data_bytes): &SegmentIdData,
+    params: &mut ParamsTracker,
+) -> Vec<ParamsChange> {
+    if !params.observe(stream, segment) {
+        return Vec::new();
+    }
+
+    let bytes = init_bytes.iter().chain(data_bytes).copied().collect();
+    match StreamParams::probe(bytes).await {
+        Ok(Some(p)) => {
+            event!(
+                Level::DEBUG,
+                "Stream {} segment {} is {}",
+                stream,
+                segment.id(),
+                p
+            );
+            params.set_params(stream, segment, p)
+        }
+        Ok(None) => Vec::new(),
+        Err(e) => {
+            event!(Level::DEBUG, "Failed to probe {}: {:?}", segment.url(), e);
+            Vec::new()
+        }
+    }
+}
+
+/// Warn about a change of codecs or resolution, which players may not handle within one file
+fn warn_params_change(change: &ParamsChange, split: bool) {
+    let outcome = if change.at_discontinuity {
+        "output is cut at the discontinuity"
+    } else if split {
+        "output will be cut there"
     } else {
-        Vec::new()
+        "remuxed output may not play correctly, use --split-on-codec-change to cut it there"
     };
+    event!(
+        Level::WARN,
+        "Stream {} changed from {} to {} at segment {}, {}",
+        change.stream,
+        change.from,
+        change.to,
+        change.seq,
+        outcome
+    );
+}
 
-    // Fetch segment
-    let (data_bytes, final_url) = segment
-        .data
-        .fetch(client)
-        .await
-        .context("error fetching segment")?;
-    let decrypt_data_bytes = encryption.decrypt(client, &data_bytes).await?;
+/// Log formats and codecs of the segments saved for each stream, warning about streams with
+/// mixed formats
+fn report_formats(formats: &HashMap<Stream, HashMap<MediaFormat, usize>>, params: &ParamsTracker) {
+    for (stream, counts) in formats.iter().sorted_by_key(|(s, _)| s.to_string()) {
+        let summary = counts
+            .iter()
+            .sorted_by_key(|(f, &n)| (std::cmp::Reverse(n), format!("{:?}", f)))
+            .map(|(f, n)| format!("{} {:?}", n, f))
+            .join(", ");
+        let codecs = params.distinct(stream).iter().join("; ");
+        event!(
+            Level::INFO,
+            "Stream {} segments: {}{}",
+            stream,
+            summary,
+            if codecs.is_empty() {
+                String::new()
+            } else {
+                format!(" ({})", codecs)
+            }
+        );
 
-    // Concat initialization and segment
-    let bytes = init_bytes
+        if counts.len() > 1 {
+            event!(
+                Level::WARN,
+                "Stream {} contains segments of mixed formats, the encoding probably changed and remuxing may fail",
+                stream
+            );
+        }
+    }
+}
+
+/// Alternative renditions of a variant, limited to max_renditions, returns included and excluded
+/// renditions
+fn select_renditions(
+    master: &MasterPlaylist,
+    variant: &VariantStream,
+    base_url: &Url,
+    options: &DownloadOptions,
+) -> Result<RenditionSelection> {
+    // Closure to find alternative media with matching group id
+    let mut renditions = Vec::new();
+    let mut add_alternative = |group: &str, f: fn(&AlternativeMedia) -> Stream| -> Result<()> {
+        for a in master.alternatives.iter().filter(|a| a.group_id == group) {
+            if let Some(a_url) = &a.uri {
+                renditions.push((f(a), make_absolute_url(base_url, a_url)?, a));
+            }
+        }
+        Ok(())
+    };
+
+    // Add audio streams, from another group if only that one has the preferred codec
+    if let Some(group) = audio_group(master, variant, options.audio_codec.as_deref()) {
+        add_alternative(group, |a| Stream::Audio {
+            group_id: a.group_id.clone(),
+            name: a.name.clone(),
+            lang: a.language.clone(),
+            channels: a.channels.as_deref().and_then(parse_channels),
+            describes_video: a
+                .characteristics
+                .as_deref()
+                .is_some_and(|c| c.split(',').any(|c| c.trim() == DESCRIBES_VIDEO)),
+        })?;
+    }
+
+    // Add video streams
+    if let Some(group) = &variant.video {
+        add_alternative(group, |a| Stream::Video {
+            group_id: a.group_id.clone(),
+            name: a.name.clone(),
+            lang: a.language.clone(),
+        })?;
+    }
+
+    // Add subtitle streams
+    if let Some(group) = &variant.subtitles {
+        add_alternative(group, |a| Stream::Subtitle {
+            group_id: a.group_id.clone(),
+            name: a.name.clone(),
+            lang: a.language.clone(),
+            forced: a.forced,
+        })?;
+    }
+
+    // Skip audio descriptions unless wanted
+    let mut excluded = Vec::new();
+    if !options.include_audio_description {
+        let (keep, drop) = renditions.into_iter().partition(|(s, _, _)| {
+            !matches!(
+                s,
+                Stream::Audio {
+                    describes_video: true,
+                    ..
+                }
+            )
+        });
+        renditions = keep;
+        excluded = drop;
+    }
+
+    // Only keep audio renditions with the requested number of channels
+    if let Some(wanted) = options.audio_channels {
+        let channels = |s: &Stream| match s {
+            Stream::Audio { channels, .. } => Some(*channels),
+            _ => None,
+        };
+        let max = renditions.iter().filter_map(|(s, _, _)| channels(s)).max();
+        let (keep, drop) =
+            renditions
+                .into_iter()
+                .partition(|(s, _, _)| match (channels(s), wanted) {
+                    (None, _) => true,
+                    (Some(c), AudioChannels::Max) => Some(c) == max,
+                    (Some(c), AudioChannels::Count(n)) => c == Some(n),
+                });
+        renditions = keep;
+        excluded.extend(drop);
+    }
+
+    // Forced subtitles don't count towards the limits, they are kept with the renditions of their
+    // language
+    let (forced, rest): (Vec<_>, Vec<_>) = renditions
         .into_iter()
-        .chain(decrypt_data_bytes.into_iter())
+        .partition(|(s, _, _)| matches!(s, Stream::Subtitle { forced: true, .. }));
+    renditions = rest;
+
+    // Limit number of renditions, preferring default renditions, then preferred languages, then
+    // playlist order
+    let limited = options.max_renditions.is_some() || options.max_renditions_per_type.is_some();
+    if limited {
+        let prefer_lang = &options.prefer_lang;
+        let lang_rank = |lang: &Option<String>| {
+            lang.as_ref()
+                .and_then(|l| prefer_lang.iter().position(|p| lang_matches(l, p)))
+                .unwrap_or(usize::MAX)
+        };
+        renditions.sort_by_key(|(_, _, a)| (!a.default, lang_rank(&a.language)));
+    }
+    if let Some(max) = options.max_renditions_per_type {
+        let mut counts = HashMap::new();
+        let (keep, drop) = renditions.into_iter().partition(|(s, _, _)| {
+            let count = counts.entry(mem::discriminant(s)).or_insert(0);
+            *count += 1;
+            *count <= max
+        });
+        renditions = keep;
+        excluded.extend(drop);
+    }
+    if let Some(max) = options.max_renditions {
+        excluded.extend(renditions.split_off(max.min(renditions.len())));
+    }
+
+    let selected_langs: Vec<_> = renditions
+        .iter()
+        .filter_map(|(_, _, a)| a.language.clone())
+        .chain(options.prefer_lang.iter().cloned())
         .collect();
+    for rendition in forced {
+        let selected = match &rendition.2.language {
+            Some(l) => selected_langs
+                .iter()
+                .any(|s| lang_matches(l, s) || lang_matches(s, l)),
+            None => true,
+        };
+        if selected || !limited {
+            renditions.push(rendition);
+        } else {
+            excluded.push(rendition);
+        }
+    }
 
-    event!(
-        Level::INFO,
-        "Downloaded {} {}",
-        final_url,
-        segment
-            .data
-            .byte_range_string()
-            .unwrap_or_else(|| "".into())
+    Ok((
+        renditions.into_iter().map(|(s, u, _)| (s, u)).collect(),
+        excluded.into_iter().map(|(s, _, _)| s).collect(),
+    ))
+}
+
+/// Error of a playlist request that failed without a response, telling DNS and connection
+/// failures apart from other errors
+fn request_error(e: reqwest_middleware::Error, url: &Url) -> anyhow::Error {
+    let host = format!(
+        "{}:{}",
+        url.host_str().unwrap_or_default(),
+        url.port_or_known_default().unwrap_or_default()
     );
+    let context = match &e {
+        reqwest_middleware::Error::Reqwest(r) if r.is_timeout() => {
+            format!("Request for playlist {} timed out", url)
+        }
+        reqwest_middleware::Error::Reqwest(r) if r.is_connect() => {
+            let dns = std::iter::successors(Some(r as &dyn std::error::Error), |e| e.source())
+                .any(|e| e.to_string().starts_with("dns error"));
+            if dns {
+                format!(
+                    "Unable to resolve host {}",
+                    url.host_str().unwrap_or_default()
+                )
+            } else {
+                format!("Unable to connect to {}", host)
+            }
+        }
+        _ => format!("Request for playlist {} failed", url),
+    };
+    anyhow::Error::new(e).context(context)
+}
 
-    Ok((stream, segment, bytes))
+/// Referer of the origin (scheme, host and port) of url, like browsers send by default to other
+/// origins
+fn referer(url: &Url) -> Result<HeaderValue> {
+    match url.origin() {
+        Origin::Tuple(..) => Ok(HeaderValue::from_str(&format!(
+            "{}/",
+            url.origin().ascii_serialization()
+        ))?),
+        Origin::Opaque(_) => Err(anyhow::anyhow!("{} has no origin to use as Referer", url)),
+    }
 }
 
-async fn save_segment<P>(
-    (stream, mut segment, bytes): SegmentIdData,
-    downloaded_segments: &mut HashMap<Stream, BinaryHeap<(Segment, PathBuf)>>,
-    segments_directory: P,
-) -> Result<()>
-where
-    P: AsRef<Path>,
-{
-    // Detect segment format
-    segment.format = MediaFormat::detect(bytes.clone()).await?;
-
-    // Create directory if neeeded
-    fs::create_dir_all(segments_directory.as_ref()).await?;
-
-    // Save segment to disk
-    let file_path = segments_directory.as_ref().join(format!(
-        "segment_{}_{}.{}",
-        stream,
-        segment.id(),
-        segment.format.extension()
-    ));
-    event!(Level::TRACE, "saving to {:?}", &file_path);
-    let mut file = fs::File::create(&file_path).await?;
-    file.write_all(&bytes).await?;
-
-    // Remember path
-    downloaded_segments
-        .entry(stream)
-        .or_default()
-        .push((segment, file_path));
+/// Audio group to download renditions from, the group of the variant unless only another audio
+/// group of the master playlist has the preferred codec
+fn audio_group<'a>(
+    master: &'a MasterPlaylist,
+    variant: &'a VariantStream,
+    codec: Option<&str>,
+) -> Option<&'a str> {
+    let group = variant.audio.as_deref()?;
+    let codec = match codec {
+        Some(c) if !group_has_codec(master, group, c) => c,
+        _ => return Some(group),
+    };
+
+    master
+        .alternatives
+        .iter()
+        .filter(|a| a.media_type == AlternativeMediaType::Audio)
+        .map(|a| a.group_id.as_str())
+        .find(|g| group_has_codec(master, g, codec))
+        .or(Some(group))
+}
+
+/// Check if variants using an audio group list a codec, e.g. ac-3 matches ac-3 and mp4a matches
+/// mp4a.40.2
+fn group_has_codec(master: &MasterPlaylist, group: &str, codec: &str) -> bool {
+    let codec = codec.to_lowercase();
+    master
+        .variants
+        .iter()
+        .filter(|v| v.audio.as_deref() == Some(group))
+        .filter_map(|v| v.codecs.as_deref())
+        .flat_map(|c| c.split(','))
+        .map(|c| c.trim().to_lowercase())
+        .any(|c| c == codec || c.starts_with(&format!("{}.", codec)))
+}
+
+/// Parse the channel count of a CHANNELS attribute, e.g. 6 or 16/JOC
+fn parse_channels(channels: &str) -> Option<u32> {
+    channels.split('/').next()?.parse().ok()
+}
+
+/// Check if a language tag is or is more specific than a preferred language, e.g. en-US matches en
+pub(crate) fn lang_matches(lang: &str, preferred: &str) -> bool {
+    let lang = lang.to_lowercase();
+    let preferred = preferred.to_lowercase();
+    lang == preferred || lang.starts_with(&format!("{}-", preferred))
+}
+
+/// Keep only the leading run of consecutive segments of each stream
+fn trim_to_contiguous(downloaded_segments: &mut HashMap<Stream, BinaryHeap<(Segment, PathBuf)>>) {
+    for (stream, segments) in downloaded_segments.iter_mut() {
+        let sorted = std::mem::take(segments).into_sorted_vec();
+        let total = sorted.len();
+        let mut prev_seq = None;
+        for (segment, path) in sorted {
+            if prev_seq.is_some_and(|p| segment.seq != p + 1) {
+                break;
+            }
+            prev_seq = Some(segment.seq);
+            segments.push((segment, path));
+        }
+
+        if segments.len() < total {
+            event!(
+                Level::WARN,
+                "Not remuxing {} segments of {} after the first missing segment",
+                total - segments.len(),
+                stream
+            );
+        }
+    }
+}
+
+/// Check continuity counters of the MPEG-TS segments of each stream in playlist order, warning
+/// about segments with likely dropped or corrupted packets
+///
+/// Counters are reset at discontinuities and missing segments, where they are expected to jump.
+async fn validate_ts(
+    downloaded_segments: &HashMap<Stream, BinaryHeap<(Segment, PathBuf)>>,
+) -> Result<()> {
+    for (stream, segments) in downloaded_segments
+        .iter()
+        .sorted_by_key(|(s, _)| s.to_string())
+    {
+        let mut checker = ContinuityChecker::default();
+        let mut prev: Option<&Segment> = None;
+        let mut checked = 0;
+        let mut corrupt = 0;
+        for (segment, path) in segments.iter().sorted() {
+            if segment.format != MediaFormat::MpegTs {
+                continue;
+            }
+            if prev.is_none_or(|p| p.discon_seq != segment.discon_seq || p.seq + 1 != segment.seq) {
+                checker.reset();
+            }
+            prev = Some(segment);
+
+            let data = fs::read(path)
+                .await
+                .with_context(|| format!("error reading segment {:?}", path))?;
+            let first_packet = checker.packets();
+            let errors = checker.check(&data);
+            checked += 1;
+            if errors.is_empty() {
+                continue;
+            }
+
+            corrupt += 1;
+            let details = errors
+                .iter()
+                .map(|e| match e {
+                    ContinuityError::Counter {
+                        pid,
+                        packet,
+                        expected,
+                        found,
+                    } => format!(
+                        "PID {:#06x} packet {} counter {} instead of {}",
+                        pid,
+                        packet - first_packet,
+                        found,
+                        expected
+                    ),
+                    ContinuityError::LostSync { packet } => {
+                        format!("lost sync at packet {}", packet - first_packet)
+                    }
+                })
+                .join(", ");
+            event!(
+                Level::WARN,
+                "Stream {} segment {} is likely corrupt: {}",
+                stream,
+                segment.id(),
+                details
+            );
+
+            // Data after lost sync wasn't checked, start over with the next segment
+            if errors
+                .iter()
+                .any(|e| matches!(e, ContinuityError::LostSync { .. }))
+            {
+                prev = None;
+            }
+        }
+
+        if checked > 0 {
+            event!(
+                Level::INFO,
+                "Stream {} TS continuity: {} of {} segments with errors",
+                stream,
+                corrupt,
+                checked
+            );
+        }
+    }
 
     Ok(())
 }
+
+/// Give segments after encoding changes within a discontinuity their own discontinuity sequence,
+/// so that they are remuxed into separate files
+///
+/// Sequence numbers of renditions are assumed to be aligned, so all streams are cut at the same
+/// sequence number.
+fn split_on_changes(
+    downloaded_segments: &mut HashMap<Stream, BinaryHeap<(Segment, PathBuf)>>,
+    changes: &[ParamsChange],
+) {
+    let cuts: BTreeSet<_> = changes
+        .iter()
+        .filter(|c| !c.at_discontinuity)
+        .map(|c| (c.discon_seq, c.seq))
+        .collect();
+    if cuts.is_empty() {
+        return;
+    }
+    for (discon_seq, seq) in &cuts {
+        event!(
+            Level::INFO,
+            "Cutting output of discontinuity {} at segment {}",
+            discon_seq,
+            seq
+        );
+    }
+
+    // Number parts of discontinuities in order
+    let discon_seqs: BTreeSet<_> = downloaded_segments
+        .values()
+        .flatten()
+        .map(|(s, _)| s.discon_seq)
+        .collect();
+    let parts: Vec<_> = discon_seqs
+        .into_iter()
+        .map(|d| (d, 0))
+        .chain(cuts.iter().copied())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+
+    for segments in downloaded_segments.values_mut() {
+        *segments = std::mem::take(segments)
+            .into_iter()
+            .map(|(mut segment, path)| {
+                let part = parts.partition_point(|&p| p <= (segment.discon_seq, segment.seq));
+                segment.discon_seq = part.saturating_sub(1) as u64;
+                (segment, path)
+            })
+            .collect();
+    }
+}
+
+/// Error listing why each stream didn't download any segment, from the result of its fetcher
+async fn no_segments_error(
+    handles: Vec<FetcherHandle>,
+    attempts: &SegmentAttempts,
+    stopped: bool,
+) -> anyhow::Error {
+    let mut problems = Vec::with_capacity(handles.len());
+    for (stream, handle) in handles {
+        let failed = attempts.permanently_failed_in(&stream);
+        let problem = match handle.await {
+            Err(e) => format!("{}", e),
+            Ok(Err(e)) => format!("m3u8 fetcher failed: {:#}", e),
+            Ok(Ok(_)) if failed > 0 => format!("all {} segments failed to download", failed),
+            Ok(Ok(_)) if stopped => "stopped before any segment was downloaded".into(),
+            Ok(Ok(_)) => "the playlist listed no segments to download".into(),
+        };
+        problems.push(format!("{}: {}", stream, problem));
+    }
+    LivestreamDLError::NoSegments(problems).into()
+}
+
+/// Whether error of the fetcher of stream only means that the stream is an unusable alternative
+/// rendition, e.g. whose playlist is missing or not a media playlist
+fn is_rendition_problem(stream: &Stream, error: &anyhow::Error) -> bool {
+    if *stream == Stream::Main {
+        return false;
+    }
+    match error.downcast_ref::<LivestreamDLError>() {
+        Some(LivestreamDLError::ParseM3u8(_) | LivestreamDLError::NotMediaPlaylist(_)) => true,
+        Some(LivestreamDLError::NetworkRequest(r)) => r.status().is_client_error(),
+        _ => false,
+    }
+}
+
+/// Check playlist fetcher task join handles, report stream durations and record how each stream
+/// ended in statuses
+///
+/// Failed alternative renditions are only reported unless strict_renditions is set.
+async fn join_fetchers(
+    handles: Vec<FetcherHandle>,
+    strict_renditions: bool,
+    statuses: &StreamStatuses,
+) -> Result<()> {
+    // Join all fetchers to report every stream, a failed fetcher already stopped the others
+    let mut error = None;
+    for (stream, handle) in handles {
+        let res = match handle.await {
+            Ok(res) => res,
+            Err(e) => Err(e.into()),
+        };
+        let sent = match res {
+            Ok(sent) => sent,
+            Err(e) => {
+                statuses.ended(&stream, StreamEnd::Failed(format!("{:#}", e)));
+                if !strict_renditions && is_rendition_problem(&stream, &e) {
+                    event!(Level::WARN, "Stream {} was dropped: {:#}", stream, e);
+                } else if error.is_none() {
+                    error = Some(e.context("m3u8 fetcher failed"));
+                }
+                continue;
+            }
+        };
+        let end = if sent.end_list {
+            StreamEnd::Completed
+        } else {
+            StreamEnd::Stopped
+        };
+        statuses.ended(&stream, end);
+        event!(
+            Level::INFO,
+            "Stream {} duration {:.3}s (playlist claims {:.3}s)",
+            stream,
+            sent.duration.checked(),
+            sent.duration.claimed()
+        );
+        if let Some(s) = sent.skipped_backlog {
+            event!(
+                Level::INFO,
+                "Stream {} skipped backlog of {} segments ({:.3}s), media sequence {} to {}",
+                stream,
+                s.segments,
+                s.duration,
+                s.first_seq,
+                s.last_seq
+            );
+        }
+        if let Some(l) = &sent.lint {
+            l.report(&stream);
+        }
+        if let Some(a) = sent.ad_breaks.filter(|a| a.breaks() > 0) {
+            event!(
+                Level::INFO,
+                "Stream {} skipped {} ad breaks of {} segments ({:.3}s)",
+                stream,
+                a.breaks(),
+                a.skipped_segments(),
+                a.skipped_duration()
+            );
+        }
+    }
+    statuses.log();
+
+    match error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// fMP4 segment of a run with its own initialization section
+    fn fmp4_segment(seq: u64, init: &str) -> Segment {
+        let url = |path: &str| format!("https://example.com/{}", path).parse().unwrap();
+        Segment {
+            data: RemoteData::new(url(&format!("{}.m4s", seq)), None),
+            discon_seq: 0,
+            seq,
+            format: MediaFormat::FMp4,
+            initialization: Some(RemoteData::new(url(init), None)),
+            program_date_time: None,
+            duration: Some(Duration::from_secs(6)),
+        }
+    }
+
+    fn params(resolution: (u64, u64)) -> StreamParams {
+        StreamParams {
+            codecs: vec!["h264".into()],
+            resolution: Some(resolution),
+        }
+    }
+
+    #[test]
+    fn resolution_change_at_new_init_is_split() {
+        // Two runs without a discontinuity between them, whose init segments differ in resolution
+        let segments: Vec<_> = (0..4)
+            .map(|seq| fmp4_segment(seq, if seq < 2 { "720p.mp4" } else { "1080p.mp4" }))
+            .collect();
+
+        // Only the first segment of each run is probed
+        let mut tracker = ParamsTracker::default();
+        let probed: Vec<_> = segments
+            .iter()
+            .filter(|s| tracker.observe(&Stream::Main, s))
+            .collect();
+        assert_eq!(probed.iter().map(|s| s.seq).collect::<Vec<_>>(), [0, 2]);
+        assert!(tracker
+            .set_params(&Stream::Main, probed[0], params((1280, 720)))
+            .is_empty());
+        let changes = tracker.set_params(&Stream::Main, probed[1], params((1920, 1080)));
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].seq, 2);
+        assert!(!changes[0].at_discontinuity);
+        assert_eq!(changes[0].from.resolution, Some((1280, 720)));
+        assert_eq!(changes[0].to.resolution, Some((1920, 1080)));
+
+        // The second run is remuxed separately
+        let heap = segments
+            .into_iter()
+            .map(|s| {
+                let path = PathBuf::from(format!("{}.m4s", s.seq));
+                (s, path)
+            })
+            .collect();
+        let mut downloaded = HashMap::from([(Stream::Main, heap)]);
+        split_on_changes(&mut downloaded, &changes);
+        let discon_seqs: Vec<_> = downloaded[&Stream::Main]
+            .clone()
+            .into_sorted_vec()
+            .iter()
+            .map(|(s, _)| s.discon_seq)
+            .collect();
+        assert_eq!(discon_seqs, [0, 0, 1, 1]);
+    }
+}
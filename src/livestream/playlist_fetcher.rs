@@ -1,134 +1,442 @@
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
 use futures::channel::mpsc;
+use futures::StreamExt;
+use m3u8_rs::{MediaPlaylist, Playlist};
 use reqwest::Url;
-use tokio::time;
-use tracing::{event, Level};
+use tracing::{event, instrument, Level};
 
+use super::ad_breaks::AdBreaks;
+use super::discon_tracker::DisconTracker;
+use super::duration::{parse_program_date_time, segment_duration};
 use super::http_client::HttpClient;
+use super::playlist_lint::PlaylistLint;
 use super::remote_data::RemoteData;
+use super::segment_attempts::SegmentAttempts;
+use super::segment_numbers::SegmentNumbers;
+use super::source::PlaylistStream;
+use super::trick_play::TrickPlayStreams;
 use super::utils::make_absolute_url;
-use super::{Encryption, Segment, Stopper, Stream};
+use super::{
+    ControlState, Coverage, DownloadJob, DurationTracker, Encryption, HashableByteRange, Priority,
+    Segment, Stopper, Stream, Variables, EVENT_TARGET,
+};
+use crate::cli::{BacklogLimit, DisconRange};
 use crate::error::LivestreamDLError;
 use crate::livestream::MediaFormat;
 
-/// Periodically fetch m3u8 media playlist and send new segments to download task
-pub async fn m3u8_fetcher(
-    client: HttpClient,
+/// Segments listed by the first fetch of a playlist that were skipped because of a backlog limit
+#[derive(Clone, Copy, Debug)]
+pub struct SkippedBacklog {
+    pub segments: usize,
+    /// Sum of the durations of the segments in seconds
+    pub duration: f64,
+    /// Media sequence numbers of the first and last skipped segment
+    pub first_seq: u64,
+    pub last_seq: u64,
+}
+
+/// Segments sent for a stream
+#[derive(Debug)]
+pub struct SentSegments {
+    pub duration: DurationTracker,
+    pub skipped_backlog: Option<SkippedBacklog>,
+    /// Detected ad breaks if they are skipped
+    pub ad_breaks: Option<AdBreaks>,
+    /// Spec violations found in the playlists if they are checked
+    pub lint: Option<PlaylistLint>,
+    /// Set if the playlist ended with EXT-X-ENDLIST
+    pub end_list: bool,
+}
+
+/// State of the playlist fetcher of a stream, kept when the fetcher is restarted after it failed
+/// so that it continues after the segments sent before
+#[derive(Debug)]
+pub struct FetcherState {
+    pub sent: SentSegments,
+    /// Discontinuity and media sequence of the last sent segment
+    last_seg: Option<(u64, u64)>,
+    /// Initialization section of the last sent segment
+    cur_init: Option<RemoteData>,
+    discons: DisconTracker,
+    first_playlist: bool,
+}
+
+impl FetcherState {
+    /// State before the first playlist, ad breaks are detected if skip_ads is set and playlists
+    /// are checked for spec violations if lint_playlist is set
+    pub fn new(skip_ads: bool, lint_playlist: bool) -> Self {
+        Self {
+            sent: SentSegments {
+                duration: DurationTracker::new(0.0),
+                skipped_backlog: None,
+                ad_breaks: skip_ads.then(AdBreaks::default),
+                lint: lint_playlist.then(PlaylistLint::default),
+                end_list: false,
+            },
+            last_seg: None,
+            cur_init: None,
+            discons: DisconTracker::default(),
+            first_playlist: true,
+        }
+    }
+}
+
+/// Where the playlist fetchers of a download send segments and record what they see, and which
+/// segments they send
+#[derive(Clone, Debug)]
+pub struct FetcherContext {
+    /// Channel to the download task
+    pub tx: mpsc::UnboundedSender<DownloadJob>,
+    pub attempts: SegmentAttempts,
+    /// Records listed and skipped segments
+    pub coverage: Coverage,
+    /// Numbers new segments in playlist order if given
+    pub numbers: Option<SegmentNumbers>,
+    /// Records I-frames only playlists
+    pub trick_play: TrickPlayStreams,
+    /// Stop once this much media was sent
+    pub max_duration: Option<Duration>,
+    /// Only send segments in these discontinuity windows, unless empty
+    pub discon_windows: Vec<DisconRange>,
+    /// Only send the newest segments of the first playlist within this limit
+    pub backlog_limit: Option<BacklogLimit>,
+}
+
+/// Send new segments of the playlists of a stream to the download task as set by context,
+/// continuing after the segments already sent according to state, which records the sent
+/// segments
+///
+/// Segments whose download failed are sent again while they are listed and attempts are left.
+/// Segments of detected ad breaks are skipped and playlists are checked for spec violations if
+/// state was created to do so.
+#[instrument(skip_all, fields(stream = %stream))]
+pub async fn send_segments(
+    state: &mut FetcherState,
+    context: &FetcherContext,
     notify_stop: Stopper,
-    tx: mpsc::UnboundedSender<(Stream, Segment, Encryption)>,
     stream: Stream,
-    url: Url,
+    mut playlists: PlaylistStream,
 ) -> Result<()> {
-    let mut last_seg = None;
-    let mut cur_init = None;
+    let FetcherContext {
+        tx,
+        attempts,
+        coverage,
+        numbers,
+        trick_play,
+        max_duration,
+        discon_windows,
+        backlog_limit,
+    } = context;
+    // Time the last pause took, until the first playlist after it
+    let mut paused_for = None;
 
     loop {
-        // Fetch playlist
-        let now = time::Instant::now();
-        let mut found_new_segments = false;
-
-        event!(Level::TRACE, "Fetching {}", url.as_str());
-        let resp = client.get(url.clone()).send().await?;
-        let final_url = resp.url().to_string();
-        if !resp.status().is_success() {
-            return Err(LivestreamDLError::NetworkRequest(resp).into());
+        // Don't fetch the playlist while paused
+        if notify_stop.state().await == ControlState::Paused {
+            event!(Level::DEBUG, "Paused");
+            let paused_at = Instant::now();
+            if notify_stop.wait_resume().await == ControlState::Stopped {
+                return Ok(());
+            }
+            let elapsed = paused_at.elapsed();
+            event!(Level::DEBUG, "Resumed after {:.3}s", elapsed.as_secs_f64());
+            paused_for = Some(elapsed);
         }
-        let bytes = resp.bytes().await?;
 
-        let media_playlist = m3u8_rs::parse_media_playlist(&bytes)
-            .map_err(|_| LivestreamDLError::ParseM3u8(final_url))?
-            .1;
+        // Wait for next version of the playlist or until stopped
+        let next = tokio::select! {
+            biased;
+
+            // Not cancel safe, but this is ok because all stoppers are notified when stopped, so
+            // fairness doesn't matter
+            _ = notify_stop.wait() => None,
+
+            p = playlists.next() => Some(p),
+        };
+        let (media_playlist, url) = match next {
+            Some(Some(p)) => p?,
+            Some(None) => {
+                event!(Level::TRACE, "Playlist source ended");
+                return Ok(());
+            }
+            None => return Ok(()),
+        };
+        let mut new_segments = 0;
+        state
+            .sent
+            .duration
+            .set_target_duration(media_playlist.target_duration);
+        if let Some(l) = &mut state.sent.lint {
+            l.check(&media_playlist);
+        }
+        trick_play.observe(&stream, &media_playlist, &url);
+
+        // Skip the older part of the segments listed at first
+        let backlog_end = match backlog_limit {
+            Some(l) if state.first_playlist => {
+                let durations: Vec<_> =
+                    media_playlist.segments.iter().map(|s| s.duration).collect();
+                l.first_kept(&durations)
+            }
+            _ => 0,
+        };
+        state.first_playlist = false;
 
         // Loop through media segments
-        let mut discon_offset = 0;
         let mut encryption = Encryption::None;
-        for (seq, segment) in (media_playlist.media_sequence..).zip(media_playlist.segments.iter())
+        let segments = state
+            .discons
+            .segments(&media_playlist, &url, state.cur_init.clone())?;
+        if let Some(first) = segments.first() {
+            attempts.forget_before(&stream, first);
+        }
+        if let (Some(first), Some(last)) = (segments.first(), segments.last()) {
+            coverage.observe(&stream, first.seq, last.seq);
+        }
+
+        // Report segments that left the playlist while paused
+        if let (Some(paused), Some(first), Some((discon_seq, seq))) =
+            (paused_for.take(), segments.first(), state.last_seg)
+        {
+            if first.discon_seq == discon_seq && first.seq > seq + 1 {
+                event!(
+                    Level::WARN,
+                    "Missed {} segments of stream {} while paused for {:.3}s",
+                    first.seq - seq - 1,
+                    stream,
+                    paused.as_secs_f64()
+                );
+            } else if first.discon_seq > discon_seq {
+                event!(
+                    Level::WARN,
+                    "Stream {} may have missed segments while paused for {:.3}s",
+                    stream,
+                    paused.as_secs_f64()
+                );
+            }
+        }
+        for (i, (segment, media_segment)) in segments
+            .into_iter()
+            .zip(&media_playlist.segments)
+            .enumerate()
         {
-            // Calculate segment discontinuity
-            if segment.discontinuity {
-                discon_offset += 1;
+            // Check encryption
+            if let Some(key) = &media_segment.key {
+                encryption = Encryption::new(key, &url, segment.seq).await?;
+            }
+
+            // Mark backlog segments as sent without sending them
+            if i < backlog_end {
+                let skipped = state.sent.skipped_backlog.get_or_insert(SkippedBacklog {
+                    segments: 0,
+                    duration: 0.0,
+                    first_seq: segment.seq,
+                    last_seq: segment.seq,
+                });
+                skipped.segments += 1;
+                skipped.duration += media_segment.duration as f64;
+                skipped.last_seq = segment.seq;
+                coverage.skip(&stream, segment.seq);
+                state.last_seg = Some((segment.discon_seq, segment.seq));
+                state.cur_init = segment.initialization.clone();
+                if i + 1 == backlog_end {
+                    event!(
+                        Level::INFO,
+                        "Skipping backlog of {} segments ({:.3}s)",
+                        skipped.segments,
+                        skipped.duration
+                    );
+                }
+                continue;
             }
-            let discon_seq = media_playlist.discontinuity_sequence + discon_offset;
 
-            // Skip segment if already downloaded
-            if let Some(s) = last_seg {
-                if s >= (discon_seq, seq) {
+            // Skip segment if already sent, unless its download failed and should be retried
+            if let Some(s) = state.last_seg {
+                if s >= (segment.discon_seq, segment.seq) {
+                    if attempts.retry(&stream, &segment) {
+                        event!(Level::DEBUG, "Retrying segment {}", segment.url().as_str());
+                        let job = DownloadJob::new(
+                            stream.clone(),
+                            segment,
+                            encryption.clone(),
+                            Priority::Backlog,
+                        );
+                        if tx.unbounded_send(job).is_err() {
+                            return Ok(());
+                        }
+                    }
                     continue;
                 }
             }
 
-            // Check encryption
-            if let Some(key) = &segment.key {
-                encryption = Encryption::new(key, &url, seq).await?;
+            // Skip segment outside of the requested windows, stop once past all of them
+            if !discon_windows.is_empty()
+                && !discon_windows
+                    .iter()
+                    .any(|w| w.contains(segment.discon_seq))
+            {
+                let passed = discon_windows
+                    .iter()
+                    .all(|w| w.end.is_some_and(|e| segment.discon_seq > e));
+                if passed {
+                    event!(Level::INFO, "Passed the last discontinuity window");
+                    return Ok(());
+                }
+                coverage.skip(&stream, segment.seq);
+                continue;
+            }
+
+            // Mark segments of ad breaks as sent without sending them
+            if let Some(a) = &mut state.sent.ad_breaks {
+                if a.is_ad(segment.discon_seq, media_segment) {
+                    coverage.skip(&stream, segment.seq);
+                    state.last_seg = Some((segment.discon_seq, segment.seq));
+                    state.cur_init = segment.initialization.clone();
+                    continue;
+                }
+            }
+
+            // Stop once enough media was queued
+            if max_duration.is_some_and(|m| state.sent.duration.checked() >= m.as_secs_f64()) {
+                event!(Level::INFO, "Reached maximum duration");
+                return Ok(());
             }
 
             // Segment is new
-            last_seg = Some((discon_seq, seq));
-            found_new_segments = true;
-
-            // Parse URL
-            let seg_url = make_absolute_url(&url, &segment.uri)?;
-
-            // Make Initialization
-            let init = if let Some(map) = &segment.map {
-                let init =
-                    RemoteData::new(make_absolute_url(&url, &map.uri)?, map.byte_range.clone());
-                cur_init = Some(init.clone());
-                Some(init)
-            } else {
-                cur_init.clone()
-            };
+            state.last_seg = Some((segment.discon_seq, segment.seq));
+            new_segments += 1;
+            state.sent.duration.push(
+                media_segment.duration,
+                media_segment.program_date_time.as_deref(),
+                media_segment.discontinuity,
+            );
+            state.cur_init = segment.initialization.clone();
 
             // Download segment
-            event!(Level::TRACE, "Found new segment {}", seg_url.as_str());
-            if tx
-                .unbounded_send((
-                    stream.clone(),
-                    Segment {
-                        data: RemoteData::new(seg_url, segment.byte_range.clone()),
-                        discon_seq,
-                        seq,
-                        format: MediaFormat::Unknown,
-                        initialization: init,
-                    },
-                    encryption.clone(),
-                ))
-                .is_err()
-            {
+            event!(Level::TRACE, "Found new segment {}", segment.url().as_str());
+            attempts.start(&stream, &segment);
+            if let Some(n) = &numbers {
+                n.assign(&stream, &segment);
+            }
+            let job = DownloadJob::new(
+                stream.clone(),
+                segment,
+                encryption.clone(),
+                Priority::Backlog,
+            );
+            if tx.unbounded_send(job).is_err() {
                 return Ok(());
             }
         }
 
+        event!(
+            target: EVENT_TARGET,
+            Level::DEBUG,
+            stream = %stream,
+            url = %url,
+            new_segments,
+            end_list = media_playlist.end_list,
+            "playlist_refreshed"
+        );
+
         // Return if stream ended
         if media_playlist.end_list {
             event!(Level::TRACE, "Playlist ended");
+            state.sent.end_list = true;
             return Ok(());
         }
 
-        let wait_duration = if found_new_segments {
-            // Wait for target duration if new segments were found
-            Duration::from_secs_f32(media_playlist.target_duration)
-        } else {
-            // Otherwise wait for half target duration
-            Duration::from_secs_f32(media_playlist.target_duration / 2.0)
-        };
+        // Return if stopped
+        if notify_stop.stopped().await {
+            return Ok(());
+        }
+    }
+}
 
-        // Wait until next interval or if stopped
-        tokio::select! {
-            biased;
+/// Fetch the media playlist of a stream once, fails if it is a master playlist
+pub async fn fetch_media_playlist(
+    client: &HttpClient,
+    stream: &Stream,
+    url: &Url,
+    variables: &Variables,
+) -> Result<MediaPlaylist> {
+    let (body, _) = client.fetch_playlist(url).await?;
+    let (body, _) = Variables::substitute(&body, url, variables)?;
+    match m3u8_rs::parse_playlist(&body) {
+        Ok((_, Playlist::MediaPlaylist(p))) => Ok(p),
+        Ok((_, Playlist::MasterPlaylist(_))) => {
+            Err(anyhow::anyhow!("Stream {} is a master playlist", stream))
+        }
+        Err(_) => Err(LivestreamDLError::ParseM3u8(url.to_string()).into()),
+    }
+}
 
-            // Not cancel safe, but this is ok because all stoppers are notified when stopped, so
-            // fairness doesn't matter
-            _ = notify_stop.wait() => {},
+/// Segments of a media playlist fetched from url, init is the initialization section in effect
+/// before its first segment
+pub fn playlist_segments(
+    media_playlist: &MediaPlaylist,
+    url: &Url,
+    mut init: Option<RemoteData>,
+) -> Result<Vec<Segment>> {
+    let mut segments = Vec::with_capacity(media_playlist.segments.len());
+    let mut discon_offset = 0;
+    let mut next_pdt = None;
+    let mut prev_range: Option<(Url, HashableByteRange)> = None;
+    for (seq, segment) in (media_playlist.media_sequence..).zip(media_playlist.segments.iter()) {
+        // Calculate segment discontinuity
+        if segment.discontinuity {
+            discon_offset += 1;
+        }
+        let discon_seq = media_playlist.discontinuity_sequence + discon_offset;
 
-            _ = time::sleep_until(now + wait_duration) => {},
+        // Program date time, extrapolated from earlier segments if not given
+        let pdt = match segment
+            .program_date_time
+            .as_deref()
+            .and_then(parse_program_date_time)
+        {
+            Some(p) => Some(p),
+            None if segment.discontinuity => None,
+            None => next_pdt,
         };
+        next_pdt = pdt.map(|p| p + ::time::Duration::seconds_f32(segment.duration));
 
-        // Return if stopped
-        if notify_stop.stopped().await {
-            return Ok(());
+        // Parse URL
+        let seg_url = make_absolute_url(url, &segment.uri)?;
+
+        // Make Initialization
+        if let Some(map) = &segment.map {
+            init = Some(RemoteData::new(
+                make_absolute_url(url, &map.uri)?,
+                map.byte_range.clone(),
+            ));
         }
+
+        // Byte ranges without offset continue from the previous range of the same resource
+        let byte_range = segment
+            .byte_range
+            .as_ref()
+            .map(|b| match (b.offset, &prev_range) {
+                (Some(o), _) => HashableByteRange::from_offset_len(o, b.length),
+                (None, Some((u, prev))) if u == &seg_url => {
+                    HashableByteRange::following(prev, b.length)
+                }
+                (None, _) => HashableByteRange::from_offset_len(0, b.length),
+            });
+        prev_range = byte_range.clone().map(|b| (seg_url.clone(), b));
+
+        segments.push(Segment {
+            data: RemoteData::new(seg_url, byte_range.map(|b| b.into_inner())),
+            discon_seq,
+            seq,
+            format: MediaFormat::Unknown,
+            initialization: init.clone(),
+            program_date_time: pdt,
+            duration: segment_duration(segment.duration, media_playlist.target_duration),
+        });
     }
+
+    Ok(segments)
 }
@@ -0,0 +1,251 @@
+use std::collections::BTreeMap;
+
+use m3u8_rs::{MediaPlaylist, MediaPlaylistType};
+use tracing::{event, Level};
+
+/// Kinds of spec violations, each reported once per stream
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+enum Check {
+    MissingEndList,
+    TargetDuration,
+    Version,
+    MissingMap,
+    SequenceGap,
+    SequenceBackwards,
+    DisconSequence,
+}
+
+/// Violations of the HLS specification found in the media playlists of a stream that the
+/// download tolerates, checked with --lint-playlist
+///
+/// Findings are deduplicated by kind, keeping the first occurrence and counting the refreshes
+/// they were found in.
+#[derive(Default, Debug)]
+pub(crate) struct PlaylistLint {
+    refreshes: usize,
+    /// Discontinuity sequence of each segment of the previous refresh by media sequence
+    previous: BTreeMap<u64, u64>,
+    /// First finding of each kind and the number of refreshes it was found in
+    findings: BTreeMap<Check, (String, usize)>,
+}
+
+impl PlaylistLint {
+    /// Check a newly fetched version of the playlist, versions must be checked in fetch order
+    pub fn check(&mut self, playlist: &MediaPlaylist) {
+        self.refreshes += 1;
+        let mut found = BTreeMap::new();
+
+        if playlist.playlist_type == Some(MediaPlaylistType::Vod) && !playlist.end_list {
+            found.insert(
+                Check::MissingEndList,
+                "VOD playlist has no EXT-X-ENDLIST".to_string(),
+            );
+        }
+
+        // Rounded EXTINF durations must not exceed the target duration
+        let longest = playlist
+            .segments
+            .iter()
+            .map(|s| s.duration)
+            .fold(0.0, f32::max);
+        if longest.round() > playlist.target_duration {
+            found.insert(
+                Check::TargetDuration,
+                format!(
+                    "EXTINF {} is longer than EXT-X-TARGETDURATION {}",
+                    longest, playlist.target_duration
+                ),
+            );
+        }
+
+        if let Some((required, feature)) = required_version(playlist) {
+            let version = playlist.version.max(1);
+            if version < required {
+                found.insert(
+                    Check::Version,
+                    format!(
+                        "{} requires EXT-X-VERSION {}, playlist has version {}",
+                        feature, required, version
+                    ),
+                );
+            }
+        }
+
+        // fMP4 segments need an initialization section
+        let mut has_map = false;
+        for segment in &playlist.segments {
+            has_map |= segment.map.is_some();
+            let path = segment.uri.split(['?', '#']).next().unwrap_or_default();
+            if !has_map && (path.ends_with(".m4s") || path.ends_with(".mp4")) {
+                found.insert(
+                    Check::MissingMap,
+                    format!("fMP4 segment {} has no EXT-X-MAP", segment.uri),
+                );
+                break;
+            }
+        }
+
+        // Discontinuity sequences of segments by media sequence
+        let mut discon_seq = playlist.discontinuity_sequence;
+        let current: BTreeMap<_, _> = (playlist.media_sequence..)
+            .zip(&playlist.segments)
+            .map(|(seq, s)| {
+                if s.discontinuity {
+                    discon_seq += 1;
+                }
+                (seq, discon_seq)
+            })
+            .collect();
+
+        // Compare with the previous refresh
+        if let (Some((&prev_first, _)), Some((&prev_last, _))) = (
+            self.previous.first_key_value(),
+            self.previous.last_key_value(),
+        ) {
+            let first = playlist.media_sequence;
+            if first < prev_first {
+                found.insert(
+                    Check::SequenceBackwards,
+                    format!(
+                        "EXT-X-MEDIA-SEQUENCE went back from {} to {}",
+                        prev_first, first
+                    ),
+                );
+            } else if first > prev_last + 1 {
+                found.insert(
+                    Check::SequenceGap,
+                    format!(
+                        "Media sequence jumped from {} to {} between refreshes, segments were \
+                         removed before they could be fetched",
+                        prev_last, first
+                    ),
+                );
+            }
+            let changed = current
+                .iter()
+                .find(|(seq, d)| self.previous.get(seq).is_some_and(|p| p != *d));
+            if let Some((seq, d)) = changed {
+                found.insert(
+                    Check::DisconSequence,
+                    format!(
+                        "Segment {} is in discontinuity sequence {}, but was in {} in the \
+                         previous refresh",
+                        seq, d, self.previous[seq]
+                    ),
+                );
+            }
+        }
+        self.previous = current;
+
+        for (check, finding) in found {
+            self.findings.entry(check).or_insert((finding, 0)).1 += 1;
+        }
+    }
+
+    /// First finding of each kind and the number of refreshes it was found in
+    pub fn findings(&self) -> impl Iterator<Item = (&str, usize)> {
+        self.findings.values().map(|(f, n)| (f.as_str(), *n))
+    }
+
+    /// Log the findings about the playlist of stream
+    pub fn report(&self, stream: impl std::fmt::Display) {
+        if self.findings.is_empty() {
+            event!(
+                Level::INFO,
+                "Playlist of stream {} follows the specification in {} refreshes",
+                stream,
+                self.refreshes
+            );
+            return;
+        }
+        for (finding, count) in self.findings() {
+            event!(
+                Level::WARN,
+                "Playlist of stream {}: {} (in {} of {} refreshes)",
+                stream,
+                finding,
+                count,
+                self.refreshes
+            );
+        }
+    }
+}
+
+/// Highest protocol version required by a feature used in playlist, and that feature
+fn required_version(playlist: &MediaPlaylist) -> Option<(usize, &'static str)> {
+    let segments = &playlist.segments;
+    let keys = || segments.iter().filter_map(|s| s.key.as_ref());
+    [
+        (
+            if playlist.i_frames_only { 5 } else { 6 },
+            "EXT-X-MAP",
+            segments.iter().any(|s| s.map.is_some()),
+        ),
+        (
+            5,
+            "KEYFORMAT",
+            keys().any(|k| k.keyformat.is_some() || k.keyformatversions.is_some()),
+        ),
+        (
+            4,
+            "EXT-X-BYTERANGE",
+            segments.iter().any(|s| s.byte_range.is_some()),
+        ),
+        (4, "EXT-X-I-FRAMES-ONLY", playlist.i_frames_only),
+        (
+            3,
+            "Fractional EXTINF duration",
+            segments.iter().any(|s| s.duration.fract() != 0.0),
+        ),
+        (
+            2,
+            "IV attribute of EXT-X-KEY",
+            keys().any(|k| k.iv.is_some()),
+        ),
+    ]
+    .into_iter()
+    .find(|(_, _, used)| *used)
+    .map(|(version, feature, _)| (version, feature))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(playlist: &str) -> MediaPlaylist {
+        m3u8_rs::parse_media_playlist_res(playlist.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn findings_are_collected_across_refreshes() {
+        let mut lint = PlaylistLint::default();
+        lint.check(&parse(
+            "#EXTM3U
+#EXT-X-TARGETDURATION:4
+#EXT-X-MEDIA-SEQUENCE:0
+#EXTINF:6.5,
+0.m4s
+#EXTINF:4.0,
+1.m4s
+",
+        ));
+        lint.check(&parse(
+            "#EXTM3U
+#EXT-X-VERSION:3
+#EXT-X-TARGETDURATION:6
+#EXT-X-MEDIA-SEQUENCE:5
+#EXTINF:6.0,
+5.ts
+",
+        ));
+        assert_eq!(lint.findings().count(), 4);
+        assert!(lint
+            .findings()
+            .any(|(f, _)| f.contains("EXT-X-TARGETDURATION 4")));
+        assert!(lint
+            .findings()
+            .any(|(f, _)| f.contains("requires EXT-X-VERSION 3")));
+        assert!(lint.findings().any(|(f, _)| f.contains("EXT-X-MAP")));
+        assert!(lint.findings().any(|(f, _)| f.contains("from 1 to 5")));
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::{HashMap, HashSet};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use futures::{stream, StreamExt, TryStreamExt};
+use reqwest::Url;
+use tokio::fs;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tracing::{event, Level};
+
+use super::http_client::HttpClient;
+use super::playlist_fetcher::{fetch_media_playlist, playlist_segments};
+use super::remote_data::RemoteData;
+use super::sink::SegmentSink;
+use super::{MediaFormat, Segment, Stream, Variables};
+
+/// Stream and discontinuity sequence of a preallocated file
+type FileKey = (Stream, u64);
+
+/// Position of a segment in its file
+#[derive(Clone, Copy, Debug)]
+struct Slot {
+    offset: u64,
+    init_len: u64,
+    data_len: u64,
+}
+
+/// Planned layout of one file for each stream and discontinuity, where every segment is prefixed
+/// with its initialization section like saved segment files are
+#[derive(Default, Debug)]
+pub struct Layout {
+    file_sizes: HashMap<FileKey, u64>,
+    slots: HashMap<(Stream, u64, u64), Slot>,
+}
+
+impl Layout {
+    /// Plan the layout of streams from their media playlists and the sizes of their segments,
+    /// fails unless all playlists are finished, unencrypted and of known size
+    pub async fn plan<'a>(
+        client: &HttpClient,
+        streams: impl IntoIterator<Item = (&'a Stream, &'a Url)>,
+        variables: &Variables,
+        concurrency: usize,
+    ) -> Result<Self> {
+        let mut layout = Self::default();
+        for (stream, url) in streams {
+            let segments = vod_segments(client, stream, url, variables).await?;
+
+            // Get sizes of segments and initialization sections
+            let data: HashSet<_> = segments
+                .iter()
+                .flat_map(|s| std::iter::once(&s.data).chain(&s.initialization))
+                .collect();
+            let sizes: HashMap<_, _> = stream::iter(data)
+                .map(|d| async move { Ok::<_, anyhow::Error>((d, d.size(client).await?)) })
+                .buffer_unordered(concurrency)
+                .try_collect()
+                .await?;
+
+            for segment in &segments {
+                let file_size = layout
+                    .file_sizes
+                    .entry((stream.clone(), segment.discon_seq))
+                    .or_default();
+                let slot = Slot {
+                    offset: *file_size,
+                    init_len: segment.initialization.as_ref().map_or(0, |i| sizes[i]),
+                    data_len: sizes[&segment.data],
+                };
+                *file_size += slot.init_len + slot.data_len;
+                layout
+                    .slots
+                    .insert((stream.clone(), segment.discon_seq, segment.seq), slot);
+            }
+        }
+
+        Ok(layout)
+    }
+
+    /// Total size of all files
+    pub fn total_size(&self) -> u64 {
+        self.file_sizes.values().sum()
+    }
+}
+
+/// Segments of a stream that must be a finished, unencrypted media playlist
+async fn vod_segments(
+    client: &HttpClient,
+    stream: &Stream,
+    url: &Url,
+    variables: &Variables,
+) -> Result<Vec<Segment>> {
+    let playlist = fetch_media_playlist(client, stream, url, variables).await?;
+    if !playlist.end_list {
+        return Err(anyhow::anyhow!("Stream {} is not a finished VOD", stream));
+    }
+    let encrypted = playlist
+        .segments
+        .iter()
+        .filter_map(|s| s.key.as_ref())
+        .any(|k| k.method != "NONE");
+    if encrypted {
+        return Err(anyhow::anyhow!("Stream {} is encrypted", stream));
+    }
+
+    playlist_segments(&playlist, url, None)
+}
+
+/// Open preallocated file and the format of its first written segment
+#[derive(Debug)]
+struct PreallocatedFile {
+    path: PathBuf,
+    file: fs::File,
+    format: Option<MediaFormat>,
+}
+
+/// Sink that writes segments at their planned offsets into preallocated files, so that segments
+/// can be written in any order without intermediate files
+#[derive(Debug)]
+pub struct PreallocatedSink {
+    slots: HashMap<(Stream, u64, u64), Slot>,
+    files: HashMap<FileKey, PreallocatedFile>,
+    inits: HashMap<RemoteData, Vec<u8>>,
+    written: HashSet<(Stream, u64, u64)>,
+}
+
+impl PreallocatedSink {
+    /// Create the files of a layout in output_dir at their full size
+    pub async fn create(layout: Layout, output_dir: &Path) -> Result<Self> {
+        let mut files = HashMap::new();
+        for ((stream, discon_seq), size) in layout.file_sizes {
+            let path = output_dir.join(format!("{}_{:010}.part", stream.file_id(), discon_seq));
+            let file = fs::File::create(&path)
+                .await
+                .with_context(|| format!("error creating {:?}", path))?;
+            file.set_len(size).await?;
+            files.insert(
+                (stream, discon_seq),
+                PreallocatedFile {
+                    path,
+                    file,
+                    format: None,
+                },
+            );
+        }
+
+        Ok(Self {
+            slots: layout.slots,
+            files,
+            inits: HashMap::new(),
+            written: HashSet::new(),
+        })
+    }
+
+    /// Flush files and name them after the format of their segments, returns the stream, path
+    /// and format of the files of each discontinuity
+    pub async fn finish(self) -> Result<HashMap<u64, Vec<(Stream, PathBuf, MediaFormat)>>> {
+        let missing = self.slots.len() - self.written.len();
+        if missing > 0 {
+            event!(
+                Level::WARN,
+                "{} segments failed to download, their parts of the output are empty",
+                missing
+            );
+        }
+
+        let mut discons: HashMap<_, Vec<_>> = HashMap::new();
+        for ((stream, discon_seq), f) in self.files {
+            f.file.sync_all().await?;
+            let format = f.format.unwrap_or(MediaFormat::Unknown);
+            let path = f.path.with_extension(format.extension());
+            fs::rename(&f.path, &path).await?;
+            discons
+                .entry(discon_seq)
+                .or_default()
+                .push((stream, path, format));
+        }
+
+        Ok(discons)
+    }
+}
+
+#[async_trait]
+impl SegmentSink for PreallocatedSink {
+    async fn on_init(&mut self, _: &Stream, init: &RemoteData, data: &[u8]) -> Result<()> {
+        self.inits.insert(init.clone(), data.to_vec());
+        Ok(())
+    }
+
+    async fn on_segment(&mut self, stream: &Stream, segment: &Segment, data: &[u8]) -> Result<()> {
+        let key = (stream.clone(), segment.discon_seq, segment.seq);
+        let slot = *self.slots.get(&key).ok_or_else(|| {
+            anyhow::anyhow!("Segment {} of {} was not planned", segment.id(), stream)
+        })?;
+        let init: &[u8] = segment
+            .initialization
+            .as_ref()
+            .and_then(|i| self.inits.get(i))
+            .map_or(&[], Vec::as_slice);
+        if init.len() as u64 != slot.init_len || data.len() as u64 != slot.data_len {
+            return Err(anyhow::anyhow!(
+                "Size of segment {} of {} changed from {} to {} bytes",
+                segment.id(),
+                stream,
+                slot.init_len + slot.data_len,
+                init.len() + data.len()
+            ));
+        }
+
+        let f = self
+            .files
+            .get_mut(&(stream.clone(), segment.discon_seq))
+            .ok_or_else(|| anyhow::anyhow!("No preallocated file for {}", stream))?;
+        event!(
+            Level::TRACE,
+            "writing {} to {:?} at {}",
+            segment.id(),
+            &f.path,
+            slot.offset
+        );
+        f.file.seek(SeekFrom::Start(slot.offset)).await?;
+        f.file.write_all(init).await?;
+        f.file.write_all(data).await?;
+        f.format.get_or_insert_with(|| segment.format.clone());
+        self.written.insert(key);
+
+        Ok(())
+    }
+}
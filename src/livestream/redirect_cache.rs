@@ -0,0 +1,200 @@
+use std::sync::{Arc, Mutex};
+
+use lru::LruCache;
+use reqwest::Url;
+
+/// Number of remembered redirect targets
+const CAPACITY: usize = 64;
+
+/// URL of a directory and query of the URLs in it
+type DirKey = (Url, Option<String>);
+
+/// Redirect targets of segment URLs, used to request later segments from the same directory
+/// directly instead of following a redirect for each of them
+///
+/// A redirect is only cached if it keeps the file name of the segment, e.g. from
+/// `https://origin/live/1.ts` to `https://cdn/abc/1.ts?token=t1`, and consecutive segments of
+/// the directory were redirected to the same directory and query, so that per-segment signed
+/// URLs are never reused. Later segments of the same directory and query are then rewritten to
+/// the cached directory and query, e.g. `2.ts` to `https://cdn/abc/2.ts?token=t1`. Once a
+/// rewritten request fails, the directory is no longer rewritten.
+#[derive(Clone, Debug)]
+pub(crate) struct RedirectCache(Arc<Mutex<Targets>>);
+
+#[derive(Debug)]
+struct Targets {
+    /// Redirect targets used to rewrite URLs
+    cached: LruCache<DirKey, Url>,
+    /// Last redirect target of directories not cached yet
+    candidates: LruCache<DirKey, Url>,
+    /// Directories whose rewritten requests failed
+    failed: LruCache<DirKey, ()>,
+}
+
+impl Default for RedirectCache {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new(Targets {
+            cached: LruCache::new(CAPACITY),
+            candidates: LruCache::new(CAPACITY),
+            failed: LruCache::new(CAPACITY),
+        })))
+    }
+}
+
+impl RedirectCache {
+    /// URL to request instead of url if the redirect target of its directory is cached
+    pub fn rewrite(&self, url: &Url) -> Option<Url> {
+        let (dir, name) = split(url)?;
+        let mut target = self.0.lock().unwrap().cached.get(&dir)?.clone();
+        let path = target.path();
+        let path = format!("{}{}", &path[..=path.rfind('/')?], name);
+        target.set_path(&path);
+        Some(target)
+    }
+
+    /// Remember the redirect target of the directory of url if final_url keeps its file name and
+    /// the previous redirect of the directory went to the same directory and query
+    pub fn record(&self, url: &Url, final_url: &Url) {
+        if url == final_url {
+            return;
+        }
+        let ((dir, name), (target_dir, final_name)) = match (split(url), split(final_url)) {
+            (Some(s), Some(t)) => (s, t),
+            _ => return,
+        };
+        let mut targets = self.0.lock().unwrap();
+        if name != final_name || targets.failed.contains(&dir) {
+            return;
+        }
+        let agrees = match targets.cached.get(&dir) {
+            Some(_) => true,
+            None => targets
+                .candidates
+                .pop(&dir)
+                .and_then(|c| split(&c))
+                .is_some_and(|(c, _)| c == target_dir),
+        };
+        if agrees {
+            targets.cached.put(dir, final_url.clone());
+        } else {
+            targets.candidates.put(dir, final_url.clone());
+        }
+    }
+
+    /// Forget the redirect target of the directory of url, e.g. after its token expired
+    pub fn invalidate(&self, url: &Url) {
+        if let Some((dir, _)) = split(url) {
+            let mut targets = self.0.lock().unwrap();
+            targets.cached.pop(&dir);
+            targets.candidates.pop(&dir);
+        }
+    }
+
+    /// Stop rewriting the directory of url after a rewritten request failed
+    pub fn failed(&self, url: &Url) {
+        self.invalidate(url);
+        if let Some((dir, _)) = split(url) {
+            self.0.lock().unwrap().failed.put(dir, ());
+        }
+    }
+}
+
+/// Directory and query of url, and its file name
+fn split(url: &Url) -> Option<(DirKey, String)> {
+    let name = url.path_segments()?.next_back()?.to_owned();
+    let mut dir = url.clone();
+    dir.path_segments_mut().ok()?.pop();
+    dir.set_query(None);
+    dir.set_fragment(None);
+    Some(((dir, url.query().map(str::to_owned)), name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Origin redirecting to a CDN that accepts the token of its redirects
+    struct Origin {
+        requests: usize,
+        /// Token of the redirect of a request
+        token: fn(usize) -> usize,
+    }
+
+    impl Origin {
+        /// Final URL of a request, None if it failed
+        fn get(&mut self, url: &Url) -> Option<Url> {
+            self.requests += 1;
+            let name = url.path_segments()?.next_back()?.to_owned();
+            let token = (self.token)(self.requests);
+            match url.host_str()? {
+                "origin" => Some(
+                    format!("https://cdn/abc/{}?token={}", name, token)
+                        .parse()
+                        .unwrap(),
+                ),
+                "cdn" => (url.query()? == format!("token={}", token)).then(|| url.clone()),
+                _ => None,
+            }
+        }
+    }
+
+    /// Fetch segments like HttpSegmentFetcher, returns the number of requests sent
+    fn fetch_segments(cache: &RedirectCache, origin: &mut Origin, count: u64) -> usize {
+        for seq in 0..count {
+            let url: Url = format!("https://origin/live/{}.ts", seq).parse().unwrap();
+            let final_url = match cache.rewrite(&url) {
+                Some(rewritten) => match origin.get(&rewritten) {
+                    Some(u) => u,
+                    None => {
+                        cache.failed(&url);
+                        origin.get(&url).unwrap()
+                    }
+                },
+                None => origin.get(&url).unwrap(),
+            };
+            assert_eq!(final_url.path(), format!("/abc/{}.ts", seq));
+            cache.record(&url, &final_url);
+        }
+        origin.requests
+    }
+
+    #[test]
+    fn stable_redirect_is_cached_once_confirmed() {
+        let mut origin = Origin {
+            requests: 0,
+            token: |_| 0,
+        };
+        let cache = RedirectCache::default();
+        assert_eq!(fetch_segments(&cache, &mut origin, 20), 20);
+        let url = "https://origin/live/20.ts".parse().unwrap();
+        assert_eq!(
+            cache.rewrite(&url).unwrap().as_str(),
+            "https://cdn/abc/20.ts?token=0"
+        );
+    }
+
+    #[test]
+    fn per_segment_signed_redirects_are_not_cached() {
+        let mut origin = Origin {
+            requests: 0,
+            token: |request| request,
+        };
+        let cache = RedirectCache::default();
+        assert_eq!(fetch_segments(&cache, &mut origin, 8), 8);
+    }
+
+    #[test]
+    fn failed_directory_is_no_longer_rewritten() {
+        // Token rotates after 10 requests
+        let mut origin = Origin {
+            requests: 0,
+            token: |request| request / 10,
+        };
+        let cache = RedirectCache::default();
+
+        // One failed rewritten request, then every redirect is followed
+        assert_eq!(fetch_segments(&cache, &mut origin, 20), 21);
+        let url = "https://origin/live/20.ts".parse().unwrap();
+        assert!(cache.rewrite(&url).is_none());
+    }
+}
@@ -1,12 +1,21 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
 use anyhow::Result;
 use m3u8_rs::ByteRange;
-use reqwest::header::{self, HeaderMap};
-use reqwest::Url;
+use reqwest::header::{self, HeaderMap, HeaderName};
+use reqwest::{Response, StatusCode, Url};
+use tracing::{event, Level};
 
+use super::byte_budget::{ByteBudget, Reservation};
 use super::http_client::HttpClient;
-use super::HashableByteRange;
+use super::traffic::{BodyCounter, RequestCategory};
+use super::{HashableByteRange, ResponseTiming};
 use crate::error::LivestreamDLError;
 
+/// Values of captured response headers by name
+pub type ResponseHeaders = BTreeMap<String, String>;
+
 #[derive(Clone, PartialEq, Eq, Hash, Debug)]
 pub struct RemoteData(Url, Option<HashableByteRange>);
 
@@ -19,33 +28,318 @@ impl RemoteData {
         &self.0
     }
 
-    pub fn byte_range_string(&self) -> Option<String> {
-        let start = self.1.as_ref()?.offset.unwrap_or(0);
-        let end = start + self.1.as_ref()?.length.saturating_sub(1);
+    /// Same byte range of another URL
+    pub fn with_url(&self, url: Url) -> Self {
+        Self(url, self.1.clone())
+    }
 
-        Some(format!("bytes={}-{}", start, end))
+    pub fn byte_range(&self) -> Option<&ByteRange> {
+        self.1.as_deref()
+    }
+
+    /// Value of the HTTP Range header requesting this data, None for whole resources
+    ///
+    /// ```
+    /// use livestream_dl::livestream::RemoteData;
+    /// use m3u8_rs::ByteRange;
+    ///
+    /// let data = |length, offset| {
+    ///     RemoteData::new(
+    ///         "http://localhost/seg.ts".parse().unwrap(),
+    ///         Some(ByteRange { length, offset }),
+    ///     )
+    /// };
+    /// let url = "http://localhost/seg.ts".parse().unwrap();
+    /// assert_eq!(RemoteData::new(url, None).byte_range_string(), None);
+    /// assert_eq!(data(1000, Some(0)).byte_range_string().unwrap(), "bytes=0-999");
+    /// assert_eq!(data(1000, Some(5000)).byte_range_string().unwrap(), "bytes=5000-5999");
+    /// assert_eq!(data(1, Some(5000)).byte_range_string().unwrap(), "bytes=5000-5000");
+    /// // Offsets of ranges continuing the previous one are resolved when parsing playlists, a
+    /// // missing offset is the start of the resource
+    /// assert_eq!(data(1000, None).byte_range_string().unwrap(), "bytes=0-999");
+    /// // Empty ranges can't be requested, they request the first byte instead
+    /// assert_eq!(data(0, Some(5000)).byte_range_string().unwrap(), "bytes=5000-5000");
+    /// ```
+    pub fn byte_range_string(&self) -> Option<String> {
+        self.1.as_ref().map(HashableByteRange::to_http_range)
     }
 
     /// Fetch this segment and return (bytes, final url)
     pub async fn fetch(&self, client: &HttpClient) -> Result<(Vec<u8>, Url)> {
-        // Add byte range headers if needed
+        let (bytes, final_url, _, _, _) = self
+            .fetch_within(client, &ByteBudget::unlimited(), &[], None, None)
+            .await?;
+        Ok((bytes, final_url))
+    }
+
+    /// Size of this data, from the byte range if available, otherwise from the response to a
+    /// request checking it, see [`RemoteData::check`]
+    pub async fn size(&self, client: &HttpClient) -> Result<u64> {
+        if let Some(b) = self.byte_range() {
+            return Ok(b.length);
+        }
+
+        self.check(client)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Size of {} is unknown", self.url()))
+    }
+
+    /// Check that this data is available without downloading it, returns its size if known
+    ///
+    /// A HEAD request with the headers of a download is sent. If the server doesn't answer it
+    /// successfully, e.g. because it doesn't support HEAD, only the first byte is requested
+    /// instead, and the size is taken from the Content-Range header.
+    pub async fn check(&self, client: &HttpClient) -> Result<Option<u64>> {
+        let known_size = self.byte_range().map(|b| b.length);
+        if let Some(bytes) = client.read_local(self.url()).await {
+            return Ok(Some(self.local_range(bytes?)?.len() as u64));
+        }
+
+        let req = client
+            .head_media(self.url().clone())
+            .headers(self.request_headers()?);
+        match req.send().await {
+            Ok(resp) if resp.status().is_success() => {
+                // The Content-Length of HEAD responses isn't their body length
+                let length = resp
+                    .headers()
+                    .get(header::CONTENT_LENGTH)
+                    .and_then(|l| l.to_str().ok()?.parse().ok());
+                return Ok(known_size.or(length));
+            }
+            Ok(resp) => event!(
+                Level::DEBUG,
+                "HEAD request for {} returned status code {}, requesting its first byte",
+                self.url(),
+                resp.status().as_u16()
+            ),
+            Err(e) => event!(
+                Level::DEBUG,
+                "HEAD request for {} failed, requesting its first byte: {:#}",
+                self.url(),
+                e
+            ),
+        }
+
+        let start = self.1.as_ref().map_or(0, |b| b.start());
+        let resp = client
+            .get_media(self.url().clone())
+            .header(header::RANGE, format!("bytes={0}-{0}", start))
+            .send()
+            .await?;
+        if !resp.status().is_success() {
+            return Err(LivestreamDLError::NetworkRequest(resp).into());
+        }
+
+        // Servers ignoring the Range header return the whole resource, which is dropped unread
+        let size = match resp.status() {
+            StatusCode::PARTIAL_CONTENT => resp
+                .headers()
+                .get(header::CONTENT_RANGE)
+                .and_then(|r| r.to_str().ok()?.rsplit('/').next()?.parse().ok()),
+            _ => resp.content_length(),
+        };
+        Ok(known_size.or(size))
+    }
+
+    /// Byte range of the content of a local URL, or all of it
+    fn local_range(&self, mut bytes: Vec<u8>) -> Result<Vec<u8>> {
+        let range = match &self.1 {
+            Some(r) => r,
+            None => return Ok(bytes),
+        };
+        let (start, end) = (range.start() as usize, range.end() as usize);
+        if bytes.len() < end {
+            return Err(anyhow::anyhow!(
+                "Byte range {} of {} is beyond its {} bytes",
+                range.to_http_range(),
+                self.url(),
+                bytes.len()
+            ));
+        }
+        bytes.truncate(end);
+        bytes.drain(..start);
+        Ok(bytes)
+    }
+
+    /// Headers of requests for this data, a Range header if it is a byte range
+    fn request_headers(&self) -> Result<HeaderMap> {
         let mut header_map = HeaderMap::new();
         if let Some(ref range) = self.byte_range_string() {
             header_map.insert(header::RANGE, header::HeaderValue::from_str(range)?);
         }
+        Ok(header_map)
+    }
+
+    /// Fetch this segment after reserving its size in budget and return (bytes, final url,
+    /// reservation, values of the response headers named in capture, timing of the response)
+    ///
+    /// The size is taken from the byte range if available, otherwise from the Content-Length
+    /// header. Data of unknown size is not counted. If timeout is given, it replaces the timeout
+    /// of the client. If max_size is given, reading a larger body is aborted with an error.
+    pub async fn fetch_within(
+        &self,
+        client: &HttpClient,
+        budget: &ByteBudget,
+        capture: &[HeaderName],
+        timeout: Option<Duration>,
+        max_size: Option<u64>,
+    ) -> Result<(Vec<u8>, Url, Reservation, ResponseHeaders, ResponseTiming)> {
+        // Reserve known size before connecting
+        let reservation = match &self.1 {
+            Some(b) => Some(budget.reserve(b.length).await?),
+            None => None,
+        };
+
+        // Read local data without a request
+        if let Some(bytes) = client.read_local(self.url()).await {
+            let start = Instant::now();
+            let bytes = self.local_range(bytes?)?;
+            self.check_size(bytes.len() as u64, max_size)?;
+            let reservation = match reservation {
+                Some(r) => r,
+                None => budget.reserve(bytes.len() as u64).await?,
+            };
+            let timing = ResponseTiming {
+                ttfb: start.elapsed(),
+                total: start.elapsed(),
+                decrypt: None,
+            };
+            return Ok((
+                bytes,
+                self.url().clone(),
+                reservation,
+                ResponseHeaders::new(),
+                timing,
+            ));
+        }
 
         // Fetch data
-        let resp = client
-            .get(self.url().clone())
-            .headers(header_map)
-            .send()
-            .await?;
+        let mut req = client
+            .get_media(self.url().clone())
+            .headers(self.request_headers()?);
+        if let Some(t) = timeout {
+            req = req.timeout(t);
+        }
+        let start = Instant::now();
+        let resp = req.send().await?;
+        let ttfb = start.elapsed();
         if !resp.status().is_success() {
             return Err(LivestreamDLError::NetworkRequest(resp).into());
         }
         let final_url = resp.url().clone();
-        let bytes = resp.bytes().await?.into_iter().collect();
+        let status = resp.status();
+        let headers = capture
+            .iter()
+            .filter_map(|name| {
+                let value = resp.headers().get(name)?;
+                Some((
+                    name.to_string(),
+                    String::from_utf8_lossy(value.as_bytes()).into_owned(),
+                ))
+            })
+            .collect();
 
-        Ok((bytes, final_url))
+        // Servers ignoring the Range header send the whole resource, of which only the bytes up to
+        // the end of the range are read
+        let read_limit = match &self.1 {
+            Some(range) if status == StatusCode::OK => Some(range.end()),
+            _ => None,
+        };
+        if let Some(length) = resp.content_length() {
+            self.check_size(read_limit.map_or(length, |l| length.min(l)), max_size)?;
+        }
+
+        // Otherwise reserve size from headers before reading body. The reservation of an ignored
+        // range is replaced rather than extended, so that no task waits while holding a part of
+        // the budget
+        let reservation = match (reservation, read_limit) {
+            (Some(r), Some(limit)) => {
+                drop(r);
+                budget.reserve(limit).await?
+            }
+            (Some(r), None) => r,
+            (None, _) => budget.reserve(resp.content_length().unwrap_or(0)).await?,
+        };
+
+        let mut counter = client.traffic().body(RequestCategory::Segment);
+        let bytes = self
+            .read_body(resp, max_size, read_limit, &mut counter)
+            .await?;
+        let timing = ResponseTiming {
+            ttfb,
+            total: start.elapsed(),
+            decrypt: None,
+        };
+
+        // Servers ignoring the Range header return the whole resource
+        let bytes = match &self.1 {
+            Some(range) if status == StatusCode::OK => {
+                let (start, end) = (range.start() as usize, range.end() as usize);
+                if bytes.len() < end {
+                    return Err(anyhow::anyhow!(
+                        "Server ignored byte range {} of {} and returned only {} bytes",
+                        range.to_http_range(),
+                        final_url,
+                        bytes.len()
+                    ));
+                }
+                event!(
+                    Level::DEBUG,
+                    "Server ignored byte range {} of {}, slicing {} bytes from response",
+                    range.to_http_range(),
+                    final_url,
+                    bytes.len()
+                );
+                client.traffic().wasted(bytes.len() as u64 - range.length);
+                bytes[start..end].to_vec()
+            }
+            Some(range) if bytes.len() as u64 != range.length => {
+                return Err(anyhow::anyhow!(
+                    "Expected {} bytes for byte range {} of {}, got {}",
+                    range.length,
+                    range.to_http_range(),
+                    final_url,
+                    bytes.len()
+                ));
+            }
+            _ => bytes,
+        };
+        counter.finish();
+
+        Ok((bytes, final_url, reservation, headers, timing))
+    }
+
+    /// Body of resp, read in chunks counted by counter to stop once it is larger than max_size,
+    /// or after its first limit bytes if given
+    async fn read_body(
+        &self,
+        mut resp: Response,
+        max_size: Option<u64>,
+        limit: Option<u64>,
+        counter: &mut BodyCounter,
+    ) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        while let Some(chunk) = resp.chunk().await? {
+            counter.add(chunk.len());
+            let left = limit.map_or(u64::MAX, |l| l - bytes.len() as u64);
+            let chunk = &chunk[..chunk.len().min(left.try_into().unwrap_or(usize::MAX))];
+            self.check_size((bytes.len() + chunk.len()) as u64, max_size)?;
+            bytes.extend_from_slice(chunk);
+            if limit == Some(bytes.len() as u64) {
+                break;
+            }
+        }
+        Ok(bytes)
+    }
+
+    /// Fail if size is larger than max_size
+    fn check_size(&self, size: u64, max_size: Option<u64>) -> Result<()> {
+        match max_size {
+            Some(max) if size > max => {
+                Err(LivestreamDLError::SegmentTooLarge(self.url().to_string(), max).into())
+            }
+            _ => Ok(()),
+        }
     }
 }
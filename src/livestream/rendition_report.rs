@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use reqwest::Url;
+
+use super::utils::make_absolute_url;
+use super::variables::parse_attributes;
+
+/// Tag of low-latency playlists reporting the state of another rendition
+const RENDITION_REPORT_TAG: &str = "#EXT-X-RENDITION-REPORT:";
+
+/// Last media sequence number and part of another rendition, reported by an
+/// EXT-X-RENDITION-REPORT tag of a media playlist
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RenditionReport {
+    /// Playlist of the rendition
+    pub url: Url,
+    pub last_msn: Option<u64>,
+    pub last_part: Option<u64>,
+}
+
+impl RenditionReport {
+    /// Rendition reports of the media playlist at url, skipping invalid ones
+    ///
+    /// m3u8-rs drops tags after the last segment, where reports are, so they are parsed from the
+    /// playlist text.
+    ///
+    /// ```
+    /// use livestream_dl::livestream::RenditionReport;
+    ///
+    /// let url = "https://example.com/live/720p.m3u8".parse().unwrap();
+    /// let playlist = b"#EXTM3U\n#EXT-X-TARGETDURATION:4\n#EXT-X-MEDIA-SEQUENCE:100\n\
+    ///     #EXTINF:4.0,\n100.ts\n\
+    ///     #EXT-X-RENDITION-REPORT:URI=\"1080p.m3u8\",LAST-MSN=100,LAST-PART=3\n\
+    ///     #EXT-X-RENDITION-REPORT:URI=\"../audio/en.m3u8?token=a\",LAST-MSN=99\n\
+    ///     #EXT-X-RENDITION-REPORT:LAST-MSN=99\n";
+    /// assert_eq!(
+    ///     RenditionReport::parse_all(playlist, &url),
+    ///     [
+    ///         RenditionReport {
+    ///             url: "https://example.com/live/1080p.m3u8".parse().unwrap(),
+    ///             last_msn: Some(100),
+    ///             last_part: Some(3),
+    ///         },
+    ///         RenditionReport {
+    ///             url: "https://example.com/audio/en.m3u8?token=a".parse().unwrap(),
+    ///             last_msn: Some(99),
+    ///             last_part: None,
+    ///         },
+    ///     ]
+    /// );
+    /// ```
+    pub fn parse_all(playlist: &[u8], url: &Url) -> Vec<Self> {
+        String::from_utf8_lossy(playlist)
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix(RENDITION_REPORT_TAG))
+            .filter_map(|attributes| {
+                let parsed = parse_attributes(attributes);
+                let get = |key| parsed.iter().find(|(k, _)| *k == key).map(|(_, v)| *v);
+                Some(Self {
+                    url: make_absolute_url(url, get("URI")?).ok()?,
+                    last_msn: get("LAST-MSN").and_then(|n| n.parse().ok()),
+                    last_part: get("LAST-PART").and_then(|n| n.parse().ok()),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Highest last media sequence number reported for each rendition, shared by the playlist
+/// fetchers of all streams to notice renditions whose playlist lags behind
+#[derive(Clone, Default, Debug)]
+pub struct RenditionReports(Arc<Mutex<HashMap<Url, u64>>>);
+
+impl RenditionReports {
+    /// Record reports of a playlist
+    pub fn record(&self, reports: &[RenditionReport]) {
+        let mut reported = self.0.lock().unwrap();
+        for r in reports {
+            if let Some(msn) = r.last_msn {
+                let last = reported.entry(key(&r.url)).or_insert(msn);
+                *last = msn.max(*last);
+            }
+        }
+    }
+
+    /// Media sequence number the last segment of the rendition at url was reported at, if it is
+    /// after last_msn
+    pub fn ahead_of(&self, url: &Url, last_msn: u64) -> Option<u64> {
+        let reported = *self.0.lock().unwrap().get(&key(url))?;
+        (reported > last_msn).then_some(reported)
+    }
+}
+
+/// Rendition reports are matched by URL without query, which may hold changing tokens or
+/// low-latency directives
+fn key(url: &Url) -> Url {
+    let mut url = url.clone();
+    url.set_query(None);
+    url.set_fragment(None);
+    url
+}
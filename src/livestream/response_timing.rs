@@ -0,0 +1,102 @@
+use std::time::Duration;
+
+use tracing::{event, Level};
+
+/// Time to the response headers (TTFB) and to the end of the body of a segment request, and
+/// time spent decrypting the body if it was encrypted
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ResponseTiming {
+    pub ttfb: Duration,
+    pub total: Duration,
+    pub decrypt: Option<Duration>,
+}
+
+/// Response timings of downloaded segments, summarized as percentiles
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use livestream_dl::livestream::{ResponseTiming, TimingStats};
+///
+/// let mut stats = TimingStats::default();
+/// for ms in 1..=100 {
+///     stats.push(ResponseTiming {
+///         ttfb: Duration::from_millis(ms),
+///         total: Duration::from_millis(ms * 10),
+///         decrypt: (ms % 2 == 0).then(|| Duration::from_micros(ms)),
+///     });
+/// }
+/// assert_eq!(stats.ttfb_percentile(50.0), Some(Duration::from_millis(50)));
+/// assert_eq!(stats.ttfb_percentile(95.0), Some(Duration::from_millis(95)));
+/// assert_eq!(stats.total_percentile(95.0), Some(Duration::from_millis(950)));
+/// assert_eq!(stats.decrypt_percentile(50.0), Some(Duration::from_micros(50)));
+/// assert_eq!(TimingStats::default().ttfb_percentile(50.0), None);
+/// ```
+#[derive(Default, Debug)]
+pub struct TimingStats {
+    ttfb: Vec<Duration>,
+    total: Vec<Duration>,
+    decrypt: Vec<Duration>,
+}
+
+impl TimingStats {
+    pub fn push(&mut self, timing: ResponseTiming) {
+        self.ttfb.push(timing.ttfb);
+        self.total.push(timing.total);
+        self.decrypt.extend(timing.decrypt);
+    }
+
+    /// Time to first byte below which p percent of responses arrived
+    pub fn ttfb_percentile(&self, p: f64) -> Option<Duration> {
+        percentile(&self.ttfb, p)
+    }
+
+    /// Total download time below which p percent of responses finished
+    pub fn total_percentile(&self, p: f64) -> Option<Duration> {
+        percentile(&self.total, p)
+    }
+
+    /// Decryption time below which p percent of encrypted segments were decrypted
+    pub fn decrypt_percentile(&self, p: f64) -> Option<Duration> {
+        percentile(&self.decrypt, p)
+    }
+
+    /// Log the median and 95th percentile of the timings
+    pub fn log(&self) {
+        if let (Some(ttfb_50), Some(ttfb_95), Some(total_50), Some(total_95)) = (
+            self.ttfb_percentile(50.0),
+            self.ttfb_percentile(95.0),
+            self.total_percentile(50.0),
+            self.total_percentile(95.0),
+        ) {
+            event!(
+                Level::INFO,
+                "Segment responses of {} downloads: time to first byte p50 {:.3}s, p95 {:.3}s, total time p50 {:.3}s, p95 {:.3}s",
+                self.ttfb.len(),
+                ttfb_50.as_secs_f64(),
+                ttfb_95.as_secs_f64(),
+                total_50.as_secs_f64(),
+                total_95.as_secs_f64()
+            );
+        }
+        if let (Some(decrypt_50), Some(decrypt_95)) =
+            (self.decrypt_percentile(50.0), self.decrypt_percentile(95.0))
+        {
+            event!(
+                Level::INFO,
+                "Decryption of {} segments: p50 {:.3}s, p95 {:.3}s",
+                self.decrypt.len(),
+                decrypt_50.as_secs_f64(),
+                decrypt_95.as_secs_f64()
+            );
+        }
+    }
+}
+
+/// Nearest-rank percentile p of values
+fn percentile(values: &[Duration], p: f64) -> Option<Duration> {
+    let mut sorted = values.to_vec();
+    sorted.sort_unstable();
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted.get(rank.clamp(1, sorted.len().max(1)) - 1).copied()
+}
@@ -0,0 +1,267 @@
+use std::collections::{BinaryHeap, HashSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::stream::{FusedStream, FuturesUnordered};
+use futures::{Stream, StreamExt};
+use tracing::{event, Level};
+
+use super::{DownloadJob, Segment};
+
+/// Holding back of jobs of secondary streams while jobs of other streams wait too long to start
+struct Pressure {
+    secondary: HashSet<super::Stream>,
+    threshold: Duration,
+    active: bool,
+    held: Vec<DownloadJob>,
+    /// Segments that were held back at least once
+    deferred: HashSet<(super::Stream, u64, u64)>,
+}
+
+impl Pressure {
+    /// Update whether secondary jobs are held back from the jobs waiting in queue, returns held
+    /// jobs to queue again if not
+    ///
+    /// Holding back starts once a job of another stream waited longer than threshold, and stops
+    /// once none of them waits anymore.
+    fn update(&mut self, queue: &BinaryHeap<DownloadJob>) -> Vec<DownloadJob> {
+        let lag = queue
+            .iter()
+            .filter(|j| !self.secondary.contains(&j.stream))
+            .map(|j| j.enqueued.elapsed())
+            .max();
+        match lag {
+            Some(l) if !self.active && l > self.threshold => {
+                event!(
+                    Level::INFO,
+                    "Primary streams waited {:.1}s for downloads, deferring secondary streams",
+                    l.as_secs_f64()
+                );
+                self.active = true;
+            }
+            None if self.active => {
+                event!(
+                    Level::INFO,
+                    "Primary streams caught up, resuming {} deferred segments of secondary streams",
+                    self.held.len()
+                );
+                self.active = false;
+            }
+            _ => (),
+        }
+
+        if self.active {
+            Vec::new()
+        } else {
+            std::mem::take(&mut self.held)
+        }
+    }
+
+    /// Hold back job if needed, returns it otherwise
+    fn hold(&mut self, job: DownloadJob) -> Option<DownloadJob> {
+        if self.active && self.secondary.contains(&job.stream) {
+            self.deferred
+                .insert((job.stream.clone(), job.segment.discon_seq, job.segment.seq));
+            self.held.push(job);
+            None
+        } else {
+            Some(job)
+        }
+    }
+}
+
+/// Run download jobs with a limited number of workers, starting the most urgent queued job
+/// whenever a worker is free
+///
+/// Jobs of the same priority are started in the order they were queued, and results are returned
+/// in order of completion.
+pub(crate) struct Scheduler<St, F, Fut> {
+    jobs: Option<St>,
+    queue: BinaryHeap<DownloadJob>,
+    running: FuturesUnordered<Fut>,
+    workers: usize,
+    start: F,
+    pressure: Option<Pressure>,
+}
+
+impl<St, F, Fut> Scheduler<St, F, Fut>
+where
+    St: Stream<Item = DownloadJob> + Unpin,
+    F: FnMut(DownloadJob) -> Fut + Unpin,
+    Fut: Future,
+{
+    pub fn new(jobs: St, workers: usize, start: F) -> Self {
+        Self {
+            jobs: Some(jobs),
+            queue: BinaryHeap::new(),
+            running: FuturesUnordered::new(),
+            workers: workers.max(1),
+            start,
+            pressure: None,
+        }
+    }
+
+    /// Hold back jobs of secondary streams while a job of another stream waits longer than
+    /// threshold to start, until none of them waits anymore
+    pub fn defer_secondary(
+        mut self,
+        secondary: HashSet<super::Stream>,
+        threshold: Duration,
+    ) -> Self {
+        self.pressure = Some(Pressure {
+            secondary,
+            threshold,
+            active: false,
+            held: Vec::new(),
+            deferred: HashSet::new(),
+        });
+        self
+    }
+
+    /// Number of segments that were held back at least once
+    pub fn deferred(&self) -> usize {
+        self.pressure.as_ref().map_or(0, |p| p.deferred.len())
+    }
+
+    /// Whether segment of stream was held back at least once
+    pub fn was_deferred(&self, stream: &super::Stream, segment: &Segment) -> bool {
+        self.pressure.as_ref().is_some_and(|p| {
+            p.deferred
+                .contains(&(stream.clone(), segment.discon_seq, segment.seq))
+        })
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.jobs.is_none() && self.queue.is_empty() && self.held().is_empty()
+    }
+
+    /// Stop accepting and starting jobs, jobs that are already running still finish
+    pub fn close(&mut self) {
+        self.jobs = None;
+        self.queue.clear();
+        if let Some(p) = &mut self.pressure {
+            p.held.clear();
+        }
+    }
+
+    /// Jobs held back by pressure
+    fn held(&self) -> &[DownloadJob] {
+        self.pressure.as_ref().map_or(&[], |p| &p.held)
+    }
+}
+
+impl<St, F, Fut> Stream for Scheduler<St, F, Fut>
+where
+    St: Stream<Item = DownloadJob> + Unpin,
+    F: FnMut(DownloadJob) -> Fut + Unpin,
+    Fut: Future,
+{
+    type Item = Fut::Output;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        // Queue all jobs that are ready
+        while let Some(jobs) = this.jobs.as_mut() {
+            match jobs.poll_next_unpin(cx) {
+                Poll::Ready(Some(job)) => this.queue.push(job),
+                Poll::Ready(None) => this.jobs = None,
+                Poll::Pending => break,
+            }
+        }
+
+        // Queue held jobs again once primary streams caught up
+        if let Some(p) = &mut this.pressure {
+            this.queue.extend(p.update(&this.queue));
+        }
+
+        // Start most urgent jobs on free workers
+        while this.running.len() < this.workers {
+            let job = match this.queue.pop() {
+                Some(job) => job,
+                None => break,
+            };
+            let job = match &mut this.pressure {
+                Some(p) => p.hold(job),
+                None => Some(job),
+            };
+            if let Some(job) = job {
+                this.running.push((this.start)(job));
+            }
+        }
+
+        match this.running.poll_next_unpin(cx) {
+            Poll::Ready(Some(output)) => Poll::Ready(Some(output)),
+            // Nothing running, finished if no more jobs can arrive
+            Poll::Ready(None) if this.jobs.is_none() => Poll::Ready(None),
+            _ => Poll::Pending,
+        }
+    }
+}
+
+impl<St, F, Fut> FusedStream for Scheduler<St, F, Fut>
+where
+    St: Stream<Item = DownloadJob> + Unpin,
+    F: FnMut(DownloadJob) -> Fut + Unpin,
+    Fut: Future,
+{
+    fn is_terminated(&self) -> bool {
+        self.jobs.is_none()
+            && self.queue.is_empty()
+            && self.held().is_empty()
+            && self.running.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{executor, future, stream};
+
+    use super::*;
+    use crate::livestream::{Encryption, MediaFormat, Priority, RemoteData};
+
+    fn job(seq: u64, priority: Priority) -> DownloadJob {
+        let segment = Segment {
+            data: RemoteData::new(
+                format!("http://localhost/{}.ts", seq).parse().unwrap(),
+                None,
+            ),
+            discon_seq: 0,
+            seq,
+            format: MediaFormat::Unknown,
+            initialization: None,
+            program_date_time: None,
+            duration: None,
+        };
+        DownloadJob::new(
+            super::super::Stream::Main,
+            segment,
+            Encryption::None,
+            priority,
+        )
+    }
+
+    fn run(jobs: Vec<DownloadJob>) -> Vec<u64> {
+        let scheduler = Scheduler::new(stream::iter(jobs), 1, |j| future::ready(j.segment.seq));
+        executor::block_on(scheduler.collect())
+    }
+
+    #[test]
+    fn equal_priorities_run_in_order() {
+        let jobs = (0..5).map(|i| job(i, Priority::Backlog)).collect();
+        assert_eq!(run(jobs), [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn urgent_jobs_skip_the_queue() {
+        let jobs = vec![
+            job(0, Priority::Low),
+            job(1, Priority::Backlog),
+            job(2, Priority::Init),
+            job(3, Priority::LiveEdge),
+        ];
+        assert_eq!(run(jobs), [2, 3, 1, 0]);
+    }
+}
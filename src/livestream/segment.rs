@@ -1,7 +1,10 @@
+use std::time::Duration;
+
 use reqwest::Url;
+use time::OffsetDateTime;
 
 use super::remote_data::RemoteData;
-use super::MediaFormat;
+use super::{MediaFormat, Stream};
 
 /// Type of media segment
 #[derive(Clone, PartialEq, Eq, Hash, Debug)]
@@ -11,6 +14,10 @@ pub struct Segment {
     pub seq: u64,
     pub format: MediaFormat,
     pub initialization: Option<RemoteData>,
+    /// Program date time of the start of the segment, if known
+    pub program_date_time: Option<OffsetDateTime>,
+    /// Duration of the segment claimed by its EXTINF, if known
+    pub duration: Option<Duration>,
 }
 
 impl Segment {
@@ -19,10 +26,134 @@ impl Segment {
         self.data.url()
     }
 
-    /// String identifier of segment
+    /// String identifier of segment, unique within its stream
+    ///
+    /// Identifiers are part of segment file names, which resuming and merging parse, so they
+    /// must not change.
+    ///
+    /// ```
+    /// use livestream_dl::livestream::{MediaFormat, RemoteData, Segment};
+    ///
+    /// let segment = |discon_seq, seq| Segment {
+    ///     data: RemoteData::new("http://localhost/seg.ts".parse().unwrap(), None),
+    ///     discon_seq,
+    ///     seq,
+    ///     format: MediaFormat::MpegTs,
+    ///     initialization: None,
+    ///     program_date_time: None,
+    ///     duration: None,
+    /// };
+    /// assert_eq!(segment(0, 0).id(), "d0000000000s0000000000");
+    /// assert_eq!(segment(0, 42).id(), "d0000000000s0000000042");
+    /// assert_eq!(segment(3, 1234567).id(), "d0000000003s0001234567");
+    /// // Numbers too long for the padding are written in full
+    /// assert_eq!(
+    ///     segment(1, 12345678901).id(),
+    ///     "d0000000001s12345678901"
+    /// );
+    /// assert_eq!(
+    ///     segment(u64::MAX, u64::MAX).id(),
+    ///     "d18446744073709551615s18446744073709551615"
+    /// );
+    /// ```
     pub fn id(&self) -> String {
         format!("d{:010}s{:010}", self.discon_seq, self.seq)
     }
+
+    /// Name of the file this segment of stream is saved to, named by its number within the stream
+    /// if given, otherwise by its identifier
+    ///
+    /// ```
+    /// use livestream_dl::livestream::{MediaFormat, RemoteData, Segment, Stream};
+    ///
+    /// let segment = |discon_seq, seq, format| Segment {
+    ///     data: RemoteData::new("http://localhost/seg".parse().unwrap(), None),
+    ///     discon_seq,
+    ///     seq,
+    ///     format,
+    ///     initialization: None,
+    ///     program_date_time: None,
+    ///     duration: None,
+    /// };
+    /// let audio = Stream::Audio {
+    ///     group_id: "aac".into(),
+    ///     name: "English 5.1".into(),
+    ///     lang: Some("en".into()),
+    ///     channels: Some(6),
+    ///     describes_video: false,
+    /// };
+    /// let subtitles = Stream::Subtitle {
+    ///     group_id: "".into(),
+    ///     name: "Forced/Signs".into(),
+    ///     lang: None,
+    ///     forced: true,
+    /// };
+    /// let names = [
+    ///     (&Stream::Main, segment(0, 7, MediaFormat::MpegTs), None),
+    ///     (&Stream::Main, segment(2, 7, MediaFormat::FMp4), None),
+    ///     (&Stream::Main, segment(0, 7, MediaFormat::Unknown), None),
+    ///     (&audio, segment(0, 7, MediaFormat::Adts), None),
+    ///     (&audio, segment(1, 8, MediaFormat::Aac), None),
+    ///     (&subtitles, segment(0, 7, MediaFormat::WebVtt), None),
+    ///     (&Stream::Main, segment(0, 7, MediaFormat::MpegTs), Some(1)),
+    ///     (&audio, segment(0, 7, MediaFormat::EAc3), Some(123)),
+    ///     (&subtitles, segment(0, 7, MediaFormat::WebVtt), Some(1234567)),
+    /// ]
+    /// .map(|(stream, segment, number)| segment.file_name(stream, number));
+    /// assert_eq!(
+    ///     names,
+    ///     [
+    ///         "segment_main_d0000000000s0000000007.ts",
+    ///         "segment_main_d0000000002s0000000007.mp4",
+    ///         "segment_main_d0000000000s0000000007.ts",
+    ///         "segment_audio_aac_English 5.1_d0000000000s0000000007.aac",
+    ///         "segment_audio_aac_English 5.1_d0000000001s0000000008.m4a",
+    ///         "segment_subtitle_Forced-Signs_d0000000000s0000000007.vtt",
+    ///         "main_000001.ts",
+    ///         "audio_aac_English 5.1_000123.eac3",
+    ///         "subtitle_Forced-Signs_1234567.vtt",
+    ///     ]
+    /// );
+    ///
+    /// // Descriptive names can be parsed back
+    /// assert_eq!(
+    ///     Segment::parse_file_name(&names[4]),
+    ///     Some(("audio_aac_English 5.1", 1, 8, MediaFormat::Aac))
+    /// );
+    /// assert_eq!(
+    ///     Segment::parse_file_name(&names[5]),
+    ///     Some(("subtitle_Forced-Signs", 0, 7, MediaFormat::WebVtt))
+    /// );
+    /// assert_eq!(Segment::parse_file_name(&names[6]), None);
+    /// assert_eq!(Segment::parse_file_name("segment_main_d1s2"), None);
+    /// assert_eq!(Segment::parse_file_name("segment_main_d1s2.ts.part"), None);
+    /// ```
+    pub fn file_name(&self, stream: &Stream, number: Option<u64>) -> String {
+        match number {
+            Some(n) => format!("{}_{:06}.{}", stream.file_id(), n, self.format.extension()),
+            None => format!(
+                "segment_{}_{}.{}",
+                stream.file_id(),
+                self.id(),
+                self.format.extension()
+            ),
+        }
+    }
+
+    /// Stream identifier, discontinuity sequence, sequence number and format of a segment saved
+    /// to a file named by [`Segment::file_name`] without number, or None for other file names
+    pub fn parse_file_name(file_name: &str) -> Option<(&str, u64, u64, MediaFormat)> {
+        // File names look like segment_{stream}_d{discon_seq}s{seq}.{ext}
+        let (stem, ext) = file_name.rsplit_once('.')?;
+        let (stream, id) = stem.strip_prefix("segment_")?.rsplit_once('_')?;
+        let (discon_seq, seq) = id.strip_prefix('d')?.split_once('s')?;
+        Some((
+            stream,
+            discon_seq.parse().ok()?,
+            seq.parse().ok()?,
+            MediaFormat::from_extension(ext),
+        ))
+    }
 }
 
 impl PartialOrd for Segment {
@@ -0,0 +1,168 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use super::{Segment, Stream};
+
+/// Discontinuity sequence and media sequence of a segment
+type SegmentKey = (u64, u64);
+
+/// Download attempts of a segment
+#[derive(Clone, Copy, Debug)]
+struct Attempt {
+    count: u32,
+    failed: bool,
+}
+
+#[derive(Default, Debug)]
+struct StreamAttempts {
+    segments: BTreeMap<SegmentKey, Attempt>,
+    /// Set once the playlist fetcher finished, so failed segments won't be listed again
+    ended: bool,
+    permanently_failed: usize,
+}
+
+#[derive(Default, Debug)]
+struct Inner {
+    streams: HashMap<Stream, StreamAttempts>,
+    permanently_failed: usize,
+}
+
+/// Download attempts of the segments in the live window of each stream, shared by the playlist
+/// fetchers and the downloader so that failed segments are retried while they are listed, up to
+/// a maximum number of attempts
+#[derive(Clone, Debug)]
+pub struct SegmentAttempts {
+    max_attempts: u32,
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl SegmentAttempts {
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts,
+            inner: Arc::default(),
+        }
+    }
+
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// Count an attempt of a newly listed segment
+    pub fn start(&self, stream: &Stream, segment: &Segment) {
+        let mut inner = self.inner.lock().unwrap();
+        let s = inner.streams.entry(stream.clone()).or_default();
+        s.ended = false;
+        let attempt = s
+            .segments
+            .entry((segment.discon_seq, segment.seq))
+            .or_insert(Attempt {
+                count: 0,
+                failed: false,
+            });
+        attempt.count += 1;
+        attempt.failed = false;
+    }
+
+    /// Count another attempt of a listed segment if its last attempt failed and attempts are
+    /// left, returns true if it should be downloaded again
+    pub fn retry(&self, stream: &Stream, segment: &Segment) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let attempt = inner
+            .streams
+            .get_mut(stream)
+            .and_then(|s| s.segments.get_mut(&(segment.discon_seq, segment.seq)));
+        match attempt {
+            Some(a) if a.failed && a.count < self.max_attempts => {
+                a.count += 1;
+                a.failed = false;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Forget a downloaded segment
+    pub fn succeeded(&self, stream: &Stream, segment: &Segment) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(s) = inner.streams.get_mut(stream) {
+            s.segments.remove(&(segment.discon_seq, segment.seq));
+        }
+    }
+
+    /// Record a failed attempt, returns the number of attempts so far if the segment will be
+    /// retried once listed again, or None if it failed permanently
+    pub fn failed(&self, stream: &Stream, segment: &Segment) -> Option<u32> {
+        let mut inner = self.inner.lock().unwrap();
+        let (attempt, ended) = match inner.streams.get_mut(stream) {
+            Some(s) => (
+                s.segments.get_mut(&(segment.discon_seq, segment.seq)),
+                s.ended,
+            ),
+            None => (None, false),
+        };
+        let retry = match attempt {
+            Some(a) if !ended && a.count < self.max_attempts => {
+                a.failed = true;
+                Some(a.count)
+            }
+            _ => None,
+        };
+        if retry.is_none() {
+            if let Some(s) = inner.streams.get_mut(stream) {
+                s.segments.remove(&(segment.discon_seq, segment.seq));
+                s.permanently_failed += 1;
+            }
+            inner.permanently_failed += 1;
+        }
+        retry
+    }
+
+    /// Forget segments before the first segment listed in the playlist, failed segments among
+    /// them failed permanently
+    pub fn forget_before(&self, stream: &Stream, first: &Segment) {
+        let mut inner = self.inner.lock().unwrap();
+        let failed = match inner.streams.get_mut(stream) {
+            Some(s) => {
+                let kept = s.segments.split_off(&(first.discon_seq, first.seq));
+                let dropped = std::mem::replace(&mut s.segments, kept);
+                let failed = dropped.values().filter(|a| a.failed).count();
+                s.permanently_failed += failed;
+                failed
+            }
+            None => return,
+        };
+        inner.permanently_failed += failed;
+    }
+
+    /// Mark the playlist of a stream as finished, its failed segments failed permanently
+    pub fn end(&self, stream: &Stream) {
+        let mut inner = self.inner.lock().unwrap();
+        let failed = match inner.streams.get_mut(stream) {
+            Some(s) => {
+                s.ended = true;
+                let failed = s.segments.values().filter(|a| a.failed).count();
+                s.segments.retain(|_, a| !a.failed);
+                s.permanently_failed += failed;
+                failed
+            }
+            None => return,
+        };
+        inner.permanently_failed += failed;
+    }
+
+    /// Number of segments that failed permanently
+    pub fn permanently_failed(&self) -> usize {
+        self.inner.lock().unwrap().permanently_failed
+    }
+
+    /// Number of segments of stream that failed permanently
+    pub fn permanently_failed_in(&self, stream: &Stream) -> usize {
+        self.inner
+            .lock()
+            .unwrap()
+            .streams
+            .get(stream)
+            .map_or(0, |s| s.permanently_failed)
+    }
+}
@@ -0,0 +1,64 @@
+use anyhow::Result;
+use futures::{stream, StreamExt};
+use reqwest::Url;
+use serde::Serialize;
+
+use super::http_client::HttpClient;
+use super::playlist_fetcher::{fetch_media_playlist, playlist_segments};
+use super::{Stream, Variables};
+
+/// Availability of a segment listed by the playlist of a stream, checked without downloading it
+#[derive(Serialize, Debug)]
+pub struct SegmentCheck {
+    pub stream: Stream,
+    pub url: String,
+    /// Size in bytes if available and known
+    pub size: Option<u64>,
+    /// Why the segment isn't available
+    pub error: Option<String>,
+}
+
+impl SegmentCheck {
+    pub fn available(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Check the segments and initialization sections currently listed by the media playlist of each
+/// stream, concurrency at a time
+pub async fn check_segments<'a>(
+    client: &HttpClient,
+    streams: impl IntoIterator<Item = (&'a Stream, &'a Url)>,
+    variables: &Variables,
+    concurrency: usize,
+) -> Result<Vec<SegmentCheck>> {
+    let mut checks = Vec::new();
+    for (stream, url) in streams {
+        let playlist = fetch_media_playlist(client, stream, url, variables).await?;
+        let mut data = Vec::new();
+        for segment in playlist_segments(&playlist, url, None)? {
+            if let Some(i) = segment.initialization {
+                if !data.contains(&i) {
+                    data.push(i);
+                }
+            }
+            data.push(segment.data);
+        }
+
+        let stream_checks = stream::iter(data)
+            .map(|d| async move {
+                let res = d.check(client).await;
+                SegmentCheck {
+                    stream: stream.clone(),
+                    url: client.request_url(d.url().clone()).to_string(),
+                    size: res.as_ref().ok().copied().flatten(),
+                    error: res.err().map(|e| format!("{:#}", e)),
+                }
+            })
+            .buffered(concurrency)
+            .collect::<Vec<_>>()
+            .await;
+        checks.extend(stream_checks);
+    }
+    Ok(checks)
+}
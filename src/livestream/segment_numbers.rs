@@ -0,0 +1,45 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use super::{Segment, Stream};
+
+#[derive(Default, Debug)]
+struct Inner {
+    /// Last number given to a segment of each stream
+    last: HashMap<Stream, u64>,
+    /// Numbers of sent segments that weren't saved yet
+    assigned: HashMap<(Stream, u64, u64), u64>,
+}
+
+/// Sequential numbers of the segments of each stream, counted from 1 in playlist order
+///
+/// Playlist fetchers number segments when they first send them, so the numbers follow playlist
+/// order even though downloads complete out of order.
+#[derive(Clone, Default, Debug)]
+pub struct SegmentNumbers {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl SegmentNumbers {
+    /// Give the next number of its stream to a newly listed segment
+    pub fn assign(&self, stream: &Stream, segment: &Segment) {
+        let mut inner = self.inner.lock().unwrap();
+        let key = (stream.clone(), segment.discon_seq, segment.seq);
+        if inner.assigned.contains_key(&key) {
+            return;
+        }
+        let last = inner.last.entry(stream.clone()).or_default();
+        *last += 1;
+        let number = *last;
+        inner.assigned.insert(key, number);
+    }
+
+    /// Number of a segment that is being saved
+    pub fn take(&self, stream: &Stream, segment: &Segment) -> Option<u64> {
+        self.inner.lock().unwrap().assigned.remove(&(
+            stream.clone(),
+            segment.discon_seq,
+            segment.seq,
+        ))
+    }
+}
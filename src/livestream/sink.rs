@@ -0,0 +1,406 @@
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::channel::mpsc;
+use tokio::io::AsyncWriteExt;
+use tokio::{fs, time};
+use tracing::{event, Level};
+
+use super::journal::{Journal, JournalEntry};
+use super::remote_data::RemoteData;
+use super::{OpenFiles, Segment, SegmentNumbers, Stream, TsAppender, EVENT_TARGET};
+
+/// Interval of checking whether an unavailable segments directory returned
+const OUTPUT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Destination of downloaded segments
+///
+/// Errors returned by a sink are treated like failed segment downloads: they are logged and the
+/// download continues.
+///
+/// ```
+/// use anyhow::Result;
+/// use async_trait::async_trait;
+/// use livestream_dl::livestream::{RemoteData, Segment, SegmentSink, Stream};
+///
+/// /// Collect segments in memory
+/// #[derive(Default)]
+/// struct MemorySink(Vec<(Stream, Segment, Vec<u8>)>);
+///
+/// #[async_trait]
+/// impl SegmentSink for MemorySink {
+///     async fn on_init(&mut self, _: &Stream, _: &RemoteData, _: &[u8]) -> Result<()> {
+///         Ok(())
+///     }
+///
+///     async fn on_segment(&mut self, stream: &Stream, segment: &Segment, data: &[u8]) -> Result<()> {
+///         self.0.push((stream.clone(), segment.clone(), data.to_vec()));
+///         Ok(())
+///     }
+/// }
+/// ```
+#[async_trait]
+pub trait SegmentSink: Send {
+    /// Called with the initialization section of a stream before the first segment that uses it
+    async fn on_init(&mut self, stream: &Stream, init: &RemoteData, data: &[u8]) -> Result<()>;
+
+    /// Called with the decrypted data of each downloaded segment, in order of completion
+    async fn on_segment(&mut self, stream: &Stream, segment: &Segment, data: &[u8]) -> Result<()>;
+}
+
+/// Sink that saves each segment to its own file, prefixed with its initialization section
+#[derive(Debug)]
+pub struct FileSink {
+    segments_directory: PathBuf,
+    /// Initialization sections, shared so that saving a segment doesn't copy its initialization
+    inits: HashMap<RemoteData, Arc<[u8]>>,
+    downloaded_segments: HashMap<Stream, BinaryHeap<(Segment, PathBuf)>>,
+    journal: Option<Arc<Journal>>,
+    saved_tx: Option<mpsc::UnboundedSender<PathBuf>>,
+    numbers: Option<SegmentNumbers>,
+    /// Set once the segments directory was created
+    created_directory: bool,
+    /// Write segments to a .part file renamed once complete
+    part_files: bool,
+    /// How long to wait for a vanished segments directory to return, and how many bytes of
+    /// segments to buffer meanwhile before waiting for it
+    output_grace: Option<(Duration, Option<u64>)>,
+    /// Since when the segments directory is unavailable
+    unavailable_since: Option<Instant>,
+    /// Segments waiting for the segments directory to return, in order
+    pending: VecDeque<PendingSegment>,
+    pending_bytes: u64,
+    /// Durations the segments directory was unavailable
+    outages: Vec<Duration>,
+    /// Appends segments of the main stream to a single file
+    appender: Option<TsAppender>,
+    open_files: OpenFiles,
+}
+
+/// Segment to save to path, with its initialization section
+#[derive(Debug)]
+struct PendingSegment {
+    stream: Stream,
+    segment: Segment,
+    path: PathBuf,
+    contents: Vec<u8>,
+}
+
+impl FileSink {
+    pub fn new(segments_directory: impl AsRef<Path>) -> Self {
+        Self {
+            segments_directory: segments_directory.as_ref().to_path_buf(),
+            inits: HashMap::new(),
+            downloaded_segments: HashMap::new(),
+            journal: None,
+            saved_tx: None,
+            numbers: None,
+            created_directory: false,
+            part_files: true,
+            output_grace: None,
+            unavailable_since: None,
+            pending: VecDeque::new(),
+            pending_bytes: 0,
+            outages: Vec::new(),
+            appender: None,
+            open_files: OpenFiles::default(),
+        }
+    }
+    /// Start with segments saved by a previous run
+    pub fn resume_from(
+        mut self,
+        downloaded_segments: HashMap<Stream, BinaryHeap<(Segment, PathBuf)>>,
+    ) -> Self {
+        self.downloaded_segments = downloaded_segments;
+        self
+    }
+
+    /// Record saved segments in a journal
+    pub fn with_journal(mut self, journal: Arc<Journal>) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+
+    /// Open no more segment files at once than open_files allows
+    pub fn with_open_files(mut self, open_files: OpenFiles) -> Self {
+        self.open_files = open_files;
+        self
+    }
+
+    /// Send the path of each saved segment to a channel
+    pub fn notify_saved(mut self, tx: mpsc::UnboundedSender<PathBuf>) -> Self {
+        self.saved_tx = Some(tx);
+        self
+    }
+
+    /// Name segment files by their number within their stream, e.g. main_000001.ts, falling back
+    /// to descriptive names for segments without a number
+    pub fn with_sequential_names(mut self, numbers: SegmentNumbers) -> Self {
+        self.numbers = Some(numbers);
+        self
+    }
+
+    /// Also append the segments of the main stream in order to a single file
+    pub(crate) fn append_ts(mut self, appender: TsAppender) -> Self {
+        self.appender = Some(appender);
+        self
+    }
+
+    /// Write segment files in place instead of to a .part file renamed once complete
+    pub fn write_in_place(mut self) -> Self {
+        self.part_files = false;
+        self
+    }
+
+    /// Keep segments in memory while the segments directory is unavailable, e.g. because the
+    /// disk it is on was briefly disconnected, and write them once it returns
+    ///
+    /// Once more than max_buffered bytes are buffered, saving waits for the directory. Segments
+    /// fail only if the directory doesn't return within grace.
+    pub fn with_output_grace(mut self, grace: Duration, max_buffered: Option<u64>) -> Self {
+        self.output_grace = Some((grace, max_buffered));
+        self
+    }
+
+    /// Write segments still buffered because the segments directory is unavailable, waiting for
+    /// it to return, and log how long it was unavailable
+    pub async fn finish(&mut self) -> Result<()> {
+        if let Some(appender) = &mut self.appender {
+            if let Err(e) = appender.finish().await {
+                event!(Level::WARN, "Unable to append segments: {:#}", e);
+            }
+        }
+        let res = self.flush_pending(true).await;
+        if !self.outages.is_empty() {
+            event!(
+                Level::WARN,
+                "Segments directory was unavailable {} times for {:.1}s in total, longest {:.1}s",
+                self.outages.len(),
+                self.outages.iter().sum::<Duration>().as_secs_f64(),
+                self.outages
+                    .iter()
+                    .max()
+                    .unwrap_or(&Duration::ZERO)
+                    .as_secs_f64()
+            );
+        }
+        res
+    }
+
+    /// Paths of saved segments for each stream
+    pub fn into_downloaded_segments(self) -> HashMap<Stream, BinaryHeap<(Segment, PathBuf)>> {
+        self.downloaded_segments
+    }
+}
+
+impl FileSink {
+    /// Write the concatenated parts to path, flushed before returning
+    ///
+    /// Unless written in place, the file only gets its name once complete, so that a crash can't
+    /// leave a truncated segment.
+    async fn write_file(&self, path: &Path, parts: &[&[u8]]) -> io::Result<()> {
+        let part_path = self.part_files.then(|| {
+            let mut part_path = path.as_os_str().to_owned();
+            part_path.push(".part");
+            PathBuf::from(part_path)
+        });
+        let mut file = fs::File::create(part_path.as_deref().unwrap_or(path)).await?;
+        for part in parts {
+            file.write_all(part).await?;
+        }
+        file.flush().await?;
+        drop(file);
+        if let Some(part_path) = part_path {
+            fs::rename(part_path, path).await?;
+        }
+        Ok(())
+    }
+
+    /// Record a segment saved to path
+    fn saved(&mut self, stream: &Stream, segment: &Segment, path: PathBuf) {
+        if let Some(journal) = &self.journal {
+            journal.record(&JournalEntry::saved(stream, segment, &path));
+            journal.flush();
+        }
+        if let Some(tx) = &self.saved_tx {
+            let _ = tx.unbounded_send(path.clone());
+        }
+        self.downloaded_segments
+            .entry(stream.clone())
+            .or_default()
+            .push((segment.clone(), path));
+    }
+
+    /// Save the concatenated parts of a segment, buffering them if the segments directory is
+    /// unavailable
+    async fn save(
+        &mut self,
+        stream: &Stream,
+        segment: &Segment,
+        path: PathBuf,
+        parts: &[&[u8]],
+    ) -> Result<()> {
+        event!(Level::TRACE, "saving to {:?}", &path);
+        let permit = self.open_files.acquire().await?;
+        let res = self.write_file(&path, parts).await;
+        drop(permit);
+        match res {
+            Ok(()) => {
+                self.saved(stream, segment, path);
+                Ok(())
+            }
+            Err(e) if self.output_grace.is_some() && !is_directory(&self.segments_directory) => {
+                let grace = self.output_grace.map_or(Duration::ZERO, |(g, _)| g);
+                event!(
+                    Level::WARN,
+                    "Segments directory {:?} is unavailable ({}), keeping segments in memory for up \
+                     to {}s until it returns",
+                    self.segments_directory,
+                    e,
+                    grace.as_secs_f64()
+                );
+                event!(
+                    target: EVENT_TARGET,
+                    Level::DEBUG,
+                    path = ?self.segments_directory,
+                    error = %e,
+                    "output_unavailable"
+                );
+                self.unavailable_since = Some(Instant::now());
+                self.pending.push_front(PendingSegment {
+                    stream: stream.clone(),
+                    segment: segment.clone(),
+                    path,
+                    contents: parts.concat(),
+                });
+                self.pending_bytes = self.pending.iter().map(|p| p.contents.len() as u64).sum();
+                Ok(())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Write buffered segments in order if the segments directory returned, waiting for it if
+    /// wait is set or too much is buffered
+    ///
+    /// Errors and drops the buffered segments if the directory doesn't return within the grace
+    /// period.
+    async fn flush_pending(&mut self, wait: bool) -> Result<()> {
+        while let Some(since) = self.unavailable_since {
+            let (grace, max_buffered) = self.output_grace.unwrap_or_default();
+            if !is_directory(&self.segments_directory) {
+                let waited = since.elapsed();
+                if waited >= grace {
+                    let lost = self.pending.len();
+                    self.pending.clear();
+                    self.pending_bytes = 0;
+                    self.unavailable_since = None;
+                    self.outages.push(waited);
+                    return Err(anyhow::anyhow!(
+                        "Segments directory {:?} was unavailable for {:.1}s, dropping {} buffered \
+                         segments",
+                        self.segments_directory,
+                        waited.as_secs_f64(),
+                        lost
+                    ));
+                }
+                if !wait && max_buffered.is_none_or(|m| self.pending_bytes <= m) {
+                    return Ok(());
+                }
+                time::sleep(OUTPUT_POLL_INTERVAL.min(grace - waited)).await;
+                continue;
+            }
+
+            let unavailable = since.elapsed();
+            self.outages.push(unavailable);
+            event!(
+                Level::WARN,
+                "Segments directory {:?} is back after {:.1}s, writing {} buffered segments",
+                self.segments_directory,
+                unavailable.as_secs_f64(),
+                self.pending.len()
+            );
+            event!(
+                target: EVENT_TARGET,
+                Level::DEBUG,
+                path = ?self.segments_directory,
+                unavailable_secs = unavailable.as_secs_f64(),
+                buffered = self.pending.len(),
+                "output_restored"
+            );
+            self.unavailable_since = None;
+            while let Some(pending) = self.pending.pop_front() {
+                self.pending_bytes -= pending.contents.len() as u64;
+                // Other segments are still written if one fails
+                let PendingSegment {
+                    stream,
+                    segment,
+                    path,
+                    contents,
+                } = pending;
+                if let Err(e) = self.save(&stream, &segment, path, &[&contents]).await {
+                    event!(Level::WARN, "{:?}", e);
+                }
+                // Stop if it vanished again
+                if self.unavailable_since.is_some() {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SegmentSink for FileSink {
+    async fn on_init(&mut self, _: &Stream, init: &RemoteData, data: &[u8]) -> Result<()> {
+        self.inits.insert(init.clone(), Arc::from(data));
+        Ok(())
+    }
+
+    async fn on_segment(&mut self, stream: &Stream, segment: &Segment, data: &[u8]) -> Result<()> {
+        // Create directory if neeeded
+        if !self.created_directory {
+            fs::create_dir_all(&self.segments_directory).await?;
+            self.created_directory = true;
+        }
+
+        // Save segment to disk
+        let number = self.numbers.as_ref().and_then(|n| n.take(stream, segment));
+        let file_name = segment.file_name(stream, number);
+        let path = self.segments_directory.join(file_name);
+        let init = segment
+            .initialization
+            .as_ref()
+            .and_then(|i| self.inits.get(i))
+            .cloned();
+        let init = init.as_deref().unwrap_or_default();
+        if let (Some(appender), Stream::Main) = (&mut self.appender, stream) {
+            appender.push(segment, [init, data].concat()).await;
+        }
+
+        // Queue behind buffered segments while the segments directory is unavailable
+        if self.unavailable_since.is_some() {
+            let contents = [init, data].concat();
+            self.pending_bytes += contents.len() as u64;
+            self.pending.push_back(PendingSegment {
+                stream: stream.clone(),
+                segment: segment.clone(),
+                path,
+                contents,
+            });
+            return self.flush_pending(false).await;
+        }
+        self.save(stream, segment, path, &[init, data]).await
+    }
+}
+
+/// Check if path is a directory that can be accessed
+fn is_directory(path: &Path) -> bool {
+    std::fs::metadata(path).is_ok_and(|m| m.is_dir())
+}
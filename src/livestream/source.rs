@@ -0,0 +1,396 @@
+use std::fmt::Debug;
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::stream::{self, BoxStream};
+use m3u8_rs::{MediaPlaylist, Playlist};
+use reqwest::header::HeaderName;
+use reqwest::Url;
+use tokio::time;
+use tracing::{event, Level};
+
+use super::byte_budget::{ByteBudget, Reservation};
+use super::http_client::HttpClient;
+use super::redirect_cache::RedirectCache;
+use super::remote_data::{RemoteData, ResponseHeaders};
+use super::rendition_report::{RenditionReport, RenditionReports};
+use super::utils::{best_variant, make_absolute_url};
+use super::{ResponseTiming, Stream, Variables};
+use crate::error::LivestreamDLError;
+
+/// Maximum depth of master playlists nested in a stream before giving up
+const MAX_NESTED_MASTERS: usize = 4;
+
+/// Target duration assumed for media playlists without a usable EXT-X-TARGETDURATION
+const DEFAULT_TARGET_DURATION: Duration = Duration::from_secs(6);
+
+/// Shortest time between two fetches of a media playlist
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Versions of the media playlist of a stream, each with the URL its URIs are relative to
+pub type PlaylistStream = BoxStream<'static, Result<(MediaPlaylist, Url)>>;
+
+/// Source of the media playlists of streams
+///
+/// The download ends for a stream once a playlist with EXT-X-ENDLIST is yielded, its playlist
+/// stream ends or yields an error.
+pub trait PlaylistSource: Send + Sync + Debug {
+    /// Versions of the media playlist of stream, starting at url, as they become available
+    fn playlists(&self, stream: &Stream, url: &Url) -> PlaylistStream;
+}
+
+/// Data of a segment or initialization returned by a SegmentFetcher
+#[derive(Debug)]
+pub struct FetchedData {
+    pub bytes: Vec<u8>,
+    /// URL the data was fetched from, after redirects
+    pub url: Url,
+    /// Values of captured response headers
+    pub headers: ResponseHeaders,
+    /// Timing of the response if fetched over HTTP
+    pub timing: Option<ResponseTiming>,
+    /// Share of the byte budget held until the data is saved
+    reservation: Option<Reservation>,
+}
+
+impl FetchedData {
+    pub fn new(bytes: Vec<u8>, url: Url) -> Self {
+        Self {
+            bytes,
+            url,
+            headers: ResponseHeaders::new(),
+            timing: None,
+            reservation: None,
+        }
+    }
+
+    /// Split into data and the reservation held until it is saved
+    pub(super) fn into_parts(
+        self,
+    ) -> (
+        Vec<u8>,
+        Url,
+        ResponseHeaders,
+        Option<ResponseTiming>,
+        Option<Reservation>,
+    ) {
+        (
+            self.bytes,
+            self.url,
+            self.headers,
+            self.timing,
+            self.reservation,
+        )
+    }
+}
+
+/// Source of the data of segments and initializations
+#[async_trait]
+pub trait SegmentFetcher: Send + Sync + Debug {
+    /// Fetch the data of a segment
+    async fn fetch(&self, data: &RemoteData) -> Result<FetchedData>;
+
+    /// Fetch the data of a segment again after its download failed, e.g. with a longer timeout,
+    /// same as fetch by default
+    async fn refetch(&self, data: &RemoteData) -> Result<FetchedData> {
+        self.fetch(data).await
+    }
+
+    /// Fetch the data of an initialization section, same as a segment by default
+    async fn fetch_init(&self, init: &RemoteData) -> Result<Vec<u8>> {
+        Ok(self.fetch(init).await?.bytes)
+    }
+}
+
+/// Playlist source that polls media playlists over HTTP, following nested master playlists to
+/// their highest bitrate variant
+///
+/// Playlists are fetched again after their target duration if they changed, otherwise after half
+/// of it.
+#[derive(Clone, Debug)]
+pub struct HttpPlaylistSource {
+    client: HttpClient,
+    variables: Variables,
+    /// Rendition reports of all fetched playlists, if followed
+    reports: Option<RenditionReports>,
+}
+
+impl HttpPlaylistSource {
+    /// Source using client, variables are imported by media playlists
+    pub(super) fn new(client: HttpClient, variables: Variables) -> Self {
+        Self {
+            client,
+            variables,
+            reports: None,
+        }
+    }
+
+    /// Fetch playlists again after the shortest interval while the rendition reports of other
+    /// playlists show that they lag behind
+    pub(super) fn following_rendition_reports(mut self) -> Self {
+        self.reports = Some(RenditionReports::default());
+        self
+    }
+}
+
+/// Polling state of a media playlist
+struct Poll {
+    client: HttpClient,
+    stream: Stream,
+    url: Url,
+    variables: Variables,
+    nested_masters: usize,
+    /// Time of the last fetch and the end of the last playlist
+    last: Option<(time::Instant, u64)>,
+    /// Time to wait before the next fetch
+    next: Option<time::Instant>,
+    /// Whether a missing target duration was already reported
+    warned_target_duration: bool,
+    reports: Option<RenditionReports>,
+}
+
+impl PlaylistSource for HttpPlaylistSource {
+    fn playlists(&self, stream: &Stream, url: &Url) -> PlaylistStream {
+        let poll = Poll {
+            client: self.client.clone(),
+            stream: stream.clone(),
+            url: url.clone(),
+            variables: self.variables.clone(),
+            nested_masters: 0,
+            last: None,
+            next: None,
+            warned_target_duration: false,
+            reports: self.reports.clone(),
+        };
+        Box::pin(stream::try_unfold(poll, |mut poll| async move {
+            if let Some(next) = poll.next {
+                time::sleep_until(next).await;
+            }
+            let playlist = poll.fetch().await?;
+            Ok(Some(((playlist, poll.url.clone()), poll)))
+        }))
+    }
+}
+
+impl Poll {
+    /// Fetch the media playlist and schedule the next fetch
+    async fn fetch(&mut self) -> Result<MediaPlaylist> {
+        loop {
+            let now = time::Instant::now();
+
+            event!(Level::TRACE, "Fetching {}", self.url.as_str());
+            let (bytes, resp_url) = self.client.fetch_playlist(&self.url).await?;
+            let final_url = resp_url.to_string();
+            let (bytes, defined) = Variables::substitute(&bytes, &self.url, &self.variables)?;
+
+            match m3u8_rs::parse_playlist(&bytes) {
+                Ok((_, Playlist::MediaPlaylist(p))) => {
+                    // Wait for target duration if new segments were found, otherwise for half
+                    // target duration
+                    let end = p.media_sequence + p.segments.len() as u64;
+                    let changed = self.last.is_none_or(|(_, e)| end > e);
+                    if !usable_target_duration(p.target_duration) && !self.warned_target_duration {
+                        event!(
+                            Level::WARN,
+                            "Playlist of stream {} has no valid EXT-X-TARGETDURATION, assuming \
+                             {}s",
+                            self.stream,
+                            DEFAULT_TARGET_DURATION.as_secs()
+                        );
+                        self.warned_target_duration = true;
+                    }
+                    let mut wait = refresh_interval(p.target_duration, changed);
+
+                    // Catch up soon if other renditions report later segments
+                    if let Some(reports) = &self.reports {
+                        reports.record(&RenditionReport::parse_all(&bytes, &resp_url));
+                        let last_msn = end.saturating_sub(1);
+                        if let Some(reported) = reports.ahead_of(&self.url, last_msn) {
+                            event!(
+                                Level::DEBUG,
+                                "Playlist of stream {} ends at segment {}, but segment {} is \
+                                 reported, fetching it again soon",
+                                self.stream,
+                                last_msn,
+                                reported
+                            );
+                            wait = MIN_REFRESH_INTERVAL;
+                        }
+                    }
+                    self.last = Some((now, end));
+                    self.next = Some(now + wait);
+                    return Ok(p);
+                }
+                // Renditions are media playlists, a master playlist is most likely a mistake
+                Ok((_, Playlist::MasterPlaylist(_))) if self.stream != Stream::Main => {
+                    return Err(LivestreamDLError::NotMediaPlaylist(final_url).into());
+                }
+                Ok((_, Playlist::MasterPlaylist(p))) => {
+                    // Variables of a nested master playlist are imported by its variants
+                    self.variables = defined;
+
+                    // Follow nested master playlist to its highest bitrate variant
+                    self.nested_masters += 1;
+                    if self.nested_masters > MAX_NESTED_MASTERS {
+                        return Err(anyhow::anyhow!(
+                            "Stream {} has too many nested master playlists: {}",
+                            self.stream,
+                            final_url
+                        ));
+                    }
+                    let variant = best_variant(&p).ok_or_else(|| {
+                        anyhow::anyhow!(
+                            "Stream {} points to a master playlist without variants: {}",
+                            self.stream,
+                            final_url
+                        )
+                    })?;
+                    self.url = make_absolute_url(&resp_url, &variant.uri)?;
+                    event!(
+                        Level::INFO,
+                        "Found nested master playlist, using variant {}",
+                        self.url
+                    );
+                }
+                Err(_) => return Err(LivestreamDLError::ParseM3u8(final_url).into()),
+            }
+        }
+    }
+}
+
+/// Whether target_duration can be used to schedule playlist refreshes
+fn usable_target_duration(target_duration: f32) -> bool {
+    target_duration.is_finite() && target_duration > 0.0
+}
+
+/// Time to wait before fetching a media playlist with target_duration again, after it changed or
+/// not
+///
+/// This is the target duration if the playlist changed and half of it otherwise, never less than
+/// a second. Playlists with a missing, zero or invalid target duration are refreshed as if it was
+/// six seconds.
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use livestream_dl::livestream::refresh_interval;
+///
+/// assert_eq!(refresh_interval(4.0, true), Duration::from_secs(4));
+/// assert_eq!(refresh_interval(4.0, false), Duration::from_secs(2));
+/// assert_eq!(refresh_interval(1.0, false), Duration::from_secs(1));
+/// assert_eq!(refresh_interval(0.0, true), Duration::from_secs(6));
+/// assert_eq!(refresh_interval(0.0, false), Duration::from_secs(3));
+/// assert_eq!(refresh_interval(-2.0, true), Duration::from_secs(6));
+/// assert_eq!(refresh_interval(f32::NAN, false), Duration::from_secs(3));
+/// ```
+pub fn refresh_interval(target_duration: f32, changed: bool) -> Duration {
+    let target = if usable_target_duration(target_duration) {
+        Duration::try_from_secs_f32(target_duration).unwrap_or(DEFAULT_TARGET_DURATION)
+    } else {
+        DEFAULT_TARGET_DURATION
+    };
+    let wait = if changed { target } else { target / 2 };
+    wait.max(MIN_REFRESH_INTERVAL)
+}
+
+/// Segment fetcher that downloads over HTTP, holding a reservation of the size of each segment
+/// in a byte budget until it is saved
+///
+/// Segments are requested from the cached redirect target of their directory if redirects are
+/// given, falling back to their own URL if that fails. Failed segments are fetched again from their own
+/// URL with refetch_timeout. Downloads of segments larger than max_segment_size are aborted.
+#[derive(Clone, Debug)]
+pub struct HttpSegmentFetcher {
+    client: HttpClient,
+    budget: ByteBudget,
+    redirects: Option<RedirectCache>,
+    capture_headers: Vec<HeaderName>,
+    refetch_timeout: Duration,
+    max_segment_size: u64,
+}
+
+impl HttpSegmentFetcher {
+    pub(super) fn new(
+        client: HttpClient,
+        budget: ByteBudget,
+        redirects: Option<RedirectCache>,
+        capture_headers: Vec<HeaderName>,
+        refetch_timeout: Duration,
+        max_segment_size: u64,
+    ) -> Self {
+        Self {
+            client,
+            budget,
+            redirects,
+            capture_headers,
+            refetch_timeout,
+            max_segment_size,
+        }
+    }
+
+    async fn fetch_from(
+        &self,
+        data: &RemoteData,
+        timeout: Option<Duration>,
+    ) -> Result<FetchedData> {
+        let (bytes, url, reservation, headers, timing) = data
+            .fetch_within(
+                &self.client,
+                &self.budget,
+                &self.capture_headers,
+                timeout,
+                Some(self.max_segment_size),
+            )
+            .await?;
+        Ok(FetchedData {
+            bytes,
+            url,
+            headers,
+            timing: Some(timing),
+            reservation: Some(reservation),
+        })
+    }
+}
+
+#[async_trait]
+impl SegmentFetcher for HttpSegmentFetcher {
+    async fn fetch(&self, data: &RemoteData) -> Result<FetchedData> {
+        let redirects = match &self.redirects {
+            Some(r) => r,
+            None => return self.fetch_from(data, None).await,
+        };
+
+        // Fetch from cached redirect target if possible
+        let fetched = match redirects.rewrite(data.url()) {
+            Some(url) => match self.fetch_from(&data.with_url(url), None).await {
+                Ok(f) => f,
+                Err(e) => {
+                    event!(
+                        Level::DEBUG,
+                        "Cached redirect target failed, following redirect of {}: {:#}",
+                        data.url(),
+                        e
+                    );
+                    redirects.failed(data.url());
+                    self.fetch_from(data, None).await?
+                }
+            },
+            None => self.fetch_from(data, None).await?,
+        };
+        redirects.record(data.url(), &fetched.url);
+
+        Ok(fetched)
+    }
+
+    async fn refetch(&self, data: &RemoteData) -> Result<FetchedData> {
+        if let Some(r) = &self.redirects {
+            r.invalidate(data.url());
+        }
+        self.fetch_from(data, Some(self.refetch_timeout)).await
+    }
+
+    async fn fetch_init(&self, init: &RemoteData) -> Result<Vec<u8>> {
+        Ok(init.fetch(&self.client).await?.0)
+    }
+}
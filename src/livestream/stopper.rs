@@ -3,28 +3,167 @@ use std::sync::Arc;
 use futures::lock::Mutex;
 use tokio::sync::Notify;
 
+/// State a Stopper puts its tasks in
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ControlState {
+    Running,
+    /// Tasks wait before doing more work, but keep their state
+    Paused,
+    /// Tasks quit, final
+    Stopped,
+}
+
+#[derive(Debug)]
+struct Shared {
+    /// Notified when stopped
+    stop: Notify,
+    /// Notified when paused or resumed
+    changed: Notify,
+    state: Mutex<ControlState>,
+}
+
+/// Used to signal m3u8 fetcher tasks to pause, resume or quit
+///
+/// A child is paused or stopped when it or one of its parents is, stopped taking precedence.
+///
+/// ```
+/// use livestream_dl::livestream::{ControlState, Stopper};
+///
+/// let runtime = tokio::runtime::Runtime::new().unwrap();
+/// runtime.block_on(async {
+///     let parent = Stopper::new();
+///     let child = parent.child();
+///     assert_eq!(child.state().await, ControlState::Running);
+///
+///     // Pausing the parent pauses the child until resumed
+///     assert!(parent.pause().await);
+///     assert!(!parent.pause().await);
+///     assert_eq!(child.state().await, ControlState::Paused);
+///     assert!(parent.resume().await);
+///     assert_eq!(child.state().await, ControlState::Running);
+///
+///     // Stopping releases tasks waiting to resume, and can't be undone
+///     assert!(child.pause().await);
+///     let waiting = tokio::spawn({
+///         let child = child.clone();
+///         async move { child.wait_resume().await }
+///     });
+///     parent.stop().await;
+///     assert_eq!(waiting.await.unwrap(), ControlState::Stopped);
+///     assert!(child.stopped().await);
+///     assert!(!parent.resume().await);
+///     assert!(!parent.pause().await);
+///     assert_eq!(parent.state().await, ControlState::Stopped);
+///     assert_eq!(child.state().await, ControlState::Stopped);
+/// });
+/// ```
 #[derive(Clone, Debug)]
-pub struct Stopper(Arc<(Notify, Mutex<bool>)>);
+pub struct Stopper {
+    shared: Arc<Shared>,
+    /// Stopper that also stops this one
+    parent: Option<Box<Stopper>>,
+}
 
-/// Used to signal m3u8 fetcher task to quit
 impl Stopper {
     pub fn new() -> Self {
-        Self(Arc::new((Notify::new(), Mutex::new(false))))
+        Self {
+            shared: Arc::new(Shared {
+                stop: Notify::new(),
+                changed: Notify::new(),
+                state: Mutex::new(ControlState::Running),
+            }),
+            parent: None,
+        }
     }
 
-    /// Wait for stopper to be notified
+    /// Create a stopper that can be stopped on its own, and is also stopped when self is stopped
+    pub fn child(&self) -> Self {
+        Self {
+            parent: Some(Box::new(self.clone())),
+            ..Self::new()
+        }
+    }
+
+    /// Wait for stopper or one of its parents to be notified
     pub async fn wait(&self) {
-        self.0 .0.notified().await;
+        let notified = self.chain().map(|s| Box::pin(s.shared.stop.notified()));
+        futures::future::select_all(notified).await;
+    }
+
+    /// Wait until neither self nor one of its parents is paused, returns the state then
+    pub async fn wait_resume(&self) -> ControlState {
+        loop {
+            // Register before checking so that changes in between aren't missed
+            let notified = self.chain().flat_map(|s| {
+                [
+                    Box::pin(s.shared.stop.notified()),
+                    Box::pin(s.shared.changed.notified()),
+                ]
+            });
+            let notified: Vec<_> = notified.collect();
+            let state = self.state().await;
+            if state != ControlState::Paused {
+                return state;
+            }
+            futures::future::select_all(notified).await;
+        }
+    }
+
+    /// State of self combined with its parents
+    pub async fn state(&self) -> ControlState {
+        let mut state = ControlState::Running;
+        for s in self.chain() {
+            match *s.shared.state.lock().await {
+                ControlState::Stopped => return ControlState::Stopped,
+                ControlState::Paused => state = ControlState::Paused,
+                ControlState::Running => {}
+            }
+        }
+        state
     }
 
     /// Check if stopped
     pub async fn stopped(&self) -> bool {
-        *self.0 .1.lock().await
+        self.state().await == ControlState::Stopped
     }
 
     /// Set to stopped and notify waiters
     pub async fn stop(&self) {
-        *self.0 .1.lock().await = true;
-        self.0 .0.notify_waiters();
+        *self.shared.state.lock().await = ControlState::Stopped;
+        self.shared.stop.notify_waiters();
+    }
+
+    /// Pause if running, returns whether it was running
+    pub async fn pause(&self) -> bool {
+        self.transition(ControlState::Running, ControlState::Paused)
+            .await
+    }
+
+    /// Resume if paused, returns whether it was paused
+    pub async fn resume(&self) -> bool {
+        self.transition(ControlState::Paused, ControlState::Running)
+            .await
+    }
+
+    /// Change own state from `from` to `to` and notify waiters, returns false if not in `from`
+    async fn transition(&self, from: ControlState, to: ControlState) -> bool {
+        let mut state = self.shared.state.lock().await;
+        if *state != from {
+            return false;
+        }
+        *state = to;
+        self.shared.changed.notify_waiters();
+        true
+    }
+
+    /// Self and all of its parents
+    fn chain(&self) -> impl Iterator<Item = &Stopper> {
+        std::iter::successors(Some(self), |s| s.parent.as_deref())
+    }
+}
+
+impl Default for Stopper {
+    fn default() -> Self {
+        Self::new()
     }
 }
@@ -1,10 +1,140 @@
+use serde::{Deserialize, Serialize};
+
 /// Type of stream
-#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
 pub enum Stream {
     Main,
 
-    // Alternative media
-    Video { name: String, lang: Option<String> },
-    Audio { name: String, lang: Option<String> },
-    Subtitle { name: String, lang: Option<String> },
+    // Alternative media, group_id, channels and accessibility flags are empty for streams of
+    // journals from older versions
+    Video {
+        #[serde(default)]
+        group_id: String,
+        name: String,
+        lang: Option<String>,
+    },
+    Audio {
+        #[serde(default)]
+        group_id: String,
+        name: String,
+        lang: Option<String>,
+        #[serde(default)]
+        channels: Option<u32>,
+        /// Describes the video for the visually impaired
+        #[serde(default)]
+        describes_video: bool,
+    },
+    Subtitle {
+        #[serde(default)]
+        group_id: String,
+        name: String,
+        lang: Option<String>,
+        /// Only shows dialogue in other languages, signs and the like
+        #[serde(default)]
+        forced: bool,
+    },
+}
+
+impl Stream {
+    /// Identifier of the stream in file names, its displayed identifier with characters that
+    /// can't be part of file names replaced by -
+    ///
+    /// ```
+    /// use livestream_dl::livestream::Stream;
+    ///
+    /// let audio = |group_id: &str, name: &str| Stream::Audio {
+    ///     group_id: group_id.into(),
+    ///     name: name.into(),
+    ///     lang: Some("en".into()),
+    ///     channels: Some(2),
+    ///     describes_video: false,
+    /// };
+    /// let video = Stream::Video {
+    ///     group_id: "vid".into(),
+    ///     name: "1080p".into(),
+    ///     lang: None,
+    /// };
+    /// let subtitles = Stream::Subtitle {
+    ///     group_id: "subs".into(),
+    ///     name: "English (CC)".into(),
+    ///     lang: Some("en".into()),
+    ///     forced: false,
+    /// };
+    /// let streams = [
+    ///     Stream::Main,
+    ///     video,
+    ///     audio("aac", "English"),
+    ///     audio("", "English"),
+    ///     audio("aac", ""),
+    ///     audio("aac_hi", "Stereo_English"),
+    ///     audio("aac", "../../etc/passwd"),
+    ///     audio("a:b", "Eng|Fr?*<>\"\\"),
+    ///     audio("aac", "Tab\there"),
+    ///     audio("aac", "Español ✓"),
+    ///     subtitles,
+    /// ];
+    /// let ids = streams.map(|s| (s.to_string(), s.file_id()));
+    /// let golden = [
+    ///     ("main", "main"),
+    ///     ("video_vid_1080p", "video_vid_1080p"),
+    ///     ("audio_aac_English", "audio_aac_English"),
+    ///     ("audio_English", "audio_English"),
+    ///     ("audio_aac_", "audio_aac_"),
+    ///     ("audio_aac_hi_Stereo_English", "audio_aac_hi_Stereo_English"),
+    ///     ("audio_aac_../../etc/passwd", "audio_aac_..-..-etc-passwd"),
+    ///     ("audio_a:b_Eng|Fr?*<>\"\\", "audio_a-b_Eng-Fr------"),
+    ///     ("audio_aac_Tab\there", "audio_aac_Tab-here"),
+    ///     ("audio_aac_Español ✓", "audio_aac_Español ✓"),
+    ///     ("subtitle_subs_English (CC)", "subtitle_subs_English (CC)"),
+    /// ];
+    /// assert_eq!(ids, golden.map(|(d, f)| (d.to_string(), f.to_string())));
+    /// ```
+    pub fn file_id(&self) -> String {
+        const RESERVED_CHARS: &[char] = &['/', '\\', '<', '>', ':', '"', '|', '?', '*'];
+        self.to_string()
+            .chars()
+            .map(|c| {
+                if c.is_control() || RESERVED_CHARS.contains(&c) {
+                    '-'
+                } else {
+                    c
+                }
+            })
+            .collect()
+    }
+
+    /// Stream with the given identifier, e.g. main or audio_stereo_English
+    ///
+    /// Group ids and names can't be told apart in identifiers, so the whole rest of the
+    /// identifier becomes the name, which still displays as the same identifier. Languages and
+    /// channels aren't part of identifiers and are left empty, and accessibility flags unset.
+    pub fn from_id(id: &str) -> Option<Self> {
+        let (kind, name) = match id.split_once('_') {
+            Some((kind, name)) => (kind, name.to_owned()),
+            None if id == "main" => return Some(Self::Main),
+            None => return None,
+        };
+        let group_id = String::new();
+        match kind {
+            "video" => Some(Self::Video {
+                group_id,
+                name,
+                lang: None,
+            }),
+            "audio" => Some(Self::Audio {
+                group_id,
+                name,
+                lang: None,
+                channels: None,
+                describes_video: false,
+            }),
+            "subtitle" => Some(Self::Subtitle {
+                group_id,
+                name,
+                lang: None,
+                forced: false,
+            }),
+            _ => None,
+        }
+    }
 }
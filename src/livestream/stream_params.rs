@@ -0,0 +1,225 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use std::process::Stdio;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::process;
+use tracing::{event, Level};
+
+use super::{RemoteData, Segment, Stream};
+
+/// Codecs and resolution of the tracks of a stream
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct StreamParams {
+    pub codecs: Vec<String>,
+    pub resolution: Option<(u64, u64)>,
+}
+
+impl StreamParams {
+    /// Probe segment data with ffprobe, returns None if the data can't be probed
+    pub async fn probe(data: Vec<u8>) -> Result<Option<Self>> {
+        #[derive(Deserialize)]
+        struct FFProbeOutput {
+            streams: Vec<FFProbeStream>,
+        }
+        #[derive(Deserialize)]
+        struct FFProbeStream {
+            codec_name: Option<String>,
+            width: Option<u64>,
+            height: Option<u64>,
+        }
+
+        // Call ffprobe to list tracks
+        let mut cmd = process::Command::new("ffprobe");
+        cmd.arg("-loglevel")
+            .arg("quiet")
+            .arg("-show_entries")
+            .arg("stream=codec_name,width,height")
+            .arg("-print_format")
+            .arg("json")
+            .arg("-")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true);
+        event!(Level::TRACE, "{:?}", cmd);
+        let mut child = cmd.spawn()?;
+
+        let mut stdin = child
+            .stdin
+            .take()
+            .ok_or_else(|| anyhow::anyhow!("Can't open ffprobe stdin"))?;
+        tokio::spawn(async move { stdin.write_all(&data).await });
+
+        let output = child.wait_with_output().await?;
+        if !output.status.success() {
+            event!(Level::TRACE, "ffprobe probe tracks failed");
+            return Ok(None);
+        }
+
+        let parsed: FFProbeOutput = match serde_json::from_slice(&output.stdout) {
+            Ok(p) => p,
+            Err(e) => {
+                event!(Level::TRACE, "Unable to parse ffprobe output: {:?}", e);
+                return Ok(None);
+            }
+        };
+        if parsed.streams.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            codecs: parsed
+                .streams
+                .iter()
+                .filter_map(|s| s.codec_name.clone())
+                .collect(),
+            resolution: parsed
+                .streams
+                .iter()
+                .find_map(|s| Some((s.width?, s.height?))),
+        }))
+    }
+}
+
+impl Display for StreamParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.codecs.join(","))?;
+        if let Some((w, h)) = self.resolution {
+            write!(f, " {}x{}", w, h)?;
+        }
+        Ok(())
+    }
+}
+
+/// Change of stream parameters between two groups of segments
+#[derive(Clone, Debug)]
+pub struct ParamsChange {
+    pub stream: Stream,
+    /// First segment with the new parameters
+    pub discon_seq: u64,
+    pub seq: u64,
+    /// Change is at a discontinuity rather than at a new initialization section
+    pub at_discontinuity: bool,
+    pub from: StreamParams,
+    pub to: StreamParams,
+}
+
+/// Segments of a stream sharing a discontinuity sequence and initialization section
+#[derive(Debug)]
+struct Group {
+    first_seq: u64,
+    params: Option<StreamParams>,
+}
+
+/// Track parameters of the first segment of each discontinuity and initialization section to
+/// detect encoding changes
+#[derive(Default, Debug)]
+pub struct ParamsTracker {
+    groups: HashMap<Stream, HashMap<(u64, Option<RemoteData>), Group>>,
+}
+
+impl ParamsTracker {
+    /// Note a saved segment, returns true if it is the first of its group and should be probed
+    pub fn observe(&mut self, stream: &Stream, segment: &Segment) -> bool {
+        let key = (segment.discon_seq, segment.initialization.clone());
+        let groups = self.groups.entry(stream.clone()).or_default();
+        match groups.get_mut(&key) {
+            Some(g) => {
+                g.first_seq = g.first_seq.min(segment.seq);
+                false
+            }
+            None => {
+                groups.insert(
+                    key,
+                    Group {
+                        first_seq: segment.seq,
+                        params: None,
+                    },
+                );
+                true
+            }
+        }
+    }
+
+    /// Set parameters of the group of a segment, returns changes to neighbouring groups
+    pub fn set_params(
+        &mut self,
+        stream: &Stream,
+        segment: &Segment,
+        params: StreamParams,
+    ) -> Vec<ParamsChange> {
+        let key = (segment.discon_seq, segment.initialization.clone());
+        if let Some(g) = self
+            .groups
+            .get_mut(stream)
+            .and_then(|groups| groups.get_mut(&key))
+        {
+            g.params = Some(params);
+        }
+
+        // Changes into this group and into the next probed group
+        let pos = (segment.discon_seq, self.groups[stream][&key].first_seq);
+        let next = self.next_probed(stream, pos);
+        self.stream_changes(stream)
+            .into_iter()
+            .filter(|c| {
+                let change_pos = Some((c.discon_seq, c.seq));
+                change_pos == Some(pos) || change_pos == next
+            })
+            .collect()
+    }
+
+    /// All detected changes in order
+    pub fn changes(&self) -> Vec<ParamsChange> {
+        self.groups
+            .keys()
+            .flat_map(|s| self.stream_changes(s))
+            .collect()
+    }
+
+    /// Distinct parameters of a stream in playlist order
+    pub fn distinct(&self, stream: &Stream) -> Vec<&StreamParams> {
+        let mut distinct = Vec::new();
+        for p in self.ordered(stream).into_values() {
+            if !distinct.contains(&p) {
+                distinct.push(p);
+            }
+        }
+        distinct
+    }
+
+    /// Probed groups of a stream in playlist order
+    fn ordered(&self, stream: &Stream) -> BTreeMap<(u64, u64), &StreamParams> {
+        self.groups
+            .get(stream)
+            .into_iter()
+            .flatten()
+            .filter_map(|((d, _), g)| Some(((*d, g.first_seq), g.params.as_ref()?)))
+            .collect()
+    }
+
+    fn next_probed(&self, stream: &Stream, pos: (u64, u64)) -> Option<(u64, u64)> {
+        self.ordered(stream)
+            .range((pos.0, pos.1 + 1)..)
+            .next()
+            .map(|(k, _)| *k)
+    }
+
+    fn stream_changes(&self, stream: &Stream) -> Vec<ParamsChange> {
+        let ordered: Vec<_> = self.ordered(stream).into_iter().collect();
+        ordered
+            .windows(2)
+            .filter(|w| w[0].1 != w[1].1)
+            .map(|w| ParamsChange {
+                stream: stream.clone(),
+                discon_seq: w[1].0 .0,
+                seq: w[1].0 .1,
+                at_discontinuity: w[0].0 .0 != w[1].0 .0,
+                from: w[0].1.clone(),
+                to: w[1].1.clone(),
+            })
+            .collect()
+    }
+}
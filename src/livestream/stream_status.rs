@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+
+use tracing::{event, Level};
+
+use super::{Segment, Stream};
+
+/// Discontinuity sequence and media sequence of a segment
+type SegmentKey = (u64, u64);
+
+/// How the playlist fetcher of a stream ended
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum StreamEnd {
+    /// The playlist ended with EXT-X-ENDLIST
+    Completed,
+    /// The download was stopped or reached a limit before the playlist ended
+    Stopped,
+    /// The playlist fetcher failed with an error
+    Failed(String),
+}
+
+impl fmt::Display for StreamEnd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Completed => write!(f, "completed"),
+            Self::Stopped => write!(f, "stopped"),
+            Self::Failed(e) => write!(f, "failed: {}", e),
+        }
+    }
+}
+
+/// Final status of a stream
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct StreamStatus {
+    pub stream: Stream,
+    pub end: StreamEnd,
+    /// Media sequence number of the last saved segment
+    pub last_seq: Option<u64>,
+}
+
+impl fmt::Display for StreamStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Stream {} {}", self.stream, self.end)?;
+        match self.last_seq {
+            Some(seq) => write!(f, ", last saved segment {}", seq),
+            None => write!(f, ", no segments saved"),
+        }
+    }
+}
+
+#[derive(Default, Debug)]
+struct Inner {
+    last_saved: HashMap<Stream, SegmentKey>,
+    /// Ends of the streams in the order their fetchers were joined
+    ends: Vec<(Stream, StreamEnd)>,
+}
+
+/// Last saved segment and end of each stream, reported once the download ends
+#[derive(Default, Debug)]
+pub struct StreamStatuses(Mutex<Inner>);
+
+impl StreamStatuses {
+    /// Record a saved segment
+    pub fn saved(&self, stream: &Stream, segment: &Segment) {
+        let key = (segment.discon_seq, segment.seq);
+        let mut inner = self.0.lock().unwrap();
+        let last = inner.last_saved.entry(stream.clone()).or_insert(key);
+        *last = key.max(*last);
+    }
+
+    /// Record how the fetcher of a stream ended
+    pub fn ended(&self, stream: &Stream, end: StreamEnd) {
+        self.0.lock().unwrap().ends.push((stream.clone(), end));
+    }
+
+    /// Status of each stream whose fetcher ended
+    pub fn statuses(&self) -> Vec<StreamStatus> {
+        let inner = self.0.lock().unwrap();
+        inner
+            .ends
+            .iter()
+            .map(|(stream, end)| StreamStatus {
+                stream: stream.clone(),
+                end: end.clone(),
+                last_seq: inner.last_saved.get(stream).map(|(_, seq)| *seq),
+            })
+            .collect()
+    }
+
+    /// Log the status of each stream, as a warning if it failed
+    pub fn log(&self) {
+        for status in self.statuses() {
+            match status.end {
+                StreamEnd::Failed(_) => event!(Level::WARN, "{}", status),
+                _ => event!(Level::INFO, "{}", status),
+            }
+        }
+    }
+}
@@ -0,0 +1,346 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use reqwest::{Request, Response};
+use reqwest_middleware::{Middleware, Next};
+use serde::Serialize;
+use task_local_extensions::Extensions;
+use tracing::{event, Level};
+
+use super::EVENT_TARGET;
+
+/// Kind of resource a response body belongs to
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RequestCategory {
+    Playlist,
+    Key,
+    /// Media segments and initialization sections
+    Segment,
+    Other,
+}
+
+impl RequestCategory {
+    const ALL: [Self; 4] = [Self::Segment, Self::Playlist, Self::Key, Self::Other];
+
+    fn index(self) -> usize {
+        match self {
+            Self::Playlist => 0,
+            Self::Key => 1,
+            Self::Segment => 2,
+            Self::Other => 3,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Playlist => "playlists",
+            Self::Key => "keys",
+            Self::Segment => "segments",
+            Self::Other => "other",
+        }
+    }
+}
+
+#[derive(Default, Debug)]
+struct Counters {
+    requests: AtomicU64,
+    /// Responses by status class, index 0 counts requests that failed without a response
+    responses: [AtomicU64; 6],
+    /// Body bytes received by request category
+    received: [AtomicU64; 4],
+    wasted: AtomicU64,
+    saved: AtomicU64,
+}
+
+/// Requests sent and bytes received by the HTTP client, compared to the bytes of media saved
+///
+/// Requests and responses are counted by [`TrafficMiddleware`], which sees every attempt of the
+/// retry middleware. Body bytes are counted while the existing code reads them, with a
+/// [`BodyCounter`] per response.
+#[derive(Clone, Default, Debug)]
+pub struct TrafficStats(Arc<Counters>);
+
+/// Snapshot of [`TrafficStats`]
+#[derive(Clone, Default, PartialEq, Eq, Serialize, Debug)]
+pub struct TrafficSummary {
+    pub requests: u64,
+    /// Responses by status class 1xx to 5xx
+    pub responses: [u64; 5],
+    /// Requests that failed without a response
+    pub failed: u64,
+    pub playlist_bytes: u64,
+    pub key_bytes: u64,
+    pub segment_bytes: u64,
+    pub other_bytes: u64,
+    /// Bytes received and thrown away because their download failed or was aborted
+    pub wasted_bytes: u64,
+    /// Bytes of segments and initializations saved
+    pub saved_bytes: u64,
+}
+
+impl TrafficStats {
+    /// Middleware counting requests and their responses
+    pub fn middleware(&self) -> TrafficMiddleware {
+        TrafficMiddleware(self.clone())
+    }
+
+    /// Counter of the body of a response, see [`BodyCounter`]
+    pub fn body(&self, category: RequestCategory) -> BodyCounter {
+        BodyCounter {
+            stats: self.clone(),
+            category,
+            bytes: 0,
+            kept: false,
+        }
+    }
+
+    /// Count a body that was read at once
+    pub fn received(&self, category: RequestCategory, bytes: usize) {
+        let mut body = self.body(category);
+        body.add(bytes);
+        body.finish();
+    }
+
+    /// Count bytes received and thrown away later, e.g. parts of a response outside of the
+    /// requested range
+    pub fn wasted(&self, bytes: u64) {
+        self.0.wasted.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Count bytes of media saved
+    pub fn saved(&self, bytes: u64) {
+        self.0.saved.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Counts so far
+    ///
+    /// ```
+    /// use livestream_dl::livestream::{RequestCategory, TrafficStats};
+    ///
+    /// let stats = TrafficStats::default();
+    /// let mut body = stats.body(RequestCategory::Segment);
+    /// body.add(1000);
+    /// body.finish();
+    /// stats.saved(1000);
+    ///
+    /// // Bodies dropped before they are finished were thrown away
+    /// let mut body = stats.body(RequestCategory::Segment);
+    /// body.add(300);
+    /// drop(body);
+    /// stats.body(RequestCategory::Playlist).add(200);
+    ///
+    /// let summary = stats.summary();
+    /// assert_eq!(summary.segment_bytes, 1300);
+    /// assert_eq!(summary.received_bytes(), 1500);
+    /// assert_eq!(summary.wasted_bytes, 500);
+    /// assert_eq!(summary.saved_bytes, 1000);
+    /// assert_eq!(
+    ///     summary.to_string(),
+    ///     "0 requests, received 1.46 KiB (segments 87%, playlists 13%), 500 B wasted, \
+    ///      1000 B of media saved"
+    /// );
+    /// ```
+    pub fn summary(&self) -> TrafficSummary {
+        let c = &self.0;
+        let load = |a: &AtomicU64| a.load(Ordering::Relaxed);
+        let received = |category: RequestCategory| load(&c.received[category.index()]);
+        TrafficSummary {
+            requests: load(&c.requests),
+            responses: [1, 2, 3, 4, 5].map(|i| load(&c.responses[i])),
+            failed: load(&c.responses[0]),
+            playlist_bytes: received(RequestCategory::Playlist),
+            key_bytes: received(RequestCategory::Key),
+            segment_bytes: received(RequestCategory::Segment),
+            other_bytes: received(RequestCategory::Other),
+            wasted_bytes: load(&c.wasted),
+            saved_bytes: load(&c.saved),
+        }
+    }
+
+    /// Record the counts so far in the event log
+    pub fn record(&self) {
+        let s = self.summary();
+        event!(
+            target: EVENT_TARGET,
+            Level::DEBUG,
+            requests = s.requests,
+            failed = s.failed,
+            received_bytes = s.received_bytes(),
+            playlist_bytes = s.playlist_bytes,
+            key_bytes = s.key_bytes,
+            segment_bytes = s.segment_bytes,
+            wasted_bytes = s.wasted_bytes,
+            saved_bytes = s.saved_bytes,
+            "traffic_stats"
+        );
+        event!(Level::DEBUG, "Traffic so far: {}", s);
+    }
+
+    /// Log the final counts, and record them in the event log
+    pub fn log(&self) {
+        self.record();
+        let s = self.summary();
+        if s.requests == 0 {
+            return;
+        }
+        let responses = (1..=5)
+            .zip(s.responses)
+            .filter(|(_, n)| *n > 0)
+            .map(|(class, n)| format!("{} {}xx", n, class))
+            .chain((s.failed > 0).then(|| format!("{} failed without response", s.failed)))
+            .collect::<Vec<_>>()
+            .join(", ");
+        event!(
+            Level::INFO,
+            "Sent {} HTTP requests, responses: {}",
+            s.requests,
+            responses
+        );
+        let received = RequestCategory::ALL
+            .iter()
+            .map(|c| (c, s.received(*c)))
+            .filter(|(_, bytes)| *bytes > 0)
+            .map(|(c, bytes)| {
+                let percent = s.percent(bytes);
+                format!("{} {} ({:.1}%)", c.name(), format_bytes(bytes), percent)
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        event!(
+            Level::INFO,
+            "Received {}: {}",
+            format_bytes(s.received_bytes()),
+            received
+        );
+        event!(
+            Level::INFO,
+            "Saved {} of media, {} ({:.1}%) of received data was thrown away by failed or \
+             aborted downloads",
+            format_bytes(s.saved_bytes),
+            format_bytes(s.wasted_bytes),
+            s.percent(s.wasted_bytes)
+        );
+    }
+
+    fn count_response(&self, status_class: usize) {
+        self.0.responses[status_class.min(5)].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl TrafficSummary {
+    /// Body bytes received of all categories
+    pub fn received_bytes(&self) -> u64 {
+        self.playlist_bytes + self.key_bytes + self.segment_bytes + self.other_bytes
+    }
+
+    fn received(&self, category: RequestCategory) -> u64 {
+        match category {
+            RequestCategory::Playlist => self.playlist_bytes,
+            RequestCategory::Key => self.key_bytes,
+            RequestCategory::Segment => self.segment_bytes,
+            RequestCategory::Other => self.other_bytes,
+        }
+    }
+
+    /// Percentage of the received bytes
+    fn percent(&self, bytes: u64) -> f64 {
+        match self.received_bytes() {
+            0 => 0.0,
+            total => bytes as f64 / total as f64 * 100.0,
+        }
+    }
+}
+
+impl fmt::Display for TrafficSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let shares = RequestCategory::ALL
+            .iter()
+            .map(|c| (c, self.received(*c)))
+            .filter(|(_, bytes)| *bytes > 0)
+            .map(|(c, bytes)| format!("{} {:.0}%", c.name(), self.percent(bytes)))
+            .collect::<Vec<_>>()
+            .join(", ");
+        write!(
+            f,
+            "{} requests, received {} ({}), {} wasted, {} of media saved",
+            self.requests,
+            format_bytes(self.received_bytes()),
+            shares,
+            format_bytes(self.wasted_bytes),
+            format_bytes(self.saved_bytes)
+        )
+    }
+}
+
+/// Bytes of a response body received so far, counted as wasted if the counter is dropped before
+/// the body was finished, e.g. because reading it failed or the download was aborted
+#[derive(Debug)]
+pub struct BodyCounter {
+    stats: TrafficStats,
+    category: RequestCategory,
+    bytes: u64,
+    kept: bool,
+}
+
+impl BodyCounter {
+    /// Count a chunk of the body
+    pub fn add(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+        self.stats.0.received[self.category.index()].fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Keep the bytes of the body
+    pub fn finish(mut self) {
+        self.kept = true;
+    }
+}
+
+impl Drop for BodyCounter {
+    fn drop(&mut self) {
+        if !self.kept {
+            self.stats.wasted(self.bytes);
+        }
+    }
+}
+
+/// Middleware counting requests and responses by status class in [`TrafficStats`]
+#[derive(Debug)]
+pub struct TrafficMiddleware(TrafficStats);
+
+#[async_trait]
+impl Middleware for TrafficMiddleware {
+    async fn handle(
+        &self,
+        req: Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<Response> {
+        self.0 .0.requests.fetch_add(1, Ordering::Relaxed);
+        let res = next.run(req, extensions).await;
+        match &res {
+            Ok(resp) => self.0.count_response(resp.status().as_u16() as usize / 100),
+            Err(_) => self.0.count_response(0),
+        }
+        res
+    }
+}
+
+/// Size in bytes, or in KiB, MiB or GiB once it is at least one of them
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 3] = ["KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = None;
+    for u in UNITS {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = Some(u);
+    }
+    match unit {
+        Some(u) => format!("{:.2} {}", size, u),
+        None => format!("{} B", bytes),
+    }
+}
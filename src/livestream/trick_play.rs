@@ -0,0 +1,73 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use m3u8_rs::MediaPlaylist;
+use reqwest::Url;
+use tracing::{event, Level};
+
+use super::{Stream, EVENT_TARGET};
+
+/// Streams whose media playlists are marked EXT-X-I-FRAMES-ONLY
+///
+/// Segments of these playlists are byte ranges holding a single keyframe each, meant for trick
+/// play like seek previews. Muxing them like normal video produces a broken output, so they are
+/// left out of remuxing and their segments are kept as downloaded.
+#[derive(Clone, Default, Debug)]
+pub(crate) struct TrickPlayStreams(Arc<Mutex<HashSet<Stream>>>);
+
+impl TrickPlayStreams {
+    /// Record stream if playlist, fetched from url, is I-frames only
+    pub fn observe(&self, stream: &Stream, playlist: &MediaPlaylist, url: &Url) {
+        if !playlist.i_frames_only || !self.0.lock().unwrap().insert(stream.clone()) {
+            return;
+        }
+        event!(
+            target: EVENT_TARGET,
+            Level::DEBUG,
+            stream = %stream,
+            url = %url,
+            "i_frames_only"
+        );
+        event!(
+            Level::WARN,
+            "Playlist of stream {} is I-frames only, its segments are single keyframes for trick \
+             play and won't be remuxed: {}",
+            stream,
+            url
+        );
+    }
+
+    /// Check if stream is I-frames only
+    pub fn contains(&self, stream: &Stream) -> bool {
+        self.0.lock().unwrap().contains(stream)
+    }
+
+    /// Log the streams left out of remuxing
+    pub fn log_excluded(&self) {
+        for stream in self.0.lock().unwrap().iter() {
+            event!(
+                Level::WARN,
+                "Not remuxing I-frames only stream {}, its segments are kept as downloaded",
+                stream
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn i_frames_only_streams_are_recorded() {
+        let url = "https://example.com/iframes.m3u8".parse().unwrap();
+        let playlist = b"#EXTM3U\n#EXT-X-TARGETDURATION:4\n#EXT-X-I-FRAMES-ONLY\n\
+            #EXTINF:4,\n#EXT-X-BYTERANGE:9024@376\n0.ts\n#EXT-X-ENDLIST\n";
+        let playlist = m3u8_rs::parse_media_playlist_res(playlist).unwrap();
+
+        let trick_play = TrickPlayStreams::default();
+        assert!(!trick_play.contains(&Stream::Main));
+        trick_play.observe(&Stream::Main, &playlist, &url);
+        assert!(trick_play.contains(&Stream::Main));
+    }
+}
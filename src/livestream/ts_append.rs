@@ -0,0 +1,255 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::process;
+use tracing::{event, Level};
+
+use super::{MediaFormat, Segment};
+
+/// How long a segment waits for missing earlier segments before they are skipped
+const REORDER_WAIT: Duration = Duration::from_secs(10);
+
+/// Interval between syncs of the appended file to disk
+const SYNC_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Appends saved segments in order to a single growing MPEG-TS file, which other programs can
+/// read while it's written, e.g. with tail -f
+///
+/// Segments completing out of order wait for earlier ones. Missing segments, including any before
+/// the first one, are skipped once a later segment waited for REORDER_WAIT or more than window
+/// segments wait, earlier segments arriving after that are dropped. Only whole segments are
+/// written, a failed write is truncated away.
+#[derive(Debug)]
+pub(crate) struct TsAppender {
+    path: PathBuf,
+    file: File,
+    /// Convert segments of other formats to MPEG-TS with ffmpeg instead of failing
+    transcode: bool,
+    window: usize,
+    /// Segments waiting for earlier ones by discontinuity and media sequence, with contents and
+    /// arrival time
+    pending: BTreeMap<(u64, u64), (Segment, Vec<u8>, Instant)>,
+    /// Media sequence of the next segment to append, if any was appended
+    next: Option<u64>,
+    last_sync: Instant,
+    /// Set once appending failed, no more segments are appended
+    failed: bool,
+}
+
+impl TsAppender {
+    /// Open path for appending, creating it if needed
+    pub async fn create(path: impl AsRef<Path>, transcode: bool, window: usize) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path.as_ref())
+            .await?;
+        Ok(Self {
+            path: path.as_ref().to_path_buf(),
+            file,
+            transcode,
+            window,
+            pending: BTreeMap::new(),
+            next: None,
+            last_sync: Instant::now(),
+            failed: false,
+        })
+    }
+
+    /// Append a saved segment, prefixed with its initialization, once earlier segments are
+    /// appended
+    pub async fn push(&mut self, segment: &Segment, contents: Vec<u8>) {
+        if self.failed {
+            return;
+        }
+        if self.next.is_some_and(|n| segment.seq < n) {
+            event!(
+                Level::WARN,
+                "Segment {} arrived after later segments were appended to {:?}, not appending it",
+                segment.url(),
+                self.path
+            );
+            return;
+        }
+        self.pending.insert(
+            (segment.discon_seq, segment.seq),
+            (segment.clone(), contents, Instant::now()),
+        );
+        if let Err(e) = self.flush(false).await {
+            event!(
+                Level::ERROR,
+                "Stopped appending segments to {:?}: {:#}",
+                self.path,
+                e
+            );
+            self.failed = true;
+        }
+    }
+
+    /// Append all waiting segments and sync the file to disk
+    pub async fn finish(&mut self) -> Result<()> {
+        if !self.failed {
+            self.flush(true).await?;
+        }
+        self.file.sync_all().await?;
+        Ok(())
+    }
+
+    /// Append waiting segments in order while they are next or can't wait any longer, or all of
+    /// them if all is set
+    async fn flush(&mut self, all: bool) -> Result<()> {
+        loop {
+            let crowded = self.pending.len() > self.window;
+            let entry = match self.pending.first_entry() {
+                Some(e) => e,
+                None => break,
+            };
+            let (segment, _, arrived) = entry.get();
+            let is_next = self.next.is_some_and(|n| segment.seq == n);
+            let overdue = crowded || arrived.elapsed() >= REORDER_WAIT;
+            if !(all || is_next || overdue) {
+                break;
+            }
+            let (segment, contents, _) = entry.remove();
+            if let Some(n) = self.next.filter(|n| segment.seq > *n) {
+                event!(
+                    Level::WARN,
+                    "Skipping {} missing segments in {:?}",
+                    segment.seq - n,
+                    self.path
+                );
+            }
+            self.append(&segment, contents).await?;
+            self.next = Some(segment.seq + 1);
+        }
+
+        if self.last_sync.elapsed() >= SYNC_INTERVAL {
+            self.file.sync_data().await?;
+            self.last_sync = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Append the contents of a segment in one piece, converted to MPEG-TS if needed
+    async fn append(&mut self, segment: &Segment, contents: Vec<u8>) -> Result<()> {
+        let contents = match segment.format {
+            MediaFormat::MpegTs => contents,
+            _ if self.transcode => to_mpegts(contents).await?,
+            ref f => {
+                return Err(anyhow::anyhow!(
+                    "--append-ts only supports MPEG-TS segments, got {:?} segments, convert them \
+                     with --append-ts-transcode",
+                    f
+                ))
+            }
+        };
+
+        // Cut off a partially appended segment
+        let len = self.file.metadata().await?.len();
+        if let Err(e) = self.file.write_all(&contents).await {
+            self.file.set_len(len).await?;
+            return Err(e.into());
+        }
+        self.file.flush().await?;
+        event!(
+            Level::TRACE,
+            "Appended {} to {:?}",
+            segment.url(),
+            self.path
+        );
+        Ok(())
+    }
+}
+
+/// Convert a segment to MPEG-TS with ffmpeg, keeping its timestamps so that converted segments
+/// play continuously
+async fn to_mpegts(data: Vec<u8>) -> Result<Vec<u8>> {
+    let mut cmd = process::Command::new("ffmpeg");
+    cmd.arg("-loglevel")
+        .arg("error")
+        .arg("-i")
+        .arg("-")
+        .arg("-map")
+        .arg("0")
+        .arg("-c")
+        .arg("copy")
+        .arg("-copyts")
+        .arg("-muxdelay")
+        .arg("0")
+        .arg("-f")
+        .arg("mpegts")
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    event!(Level::TRACE, "{:?}", cmd);
+    let mut child = cmd.spawn()?;
+
+    let mut stdin = child
+        .stdin
+        .take()
+        .ok_or_else(|| anyhow::anyhow!("Can't open ffmpeg stdin"))?;
+    tokio::spawn(async move { stdin.write_all(&data).await });
+
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "ffmpeg failed to convert segment to MPEG-TS: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::livestream::RemoteData;
+
+    fn segment(seq: u64) -> Segment {
+        Segment {
+            data: RemoteData::new(
+                format!("http://localhost/{}.ts", seq).parse().unwrap(),
+                None,
+            ),
+            discon_seq: 0,
+            seq,
+            format: MediaFormat::MpegTs,
+            initialization: None,
+            program_date_time: None,
+            duration: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn segments_are_appended_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("live.ts");
+        let mut appender = TsAppender::create(&path, false, 2).await.unwrap();
+        let read = || std::fs::read(&path).unwrap();
+
+        // Appending starts once more than 2 segments wait, or the first one waited a while
+        appender.push(&segment(1), b"1".to_vec()).await;
+        appender.push(&segment(0), b"0".to_vec()).await;
+        assert_eq!(read(), b"");
+        appender.push(&segment(3), b"3".to_vec()).await;
+        assert_eq!(read(), b"01");
+        appender.push(&segment(2), b"2".to_vec()).await;
+        assert_eq!(read(), b"0123");
+
+        // 4 is skipped once more than 2 later segments wait for it, and dropped if it arrives
+        appender.push(&segment(5), b"5".to_vec()).await;
+        appender.push(&segment(6), b"6".to_vec()).await;
+        assert_eq!(read(), b"0123");
+        appender.push(&segment(7), b"7".to_vec()).await;
+        appender.push(&segment(4), b"4".to_vec()).await;
+        appender.finish().await.unwrap();
+        assert_eq!(read(), b"0123567");
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+
+/// Size of MPEG-TS packets
+const PACKET_SIZE: usize = 188;
+/// First byte of every MPEG-TS packet
+const SYNC_BYTE: u8 = 0x47;
+/// PID of null packets, which have no meaningful continuity counter
+const NULL_PID: u16 = 0x1fff;
+
+/// Continuity problem found in an MPEG-TS stream
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum ContinuityError {
+    /// Continuity counter of a PID skipped values, packets were probably dropped
+    Counter {
+        pid: u16,
+        packet: u64,
+        expected: u8,
+        found: u8,
+    },
+    /// Packet doesn't start with the sync byte, rest of the data is not checked
+    LostSync { packet: u64 },
+}
+
+/// Check the 4-bit continuity counters of each PID across consecutive MPEG-TS data
+#[derive(Default, Debug)]
+pub struct ContinuityChecker {
+    /// Last counter and whether the packet carried a payload, by PID
+    counters: HashMap<u16, (u8, bool)>,
+    packets: u64,
+}
+
+impl ContinuityChecker {
+    /// Number of packets checked so far
+    pub fn packets(&self) -> u64 {
+        self.packets
+    }
+
+    /// Forget previous counters, e.g. at a discontinuity where counters may restart
+    pub fn reset(&mut self) {
+        self.counters.clear();
+    }
+
+    /// Check packets following the previously checked data, returns the problems found
+    pub fn check(&mut self, data: &[u8]) -> Vec<ContinuityError> {
+        let mut errors = Vec::new();
+        for packet in data.chunks(PACKET_SIZE) {
+            let index = self.packets;
+            self.packets += 1;
+
+            if packet.len() < 4 || packet[0] != SYNC_BYTE {
+                errors.push(ContinuityError::LostSync { packet: index });
+                break;
+            }
+
+            let pid = (u16::from(packet[1] & 0x1f) << 8) | u16::from(packet[2]);
+            if pid == NULL_PID {
+                continue;
+            }
+            let adaptation_field_control = (packet[3] >> 4) & 0x3;
+            let has_payload = adaptation_field_control & 0x1 != 0;
+            let counter = packet[3] & 0x0f;
+
+            // Counters may jump where the adaptation field sets the discontinuity indicator
+            let discontinuity_indicator = adaptation_field_control & 0x2 != 0
+                && packet.len() > 5
+                && packet[4] > 0
+                && packet[5] & 0x80 != 0;
+
+            if let Some(&(last, last_had_payload)) = self.counters.get(&pid) {
+                // Counters only increment on packets with payload, duplicate packets are allowed
+                let expected = if has_payload { (last + 1) & 0x0f } else { last };
+                let duplicate = has_payload && last_had_payload && counter == last;
+                if !discontinuity_indicator && counter != expected && !duplicate {
+                    errors.push(ContinuityError::Counter {
+                        pid,
+                        packet: index,
+                        expected,
+                        found: counter,
+                    });
+                }
+            }
+            self.counters.insert(pid, (counter, has_payload));
+        }
+
+        errors
+    }
+}
@@ -1,11 +1,80 @@
-use anyhow::Result;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use m3u8_rs::{MasterPlaylist, VariantStream};
 use reqwest::Url;
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::cli::SegmentsDirNaming;
 
 /// Create absolute url from a possibly relative url and a base url if needed
 pub fn make_absolute_url(base: &Url, url: &str) -> Result<Url> {
     match Url::parse(url) {
         Ok(u) => Ok(u),
-        Err(e) if e == url::ParseError::RelativeUrlWithoutBase => Ok(base.join(url)?),
+        Err(url::ParseError::RelativeUrlWithoutBase) => Ok(base.join(url)?),
         Err(e) => Err(e.into()),
     }
 }
+
+/// Highest bitrate variant of a master playlist, I-frame variants are only for trick play
+pub fn best_variant(playlist: &MasterPlaylist) -> Option<&VariantStream> {
+    playlist
+        .variants
+        .iter()
+        .filter(|v| !v.is_i_frame)
+        .filter_map(|v| Some((v.bandwidth.parse::<u64>().ok()?, v)))
+        .max_by_key(|(x, _)| *x)
+        .map(|(_, v)| v)
+}
+
+/// Name of the segments directory shared by all downloads into an output directory
+pub const SHARED_SEGMENTS_DIR: &str = "segments";
+
+/// Name of the segments directory of a download of the playlist at url
+///
+/// The query is left out of the hashed URL, so that resuming with a newly signed URL of the same
+/// playlist finds its segments.
+pub fn segments_dir_name(naming: SegmentsDirNaming, url: &Url) -> String {
+    match naming {
+        SegmentsDirNaming::Shared => SHARED_SEGMENTS_DIR.to_string(),
+        SegmentsDirNaming::Url => {
+            let mut url = url.clone();
+            url.set_query(None);
+            url.set_fragment(None);
+            format!(
+                "{}-{:016x}",
+                SHARED_SEGMENTS_DIR,
+                xxh3_64(url.as_str().as_bytes())
+            )
+        }
+        SegmentsDirNaming::Run => {
+            format!("{}-{:08x}", SHARED_SEGMENTS_DIR, rand::random::<u32>())
+        }
+    }
+}
+
+/// Create the output directory and check that files can be written to it, returns its canonical
+/// path
+pub fn prepare_output_dir(output: &Path) -> Result<PathBuf> {
+    const PROBE_FILE_NAME: &str = ".livestream-dl-write-test";
+
+    let check = || -> std::io::Result<PathBuf> {
+        std::fs::create_dir_all(output)?;
+        let probe = output.join(PROBE_FILE_NAME);
+        std::fs::write(&probe, b"")?;
+        std::fs::remove_file(&probe)?;
+        std::fs::canonicalize(output)
+    };
+
+    check().with_context(|| {
+        let cwd = std::env::current_dir()
+            .map(|d| d.display().to_string())
+            .unwrap_or_else(|_| "unknown".to_owned());
+        format!(
+            "output directory {:?} is not writable. Check that its parent directories exist or can \
+             be created, that you have write permission, and that the filesystem is not read-only \
+             or full. Relative paths are resolved against the working directory {:?}",
+            output, cwd
+        )
+    })
+}
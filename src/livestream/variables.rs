@@ -0,0 +1,164 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use anyhow::Result;
+use reqwest::Url;
+
+const DEFINE_TAG: &str = "#EXT-X-DEFINE:";
+
+/// Variables defined by the EXT-X-DEFINE tags of a playlist
+#[derive(Clone, Default, Debug)]
+pub(crate) struct Variables(HashMap<String, String>);
+
+impl Variables {
+    /// Define the variables of a playlist fetched from url and substitute them into its other
+    /// lines, importing variables from parent, the variables of its master playlist
+    ///
+    /// Playlists without EXT-X-DEFINE tags are returned unchanged.
+    pub fn substitute<'a>(
+        playlist: &'a [u8],
+        url: &Url,
+        parent: &Variables,
+    ) -> Result<(Cow<'a, [u8]>, Self)> {
+        let mut variables = Self::default();
+        let text = match std::str::from_utf8(playlist) {
+            Ok(t) if t.contains(DEFINE_TAG) => t,
+            _ => return Ok((Cow::Borrowed(playlist), variables)),
+        };
+
+        let mut substituted = String::with_capacity(text.len());
+        for line in text.lines() {
+            match line.strip_prefix(DEFINE_TAG) {
+                Some(attributes) => variables.define(attributes, url, parent)?,
+                None => {
+                    substituted.push_str(&variables.replace(line)?);
+                    substituted.push('\n');
+                }
+            }
+        }
+
+        Ok((Cow::Owned(substituted.into_bytes()), variables))
+    }
+
+    /// Define a variable from the attributes of an EXT-X-DEFINE tag
+    fn define(&mut self, attributes: &str, url: &Url, parent: &Variables) -> Result<()> {
+        let parsed = parse_attributes(attributes);
+        let get = |key| parsed.iter().find(|(k, _)| *k == key).map(|(_, v)| *v);
+
+        let (name, value) = match (get("NAME"), get("VALUE"), get("IMPORT"), get("QUERYPARAM")) {
+            (Some(name), Some(value), None, None) => (name, value.to_owned()),
+            (None, None, Some(name), None) => {
+                let value = parent.0.get(name).ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "Imported variable {:?} is not defined by the master playlist",
+                        name
+                    )
+                })?;
+                (name, value.clone())
+            }
+            (None, None, None, Some(name)) => {
+                let value = url
+                    .query_pairs()
+                    .find(|(k, _)| k == name)
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("Query parameter {:?} not found in {}", name, url)
+                    })?
+                    .1;
+                (name, value.into_owned())
+            }
+            _ => return Err(anyhow::anyhow!("Invalid EXT-X-DEFINE tag: {}", attributes)),
+        };
+
+        self.0.insert(name.to_owned(), value);
+        Ok(())
+    }
+
+    /// Replace variable references like {$name} in a line
+    fn replace(&self, line: &str) -> Result<String> {
+        let mut replaced = String::with_capacity(line.len());
+        let mut rest = line;
+        while let Some(start) = rest.find("{$") {
+            let end = match rest[start..].find('}') {
+                Some(e) => start + e,
+                None => break,
+            };
+            let name = &rest[start + 2..end];
+            let value = self
+                .0
+                .get(name)
+                .ok_or_else(|| anyhow::anyhow!("Variable {:?} is not defined", name))?;
+            replaced.push_str(&rest[..start]);
+            replaced.push_str(value);
+            rest = &rest[end + 1..];
+        }
+        replaced.push_str(rest);
+
+        Ok(replaced)
+    }
+}
+
+/// Split an attribute list into keys and unquoted values
+pub(super) fn parse_attributes(attributes: &str) -> Vec<(&str, &str)> {
+    let mut parts = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in attributes.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                parts.push(&attributes[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    parts.push(&attributes[start..]);
+
+    parts
+        .into_iter()
+        .filter_map(|p| {
+            let (k, v) = p.split_once('=')?;
+            Some((k.trim(), v.trim().trim_matches('"')))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn media_playlists_import_master_variables() {
+        let master = b"#EXTM3U
+#EXT-X-DEFINE:NAME=\"cdn\",VALUE=\"https://cdn.example\"
+#EXT-X-DEFINE:QUERYPARAM=\"token\"
+#EXT-X-STREAM-INF:BANDWIDTH=1000
+{$cdn}/media.m3u8?token={$token}
+";
+        let url = "https://example.com/master.m3u8?token=abc".parse().unwrap();
+        let (master, master_vars) =
+            Variables::substitute(master, &url, &Variables::default()).unwrap();
+        assert!(std::str::from_utf8(&master)
+            .unwrap()
+            .contains("\nhttps://cdn.example/media.m3u8?token=abc\n"));
+
+        let media = b"#EXTM3U
+#EXT-X-DEFINE:IMPORT=\"cdn\"
+#EXT-X-MAP:URI=\"{$cdn}/init.mp4\"
+#EXTINF:6.0,
+{$cdn}/seg0.mp4
+";
+        let url = "https://cdn.example/media.m3u8".parse().unwrap();
+        let (media, _) = Variables::substitute(media, &url, &master_vars).unwrap();
+        let media = std::str::from_utf8(&media).unwrap();
+        assert!(media.contains("URI=\"https://cdn.example/init.mp4\""));
+        assert!(media.contains("\nhttps://cdn.example/seg0.mp4\n"));
+    }
+
+    #[test]
+    fn undefined_variables_are_an_error() {
+        let url = "https://cdn.example/media.m3u8".parse().unwrap();
+        let media = b"#EXTM3U\n#EXT-X-DEFINE:NAME=\"a\",VALUE=\"b\"\n{$c}/seg0.ts\n";
+        assert!(Variables::substitute(media, &url, &Variables::default()).is_err());
+    }
+}
@@ -0,0 +1,64 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use m3u8_rs::VariantStream;
+use serde::{Deserialize, Serialize};
+
+/// Characteristics of a selected variant, saved to select the same variant in later runs
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct VariantSelection {
+    pub resolution: Option<String>,
+    pub codecs: Option<String>,
+    pub bandwidth: u64,
+}
+
+impl VariantSelection {
+    pub const FILE_NAME: &'static str = "variant.json";
+
+    pub fn new(variant: &VariantStream) -> Self {
+        Self {
+            resolution: variant.resolution.clone(),
+            codecs: variant.codecs.clone(),
+            bandwidth: variant.bandwidth.parse().unwrap_or_default(),
+        }
+    }
+
+    /// Load a saved selection, returns None if there is none
+    pub fn load(path: impl AsRef<Path>) -> Result<Option<Self>> {
+        match std::fs::read(path.as_ref()) {
+            Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes).with_context(|| {
+                format!("error parsing variant selection {:?}", path.as_ref())
+            })?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        std::fs::write(path.as_ref(), serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("error saving variant selection {:?}", path.as_ref()))
+    }
+
+    /// Short description of the quality of the variant, e.g. 720p, or 2500k without resolution
+    pub fn quality(&self) -> String {
+        match self.resolution.as_deref().and_then(|r| r.split_once('x')) {
+            Some((_, height)) => format!("{}p", height),
+            None => format!("{}k", self.bandwidth / 1000),
+        }
+    }
+
+    /// Variant other than I-frame variants with the same resolution and codecs and the closest
+    /// bandwidth
+    pub fn find<'a>(&self, variants: &'a [VariantStream]) -> Option<&'a VariantStream> {
+        variants
+            .iter()
+            .filter(|v| !v.is_i_frame)
+            .filter(|v| v.resolution == self.resolution && v.codecs == self.codecs)
+            .min_by_key(|v| {
+                v.bandwidth
+                    .parse::<u64>()
+                    .unwrap_or_default()
+                    .abs_diff(self.bandwidth)
+            })
+    }
+}
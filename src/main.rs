@@ -1,30 +1,60 @@
-mod cli;
-mod error;
-mod livestream;
-mod mux;
-
+use std::fs::OpenOptions;
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
 use anyhow::{Context, Result};
-use clap::Parser;
-use livestream::Livestream;
+use clap::{ArgMatches, CommandFactory, FromArgMatches};
+use itertools::Itertools;
+use livestream_dl::cli;
+use livestream_dl::effective_options::EffectiveOptions;
+use livestream_dl::livestream::{
+    merge, Distribution, FdEstimate, Livestream, Stopper, Stream, EVENT_TARGET,
+};
+#[cfg(feature = "progress")]
+use livestream_dl::progress::ProgressLayer;
+use serde::Serialize;
 use tracing::{event, Level};
-use tracing_subscriber::filter::{FilterExt, LevelFilter};
+use tracing_subscriber::filter::{FilterExt, LevelFilter, Targets};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::{EnvFilter, Layer};
 
+/// Soft open file limit to raise to if the estimate doesn't fit, with headroom since estimates
+/// are rough
+const RAISED_OPEN_FILES: u64 = 4096;
+
 fn main() -> Result<()> {
     // Parse CLI args
-    let args = cli::Args::parse();
+    let matches = cli::Args::command().get_matches();
+    let mut args = cli::Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if let Err(e) = args.validate() {
+        e.exit();
+    }
 
-    // Init logging
-    init_tracing()?;
+    // Init logging, with progress bars while downloading
+    let downloading = args.command.is_none() && args.download_options.measure.is_none();
+    init_tracing(args.download_options.event_log.as_deref(), downloading)?;
 
-    // Create output directory before spawning tokio runtime to use local utc offset
-    let output = gen_output_dir(&args.download_options.output)?;
+    // Make sure the configured concurrency doesn't run out of file descriptors
+    if args.command.is_none() {
+        fit_open_file_limit(&mut args)?;
+    }
+
+    let result = match &args.command {
+        Some(cli::Command::Merge(merge_args)) => run_merge(merge_args),
+        Some(cli::Command::Bench(bench_args)) => run_bench(bench_args),
+        // Nothing is written to disk while measuring, not even the output directory
+        None if args.download_options.measure.is_some() => run_measure(args),
+        None => {
+            // Create output directory before spawning tokio runtime to use local utc offset
+            let output =
+                gen_output_dir(&args.download_options.output, args.download_options.resume)?;
+            run(args, &matches, output)
+        }
+    };
 
     // Run main program
-    if let Err(e) = run(args, output) {
+    if let Err(e) = result {
         event!(Level::ERROR, "{:?}", e);
         std::process::exit(1);
     }
@@ -33,51 +63,372 @@ fn main() -> Result<()> {
 }
 
 #[tokio::main]
-async fn run(args: cli::Args, output: impl AsRef<Path>) -> Result<()> {
-    let (livestream, stopper) = Livestream::new(&args.m3u8_url, &args)
+async fn run(args: cli::Args, matches: &ArgMatches, output: impl AsRef<Path>) -> Result<()> {
+    let url = args
+        .m3u8_url
+        .as_ref()
+        .expect("m3u8 url is required without a subcommand");
+    let (livestream, stopper) = Livestream::new(url, &args)
         .await
         .context("error initializing livestream downloader")?;
 
-    // Gracefully exit on ctrl-c
+    let effective = EffectiveOptions::new(matches, &livestream, output.as_ref()).await;
+    if args.download_options.print_effective_options {
+        println!("{}", serde_json::to_string_pretty(&effective)?);
+        return Ok(());
+    }
+    if let Some(format) = args.download_options.print_selected_urls {
+        print_selected_urls(&livestream, format)?;
+        return Ok(());
+    }
+    if args.download_options.check_segments {
+        return check_segments(&livestream).await;
+    }
+    effective.log();
+
+    for (stream, url) in livestream.streams() {
+        event!(Level::INFO, "Found stream {}: {}", stream, url);
+    }
+
+    stop_on_ctrl_c(stopper);
+
+    // Download stream
+    event!(Level::INFO, "Downloading stream to {:?}", output.as_ref());
+    livestream.download(output.as_ref()).await?;
+
+    Ok(())
+}
+
+/// Print the media playlist URLs of the selected streams, ordered by stream
+fn print_selected_urls(livestream: &Livestream, format: cli::PrintFormat) -> Result<()> {
+    #[derive(Serialize)]
+    struct SelectedUrl<'a> {
+        id: String,
+        stream: &'a Stream,
+        url: String,
+    }
+
+    let urls: Vec<_> = livestream
+        .playlist_urls()
+        .map(|(stream, url)| SelectedUrl {
+            id: stream.to_string(),
+            stream,
+            url: url.to_string(),
+        })
+        .sorted_by(|a, b| a.id.cmp(&b.id))
+        .collect();
+    match format {
+        cli::PrintFormat::Text => urls.iter().for_each(|u| println!("{}", u.url)),
+        cli::PrintFormat::Json => println!("{}", serde_json::to_string_pretty(&urls)?),
+    }
+    Ok(())
+}
+
+/// Print the number and size of available segments of each stream and the segments that aren't
+/// available, fails if a segment isn't available
+async fn check_segments(livestream: &Livestream) -> Result<()> {
+    let checks = livestream.check_segments().await?;
+    let mut missing = 0;
+    for (stream, checks) in &checks
+        .iter()
+        .sorted_by_key(|c| c.stream.to_string())
+        .group_by(|c| &c.stream)
     {
-        #[cfg(target_family = "unix")]
-        let mut stream = {
-            use tokio::signal::unix::{signal, SignalKind};
-            signal(SignalKind::interrupt()).unwrap()
-        };
-        #[cfg(target_family = "windows")]
-        let mut stream = {
-            use tokio::signal::windows::ctrl_c;
-            ctrl_c().unwrap()
-        };
+        let checks: Vec<_> = checks.collect();
+        let available: Vec<_> = checks.iter().filter(|c| c.available()).collect();
+        let size: u64 = available.iter().filter_map(|c| c.size).sum();
+        let unknown = available.iter().filter(|c| c.size.is_none()).count();
+        print!(
+            "{}: {} of {} available, {} bytes",
+            stream,
+            available.len(),
+            checks.len(),
+            size
+        );
+        if unknown > 0 {
+            print!(" and {} of unknown size", unknown);
+        }
+        println!();
+        for c in checks.iter().filter(|c| !c.available()) {
+            println!(
+                "  missing {}: {}",
+                c.url,
+                c.error.as_deref().unwrap_or_default()
+            );
+            missing += 1;
+        }
+    }
 
-        tokio::spawn(async move {
-            stream.recv().await;
+    if missing > 0 {
+        return Err(anyhow::anyhow!("{} segments are not available", missing));
+    }
+    Ok(())
+}
+
+/// Raise the open file limit or reduce concurrency so that downloads don't fail with too many
+/// open files
+///
+/// The soft limit is raised towards the hard limit if needed. Concurrency is reduced with a
+/// warning if it can't be raised far enough.
+fn fit_open_file_limit(args: &mut cli::Args) -> Result<()> {
+    let estimate = FdEstimate::new(args);
+    let required = estimate.required();
+    let (soft, hard) = match open_file_limit() {
+        Some((soft, _)) if soft >= required => return Ok(()),
+        Some(limits) => limits,
+        // No per process limit to check
+        None => return Ok(()),
+    };
+
+    let limit = match raise_open_file_limit(required.max(RAISED_OPEN_FILES).min(hard)) {
+        Some(raised) => {
             event!(
-                Level::WARN,
-                "Stopping download... Press Ctrl-C again to force stop"
+                Level::INFO,
+                "Raised open file limit from {} to {}",
+                soft,
+                raised
             );
-            stopper.stop().await;
+            raised
+        }
+        None => soft,
+    };
+    let fitted = estimate.fit(limit).ok_or_else(|| {
+        let minimum = FdEstimate {
+            downloads: 1,
+            uploads: estimate.uploads.min(1),
+            ..estimate
+        };
+        anyhow::anyhow!(
+            "Open file limit {} is too low, at least {} are needed, raise it with ulimit -n",
+            limit,
+            minimum.required()
+        )
+    })?;
+    if fitted != estimate {
+        event!(
+            Level::WARN,
+            "Open file limit {} is too low for {} concurrent downloads and {} uploads (about {} \
+             needed), using {} downloads and {} uploads, raise it with ulimit -n",
+            limit,
+            estimate.downloads,
+            estimate.uploads,
+            required,
+            fitted.downloads,
+            fitted.uploads
+        );
+    }
 
-            tokio::spawn(async move {
-                stream.recv().await;
-                event!(Level::WARN, "Force stopping process");
-                std::process::exit(1);
-            });
+    args.network_options.max_concurrent_downloads = fitted.downloads;
+    #[cfg(feature = "s3")]
+    if fitted.uploads > 0 {
+        args.upload_options.max_concurrent_uploads = fitted.uploads;
+    }
+    Ok(())
+}
+
+/// Soft and hard limit on the number of open file descriptors
+#[cfg(target_family = "unix")]
+fn open_file_limit() -> Option<(u64, u64)> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: getrlimit only writes to the passed rlimit
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return None;
+    }
+    // rlim_t is 32 bits on some platforms
+    #[allow(clippy::unnecessary_cast)]
+    Some((limit.rlim_cur as u64, limit.rlim_max as u64))
+}
+
+/// Raise the soft limit on open file descriptors to limit and return the new soft limit
+#[cfg(target_family = "unix")]
+fn raise_open_file_limit(limit: u64) -> Option<u64> {
+    let (soft, hard) = open_file_limit()?;
+    if limit <= soft {
+        return None;
+    }
+    let new = libc::rlimit {
+        rlim_cur: limit as libc::rlim_t,
+        rlim_max: hard as libc::rlim_t,
+    };
+    // SAFETY: setrlimit only reads the passed rlimit
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &new) } != 0 {
+        return None;
+    }
+    Some(limit)
+}
+
+/// Windows has no small per process limit on sockets and files opened through its API
+#[cfg(not(target_family = "unix"))]
+fn open_file_limit() -> Option<(u64, u64)> {
+    None
+}
+
+#[cfg(not(target_family = "unix"))]
+fn raise_open_file_limit(_: u64) -> Option<u64> {
+    None
+}
+
+/// Gracefully stop the download on ctrl-c, exit on a second ctrl-c
+fn stop_on_ctrl_c(stopper: Stopper) {
+    #[cfg(target_family = "unix")]
+    let mut stream = {
+        use tokio::signal::unix::{signal, SignalKind};
+        signal(SignalKind::interrupt()).unwrap()
+    };
+    #[cfg(target_family = "windows")]
+    let mut stream = {
+        use tokio::signal::windows::ctrl_c;
+        ctrl_c().unwrap()
+    };
+
+    tokio::spawn(async move {
+        stream.recv().await;
+        event!(
+            Level::WARN,
+            "Stopping download... Press Ctrl-C again to force stop"
+        );
+        stopper.stop().await;
+
+        tokio::spawn(async move {
+            stream.recv().await;
+            event!(Level::WARN, "Force stopping process");
+            std::process::exit(1);
         });
+    });
+}
+
+#[tokio::main]
+async fn run_measure(args: cli::Args) -> Result<()> {
+    let duration = args
+        .download_options
+        .measure
+        .expect("run_measure is only called with --measure");
+    let url = args
+        .m3u8_url
+        .as_ref()
+        .expect("m3u8 url is required without a subcommand");
+    let (livestream, stopper) = Livestream::new(url, &args)
+        .await
+        .context("error initializing livestream downloader")?;
+    stop_on_ctrl_c(stopper);
+
+    event!(
+        Level::INFO,
+        "Measuring stream for {:?}, segments are discarded",
+        duration
+    );
+    let measurement = livestream.measure(duration).await?;
+
+    println!(
+        "Measured {} for {:.1}s",
+        measurement.url, measurement.elapsed
+    );
+    for s in &measurement.streams {
+        println!("Stream {}", s.stream);
+        println!(
+            "  segments:          {} ({:.1} MB, {:.1}s of media)",
+            s.segments,
+            s.bytes as f64 / 1e6,
+            s.media_duration
+        );
+        println!(
+            "  bitrate:           average {}, peak {}",
+            format_bitrate(s.average_bitrate),
+            format_bitrate(s.peak_bitrate)
+        );
+        println!(
+            "  segment duration:  {}",
+            format_distribution(s.segment_duration.as_ref())
+        );
+        println!(
+            "  playlist refresh:  {} fetches, every {}",
+            s.playlist_refreshes,
+            format_distribution(s.refresh_interval.as_ref())
+        );
+        println!(
+            "  discontinuities:   {} ({})",
+            s.discontinuities,
+            s.discontinuity_rate
+                .map_or("-".to_string(), |r| format!("{:.2} per hour", r))
+        );
+        println!("  failed segments:   {}", s.failed_segments);
     }
 
-    // Download stream
-    event!(Level::INFO, "Downloading stream to {:?}", output.as_ref());
-    livestream.download(output.as_ref()).await?;
+    if let Some(path) = &args.download_options.measure_json {
+        std::fs::write(path, serde_json::to_string_pretty(&measurement)?)
+            .with_context(|| format!("error writing measurement to {:?}", path))?;
+        event!(Level::INFO, "Wrote measurement to {:?}", path);
+    }
 
     Ok(())
 }
 
-fn gen_output_dir(output_dir: &Option<impl AsRef<Path>>) -> Result<PathBuf> {
+fn format_bitrate(bitrate: Option<f64>) -> String {
+    bitrate.map_or("-".to_string(), |b| format!("{:.0} kbit/s", b / 1e3))
+}
+
+fn format_distribution(distribution: Option<&Distribution>) -> String {
+    distribution.map_or("-".to_string(), |d| {
+        format!("min {:.2}s, mean {:.2}s, max {:.2}s", d.min, d.mean, d.max)
+    })
+}
+
+#[tokio::main]
+async fn run_merge(args: &cli::MergeArgs) -> Result<()> {
+    event!(Level::INFO, "Merging segments to {:?}", &args.output);
+    merge(
+        &args.segments_directories,
+        &args.output,
+        args.container,
+        args.overwrite,
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn run_bench(args: &cli::BenchArgs) -> Result<()> {
+    let (livestream, _) = Livestream::new(&args.m3u8_url, &args.download_args())
+        .await
+        .context("error initializing livestream downloader")?;
+    let results = livestream.bench(args.segments, &args.concurrency).await?;
+
+    println!(
+        "{:>11}  {:>8}  {:>6}  {:>10}  {:>8}  {:>10}",
+        "concurrency", "segments", "failed", "MB", "seconds", "Mbit/s"
+    );
+    for r in &results {
+        println!(
+            "{:>11}  {:>8}  {:>6}  {:>10.1}  {:>8.2}  {:>10.1}",
+            r.concurrency,
+            r.segments,
+            r.failed,
+            r.bytes as f64 / 1e6,
+            r.elapsed.as_secs_f64(),
+            r.throughput() * 8.0 / 1e6
+        );
+    }
+    if let Some(best) = results
+        .iter()
+        .max_by(|a, b| a.throughput().total_cmp(&b.throughput()))
+    {
+        println!(
+            "Highest throughput with --max-concurrent-downloads {}",
+            best.concurrency
+        );
+    }
+
+    Ok(())
+}
+
+fn gen_output_dir(output_dir: &Option<impl AsRef<Path>>, resume: bool) -> Result<PathBuf> {
     let final_output_dir = if let Some(output_dir) = output_dir {
         // If output directory already exists, prompt user to overwrite, otherwise exit
-        if output_dir.as_ref().is_dir() {
+        if output_dir.as_ref().is_dir() && !resume {
             let response = inquire::Confirm::new(&format!(
                     "Found existing output directory {:?}, existing files may be overwritten.\nIs this OK?",
                     output_dir.as_ref()
@@ -112,19 +463,58 @@ fn gen_output_dir(output_dir: &Option<impl AsRef<Path>>) -> Result<PathBuf> {
     Ok(final_output_dir)
 }
 
-fn init_tracing() -> Result<()> {
+fn init_tracing(event_log: Option<&Path>, progress: bool) -> Result<()> {
     // Enable ANSI support on Windows for colors
     #[cfg(target_family = "windows")]
     let _ = ansi_term::enable_ansi_support();
 
-    // Log INFO to stdout
+    // Draw progress bars of downloads on interactive terminals, with logs printed above them
+    #[cfg(feature = "progress")]
+    let (stdout, progress) = match progress.then(ProgressLayer::for_terminal).flatten() {
+        Some(p) => {
+            let filter = Targets::new().with_target(EVENT_TARGET, Level::DEBUG);
+            (BoxMakeWriter::new(p.writer()), Some(p.with_filter(filter)))
+        }
+        None => (BoxMakeWriter::new(std::io::stdout), None),
+    };
+    #[cfg(not(feature = "progress"))]
+    let (stdout, progress) = {
+        let _ = progress;
+        (BoxMakeWriter::new(std::io::stdout), None::<LevelFilter>)
+    };
+
+    // Log INFO to stdout with UTC timestamps
     let stdout_log = tracing_subscriber::fmt::layer()
         .compact()
-        .without_time()
+        .with_writer(stdout)
         .with_filter(EnvFilter::from_env("LIVESTREAM_DL_LOG").or(LevelFilter::INFO));
 
+    // Log download events and INFO to event log file as JSON lines
+    let event_log = match event_log {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Unable to open event log {:?}", path))?;
+            let filter = Targets::new()
+                .with_target(EVENT_TARGET, Level::DEBUG)
+                .with_default(Level::INFO);
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .with_writer(Mutex::new(file))
+                    .with_filter(filter),
+            )
+        }
+        None => None,
+    };
+
     // Start logging
-    let subscriber = tracing_subscriber::Registry::default().with(stdout_log);
+    let subscriber = tracing_subscriber::Registry::default()
+        .with(stdout_log)
+        .with(event_log)
+        .with(progress);
     tracing::subscriber::set_global_default(subscriber)?;
 
     Ok(())
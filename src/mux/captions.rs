@@ -0,0 +1,123 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use tokio::{fs, process};
+use tracing::{event, Level};
+
+use crate::livestream::CaptionChannel;
+
+/// Extract the CEA-608 closed captions of channels from the muxed video at path into .srt sidecars
+/// next to it named `{stem}.{lang}.{instream_id}.srt`, returns the paths of sidecars with captions
+///
+/// Channels ffmpeg can't decode are skipped, and so are channels whose extraction fails, so
+/// that missing captions don't fail the download.
+pub async fn extract_captions(
+    path: &Path,
+    channels: &[CaptionChannel],
+    overwrite: bool,
+) -> Result<Vec<PathBuf>> {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let mut sidecars = Vec::new();
+    for channel in channels {
+        let field = match channel.data_field() {
+            Some(f) => f,
+            None => {
+                event!(
+                    Level::WARN,
+                    "Can't extract closed captions {}, only CC1 and CC3 are supported",
+                    channel
+                );
+                continue;
+            }
+        };
+        let name = match &channel.lang {
+            Some(lang) => format!("{}.{}.{}.srt", stem, lang, channel.instream_id),
+            None => format!("{}.{}.srt", stem, channel.instream_id),
+        };
+        let sidecar_path = path.with_file_name(name);
+
+        event!(
+            Level::INFO,
+            "Extracting closed captions {} of {:?} to {:?}",
+            channel,
+            path,
+            sidecar_path
+        );
+        match extract_channel(path, field, &sidecar_path, overwrite).await {
+            Ok(true) => sidecars.push(sidecar_path),
+            Ok(false) => {
+                event!(
+                    Level::WARN,
+                    "No closed captions {} found in {:?}",
+                    channel,
+                    path
+                );
+                let _ = fs::remove_file(&sidecar_path).await;
+            }
+            Err(e) => event!(
+                Level::WARN,
+                "Unable to extract closed captions {}: {:#}",
+                channel,
+                e
+            ),
+        }
+    }
+    Ok(sidecars)
+}
+
+/// Decode the captions in field of the video at path into an .srt file with ffmpeg, returns
+/// whether any were found
+async fn extract_channel(
+    path: &Path,
+    field: &str,
+    sidecar_path: &Path,
+    overwrite: bool,
+) -> Result<bool> {
+    // Captions are side data of video frames, which the movie source exposes as a stream
+    let source = format!(
+        "movie={}[out0+subcc]",
+        filter_escape(&path.to_string_lossy())
+    );
+    let mut cmd = process::Command::new("ffmpeg");
+    cmd.arg(if overwrite { "-y" } else { "-n" })
+        .arg("-data_field")
+        .arg(field)
+        .arg("-f")
+        .arg("lavfi")
+        .arg("-i")
+        .arg(source)
+        .arg("-map")
+        .arg("0:s")
+        .arg("-c:s")
+        .arg("srt")
+        .arg(sidecar_path)
+        .kill_on_drop(true);
+
+    event!(Level::TRACE, "{:?}", cmd);
+    let output = cmd.output().await?;
+    event!(
+        Level::TRACE,
+        "ffmpeg stderr: {:#?}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    if !output.status.success() {
+        return Err(anyhow::anyhow!("ffmpeg command failed"));
+    }
+
+    Ok(fs::metadata(sidecar_path).await?.len() > 0)
+}
+
+/// Escape value for a filter option, then the option for a filtergraph
+fn filter_escape(value: &str) -> String {
+    let escape = |s: &str, special: &[char]| {
+        s.chars().fold(String::new(), |mut escaped, c| {
+            if special.contains(&c) {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+            escaped
+        })
+    };
+    let option = escape(value, &['\\', '\'', ':']);
+    escape(&option, &['\\', '\'', '[', ']', ',', ';'])
+}
@@ -9,13 +9,20 @@ use tokio::io::AsyncWriteExt;
 use tokio::{fs, process};
 use tracing::{event, Level};
 
-use crate::livestream::{MediaFormat, Segment, Stream};
+use crate::livestream::{MediaFormat, OpenFiles, Segment, Stream};
+
+/// Extension of concat lists written by [`write_concat_lists`]
+const CONCAT_LIST_EXTENSION: &str = "ffconcat";
+
+/// Stream, its concatenated file and the detected format of its segments
+pub type ConcatStream<'a> = (&'a Stream, PathBuf, MediaFormat);
 
 /// For each discontinuity, concatenate all streams
-pub async fn concat_streams<P: AsRef<Path>>(
-    downloaded_paths: &HashMap<Stream, BinaryHeap<(Segment, PathBuf)>>,
+pub async fn concat_streams<'a, P: AsRef<Path>>(
+    downloaded_paths: &'a HashMap<Stream, BinaryHeap<(Segment, PathBuf)>>,
     output_dir: P,
-) -> Result<HashMap<u64, Vec<(&Stream, PathBuf)>>> {
+    open_files: &OpenFiles,
+) -> Result<HashMap<u64, Vec<ConcatStream<'a>>>> {
     // Map discon seq -> Vec<(stream, concatenated path)>
     let mut discons: HashMap<_, Vec<_>> = HashMap::new();
 
@@ -42,11 +49,12 @@ pub async fn concat_streams<P: AsRef<Path>>(
                         &output_dir,
                         cur_discon_seq.unwrap(),
                     )?;
-                    concat_segments(segments_to_process.as_slice(), &file_path).await?;
+                    concat_segments(segments_to_process.as_slice(), &file_path, open_files).await?;
+                    let format = segments_to_process[0].0.format.clone();
                     discons
                         .entry(cur_discon_seq.unwrap())
                         .or_default()
-                        .push((stream, file_path));
+                        .push((stream, file_path, format));
                 }
 
                 // Reset segments to process, push current segment, and update current
@@ -61,14 +69,106 @@ pub async fn concat_streams<P: AsRef<Path>>(
         if !segments_to_process.is_empty() {
             let d = cur_discon_seq.unwrap();
             let file_path = gen_concat_path(stream, segments_to_process[0].0, &output_dir, d)?;
-            concat_segments(segments_to_process.as_slice(), &file_path).await?;
-            discons.entry(d).or_default().push((stream, file_path));
+            concat_segments(segments_to_process.as_slice(), &file_path, open_files).await?;
+            let format = segments_to_process[0].0.format.clone();
+            discons
+                .entry(d)
+                .or_default()
+                .push((stream, file_path, format));
         }
     }
 
     Ok(discons)
 }
 
+/// Write the concat list of the segments of each stream into dir, named after the stream with
+/// the .ffconcat extension, and return their paths
+pub async fn write_concat_lists(
+    downloaded_paths: &HashMap<Stream, BinaryHeap<(Segment, PathBuf)>>,
+    dir: impl AsRef<Path>,
+) -> Result<Vec<PathBuf>> {
+    let mut lists = Vec::with_capacity(downloaded_paths.len());
+    for (stream, segments) in downloaded_paths {
+        if segments.is_empty() {
+            continue;
+        }
+        let path = dir
+            .as_ref()
+            .join(format!("{}.{}", stream.file_id(), CONCAT_LIST_EXTENSION));
+        fs::write(&path, concat_list(segments, dir.as_ref())?).await?;
+        event!(Level::INFO, "Wrote concat list {:?}", path);
+        lists.push(path);
+    }
+    Ok(lists)
+}
+
+/// ffmpeg concat demuxer script listing segments in order with their EXTINF durations
+///
+/// Paths inside dir, where the script is saved, are relative to it, other paths are absolute.
+///
+/// ```
+/// use std::collections::BinaryHeap;
+/// use std::path::{Path, PathBuf};
+/// use std::time::Duration;
+///
+/// use livestream_dl::livestream::{MediaFormat, RemoteData, Segment};
+/// use livestream_dl::mux::concat_list;
+///
+/// let segment = |seq, path: &str| {
+///     let segment = Segment {
+///         data: RemoteData::new(format!("https://example.com/{}.ts", seq).parse().unwrap(), None),
+///         discon_seq: 0,
+///         seq,
+///         format: MediaFormat::MpegTs,
+///         initialization: None,
+///         program_date_time: None,
+///         duration: Some(Duration::from_secs(6)),
+///     };
+///     (segment, PathBuf::from(path))
+/// };
+/// let segments = BinaryHeap::from([
+///     segment(11, "out/segments/s11.ts"),
+///     segment(10, "out/segments/s10.ts"),
+///     segment(12, "/tmp/it's.ts"),
+/// ]);
+/// assert_eq!(
+///     concat_list(&segments, Path::new("out")).unwrap(),
+///     "ffconcat version 1.0\n\
+///      file 'segments/s10.ts'\nduration 6.000000\n\
+///      file 'segments/s11.ts'\nduration 6.000000\n\
+///      file '/tmp/it'\\''s.ts'\nduration 6.000000\n"
+/// );
+/// ```
+pub fn concat_list(segments: &BinaryHeap<(Segment, PathBuf)>, dir: &Path) -> Result<String> {
+    let segments = segments.clone().into_sorted_vec();
+    concat_script(segments.iter().map(|(s, p)| (s, p.as_path())), dir)
+}
+
+/// ffmpeg concat demuxer script listing segments in the given order, see [`concat_list`]
+fn concat_script<'a>(
+    segments: impl IntoIterator<Item = (&'a Segment, &'a Path)>,
+    dir: &Path,
+) -> Result<String> {
+    let cwd = env::current_dir()?;
+    let mut list = String::from("ffconcat version 1.0\n");
+    for (segment, path) in segments {
+        let path = match path.strip_prefix(dir) {
+            Ok(relative) => Cow::Borrowed(relative),
+            Err(_) => Cow::Owned(cwd.join(path)),
+        };
+        let path = path
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("Segment path {:?} isn't valid UTF-8", path))?;
+        // Quotes can't be escaped inside a quoted string, they end it instead
+        let quoted = path.replace('\'', "'\\''");
+        list.push_str(&format!("file '{}'\n", quoted));
+        if let Some(d) = segment.duration {
+            list.push_str(&format!("duration {:.6}\n", d.as_secs_f64()));
+        }
+    }
+    Ok(list)
+}
+
 fn gen_concat_path(
     stream: &Stream,
     segment: &Segment,
@@ -76,22 +176,27 @@ fn gen_concat_path(
     d: u64,
 ) -> Result<PathBuf> {
     let ext = segment.format.extension();
-    let file_name = format!("{}_{:010}.{}", stream, d, ext);
+    let file_name = format!("{}_{:010}.{}", stream.file_id(), d, ext);
     let file_path = output_dir.as_ref().join(file_name);
     Ok(file_path)
 }
 
-async fn concat_segments<P: AsRef<Path>>(inputs: &[(&Segment, P)], output: P) -> Result<()> {
-    if should_use_ffmpeg_concat(inputs[0].0).await? {
-        ffmpeg_concat(inputs.iter().map(|(_, p)| p), &output).await
+async fn concat_segments<P: AsRef<Path>>(
+    inputs: &[(&Segment, P)],
+    output: P,
+    open_files: &OpenFiles,
+) -> Result<()> {
+    if should_use_ffmpeg_concat(inputs[0].0) {
+        ffmpeg_concat(inputs, output).await
     } else {
-        file_concat(inputs.iter().map(|(_, p)| p), &output).await
+        file_concat(inputs.iter().map(|(_, p)| p), &output, open_files).await
     }
 }
 
 async fn file_concat<P: AsRef<Path>>(
     input_paths: impl IntoIterator<Item = P>,
     output: P,
+    open_files: &OpenFiles,
 ) -> Result<()> {
     event!(
         Level::INFO,
@@ -99,6 +204,8 @@ async fn file_concat<P: AsRef<Path>>(
         output.as_ref()
     );
 
+    // Output and the current input are open at the same time
+    let _permit = open_files.acquire_many(2).await?;
     let mut file = fs::File::create(output.as_ref()).await?;
     for path in input_paths {
         file.write_all(&fs::read(path.as_ref()).await?).await?;
@@ -106,31 +213,22 @@ async fn file_concat<P: AsRef<Path>>(
     Ok(())
 }
 
-async fn ffmpeg_concat<P: AsRef<Path>>(
-    input_paths: impl IntoIterator<Item = P>,
-    output: P,
-) -> Result<()> {
+/// Concatenate segments with the ffmpeg concat demuxer
+///
+/// The EXTINF durations of segments are passed to the demuxer, so that the output has accurate
+/// timestamps even if the segments have none.
+async fn ffmpeg_concat<P: AsRef<Path>>(inputs: &[(&Segment, P)], output: P) -> Result<()> {
     event!(
         Level::INFO,
         "ffmpeg concat demux to temporary file {:?}",
         output.as_ref()
     );
 
-    // Create concat text file
+    // Create concat script, paths in the temporary directory are relative to it
     let file = tempfile::NamedTempFile::new()?;
-    let cwd = env::current_dir()?;
-    for path in input_paths {
-        let absolute_path = if path.as_ref().is_absolute() {
-            Cow::from(path.as_ref())
-        } else {
-            Cow::Owned(cwd.join(path))
-        };
-        writeln!(
-            file.as_file(),
-            "file '{}'",
-            absolute_path.as_ref().to_str().unwrap()
-        )?;
-    }
+    let dir = file.path().parent().unwrap_or(Path::new("/"));
+    let script = concat_script(inputs.iter().map(|(s, p)| (*s, p.as_ref())), dir)?;
+    file.as_file().write_all(script.as_bytes())?;
 
     // Call ffmpeg to concat segments
     let mut cmd = process::Command::new("ffmpeg");
@@ -170,12 +268,117 @@ async fn ffmpeg_concat<P: AsRef<Path>>(
 }
 
 /// Decide whether to use file or ffmpeg concat demuxer
-async fn should_use_ffmpeg_concat(segment: &Segment) -> Result<bool> {
-    #[allow(clippy::match_like_matches_macro)]
-    let use_ffmpeg = match segment.format {
-        MediaFormat::Mp3 => true,
-        _ => false,
-    };
-
-    Ok(use_ffmpeg)
+///
+/// Containers are concatenated by ffmpeg, so that their timestamps follow the EXTINF durations of
+/// segments instead of restarting or jumping within the output. Raw audio and subtitles are
+/// concatenated as files.
+fn should_use_ffmpeg_concat(segment: &Segment) -> bool {
+    matches!(
+        segment.format,
+        MediaFormat::MpegTs | MediaFormat::FMp4 | MediaFormat::Mp3
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::livestream::RemoteData;
+
+    fn segment(seq: u64, duration: f64) -> Segment {
+        Segment {
+            data: RemoteData::new(
+                format!("https://example.com/{}.ts", seq).parse().unwrap(),
+                None,
+            ),
+            discon_seq: 0,
+            seq,
+            format: MediaFormat::MpegTs,
+            initialization: None,
+            program_date_time: None,
+            duration: Some(Duration::from_secs_f64(duration)),
+        }
+    }
+
+    #[test]
+    fn concat_script_quotes_paths() {
+        let segment = segment(0, 2.0);
+        let script =
+            concat_script([(&segment, Path::new("/tmp/x/it's.ts"))], Path::new("/tmp")).unwrap();
+        assert_eq!(
+            script,
+            "ffconcat version 1.0\nfile 'x/it'\\''s.ts'\nduration 2.000000\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn concat_script_rejects_non_utf8_paths() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let segment = segment(0, 2.0);
+        let path = Path::new(OsStr::from_bytes(b"/tmp/\xff.ts"));
+        assert!(concat_script([(&segment, path)], Path::new("/tmp")).is_err());
+    }
+
+    /// Run a command, false if it couldn't be run or failed
+    fn run(cmd: &mut Command) -> bool {
+        cmd.output().is_ok_and(|o| o.status.success())
+    }
+
+    #[tokio::test]
+    async fn ts_concat_duration_matches_extinf() {
+        if !run(Command::new("ffmpeg").arg("-version"))
+            || !run(Command::new("ffprobe").arg("-version"))
+        {
+            eprintln!("Skipping, ffmpeg and ffprobe are required");
+            return;
+        }
+
+        // Segments encoded separately all start at the same timestamp
+        let dir = tempfile::tempdir().unwrap();
+        let extinfs = [2.0, 2.0, 1.5];
+        let mut inputs = Vec::new();
+        for (seq, extinf) in extinfs.iter().enumerate() {
+            let path = dir.path().join(format!("{}.ts", seq));
+            assert!(run(Command::new("ffmpeg")
+                .args(["-v", "error", "-f", "lavfi", "-i"])
+                .arg(format!("testsrc=size=64x64:rate=10:duration={}", extinf))
+                .args(["-c:v", "mpeg2video", "-f", "mpegts"])
+                .arg(&path)));
+            inputs.push((segment(seq as u64, *extinf), path));
+        }
+        let inputs: Vec<_> = inputs.iter().map(|(s, p)| (s, p.clone())).collect();
+        let output = dir.path().join("out.ts");
+        concat_segments(&inputs, output.clone(), &OpenFiles::default())
+            .await
+            .unwrap();
+
+        let probe = Command::new("ffprobe")
+            .args([
+                "-v",
+                "error",
+                "-show_entries",
+                "format=duration",
+                "-of",
+                "csv=p=0",
+            ])
+            .arg(&output)
+            .output()
+            .unwrap();
+        let duration: f64 = String::from_utf8_lossy(&probe.stdout)
+            .trim()
+            .parse()
+            .unwrap();
+        let expected: f64 = extinfs.iter().sum();
+        assert!(
+            (duration - expected).abs() < 0.25,
+            "duration {}s, expected {}s",
+            duration,
+            expected
+        );
+    }
 }
@@ -0,0 +1,165 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use reqwest::Url;
+use time::OffsetDateTime;
+use tokio::fs;
+use tracing::{event, Level};
+
+use crate::effective_options::redact_url;
+
+/// Characters that aren't allowed in file names on common file systems
+const RESERVED_CHARS: &[char] = &['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+
+/// Move muxed files into `TITLE (YEAR)` in output_dir, named `TITLE.mp4` or `TITLE - partN.mp4`
+/// if there are several, and describe them in a Kodi style .nfo file, returns the new paths of
+/// muxed files
+///
+/// Muxed files keep their extension, and .srt subtitle sidecars are renamed with the video they
+/// belong to, e.g. `TITLE.en.srt`. date is the recording date of the capture and source the
+/// playlist it was recorded from.
+pub async fn organize_for_library(
+    muxed_paths: Vec<PathBuf>,
+    output_dir: &Path,
+    title: &str,
+    date: OffsetDateTime,
+    source: &Url,
+    overwrite: bool,
+) -> Result<Vec<PathBuf>> {
+    let name = file_name(title)?;
+    let entry_dir = output_dir.join(format!("{} ({})", name, date.year()));
+
+    // Output names are ordered by discontinuity
+    let (sidecars, mut videos): (Vec<_>, Vec<_>) = muxed_paths
+        .into_iter()
+        .partition(|p| p.extension() == Some("srt".as_ref()));
+    videos.sort();
+    let mut moves: Vec<_> = videos
+        .iter()
+        .enumerate()
+        .map(|(i, path)| {
+            let stem = if videos.len() == 1 {
+                name.clone()
+            } else {
+                format!("{} - part{}", name, i + 1)
+            };
+            let extension = path.extension().unwrap_or_default().to_string_lossy();
+            (
+                path.clone(),
+                entry_dir.join(format!("{}.{}", stem, extension)),
+            )
+        })
+        .collect();
+    for sidecar in sidecars {
+        let target = moves.iter().find_map(|(video, target)| {
+            let suffix = sidecar_suffix(video, &sidecar)?;
+            let stem = target.file_stem()?.to_string_lossy();
+            Some(entry_dir.join(format!("{}{}", stem, suffix)))
+        });
+        match target {
+            Some(target) => moves.push((sidecar, target)),
+            None => event!(
+                Level::WARN,
+                "Subtitles {:?} don't belong to a muxed video, not moving them",
+                sidecar
+            ),
+        }
+    }
+    let nfo_path = if videos.len() == 1 {
+        entry_dir.join(format!("{}.nfo", name))
+    } else {
+        entry_dir.join("movie.nfo")
+    };
+    if !overwrite {
+        let targets = moves.iter().map(|(_, t)| t);
+        if let Some(p) = targets.chain([&nfo_path]).find(|p| p.exists()) {
+            return Err(anyhow::anyhow!(
+                "Output file {:?} already exists, use --overwrite to replace it",
+                p
+            ));
+        }
+    }
+
+    fs::create_dir_all(&entry_dir).await?;
+    for (path, target) in &moves {
+        event!(Level::INFO, "Moving {:?} to {:?}", path, target);
+        fs::rename(path, target).await?;
+    }
+    fs::write(&nfo_path, nfo(title, date, source)).await?;
+
+    Ok(moves.into_iter().map(|(_, t)| t).collect())
+}
+
+/// Part of the file name of sidecar after the name of video without extension, e.g. `.en.srt`,
+/// if sidecar belongs to video
+fn sidecar_suffix<'a>(video: &Path, sidecar: &'a Path) -> Option<&'a str> {
+    if video.parent() != sidecar.parent() {
+        return None;
+    }
+    let stem = video.file_stem()?.to_str()?;
+    let suffix = sidecar.file_name()?.to_str()?.strip_prefix(stem)?;
+    // Only the language of the subtitles and whether they are forced follow the name of the video
+    let lang = suffix.strip_suffix(".srt")?;
+    let lang = lang.strip_suffix(".forced").unwrap_or(lang);
+    (lang.starts_with('.') && lang.matches('.').count() == 1).then_some(suffix)
+}
+
+/// File name stem for title, with reserved characters replaced
+fn file_name(title: &str) -> Result<String> {
+    let name: String = title
+        .chars()
+        .map(|c| {
+            if c.is_control() || RESERVED_CHARS.contains(&c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+    // Windows drops trailing dots and spaces
+    let name = name.trim().trim_end_matches('.');
+    if name.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Library title {:?} can't be used as a file name",
+            title
+        ));
+    }
+
+    Ok(name.to_string())
+}
+
+/// Contents of a Kodi style movie .nfo file
+fn nfo(title: &str, date: OffsetDateTime, source: &Url) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n\
+         <movie>\n  \
+           <title>{}</title>\n  \
+           <premiered>{}-{:02}-{:02}</premiered>\n  \
+           <year>{}</year>\n  \
+           <plot>Livestream recorded from {}</plot>\n  \
+           <tag>livestream</tag>\n\
+         </movie>\n",
+        escape_xml(title),
+        date.year(),
+        u8::from(date.month()),
+        date.day(),
+        date.year(),
+        escape_xml(&redact_url(source))
+    )
+}
+
+/// Escape text for use in XML elements
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -1,64 +1,802 @@
+mod captions;
 mod concat;
+mod library;
 
-use std::collections::{BinaryHeap, HashMap};
+use std::collections::{BTreeSet, BinaryHeap, HashMap};
+use std::mem;
 use std::path::{Path, PathBuf};
 
 use anyhow::Result;
 use isolang::Language;
+use itertools::Itertools;
 use oxilangtag::LanguageTag;
 use serde::Deserialize;
 use tokio::{fs, process};
 use tracing::{event, Level};
 
+pub use self::captions::extract_captions;
 use self::concat::concat_streams;
-use crate::livestream::{Segment, Stream};
+pub use self::concat::{concat_list, write_concat_lists, ConcatStream};
+pub use self::library::organize_for_library;
+use crate::cli::{Container, SidecarTemplate, SidecarValues, TargetResolution};
+use crate::livestream::{gaps_srt, lang_matches, GapCue, GapReport, OpenFiles, Segment, Stream};
 
-/// Remux media files into a single mp4 file with ffmpeg
+/// Name of muxed video files without a given name
+const DEFAULT_NAME: &str = "video";
+
+/// Settings to re-encode video with while muxing, instead of copying it
+#[derive(Clone, Copy, Debug)]
+pub struct Transcode {
+    /// Resolution to scale video down to
+    pub resolution: Option<TargetResolution>,
+    /// Average bitrate in bits per second, constant quality if not given
+    pub bitrate: Option<u64>,
+}
+
+impl Transcode {
+    /// ffmpeg arguments to encode video with
+    ///
+    /// ```
+    /// use livestream_dl::cli::TargetResolution;
+    /// use livestream_dl::mux::Transcode;
+    ///
+    /// let transcode = Transcode {
+    ///     resolution: Some(TargetResolution::Height(720)),
+    ///     bitrate: Some(2_000_000),
+    /// };
+    /// assert_eq!(
+    ///     transcode.video_args(),
+    ///     [
+    ///         "-c:v", "libx264", "-preset", "medium", "-pix_fmt", "yuv420p", "-b:v", "2000000",
+    ///         "-vf", "scale=-2:min(720\\,ih)",
+    ///     ]
+    /// );
+    ///
+    /// let transcode = Transcode { resolution: None, bitrate: None };
+    /// assert!(transcode.video_args().ends_with(&["-crf".to_string(), "23".to_string()]));
+    /// ```
+    pub fn video_args(&self) -> Vec<String> {
+        let mut args = vec![
+            "-c:v", "libx264", "-preset", "medium", "-pix_fmt", "yuv420p",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect::<Vec<_>>();
+        match self.bitrate {
+            Some(b) => args.extend(["-b:v".into(), b.to_string()]),
+            None => args.extend(["-crf".into(), "23".into()]),
+        }
+        if let Some(r) = self.resolution {
+            args.extend(["-vf".into(), r.scale_filter()]);
+        }
+        args
+    }
+}
+
+/// How streams are muxed into output files
+#[derive(Clone, Copy, Debug)]
+pub struct MuxOptions<'a> {
+    /// Name of outputs relative to the output directory, without extension
+    pub name: Option<&'a Path>,
+    pub container: Container,
+    /// Language of the audio stream marked as default
+    pub default_audio: Option<&'a str>,
+    /// Template of subtitle sidecar files, all subtitles are written to sidecars if given
+    pub sidecar_template: Option<&'a SidecarTemplate>,
+    /// Replace existing outputs
+    pub overwrite: bool,
+    /// Start each output at its first video keyframe
+    pub trim_to_keyframe: bool,
+    /// Settings to re-encode video with, video is copied if not given
+    pub transcode: Option<Transcode>,
+}
+
+impl MuxOptions<'_> {
+    /// Options muxing into outputs of container named after DEFAULT_NAME, copying all streams
+    pub fn new(container: Container, overwrite: bool) -> Self {
+        Self {
+            name: None,
+            container,
+            default_audio: None,
+            sidecar_template: None,
+            overwrite,
+            trim_to_keyframe: false,
+            transcode: None,
+        }
+    }
+}
+
+/// Remux media files into a single file for each discontinuity with ffmpeg, returns the paths of
+/// muxed files and their subtitle sidecars
+///
+/// Outputs are written to output_dir as set by options. If gaps is given, the gaps of each output
+/// are announced by an extra subtitle track. Segments are concatenated within open_files.
 pub async fn remux(
     downloaded_paths: HashMap<Stream, BinaryHeap<(Segment, PathBuf)>>,
     output_dir: &Path,
-) -> Result<()> {
+    options: &MuxOptions<'_>,
+    gaps: Option<&GapReport>,
+    open_files: &OpenFiles,
+) -> Result<Vec<PathBuf>> {
+    // Generate output names for each discontinuity
+    let discon_seqs: BTreeSet<_> = downloaded_paths
+        .values()
+        .flat_map(|segments| segments.iter().map(|(s, _)| s.discon_seq))
+        .collect();
+    let output_paths = output_paths(output_dir, options, &discon_seqs).await?;
+
     // Get list of concatenated streams for each discontinuity
-    let discons = concat_streams(&downloaded_paths, &output_dir).await?;
+    let discons = concat_streams(&downloaded_paths, &output_dir, open_files).await?;
+
+    let mut muxed_paths = mux_discons(
+        &discons,
+        &output_paths,
+        |discon_seq, streams| start_offsets(&downloaded_paths, discon_seq, streams),
+        options,
+    )
+    .await?;
+
+    // Announce gaps in the outputs they are missing from
+    if let Some(gaps) = gaps.filter(|g| !g.is_empty()) {
+        for (discon_seq, output_path) in &output_paths {
+            let cues = gaps.cues(&downloaded_paths, *discon_seq);
+            if cues.is_empty() {
+                continue;
+            }
+
+            // Subtitles are embedded unless a sidecar template is given
+            let subtitles = match options.sidecar_template {
+                Some(_) => 0,
+                None => discons[discon_seq]
+                    .iter()
+                    .filter(|(s, _, _)| matches!(s, Stream::Subtitle { .. }))
+                    .count(),
+            };
+            if let Some(sidecar) =
+                write_gap_report(&cues, output_path, options.container, subtitles).await?
+            {
+                muxed_paths.push(sidecar);
+            }
+        }
+    }
 
+    Ok(muxed_paths)
+}
+
+/// Remux streams that are already concatenated into one file for each discontinuity with
+/// ffmpeg, returns the paths of muxed files and their subtitle sidecars
+///
+/// The concatenated files are deleted after muxing. Outputs are written to output_dir as set by
+/// options.
+pub async fn remux_concatenated(
+    discons: &HashMap<u64, Vec<ConcatStream<'_>>>,
+    output_dir: &Path,
+    options: &MuxOptions<'_>,
+) -> Result<Vec<PathBuf>> {
+    let discon_seqs = discons.keys().copied().collect();
+    let output_paths = output_paths(output_dir, options, &discon_seqs).await?;
+
+    mux_discons(discons, &output_paths, |_, _| None, options).await
+}
+
+/// Output names for each discontinuity, refusing to clobber previous outputs unless asked to
+///
+/// Outputs with a given name are numbered instead of refusing to clobber previous outputs, and
+/// their directories are created.
+async fn output_paths(
+    output_dir: &Path,
+    options: &MuxOptions<'_>,
+    discon_seqs: &BTreeSet<u64>,
+) -> Result<HashMap<u64, PathBuf>> {
+    let MuxOptions {
+        name,
+        container,
+        overwrite,
+        ..
+    } = *options;
+    let base = output_dir.join(name.unwrap_or_else(|| Path::new(DEFAULT_NAME)));
+    let mut output_paths: HashMap<_, _> = discon_seqs
+        .iter()
+        .map(|&d| (d, output_path(&base, d, discon_seqs.len(), container)))
+        .collect();
+
+    if name.is_some() {
+        if let Some(parent) = base.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        if !overwrite {
+            for path in output_paths.values_mut() {
+                *path = free_path(path);
+            }
+        }
+        for path in output_paths.values() {
+            event!(Level::INFO, "Remuxing to {:?}", path);
+        }
+    } else if !overwrite {
+        if let Some(p) = output_paths.values().find(|p| p.exists()) {
+            return Err(anyhow::anyhow!(
+                "Output file {:?} already exists, use --overwrite to replace it",
+                p
+            ));
+        }
+    }
+
+    Ok(output_paths)
+}
+
+/// Mux the concatenated streams of each discontinuity into a video file as set by options,
+/// shifted by the offsets computed by offsets_of, then delete the concatenated files
+///
+/// Subtitles that can't be embedded into the container, or all subtitles if a sidecar template is
+/// given, are written to sidecar files instead.
+async fn mux_discons(
+    discons: &HashMap<u64, Vec<ConcatStream<'_>>>,
+    output_paths: &HashMap<u64, PathBuf>,
+    offsets_of: impl Fn(u64, &[ConcatStream<'_>]) -> Option<Vec<f64>>,
+    options: &MuxOptions<'_>,
+) -> Result<Vec<PathBuf>> {
     // For each discontinuity, mux into a video file
-    for (discon_seq, concatted_streams) in &discons {
-        // Generate output name
-        const FILE_NAME: &str = "video";
-        let output_path = if discons.len() == 1 {
-            output_dir.join(FILE_NAME)
-        } else {
-            let file_name = FILE_NAME.to_string() + &format!("_{:010}", discon_seq);
-            output_dir.join(file_name)
+    let mut muxed_paths = Vec::with_capacity(discons.len());
+    for (discon_seq, all_streams) in discons {
+        let output_path = &output_paths[discon_seq];
+        let all_offsets = offsets_of(*discon_seq, all_streams);
+
+        // Split off subtitles written to sidecars
+        let sidecars = subtitle_sidecars(
+            all_streams,
+            output_path,
+            options.container,
+            options.sidecar_template,
+        )?;
+        let is_sidecar = |i: &usize| sidecars.iter().any(|(j, _)| j == i);
+        let concatted_streams: Vec<_> = (0..all_streams.len())
+            .filter(|i| !is_sidecar(i))
+            .map(|i| all_streams[i].clone())
+            .collect();
+        let offsets = all_offsets.as_ref().map(|o| {
+            (0..o.len())
+                .filter(|i| !is_sidecar(i))
+                .map(|i| o[i])
+                .collect::<Vec<_>>()
+        });
+
+        match &offsets {
+            Some(o) => event!(
+                Level::INFO,
+                "Aligning streams by program date time: {}",
+                concatted_streams
+                    .iter()
+                    .zip(o)
+                    .map(|((s, _, _), o)| format!("{} +{:.3}s", s, o))
+                    .join(", ")
+            ),
+            None if concatted_streams.len() > 1 => event!(
+                Level::INFO,
+                "Not all streams have program date times, alignment of streams is unverified"
+            ),
+            None => (),
         }
-        .with_extension("mp4");
+        let start = if options.trim_to_keyframe {
+            keyframe_start(&concatted_streams, offsets.as_deref()).await?
+        } else {
+            None
+        };
+        mux_streams(
+            &concatted_streams,
+            offsets.as_deref(),
+            start,
+            output_path,
+            options,
+        )
+        .await?;
+        muxed_paths.push(output_path.clone());
 
-        // Mux streams
-        mux_streams(concatted_streams, output_path).await?;
+        for (i, sidecar_path) in sidecars {
+            let offset = all_offsets.as_ref().map(|o| o[i]);
+            write_sidecar(
+                &all_streams[i],
+                offset,
+                start,
+                &sidecar_path,
+                options.overwrite,
+            )
+            .await?;
+            muxed_paths.push(sidecar_path);
+        }
     }
 
     // Delete original concatenated files
     for concatted_streams in discons.values() {
-        for (_, path) in concatted_streams {
+        for (_, path, _) in concatted_streams {
             event!(Level::TRACE, "Removing {}", path.to_string_lossy());
             fs::remove_file(path).await?;
         }
     }
 
+    Ok(muxed_paths)
+}
+
+/// Path of the muxed video file of a discontinuity, base is its path without extension
+fn output_path(base: &Path, discon_seq: u64, discon_count: usize, container: Container) -> PathBuf {
+    let mut path = base.as_os_str().to_owned();
+    if discon_count > 1 {
+        path.push(format!("_{:010}", discon_seq));
+    }
+    path.push(".");
+    path.push(container.extension());
+    PathBuf::from(path)
+}
+
+/// Codec WebVTT subtitles are converted to when embedding them into container, or None if they
+/// can't be embedded
+fn subtitle_codec(container: Container) -> Option<&'static str> {
+    match container {
+        Container::Mp4 => Some("mov_text"),
+        Container::Mkv => Some("srt"),
+        Container::Ts => None,
+    }
+}
+
+/// Subtitle streams written to sidecars, by index in streams, with the path of the .srt sidecar
+/// each is written to
+///
+/// Subtitles are written to sidecars if they can't be embedded into container, named by the
+/// language of the subtitles next to output_path, e.g. video.en.srt, or video.en.forced.srt for
+/// forced subtitles. If template is given, all subtitles are written to sidecars named by it.
+/// Subtitles without a language are named by their name instead.
+///
+/// ```
+/// use std::path::{Path, PathBuf};
+///
+/// use livestream_dl::cli::Container;
+/// use livestream_dl::livestream::{MediaFormat, Stream};
+/// use livestream_dl::mux::subtitle_sidecars;
+///
+/// let subtitles = Stream::Subtitle {
+///     group_id: "subs".into(),
+///     name: "English".into(),
+///     lang: Some("en".into()),
+///     forced: false,
+/// };
+/// let forced = Stream::Subtitle {
+///     group_id: "subs".into(),
+///     name: "English (forced)".into(),
+///     lang: Some("en".into()),
+///     forced: true,
+/// };
+/// let commentary = Stream::Subtitle {
+///     group_id: "subs".into(),
+///     name: "Director's commentary".into(),
+///     lang: None,
+///     forced: false,
+/// };
+/// let streams = [
+///     (&Stream::Main, PathBuf::from("main.ts"), MediaFormat::MpegTs),
+///     (&subtitles, PathBuf::from("subs.vtt"), MediaFormat::WebVtt),
+///     (&forced, PathBuf::from("forced.vtt"), MediaFormat::WebVtt),
+///     (&commentary, PathBuf::from("commentary.vtt"), MediaFormat::WebVtt),
+/// ];
+/// let output = Path::new("out/Show.S01E01.ts");
+/// assert_eq!(
+///     subtitle_sidecars(&streams, output, Container::Ts, None).unwrap(),
+///     [
+///         (1, PathBuf::from("out/Show.S01E01.en.srt")),
+///         (2, PathBuf::from("out/Show.S01E01.en.forced.srt")),
+///         (3, PathBuf::from("out/Show.S01E01.Directors-commentary.srt")),
+///     ]
+/// );
+///
+/// // Embedded into MP4 unless a template is given
+/// let output = Path::new("out/Show.S01E01.mp4");
+/// assert!(subtitle_sidecars(&streams, output, Container::Mp4, None)
+///     .unwrap()
+///     .is_empty());
+/// let template = "subs/{stem}.{lang}".parse().unwrap();
+/// assert_eq!(
+///     subtitle_sidecars(&streams[..3], output, Container::Mp4, Some(&template)).unwrap(),
+///     [
+///         (1, PathBuf::from("out/subs/Show.S01E01.en.srt")),
+///         (2, PathBuf::from("out/subs/Show.S01E01.en.forced.srt")),
+///     ]
+/// );
+/// ```
+pub fn subtitle_sidecars(
+    streams: &[ConcatStream<'_>],
+    output_path: &Path,
+    container: Container,
+    template: Option<&SidecarTemplate>,
+) -> Result<Vec<(usize, PathBuf)>> {
+    let default_template = SidecarTemplate::default();
+    let template = match template {
+        Some(t) => t,
+        None if subtitle_codec(container).is_some() => return Ok(Vec::new()),
+        None => &default_template,
+    };
+    let stem = output_path
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy();
+    let dir = output_path.parent().unwrap_or_else(|| Path::new(""));
+
+    let mut sidecars = Vec::new();
+    let mut labels = HashMap::new();
+    for (i, (stream, _, _)) in streams.iter().enumerate() {
+        let (name, lang, forced) = match stream {
+            Stream::Subtitle {
+                name, lang, forced, ..
+            } => (name, lang.as_deref(), *forced),
+            _ => continue,
+        };
+        let sanitize = |s: &str| -> String {
+            s.split_whitespace()
+                .join("-")
+                .chars()
+                .filter(|c| c.is_ascii_alphanumeric() || *c == '-')
+                .collect()
+        };
+        let lang = [lang.map(sanitize), Some(sanitize(name))]
+            .into_iter()
+            .flatten()
+            .find(|l| !l.is_empty())
+            .unwrap_or_else(|| "und".into());
+        let base = template
+            .render(&SidecarValues {
+                stem: &stem,
+                lang: &lang,
+            })
+            .map_err(|e| anyhow::anyhow!("Invalid subtitle sidecar name: {}", e))?;
+
+        let mut label = base.into_os_string();
+        if forced {
+            label.push(".forced");
+        }
+
+        // Number further subtitles of the same language
+        let count = labels.entry(label.clone()).or_insert(0);
+        *count += 1;
+        if *count > 1 {
+            label.push(format!("_{}", count));
+        }
+        label.push(".srt");
+        sidecars.push((i, dir.join(label)));
+    }
+    Ok(sidecars)
+}
+
+/// Index in streams of the first audio rendition in language lang, marked as the default audio
+/// track with --default-audio
+///
+/// ```
+/// use std::path::PathBuf;
+///
+/// use livestream_dl::livestream::{MediaFormat, Stream};
+/// use livestream_dl::mux::default_audio_stream;
+///
+/// let audio = |name: &str, lang: &str| Stream::Audio {
+///     group_id: "aud".into(),
+///     name: name.into(),
+///     lang: Some(lang.into()),
+///     channels: None,
+///     describes_video: false,
+/// };
+/// let (english, french) = (audio("English", "en-US"), audio("French", "fr"));
+/// let streams = [
+///     (&Stream::Main, PathBuf::from("main.ts"), MediaFormat::MpegTs),
+///     (&english, PathBuf::from("en.aac"), MediaFormat::Aac),
+///     (&french, PathBuf::from("fr.aac"), MediaFormat::Aac),
+/// ];
+/// assert_eq!(default_audio_stream(&streams, "fr"), Some(2));
+/// assert_eq!(default_audio_stream(&streams, "EN"), Some(1));
+/// assert_eq!(default_audio_stream(&streams, "de"), None);
+/// ```
+pub fn default_audio_stream(streams: &[ConcatStream<'_>], lang: &str) -> Option<usize> {
+    streams.iter().position(|(stream, _, _)| match stream {
+        Stream::Audio { lang: Some(l), .. } => lang_matches(l, lang),
+        _ => false,
+    })
+}
+
+/// Convert concatenated subtitles to a .srt file, shifted by offset in seconds if given and
+/// dropping cues before start if given, like the video they belong to
+async fn write_sidecar(
+    (stream, path, _): &ConcatStream<'_>,
+    offset: Option<f64>,
+    start: Option<f64>,
+    sidecar_path: &Path,
+    overwrite: bool,
+) -> Result<()> {
+    let mut cmd = process::Command::new("ffmpeg");
+    cmd.arg(if overwrite { "-y" } else { "-n" });
+    match offset {
+        Some(o) => {
+            cmd.arg("-itsoffset").arg(format!("{:.6}", o));
+        }
+        None => {
+            cmd.arg("-copyts");
+        }
+    }
+    cmd.arg("-i").arg(path);
+    if let Some(s) = start {
+        cmd.arg("-ss").arg(format!("{:.6}", s));
+    }
+
+    event!(
+        Level::INFO,
+        "Writing subtitles {} to sidecar {:?}",
+        stream,
+        sidecar_path
+    );
+    if let Some(parent) = sidecar_path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    cmd.arg("-c:s")
+        .arg("srt")
+        .arg(sidecar_path)
+        .kill_on_drop(true);
+
+    event!(Level::TRACE, "{:?}", cmd);
+    let output = cmd.output().await?;
+    event!(
+        Level::TRACE,
+        "ffmpeg stderr: {:#?}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    if !output.status.success() {
+        return Err(anyhow::anyhow!("ffmpeg command failed"));
+    }
+
     Ok(())
 }
 
-/// Mux streams into a video file
+/// Embed subtitles announcing gaps as the last subtitle track of the muxed file at output_path,
+/// which has the given number of subtitle tracks already, returns the path of the .gaps.srt
+/// sidecar they are written to instead if container can't hold subtitles or embedding fails
+async fn write_gap_report(
+    cues: &[GapCue],
+    output_path: &Path,
+    container: Container,
+    subtitles: usize,
+) -> Result<Option<PathBuf>> {
+    event!(
+        Level::WARN,
+        "Recording gaps in {:?}: {}",
+        output_path,
+        cues.iter().map(|c| c.text()).join(", ")
+    );
+    let srt_path = output_path.with_extension("gaps.srt");
+    fs::write(&srt_path, gaps_srt(cues)).await?;
+
+    let codec = match subtitle_codec(container) {
+        Some(c) => c,
+        None => {
+            event!(
+                Level::WARN,
+                "{} outputs can't hold the gap report, writing it to {:?}",
+                container.extension(),
+                srt_path
+            );
+            return Ok(Some(srt_path));
+        }
+    };
+
+    // ffmpeg can't write into its input, so mux into a temporary file replacing the output
+    let tmp_path = output_path.with_extension(format!("gaps-tmp.{}", container.extension()));
+    let mut cmd = process::Command::new("ffmpeg");
+    cmd.arg("-y")
+        .arg("-i")
+        .arg(output_path)
+        .arg("-i")
+        .arg(&srt_path)
+        .arg("-map")
+        .arg("0")
+        .arg("-map")
+        .arg("1")
+        .arg("-c")
+        .arg("copy")
+        .arg(format!("-c:s:{}", subtitles))
+        .arg(codec)
+        .arg(format!("-metadata:s:s:{}", subtitles))
+        .arg("title=Recording gaps")
+        .arg(format!("-disposition:s:{}", subtitles))
+        .arg("0");
+    if container == Container::Mp4 {
+        cmd.arg("-movflags").arg("+faststart");
+    }
+    cmd.arg(&tmp_path).kill_on_drop(true);
+
+    event!(Level::TRACE, "{:?}", cmd);
+    let output = cmd.output().await?;
+    event!(
+        Level::TRACE,
+        "ffmpeg stderr: {:#?}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    if !output.status.success() {
+        let _ = fs::remove_file(&tmp_path).await;
+        event!(
+            Level::WARN,
+            "Unable to embed the gap report into {:?}, writing it to {:?}",
+            output_path,
+            srt_path
+        );
+        return Ok(Some(srt_path));
+    }
+
+    fs::rename(&tmp_path, output_path).await?;
+    fs::remove_file(&srt_path).await?;
+    Ok(None)
+}
+
+/// path if it doesn't exist yet, otherwise the first free path with _1, _2, ... appended to its
+/// file name
+fn free_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = path.extension().unwrap_or_default().to_string_lossy();
+    let mut candidate = path.to_path_buf();
+    let mut counter = 1;
+    while candidate.exists() {
+        candidate = path.with_file_name(format!("{}_{}.{}", stem, counter, ext));
+        counter += 1;
+    }
+    candidate
+}
+
+/// Offsets in seconds of the start of each stream of a discontinuity from the earliest stream,
+/// computed from program date times
+///
+/// Returns None if there is only one stream or a stream has no program date time.
+fn start_offsets(
+    downloaded_paths: &HashMap<Stream, BinaryHeap<(Segment, PathBuf)>>,
+    discon_seq: u64,
+    streams: &[ConcatStream<'_>],
+) -> Option<Vec<f64>> {
+    if streams.len() < 2 {
+        return None;
+    }
+
+    let starts = streams
+        .iter()
+        .map(|(stream, _, _)| {
+            downloaded_paths
+                .get(*stream)?
+                .iter()
+                .map(|(s, _)| s)
+                .filter(|s| s.discon_seq == discon_seq)
+                .min()?
+                .program_date_time
+        })
+        .collect::<Option<Vec<_>>>()?;
+    let earliest = *starts.iter().min()?;
+
+    Some(
+        starts
+            .iter()
+            .map(|s| (*s - earliest).as_seconds_f64())
+            .collect(),
+    )
+}
+
+/// Start time of the output of streams at the first video keyframe, in the timeline ffmpeg uses
+/// for -ss when muxing them with offsets
+///
+/// Returns None if the streams have no video.
+async fn keyframe_start(
+    streams: &[ConcatStream<'_>],
+    offsets: Option<&[f64]>,
+) -> Result<Option<f64>> {
+    for (i, (stream, path, _)) in streams.iter().enumerate() {
+        let (keyframe, file_start) = match first_keyframe(path).await? {
+            Some(k) => k,
+            None => continue,
+        };
+        event!(
+            Level::INFO,
+            "Trimming output to the first keyframe of {}, {:.3}s after its start",
+            stream,
+            keyframe - file_start
+        );
+
+        // Timestamps are kept without offsets, otherwise inputs start at their offset
+        return Ok(Some(match offsets {
+            Some(o) => keyframe - file_start + o[i],
+            None => keyframe,
+        }));
+    }
+
+    event!(
+        Level::WARN,
+        "No video keyframe found, output is not trimmed"
+    );
+    Ok(None)
+}
+
+/// Timestamp of the first video keyframe of a media file and its start time in seconds, None if
+/// it has no video
+///
+/// Only the first minute of the file is searched.
+async fn first_keyframe(path: impl AsRef<Path>) -> Result<Option<(f64, f64)>> {
+    #[derive(Deserialize, Debug)]
+    struct FFProbeOutput {
+        #[serde(default)]
+        packets: Vec<FFProbePacket>,
+        format: FFProbeFormat,
+    }
+    #[derive(Deserialize, Debug)]
+    struct FFProbePacket {
+        pts_time: Option<String>,
+        flags: String,
+    }
+    #[derive(Deserialize, Debug)]
+    struct FFProbeFormat {
+        start_time: Option<String>,
+    }
+
+    let mut cmd = process::Command::new("ffprobe");
+    cmd.arg("-loglevel")
+        .arg("quiet")
+        .arg("-select_streams")
+        .arg("v:0")
+        .arg("-read_intervals")
+        .arg("%+60")
+        .arg("-show_entries")
+        .arg("packet=pts_time,flags:format=start_time")
+        .arg("-print_format")
+        .arg("json")
+        .arg(path.as_ref())
+        .kill_on_drop(true);
+
+    event!(Level::TRACE, "{:?}", cmd);
+    let output = cmd.output().await?;
+    let parsed: FFProbeOutput = serde_json::from_str(std::str::from_utf8(&output.stdout)?)?;
+
+    let keyframe = parsed
+        .packets
+        .iter()
+        .filter(|p| p.flags.starts_with('K'))
+        .find_map(|p| p.pts_time.as_deref()?.parse::<f64>().ok());
+    let file_start = parsed
+        .format
+        .start_time
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(0.0);
+
+    Ok(keyframe.map(|k| (k, file_start)))
+}
+
+/// Mux streams into a video file as set by options, shifting streams by offsets in seconds if
+/// given and dropping packets before start if given
 async fn mux_streams<P: AsRef<Path>>(
-    streams: &Vec<(&Stream, PathBuf)>,
+    streams: &[ConcatStream<'_>],
+    offsets: Option<&[f64]>,
+    start: Option<f64>,
     output_path: P,
+    options: &MuxOptions<'_>,
 ) -> Result<()> {
+    let MuxOptions {
+        container,
+        default_audio,
+        overwrite,
+        transcode,
+        ..
+    } = *options;
+
     // Call ffmpeg to remux video file
     let mut cmd = process::Command::new("ffmpeg");
-    cmd.arg("-y").arg("-copyts");
+    cmd.arg(if overwrite { "-y" } else { "-n" });
+
+    // Keep timestamps of the inputs unless they are aligned by offsets
+    if offsets.is_none() {
+        cmd.arg("-copyts");
+    }
 
     // Set ffmpeg input files
-    for (_, path) in streams {
+    for (i, (_, path, format)) in streams.iter().enumerate() {
+        if let Some(o) = offsets {
+            cmd.arg("-itsoffset").arg(format!("{:.6}", o[i]));
+        }
+        if let Some(demuxer) = format.demuxer() {
+            cmd.arg("-f").arg(demuxer);
+        }
         cmd.arg("-i").arg(path);
     }
 
@@ -67,10 +805,28 @@ async fn mux_streams<P: AsRef<Path>>(
         cmd.arg("-map").arg(i.to_string());
     }
 
-    // Add metadata
-    add_metadata(&mut cmd, streams).await?;
+    // Add metadata and bitstream filters
+    add_metadata(&mut cmd, streams, container, default_audio).await?;
 
-    event!(Level::INFO, "ffmpeg mux to {:?}", output_path.as_ref());
+    // Drop packets before the first keyframe
+    if let Some(s) = start {
+        cmd.arg("-ss").arg(format!("{:.6}", s));
+    }
+
+    match transcode {
+        Some(_) => event!(
+            Level::INFO,
+            "ffmpeg transcode to {:?}, this is much slower than remuxing",
+            output_path.as_ref()
+        ),
+        None => event!(Level::INFO, "ffmpeg mux to {:?}", output_path.as_ref()),
+    }
+
+    // Copy video unless transcoding
+    match transcode {
+        Some(t) => cmd.args(t.video_args()),
+        None => cmd.arg("-c:v").arg("copy"),
+    };
 
     // Set remaining ffmpeg args and run ffmpeg
     cmd.arg("-muxpreload")
@@ -79,17 +835,15 @@ async fn mux_streams<P: AsRef<Path>>(
         .arg("0")
         .arg("-avoid_negative_ts")
         .arg("make_zero")
-        .arg("-c:v")
-        .arg("copy")
         .arg("-c:a")
         .arg("copy")
         .arg("-c:s")
-        .arg("mov_text")
-        .arg("-dn")
-        .arg("-movflags")
-        .arg("+faststart")
-        .arg(output_path.as_ref())
-        .kill_on_drop(true);
+        .arg(subtitle_codec(container).unwrap_or("copy"))
+        .arg("-dn");
+    if container == Container::Mp4 {
+        cmd.arg("-movflags").arg("+faststart");
+    }
+    cmd.arg(output_path.as_ref()).kill_on_drop(true);
 
     event!(Level::TRACE, "{:?}", cmd);
     let output = cmd.output().await?;
@@ -112,20 +866,75 @@ async fn mux_streams<P: AsRef<Path>>(
     Ok(())
 }
 
-/// Pass stream names and languages to ffmpeg command
-async fn add_metadata(cmd: &mut process::Command, streams: &Vec<(&Stream, PathBuf)>) -> Result<()> {
+/// Pass stream names, languages and accessibility dispositions to ffmpeg command, and bitstream
+/// filters needed to copy audio into MP4 and Matroska
+async fn add_metadata(
+    cmd: &mut process::Command,
+    streams: &[ConcatStream<'_>],
+    container: Container,
+    default_audio: Option<&str>,
+) -> Result<()> {
+    let mut filters = Vec::new();
+
+    // Audio rendition to mark as default, all other audio tracks are marked as not default
+    let default_index = default_audio.and_then(|lang| {
+        let index = default_audio_stream(streams, lang);
+        if index.is_none() {
+            event!(
+                Level::WARN,
+                "No audio rendition in language {}, not changing the default audio track",
+                lang
+            );
+        }
+        index
+    });
+    let default_stream = default_index.map(|i| streams[i].0);
+    let mut not_default = Vec::new();
+
     // Closure to add stream metadata if available
     let mut add_lang = |stream: &Stream, t, lang, count| {
         // Language
         if let Some(l) = lang {
-            if let Ok(l) = to_iso639_2(l) {
+            if let Some(l) = to_iso639_2(l) {
                 cmd.arg(format!("-metadata:s:{}:{}", t, count))
                     .arg(format!("language={}", l));
             }
         }
 
-        // Name
-        if let Some(n) = stream.name() {
+        // Default and accessibility flags
+        let mut flags = Vec::new();
+        if default_stream.is_some_and(|d| std::ptr::eq(d, stream)) {
+            flags.push("default");
+        }
+        match stream {
+            Stream::Audio {
+                describes_video: true,
+                ..
+            } => flags.push("visual_impaired"),
+            Stream::Subtitle { forced: true, .. } => flags.push("forced"),
+            _ => (),
+        }
+        let clear_default = default_stream.is_some() && t == "a";
+        if !flags.is_empty() || clear_default {
+            let flags = if flags.is_empty() {
+                "0".to_string()
+            } else {
+                flags.join("+")
+            };
+            cmd.arg(format!("-disposition:{}:{}", t, count)).arg(flags);
+        }
+
+        // Name, with group id if another stream of the same type has the same name
+        if let Some(mut n) = stream.name() {
+            let same_name = streams
+                .iter()
+                .filter(|(s, _, _)| {
+                    mem::discriminant(*s) == mem::discriminant(stream) && s.name() == stream.name()
+                })
+                .count();
+            if let Some(g) = stream.group_id().filter(|g| same_name > 1 && !g.is_empty()) {
+                n = format!("{} ({})", n, g);
+            }
             cmd.arg(format!("-metadata:s:{}:{}", t, count))
                 .arg(format!("title={}", n))
                 .arg(format!("-metadata:s:{}:{}", t, count))
@@ -139,30 +948,54 @@ async fn add_metadata(cmd: &mut process::Command, streams: &Vec<(&Stream, PathBu
     let mut video_count = 0;
     let mut audio_count = 0;
     let mut subtitle_count = 0;
-    for (stream, p) in streams {
+    for (stream, p, format) in streams {
         match stream {
             Stream::Main => {
                 for stream in stream_type(p).await? {
                     match stream {
                         StreamType::Video => video_count += 1,
-                        StreamType::Audio => audio_count += 1,
+                        StreamType::Audio => {
+                            if let Some(f) = format.mp4_bitstream_filter() {
+                                filters.push((format!("-bsf:a:{}", audio_count), f));
+                            }
+                            if default_stream.is_some() {
+                                not_default.push(format!("-disposition:a:{}", audio_count));
+                            }
+                            audio_count += 1
+                        }
                         StreamType::Subtitle => subtitle_count += 1,
                         _ => (),
                     }
                 }
             }
             Stream::Video { lang: l, .. } => {
-                video_count = add_lang(stream, "v", l.as_ref(), video_count);
+                video_count = add_lang(stream, "v", l.as_deref(), video_count);
             }
             Stream::Audio { lang: l, .. } => {
-                audio_count = add_lang(stream, "a", l.as_ref(), audio_count);
+                if let Some(f) = format.mp4_bitstream_filter() {
+                    filters.push((format!("-bsf:a:{}", audio_count), f));
+                }
+                audio_count = add_lang(stream, "a", l.as_deref(), audio_count);
             }
             Stream::Subtitle { lang: l, .. } => {
-                subtitle_count = add_lang(stream, "s", l.as_ref(), subtitle_count);
+                subtitle_count = add_lang(stream, "s", l.as_deref(), subtitle_count);
             }
         }
     }
 
+    // Audio of the main stream isn't default if a rendition is
+    for specifier in not_default {
+        cmd.arg(specifier).arg("0");
+    }
+
+    // Set bitstream filters, MPEG-TS takes audio as it is in HLS segments
+    if container == Container::Ts {
+        filters.clear();
+    }
+    for (specifier, filter) in filters {
+        cmd.arg(specifier).arg(filter);
+    }
+
     Ok(())
 }
 
@@ -233,24 +1066,271 @@ async fn stream_type(stream_path: impl AsRef<Path>) -> Result<Vec<StreamType>> {
     Ok(r)
 }
 
-/// Convert rfc5646 language tag to iso639-3 format readable by ffmpeg
-fn to_iso639_2(lang: impl AsRef<str>) -> Result<String> {
-    // Parse language tag string
-    let tag = LanguageTag::parse(lang.as_ref())?;
-    let mut code = tag.primary_language().to_owned();
+/// Bibliographic ISO 639-2 codes of the languages whose terminology code, which ISO 639-3 uses,
+/// differs
+const ISO639_2_BIBLIOGRAPHIC: &[(&str, &str)] = &[
+    ("bod", "tib"),
+    ("ces", "cze"),
+    ("cym", "wel"),
+    ("deu", "ger"),
+    ("ell", "gre"),
+    ("eus", "baq"),
+    ("fas", "per"),
+    ("fra", "fre"),
+    ("hye", "arm"),
+    ("isl", "ice"),
+    ("kat", "geo"),
+    ("mkd", "mac"),
+    ("mri", "mao"),
+    ("msa", "may"),
+    ("mya", "bur"),
+    ("nld", "dut"),
+    ("ron", "rum"),
+    ("slk", "slo"),
+    ("sqi", "alb"),
+    ("zho", "chi"),
+];
+
+/// Convert a language tag of a playlist to the bibliographic ISO 639-2 code that ffmpeg writes to
+/// MP4 and Matroska and media servers expect, or None if the language is unknown
+///
+/// Tags may be RFC 5646 tags with a two or three letter primary language, region and script are
+/// dropped.
+///
+/// ```
+/// use livestream_dl::mux::to_iso639_2;
+///
+/// assert_eq!(to_iso639_2("en"), Some("eng"));
+/// assert_eq!(to_iso639_2("eng"), Some("eng"));
+/// assert_eq!(to_iso639_2("en-US"), Some("eng"));
+/// assert_eq!(to_iso639_2("EN-us"), Some("eng"));
+/// assert_eq!(to_iso639_2("fr-CA"), Some("fre"));
+/// assert_eq!(to_iso639_2("fra"), Some("fre"));
+/// assert_eq!(to_iso639_2("fre"), Some("fre"));
+/// assert_eq!(to_iso639_2("de"), Some("ger"));
+/// assert_eq!(to_iso639_2("zh-Hans-CN"), Some("chi"));
+/// assert_eq!(to_iso639_2("es-419"), Some("spa"));
+/// assert_eq!(to_iso639_2("xx"), None);
+/// assert_eq!(to_iso639_2("English"), None);
+/// ```
+pub fn to_iso639_2(lang: &str) -> Option<&'static str> {
+    let tag = LanguageTag::parse(lang).ok()?;
+    let primary = tag.primary_language().to_lowercase();
+    let terminology = match primary.len() {
+        2 => Language::from_639_1(&primary)?.to_639_3(),
+        3 => match ISO639_2_BIBLIOGRAPHIC.iter().find(|(_, b)| *b == primary) {
+            Some((t, _)) => t,
+            None => Language::from_639_3(&primary)?.to_639_3(),
+        },
+        _ => return None,
+    };
+    Some(
+        ISO639_2_BIBLIOGRAPHIC
+            .iter()
+            .find(|(t, _)| *t == terminology)
+            .map_or(terminology, |(_, b)| b),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+    use std::time::Duration;
+
+    use time::format_description::well_known::Rfc3339;
+    use time::OffsetDateTime;
+
+    use super::*;
+    use crate::livestream::{MediaFormat, RemoteData};
+
+    fn audio(name: &str, lang: &str) -> Stream {
+        Stream::Audio {
+            group_id: "aac".into(),
+            name: name.into(),
+            lang: Some(lang.into()),
+            channels: None,
+            describes_video: false,
+        }
+    }
+
+    /// Run a command, false if it couldn't be run or failed
+    fn run(cmd: &mut Command) -> bool {
+        cmd.output().is_ok_and(|o| o.status.success())
+    }
+
+    /// Downloaded segments of a media playlist whose segments all have a program date time
+    fn downloaded(playlist: &str) -> BinaryHeap<(Segment, PathBuf)> {
+        let playlist = m3u8_rs::parse_media_playlist_res(playlist.as_bytes()).unwrap();
+        (playlist.media_sequence..)
+            .zip(&playlist.segments)
+            .map(|(seq, s)| {
+                let segment = Segment {
+                    data: RemoteData::new(
+                        format!("https://example.com/{}", s.uri).parse().unwrap(),
+                        None,
+                    ),
+                    discon_seq: 0,
+                    seq,
+                    format: MediaFormat::MpegTs,
+                    initialization: None,
+                    program_date_time: s
+                        .program_date_time
+                        .as_deref()
+                        .and_then(|p| OffsetDateTime::parse(p, &Rfc3339).ok()),
+                    duration: Some(Duration::from_secs_f32(s.duration)),
+                };
+                (segment, PathBuf::from(&s.uri))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn audio_offset_is_computed_from_program_date_times() {
+        // Audio with shorter segments and other sequence numbers, starting 1.5s after the video
+        let video = downloaded(
+            "#EXTM3U\n#EXT-X-TARGETDURATION:6\n#EXT-X-MEDIA-SEQUENCE:100\n\
+             #EXT-X-PROGRAM-DATE-TIME:2024-05-01T12:00:00.000Z\n#EXTINF:6.0,\nv100.ts\n\
+             #EXT-X-PROGRAM-DATE-TIME:2024-05-01T12:00:06.000Z\n#EXTINF:6.0,\nv101.ts\n",
+        );
+        let audio_segments = downloaded(
+            "#EXTM3U\n#EXT-X-TARGETDURATION:4\n#EXT-X-MEDIA-SEQUENCE:40\n\
+             #EXT-X-PROGRAM-DATE-TIME:2024-05-01T12:00:01.500Z\n#EXTINF:4.0,\na40.ts\n\
+             #EXT-X-PROGRAM-DATE-TIME:2024-05-01T12:00:05.500Z\n#EXTINF:4.0,\na41.ts\n\
+             #EXT-X-PROGRAM-DATE-TIME:2024-05-01T12:00:09.500Z\n#EXTINF:4.0,\na42.ts\n",
+        );
+        let audio_stream = audio("English", "en");
+        let downloaded_paths = HashMap::from([
+            (Stream::Main, video),
+            (audio_stream.clone(), audio_segments),
+        ]);
+        let streams = [
+            (&Stream::Main, PathBuf::from("main.ts"), MediaFormat::MpegTs),
+            (
+                &audio_stream,
+                PathBuf::from("audio.ts"),
+                MediaFormat::MpegTs,
+            ),
+        ];
+
+        // Audio is delayed by its offset, within one frame at 25 fps
+        let offsets = start_offsets(&downloaded_paths, 0, &streams).unwrap();
+        assert_eq!(offsets[0], 0.0);
+        assert!((offsets[1] - 1.5).abs() < 1.0 / 25.0);
 
-    // If tag is 2 letter iso639-1, convert to 3 letter iso639-3
-    if code.len() == 2 {
-        code = Language::from_639_1(&code)
-            .ok_or_else(|| anyhow::anyhow!("Unknown language: {}", &code))?
-            .to_639_3()
-            .to_owned();
+        // Without program date times the streams are not aligned
+        let mut without_pdt = downloaded_paths.clone();
+        without_pdt.insert(
+            audio_stream.clone(),
+            without_pdt[&audio_stream]
+                .iter()
+                .cloned()
+                .map(|(mut s, p)| {
+                    s.program_date_time = None;
+                    (s, p)
+                })
+                .collect(),
+        );
+        assert_eq!(start_offsets(&without_pdt, 0, &streams), None);
     }
 
-    // Append region code if necessary
-    if let Some(r) = tag.region() {
-        code.push_str(&format!("-{}", r));
+    #[tokio::test]
+    async fn audio_renditions_are_tagged_and_default_is_flagged() {
+        let streams = [
+            (
+                &audio("English", "en"),
+                PathBuf::from("en.ts"),
+                MediaFormat::MpegTs,
+            ),
+            (
+                &audio("Français", "fr"),
+                PathBuf::from("fr.ts"),
+                MediaFormat::MpegTs,
+            ),
+        ];
+        let mut cmd = process::Command::new("ffmpeg");
+        add_metadata(&mut cmd, &streams, Container::Mkv, Some("fr"))
+            .await
+            .unwrap();
+        let args: Vec<_> = cmd
+            .as_std()
+            .get_args()
+            .map(|a| a.to_str().unwrap())
+            .collect();
+        let pairs: Vec<_> = args.chunks(2).map(|c| (c[0], c[1])).collect();
+        for arg in [
+            ("-metadata:s:a:0", "language=eng"),
+            ("-disposition:a:0", "0"),
+            ("-metadata:s:a:1", "language=fre"),
+            ("-disposition:a:1", "default"),
+        ] {
+            assert!(pairs.contains(&arg), "{:?} missing from {:?}", arg, args);
+        }
     }
 
-    Ok(code)
+    #[tokio::test]
+    async fn all_audio_renditions_are_muxed() {
+        if !run(Command::new("ffmpeg").arg("-version"))
+            || !run(Command::new("ffprobe").arg("-version"))
+        {
+            eprintln!("Skipping, ffmpeg and ffprobe are required");
+            return;
+        }
+
+        // Video without audio and two audio renditions
+        let dir = tempfile::tempdir().unwrap();
+        let input = |name: &str, source: &str, codec: &[&str]| {
+            let path = dir.path().join(name);
+            assert!(run(Command::new("ffmpeg")
+                .args(["-v", "error", "-f", "lavfi", "-i", source])
+                .args(codec)
+                .args(["-f", "mpegts"])
+                .arg(&path)));
+            path
+        };
+        let video = input(
+            "video.ts",
+            "testsrc=size=64x64:rate=10:duration=1",
+            &["-c:v", "mpeg2video"],
+        );
+        let en = input("en.ts", "sine=duration=1", &["-c:a", "aac"]);
+        let fr = input("fr.ts", "sine=duration=1", &["-c:a", "aac"]);
+        let (en_stream, fr_stream) = (audio("English", "en"), audio("Français", "fr"));
+        let streams = [
+            (&Stream::Main, video, MediaFormat::MpegTs),
+            (&en_stream, en, MediaFormat::MpegTs),
+            (&fr_stream, fr, MediaFormat::MpegTs),
+        ];
+
+        let output = dir.path().join("out.mkv");
+        let options = MuxOptions {
+            default_audio: Some("fr"),
+            ..MuxOptions::new(Container::Mkv, false)
+        };
+        mux_streams(&streams, None, None, &output, &options)
+            .await
+            .unwrap();
+
+        // Each rendition is its own track with its language, French is the default
+        let probe = Command::new("ffprobe")
+            .args(["-v", "error", "-select_streams", "a"])
+            .args([
+                "-show_entries",
+                "stream_tags=language:stream_disposition=default",
+            ])
+            .args(["-of", "json"])
+            .arg(&output)
+            .output()
+            .unwrap();
+        let probe: serde_json::Value = serde_json::from_slice(&probe.stdout).unwrap();
+        let tracks: Vec<_> = probe["streams"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|s| {
+                let lang = s["tags"]["language"].as_str().unwrap().to_string();
+                (lang, s["disposition"]["default"].as_i64().unwrap())
+            })
+            .collect();
+        assert_eq!(tracks, [("eng".to_string(), 0), ("fre".to_string(), 1)]);
+    }
 }
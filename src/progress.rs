@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io::{self, IsTerminal, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::Layer;
+
+use crate::livestream::EVENT_TARGET;
+
+/// Template of bars of streams whose playlist ended, so their number of segments is known
+const VOD_TEMPLATE: &str = "{prefix:>24.bold} [{bar:30}] {pos}/{len} segments, {msg}, ETA {eta}";
+
+/// Template of bars of live streams
+const LIVE_TEMPLATE: &str = "{prefix:>24.bold} {spinner} {elapsed_precise} {pos} segments, {msg}";
+
+/// Interval to redraw bars of live streams at
+const TICK_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Tracing layer drawing a progress bar per stream from download events
+///
+/// Logs written with [`ProgressLayer::writer`] are printed above the bars.
+pub struct ProgressLayer {
+    bars: MultiProgress,
+    streams: Mutex<HashMap<String, StreamProgress>>,
+}
+
+/// Progress of the downloads of a stream
+struct StreamProgress {
+    bar: ProgressBar,
+    /// Segments listed by the playlist
+    listed: u64,
+    bytes: u64,
+    failed: u64,
+    started: Instant,
+    /// Whether the playlist ended, so no more segments will be listed
+    ended: bool,
+}
+
+impl ProgressLayer {
+    /// Progress bars drawn to stderr, or None if stderr is not a terminal
+    pub fn for_terminal() -> Option<Self> {
+        io::stderr().is_terminal().then(|| Self {
+            bars: MultiProgress::new(),
+            streams: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Writer of stdout that hides the bars while writing
+    pub fn writer(&self) -> ProgressWriter {
+        ProgressWriter(self.bars.clone())
+    }
+
+    fn update(&self, e: DownloadEvent) {
+        let stream = match e.stream {
+            Some(s) => s,
+            None => return,
+        };
+        let mut streams = self.streams.lock().unwrap();
+        let p = streams.entry(stream).or_insert_with_key(|stream| {
+            let bar = self.bars.add(ProgressBar::new_spinner());
+            bar.set_style(ProgressStyle::with_template(LIVE_TEMPLATE).unwrap());
+            bar.set_prefix(stream.clone());
+            bar.enable_steady_tick(TICK_INTERVAL);
+            StreamProgress {
+                bar,
+                listed: 0,
+                bytes: 0,
+                failed: 0,
+                started: Instant::now(),
+                ended: false,
+            }
+        });
+
+        match e.message.as_str() {
+            "playlist_refreshed" => {
+                p.listed += e.new_segments.unwrap_or(0);
+                if e.end_list == Some(true) {
+                    p.bar.set_style(
+                        ProgressStyle::with_template(VOD_TEMPLATE)
+                            .unwrap()
+                            .progress_chars("=> "),
+                    );
+                    p.bar.disable_steady_tick();
+                    p.bar.set_length(p.listed);
+                }
+            }
+            "segment_downloaded" => {
+                p.bytes += e.bytes.unwrap_or(0);
+                p.bar.inc(1);
+            }
+            "segment_failed" => p.failed += 1,
+            "stream_ended" => p.ended = true,
+            _ => return,
+        }
+
+        // Listed segments are still downloading when the playlist ends
+        if p.ended && p.bar.position() + p.failed >= p.listed {
+            p.bar.finish();
+        }
+
+        let rate = p.bytes as f64 / p.started.elapsed().as_secs_f64().max(1.0);
+        let mut msg = format!("{}, {}/s", HumanBytes(p.bytes), HumanBytes(rate as u64));
+        if p.failed > 0 {
+            msg += &format!(", {} failed", p.failed);
+        }
+        p.bar.set_message(msg);
+    }
+}
+
+impl<S: Subscriber> Layer<S> for ProgressLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if event.metadata().target() != EVENT_TARGET {
+            return;
+        }
+        let mut e = DownloadEvent::default();
+        event.record(&mut e);
+        self.update(e);
+    }
+}
+
+/// Fields of download events used for progress
+#[derive(Default)]
+struct DownloadEvent {
+    message: String,
+    stream: Option<String>,
+    bytes: Option<u64>,
+    new_segments: Option<u64>,
+    end_list: Option<bool>,
+}
+
+impl Visit for DownloadEvent {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        match field.name() {
+            "bytes" => self.bytes = Some(value),
+            "new_segments" => self.new_segments = Some(value),
+            _ => {}
+        }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        if let Ok(value) = value.try_into() {
+            self.record_u64(field, value);
+        }
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        if field.name() == "end_list" {
+            self.end_list = Some(value);
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = value.to_owned();
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        match field.name() {
+            "message" => self.message = format!("{:?}", value),
+            "stream" => self.stream = Some(format!("{:?}", value)),
+            _ => {}
+        }
+    }
+}
+
+/// Writer of stdout that hides progress bars while writing, see [`ProgressLayer::writer`]
+#[derive(Clone)]
+pub struct ProgressWriter(MultiProgress);
+
+impl Write for ProgressWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.suspend(|| io::stdout().write(buf))
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.0.suspend(|| io::stdout().write_all(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+impl<'a> MakeWriter<'a> for ProgressWriter {
+    type Writer = Self;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::env;
+use std::path::PathBuf;
+
+use anyhow::Result;
+
+/// AWS access credentials
+#[derive(Clone)]
+pub struct Credentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("access_key_id", &self.access_key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Credentials {
+    /// Load credentials from the environment, then from the shared credentials file
+    pub fn load() -> Result<Self> {
+        if let (Ok(id), Ok(secret)) = (
+            env::var("AWS_ACCESS_KEY_ID"),
+            env::var("AWS_SECRET_ACCESS_KEY"),
+        ) {
+            return Ok(Self {
+                access_key_id: id,
+                secret_access_key: secret,
+                session_token: env::var("AWS_SESSION_TOKEN").ok(),
+            });
+        }
+
+        let path = env::var_os("AWS_SHARED_CREDENTIALS_FILE")
+            .map(PathBuf::from)
+            .or_else(|| aws_dir().map(|d| d.join("credentials")))
+            .ok_or_else(|| anyhow::anyhow!("No AWS credentials found"))?;
+        let profile = profile_name();
+        let section = read_ini_section(&path, &profile)
+            .ok_or_else(|| anyhow::anyhow!("No AWS credentials found for profile {:?}", profile))?;
+
+        match (
+            section.get("aws_access_key_id"),
+            section.get("aws_secret_access_key"),
+        ) {
+            (Some(id), Some(secret)) => Ok(Self {
+                access_key_id: id.clone(),
+                secret_access_key: secret.clone(),
+                session_token: section.get("aws_session_token").cloned(),
+            }),
+            _ => Err(anyhow::anyhow!(
+                "Incomplete AWS credentials for profile {:?} in {:?}",
+                profile,
+                path
+            )),
+        }
+    }
+}
+
+/// Find region from the environment, then from the shared config file
+pub fn region() -> String {
+    env::var("AWS_REGION")
+        .or_else(|_| env::var("AWS_DEFAULT_REGION"))
+        .ok()
+        .or_else(|| {
+            let path = env::var_os("AWS_CONFIG_FILE")
+                .map(PathBuf::from)
+                .or_else(|| aws_dir().map(|d| d.join("config")))?;
+            let profile = match profile_name().as_str() {
+                "default" => "default".to_owned(),
+                p => format!("profile {}", p),
+            };
+            read_ini_section(&path, &profile)?.remove("region")
+        })
+        .unwrap_or_else(|| "us-east-1".to_owned())
+}
+
+fn profile_name() -> String {
+    env::var("AWS_PROFILE").unwrap_or_else(|_| "default".to_owned())
+}
+
+fn aws_dir() -> Option<PathBuf> {
+    env::var_os("HOME")
+        .or_else(|| env::var_os("USERPROFILE"))
+        .map(|h| PathBuf::from(h).join(".aws"))
+}
+
+/// Read key value pairs of a section in an ini file
+fn read_ini_section(path: &PathBuf, section: &str) -> Option<HashMap<String, String>> {
+    let contents = std::fs::read_to_string(path).ok()?;
+    let mut values = None;
+    for line in contents.lines().map(str::trim) {
+        if line.starts_with('[') && line.ends_with(']') {
+            if values.is_some() {
+                break;
+            }
+            if line[1..line.len() - 1].trim() == section {
+                values = Some(HashMap::new());
+            }
+        } else if let Some(v) = values.as_mut() {
+            if let Some((key, value)) = line.split_once('=') {
+                v.insert(key.trim().to_owned(), value.trim().to_owned());
+            }
+        }
+    }
+    values
+}
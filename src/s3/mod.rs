@@ -0,0 +1,391 @@
+mod credentials;
+mod sigv4;
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use futures::channel::mpsc;
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use reqwest::{Client, Method, Url};
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+use tokio::{fs, time};
+use tracing::{event, Level};
+
+use self::credentials::Credentials;
+use crate::cli::UploadOptions;
+use crate::livestream::OpenFiles;
+
+/// Files larger than this are uploaded in parts of this size
+const PART_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Number of times to try uploading a file before giving up
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Minimal client for S3-compatible object storage
+#[derive(Debug)]
+struct S3Client {
+    client: Client,
+    credentials: Credentials,
+    region: String,
+    endpoint: Option<Url>,
+    bucket: String,
+    /// Bound on files read at once, shared with the download
+    open_files: OpenFiles,
+}
+
+impl S3Client {
+    /// URL of an object, path style for custom endpoints and virtual hosted style for AWS
+    fn object_url(&self, key: &str) -> Result<Url> {
+        let key = sigv4::uri_encode(key, true);
+        let url = match &self.endpoint {
+            Some(e) => e.join(&format!("{}/{}", self.bucket, key))?,
+            None => Url::parse(&format!(
+                "https://{}.s3.{}.amazonaws.com/{}",
+                self.bucket, self.region, key
+            ))?,
+        };
+        Ok(url)
+    }
+
+    /// Send a signed request and return the response body
+    async fn request(
+        &self,
+        method: Method,
+        key: &str,
+        query: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<(reqwest::header::HeaderMap, String)> {
+        let url = self.object_url(key)?;
+        let headers = sigv4::sign(&self.credentials, &self.region, &method, &url, query);
+        let resp = self
+            .client
+            .request(method, url)
+            .query(query)
+            .headers(headers)
+            .body(body)
+            .send()
+            .await?;
+
+        let status = resp.status();
+        let headers = resp.headers().clone();
+        let text = resp.text().await?;
+        if !status.is_success() {
+            return Err(anyhow::anyhow!(
+                "S3 request for {} returned status code {}: {}",
+                key,
+                status.as_u16(),
+                text
+            ));
+        }
+
+        Ok((headers, text))
+    }
+
+    /// Upload a file, using a multipart upload for large files, and return its size
+    async fn upload_file(&self, key: &str, path: &Path) -> Result<u64> {
+        let size = fs::metadata(path).await?.len();
+        if size <= PART_SIZE {
+            let data = {
+                let _permit = self.open_files.acquire().await?;
+                fs::read(path).await?
+            };
+            self.request(Method::PUT, key, &[], data).await?;
+        } else {
+            self.upload_multipart(key, path, size).await?;
+        }
+        Ok(size)
+    }
+
+    async fn upload_multipart(&self, key: &str, path: &Path, size: u64) -> Result<()> {
+        // Start upload
+        let (_, body) = self
+            .request(Method::POST, key, &[("uploads", "")], Vec::new())
+            .await?;
+        let upload_id = xml_value(&body, "UploadId")
+            .ok_or_else(|| anyhow::anyhow!("No upload id in response: {}", body))?;
+
+        let res = async {
+            // Upload parts
+            let _permit = self.open_files.acquire().await?;
+            let mut file = fs::File::open(path).await?;
+            let mut etags = Vec::new();
+            for (i, offset) in (0..size).step_by(PART_SIZE as usize).enumerate() {
+                let mut buf = vec![0_u8; PART_SIZE.min(size - offset) as usize];
+                file.seek(std::io::SeekFrom::Start(offset)).await?;
+                file.read_exact(&mut buf).await?;
+
+                let part_number = (i + 1).to_string();
+                let (headers, _) = self
+                    .request(
+                        Method::PUT,
+                        key,
+                        &[("partNumber", &part_number), ("uploadId", &upload_id)],
+                        buf,
+                    )
+                    .await?;
+                let etag = headers
+                    .get(reqwest::header::ETAG)
+                    .and_then(|e| e.to_str().ok())
+                    .ok_or_else(|| anyhow::anyhow!("No ETag for part {}", part_number))?;
+                etags.push((part_number, etag.to_owned()));
+            }
+
+            // Complete upload
+            let parts: String = etags
+                .iter()
+                .map(|(n, e)| {
+                    format!(
+                        "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                        n, e
+                    )
+                })
+                .collect();
+            let body = format!(
+                "<CompleteMultipartUpload>{}</CompleteMultipartUpload>",
+                parts
+            );
+            self.request(
+                Method::POST,
+                key,
+                &[("uploadId", &upload_id)],
+                body.into_bytes(),
+            )
+            .await?;
+
+            Ok(())
+        }
+        .await;
+
+        // Clean up parts of failed uploads
+        if res.is_err() {
+            let _ = self
+                .request(Method::DELETE, key, &[("uploadId", &upload_id)], Vec::new())
+                .await;
+        }
+
+        res
+    }
+}
+
+/// Extract the text of the first element with the given tag
+fn xml_value(xml: &str, tag: &str) -> Option<String> {
+    let start = xml.find(&format!("<{}>", tag))? + tag.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", tag))?;
+    Some(xml[start..end].to_owned())
+}
+
+/// Uploaded object as listed in the manifest
+#[derive(Serialize, Debug)]
+struct UploadedObject {
+    key: String,
+    size: u64,
+}
+
+/// Object that could not be uploaded as listed in the manifest
+#[derive(Serialize, Debug)]
+struct FailedObject {
+    key: String,
+    error: String,
+}
+
+#[derive(Serialize, Default, Debug)]
+struct Manifest {
+    /// Local directory the files were uploaded from
+    output_directory: PathBuf,
+    objects: Vec<UploadedObject>,
+    failed: Vec<FailedObject>,
+}
+
+/// Background uploader of finished files
+///
+/// Files are queued without blocking and uploaded with limited concurrency, retrying failed
+/// uploads. Keys are the paths of files relative to the root directory, under the given prefix.
+#[derive(Debug)]
+pub struct Uploader {
+    tx: mpsc::UnboundedSender<PathBuf>,
+    handle: JoinHandle<Result<Vec<PathBuf>>>,
+    delete_after: bool,
+}
+
+impl Uploader {
+    pub fn new(options: &UploadOptions, root: &Path, open_files: OpenFiles) -> Result<Self> {
+        let location = options
+            .upload_s3
+            .as_deref()
+            .ok_or_else(|| anyhow::anyhow!("No S3 location given"))?;
+        let (bucket, prefix) = location
+            .strip_prefix("s3://")
+            .map(|l| l.split_once('/').unwrap_or((l, "")))
+            .filter(|(b, _)| !b.is_empty())
+            .ok_or_else(|| anyhow::anyhow!("Invalid S3 location {:?}", location))?;
+        let prefix = match prefix.trim_matches('/') {
+            "" => String::new(),
+            p => format!("{}/", p),
+        };
+
+        let client = S3Client {
+            client: Client::builder()
+                .connect_timeout(Duration::from_secs(30))
+                .build()?,
+            credentials: Credentials::load()?,
+            region: credentials::region(),
+            endpoint: match &options.s3_endpoint {
+                Some(e) => Some(e.clone()),
+                None => std::env::var("AWS_ENDPOINT_URL")
+                    .ok()
+                    .map(|e| Url::parse(&e))
+                    .transpose()
+                    .context("invalid AWS_ENDPOINT_URL")?,
+            },
+            bucket: bucket.to_owned(),
+            open_files,
+        };
+        event!(
+            Level::INFO,
+            "Uploading to bucket {} with prefix {:?}",
+            client.bucket,
+            prefix
+        );
+
+        let (tx, rx) = mpsc::unbounded();
+        let handle = tokio::spawn(upload_task(
+            Arc::new(client),
+            rx,
+            root.to_path_buf(),
+            prefix,
+            options.max_concurrent_uploads,
+        ));
+
+        Ok(Self {
+            tx,
+            handle,
+            delete_after: options.upload_then_delete,
+        })
+    }
+
+    /// Channel to queue files for uploading
+    pub fn sender(&self) -> mpsc::UnboundedSender<PathBuf> {
+        self.tx.clone()
+    }
+
+    /// Wait for queued uploads to finish, upload the manifest, and delete uploaded local files if
+    /// requested
+    pub async fn finish(self) -> Result<()> {
+        drop(self.tx);
+        let uploaded = self.handle.await??;
+
+        if self.delete_after {
+            for path in uploaded {
+                event!(Level::TRACE, "Removing uploaded file {:?}", &path);
+                if let Err(e) = fs::remove_file(&path).await {
+                    event!(Level::WARN, "Failed to remove {:?}: {}", &path, e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Upload queued files until the queue is closed, then upload the manifest and return the paths of
+/// uploaded files
+async fn upload_task(
+    client: Arc<S3Client>,
+    mut rx: mpsc::UnboundedReceiver<PathBuf>,
+    root: PathBuf,
+    prefix: String,
+    max_concurrent: usize,
+) -> Result<Vec<PathBuf>> {
+    let semaphore = Arc::new(Semaphore::new(max_concurrent.max(1)));
+    let mut tasks = FuturesUnordered::new();
+
+    while let Some(path) = rx.next().await {
+        let relative = path.strip_prefix(&root).unwrap_or(&path);
+        let key = format!(
+            "{}{}",
+            prefix,
+            relative.to_string_lossy().replace('\\', "/")
+        );
+        let permit = semaphore.clone().acquire_owned().await?;
+        let client = client.clone();
+        tasks.push(tokio::spawn(async move {
+            let res = upload_with_retry(&client, &key, &path).await;
+            drop(permit);
+            (path, key, res)
+        }));
+    }
+
+    // Wait for remaining uploads
+    let mut manifest = Manifest {
+        output_directory: root.clone(),
+        ..Default::default()
+    };
+    let mut uploaded = Vec::new();
+    while let Some(r) = tasks.next().await {
+        let (path, key, res) = r?;
+        match res {
+            Ok(size) => {
+                manifest.objects.push(UploadedObject { key, size });
+                uploaded.push(path);
+            }
+            Err(e) => manifest.failed.push(FailedObject {
+                key,
+                error: format!("{:#}", e),
+            }),
+        }
+    }
+    manifest.objects.sort_by(|a, b| a.key.cmp(&b.key));
+
+    // Upload manifest
+    let key = format!("{}manifest.json", prefix);
+    client
+        .request(
+            Method::PUT,
+            &key,
+            &[],
+            serde_json::to_vec_pretty(&manifest)?,
+        )
+        .await
+        .context("error uploading manifest")?;
+    event!(
+        Level::INFO,
+        "Uploaded {} files, {} failed",
+        manifest.objects.len(),
+        manifest.failed.len()
+    );
+
+    Ok(uploaded)
+}
+
+async fn upload_with_retry(client: &S3Client, key: &str, path: &Path) -> Result<u64> {
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        match client.upload_file(key, path).await {
+            Ok(size) => {
+                event!(Level::TRACE, "Uploaded {:?} to {}", path, key);
+                return Ok(size);
+            }
+            Err(e) if attempt < MAX_ATTEMPTS => {
+                event!(
+                    Level::WARN,
+                    "Failed to upload {:?} (attempt {}), retrying: {:#}",
+                    path,
+                    attempt,
+                    e
+                );
+                time::sleep(Duration::from_secs(1 << attempt)).await;
+            }
+            Err(e) => {
+                event!(Level::WARN, "Failed to upload {:?}: {:#}", path, e);
+                return Err(e);
+            }
+        }
+    }
+}
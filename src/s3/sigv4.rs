@@ -0,0 +1,139 @@
+use hmac::{Hmac, Mac};
+use reqwest::header::{HeaderMap, HeaderValue};
+use reqwest::{Method, Url};
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+
+use super::credentials::Credentials;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Payload hash sent when the request body is not signed
+pub const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+/// Characters that don't need to be percent-encoded
+fn is_unreserved(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b'~')
+}
+
+/// Percent-encode a string as required by AWS, optionally keeping slashes
+pub fn uri_encode(s: &str, keep_slash: bool) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if is_unreserved(b) || (keep_slash && b == b'/') {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Create the headers to authenticate a request with AWS signature version 4
+///
+/// The URL path must already be percent-encoded and query parameters must be given separately,
+/// unencoded, so that they can be canonicalized.
+pub fn sign(
+    credentials: &Credentials,
+    region: &str,
+    method: &Method,
+    url: &Url,
+    query: &[(&str, &str)],
+) -> HeaderMap {
+    let now = OffsetDateTime::now_utc();
+    let date = format!(
+        "{:04}{:02}{:02}",
+        now.year(),
+        u8::from(now.month()),
+        now.day()
+    );
+    let amz_date = format!(
+        "{}T{:02}{:02}{:02}Z",
+        date,
+        now.hour(),
+        now.minute(),
+        now.second()
+    );
+
+    let host = match url.port() {
+        Some(p) => format!("{}:{}", url.host_str().unwrap_or_default(), p),
+        None => url.host_str().unwrap_or_default().to_owned(),
+    };
+
+    // Headers to sign, must be sorted by name
+    let mut headers = vec![
+        ("host", host),
+        ("x-amz-content-sha256", UNSIGNED_PAYLOAD.to_owned()),
+        ("x-amz-date", amz_date.clone()),
+    ];
+    if let Some(token) = &credentials.session_token {
+        headers.push(("x-amz-security-token", token.clone()));
+    }
+
+    let mut canonical_query: Vec<_> = query
+        .iter()
+        .map(|(k, v)| format!("{}={}", uri_encode(k, false), uri_encode(v, false)))
+        .collect();
+    canonical_query.sort();
+
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(k, v)| format!("{}:{}\n", k, v.trim()))
+        .collect();
+    let signed_headers = headers
+        .iter()
+        .map(|(k, _)| *k)
+        .collect::<Vec<_>>()
+        .join(";");
+
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method.as_str(),
+        url.path(),
+        canonical_query.join("&"),
+        canonical_headers,
+        signed_headers,
+        UNSIGNED_PAYLOAD
+    );
+
+    let scope = format!("{}/{}/s3/aws4_request", date, region);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+
+    let key = hmac(
+        format!("AWS4{}", credentials.secret_access_key).as_bytes(),
+        &date,
+    );
+    let key = hmac(&key, region);
+    let key = hmac(&key, "s3");
+    let key = hmac(&key, "aws4_request");
+    let signature = hex::encode(hmac(&key, &string_to_sign));
+
+    let authorization = format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        credentials.access_key_id, scope, signed_headers, signature
+    );
+
+    // Host is set by reqwest
+    let mut header_map = HeaderMap::new();
+    for (name, value) in headers.into_iter().filter(|(k, _)| *k != "host") {
+        if let Ok(v) = HeaderValue::from_str(&value) {
+            header_map.insert(name, v);
+        }
+    }
+    if let Ok(v) = HeaderValue::from_str(&authorization) {
+        header_map.insert(reqwest::header::AUTHORIZATION, v);
+    }
+
+    header_map
+}
@@ -0,0 +1,105 @@
+//! Fixtures shared by the integration tests
+
+#![allow(dead_code)]
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use livestream_dl::livestream::{RemoteData, Segment, SegmentSink, Stream};
+
+/// Sink discarding segments
+pub struct NullSink;
+
+#[async_trait]
+impl SegmentSink for NullSink {
+    async fn on_init(&mut self, _: &Stream, _: &RemoteData, _: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    async fn on_segment(&mut self, _: &Stream, _: &Segment, _: &[u8]) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Response of a [`MockServer`]
+pub enum Reply {
+    /// Response with a status like "200 OK" and a body of known length
+    Full(&'static str, Vec<u8>),
+    /// 200 response without Content-Length, whose body starts with a head followed by a chunk
+    /// repeated until the client disconnects
+    Endless(Vec<u8>, Vec<u8>),
+}
+
+impl Reply {
+    /// 200 response with body
+    pub fn ok(body: impl Into<Vec<u8>>) -> Self {
+        Self::Full("200 OK", body.into())
+    }
+}
+
+/// HTTP server on localhost answering each request by its path, closing connections after one
+/// response
+pub struct MockServer {
+    addr: SocketAddr,
+}
+
+impl MockServer {
+    /// Serve requests with handler on a background thread
+    pub fn start(handler: impl Fn(&str) -> Reply + Send + Sync + 'static) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handler = Arc::new(handler);
+        std::thread::spawn(move || {
+            for conn in listener.incoming().flatten() {
+                let handler = handler.clone();
+                std::thread::spawn(move || respond(conn, &*handler));
+            }
+        });
+
+        Self { addr }
+    }
+
+    /// URL of path on the server
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}/{}", self.addr, path.trim_start_matches('/'))
+    }
+}
+
+fn respond(mut conn: TcpStream, handler: &dyn Fn(&str) -> Reply) {
+    // Read the request head
+    let mut request = Vec::new();
+    let mut buf = [0; 4096];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        match conn.read(&mut buf) {
+            Ok(0) | Err(_) => return,
+            Ok(n) => request.extend_from_slice(&buf[..n]),
+        }
+    }
+    let request = String::from_utf8_lossy(&request);
+    let path = request.split(' ').nth(1).unwrap_or("/");
+
+    // Write errors only mean that the client went away
+    match handler(path) {
+        Reply::Full(status, body) => {
+            let head = format!(
+                "HTTP/1.1 {}\r\nconnection: close\r\ncontent-length: {}\r\n\r\n",
+                status,
+                body.len()
+            );
+            let _ = conn
+                .write_all(head.as_bytes())
+                .and_then(|_| conn.write_all(&body));
+        }
+        Reply::Endless(head, chunk) => {
+            let sent = conn
+                .write_all(b"HTTP/1.1 200 OK\r\nconnection: close\r\n\r\n")
+                .and_then(|_| conn.write_all(&head));
+            if sent.is_ok() {
+                while conn.write_all(&chunk).is_ok() {}
+            }
+        }
+    }
+}
@@ -0,0 +1,223 @@
+//! Downloads from playlists served over HTTP
+
+mod common;
+
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use clap::Parser;
+use livestream_dl::cli::Args;
+use livestream_dl::livestream::{
+    Livestream, RemoteData, Segment, SegmentSink, Stream, VariantSelection,
+};
+
+use common::{MockServer, Reply};
+
+/// Sink keeping the data of segments in memory
+#[derive(Default)]
+struct MemorySink(Vec<Vec<u8>>);
+
+#[async_trait]
+impl SegmentSink for MemorySink {
+    async fn on_init(&mut self, _: &Stream, _: &RemoteData, _: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    async fn on_segment(&mut self, _: &Stream, _: &Segment, data: &[u8]) -> Result<()> {
+        self.0.push(data.to_vec());
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn oversized_segments_are_aborted() {
+    // Server sending an endless body without Content-Length for the segment
+    let server = MockServer::start(|path| match path {
+        "/media.m3u8" => Reply::ok(
+            "#EXTM3U\n#EXT-X-TARGETDURATION:6\n#EXTINF:6.0,\nendless.ts\n#EXT-X-ENDLIST\n",
+        ),
+        _ => Reply::Endless(Vec::new(), vec![0x47; 65536]),
+    });
+    let url = server.url("media.m3u8");
+
+    let args = Args::parse_from([
+        "livestream-dl",
+        &url,
+        "--max-segment-size",
+        "1M",
+        "--max-attempts-per-segment",
+        "1",
+    ]);
+    let (livestream, _) = Livestream::new(&url.parse().unwrap(), &args).await.unwrap();
+    let output = tempfile::tempdir().unwrap();
+    let e = livestream.download(output.path()).await.unwrap_err();
+    assert!(e
+        .to_string()
+        .contains("main: all 1 segments failed to download"));
+
+    // The journal records why
+    let journal = std::fs::read_to_string(output.path().join("journal.ndjson")).unwrap();
+    assert!(journal.contains("larger than the maximum segment size of 1000000 bytes"));
+}
+
+#[tokio::test]
+async fn ignored_byte_ranges_are_read_up_to_their_end() {
+    // Segments are probed before they are passed to the sink
+    if !Command::new("ffprobe")
+        .arg("-version")
+        .output()
+        .is_ok_and(|o| o.status.success())
+    {
+        eprintln!("Skipping, ffprobe is required");
+        return;
+    }
+
+    // Server ignoring Range headers and sending an endless body for the segment
+    let server = MockServer::start(|path| match path {
+        "/media.m3u8" => Reply::ok(
+            "#EXTM3U\n#EXT-X-TARGETDURATION:6\n#EXTINF:6.0,\n#EXT-X-BYTERANGE:188@188\n\
+             endless.ts\n#EXT-X-ENDLIST\n",
+        ),
+        _ => Reply::Endless(Vec::new(), vec![0x47; 65536]),
+    });
+    let url = server.url("media.m3u8");
+
+    let args = Args::parse_from(["livestream-dl", &url, "--max-attempts-per-segment", "1"]);
+    let (livestream, _) = Livestream::new(&url.parse().unwrap(), &args).await.unwrap();
+    let mut sink = MemorySink::default();
+    livestream.download_with_sink(&mut sink).await.unwrap();
+    assert_eq!(sink.0, [vec![0x47; 188]]);
+}
+
+/// Download a master playlist into output with extra args, checking if the variant was saved
+async fn saves_variant(output: &std::path::Path, args: &[&str]) -> bool {
+    let server = MockServer::start(|path| match path {
+        "/master.m3u8" => Reply::ok(
+            "#EXTM3U\n#EXT-X-STREAM-INF:BANDWIDTH=1000000,RESOLUTION=1280x720\nmedia.m3u8\n",
+        ),
+        _ => Reply::ok("#EXTM3U\n#EXT-X-TARGETDURATION:6\n#EXT-X-ENDLIST\n"),
+    });
+    let url = server.url("master.m3u8");
+    let output_arg = output.to_str().unwrap();
+    let args =
+        Args::parse_from([&["livestream-dl", url.as_str(), "-o", output_arg], args].concat());
+    let (livestream, _) = Livestream::new(&url.parse().unwrap(), &args).await.unwrap();
+    // Without segments to download the variant is still saved before the download fails
+    assert!(livestream.download(output).await.is_err());
+    output.join(VariantSelection::FILE_NAME).exists()
+}
+
+#[tokio::test]
+async fn variant_is_only_saved_for_later_runs_if_pinned() {
+    let output = tempfile::tempdir().unwrap();
+    assert!(!saves_variant(output.path(), &["--no-remux"]).await);
+    assert!(saves_variant(output.path(), &["--no-remux", "--pin-variant"]).await);
+}
+
+#[tokio::test]
+async fn remote_playlists_cant_list_local_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let secret = dir.path().join("secret");
+    std::fs::write(&secret, "secret").unwrap();
+    let secret_url = reqwest::Url::from_file_path(&secret).unwrap();
+    let server = MockServer::start(move |_| {
+        Reply::ok(format!(
+            "#EXTM3U\n#EXT-X-TARGETDURATION:6\n#EXTINF:6.0,\n{}\n#EXT-X-ENDLIST\n",
+            secret_url
+        ))
+    });
+    let url = server.url("media.m3u8");
+
+    let args = Args::parse_from([
+        "livestream-dl",
+        &url,
+        "--no-remux",
+        "--max-attempts-per-segment",
+        "1",
+    ]);
+    let (livestream, _) = Livestream::new(&url.parse().unwrap(), &args).await.unwrap();
+    let output = dir.path().join("output");
+    livestream.download(&output).await.unwrap_err();
+
+    // The file isn't read, only the refusal is recorded
+    let journal = std::fs::read_to_string(output.join("journal.ndjson")).unwrap();
+    assert!(journal.contains("Refusing to read"));
+    let saved = std::fs::read_dir(&output)
+        .unwrap()
+        .flatten()
+        .filter(|e| e.file_type().unwrap().is_dir())
+        .flat_map(|d| std::fs::read_dir(d.path()).unwrap().flatten())
+        .count();
+    assert_eq!(saved, 0);
+}
+
+#[tokio::test]
+async fn expired_keys_fail_with_their_status() {
+    // The key only decrypts garbage, and is forbidden when fetched again
+    let key_requests = AtomicUsize::new(0);
+    let server = MockServer::start(move |path| match path {
+        "/media.m3u8" => Reply::ok(
+            "#EXTM3U\n#EXT-X-TARGETDURATION:6\n\
+             #EXT-X-KEY:METHOD=AES-128,URI=\"key.bin\",IV=0x00000000000000000000000000000000\n\
+             #EXTINF:6.0,\nseg0.ts\n#EXT-X-ENDLIST\n",
+        ),
+        "/key.bin" => match key_requests.fetch_add(1, Ordering::SeqCst) {
+            0 => Reply::ok(vec![0; 16]),
+            _ => Reply::Full("403 Forbidden", b"<html>Forbidden</html>".to_vec()),
+        },
+        _ => Reply::ok(vec![0; 32]),
+    });
+    let url = server.url("media.m3u8");
+
+    let args = Args::parse_from([
+        "livestream-dl",
+        &url,
+        "--no-remux",
+        "--max-attempts-per-segment",
+        "1",
+    ]);
+    let (livestream, _) = Livestream::new(&url.parse().unwrap(), &args).await.unwrap();
+    let output = tempfile::tempdir().unwrap();
+    livestream.download(output.path()).await.unwrap_err();
+
+    let journal = std::fs::read_to_string(output.path().join("journal.ndjson")).unwrap();
+    assert!(journal.contains("status code 403"), "{}", journal);
+    assert!(!journal.contains("failed to decrypt"));
+}
+
+#[tokio::test]
+async fn failed_downloads_are_not_reported_complete() {
+    // Segments are probed before they are saved
+    if !Command::new("ffprobe")
+        .arg("-version")
+        .output()
+        .is_ok_and(|o| o.status.success())
+    {
+        eprintln!("Skipping, ffprobe is required");
+        return;
+    }
+
+    // The live playlist is gone after its segment was downloaded
+    let downloaded = AtomicBool::new(false);
+    let server = MockServer::start(move |path| match path {
+        "/seg0.ts" => {
+            downloaded.store(true, Ordering::SeqCst);
+            Reply::ok(vec![0x47; 188])
+        }
+        _ if downloaded.load(Ordering::SeqCst) => Reply::Full("404 Not Found", Vec::new()),
+        _ => Reply::ok("#EXTM3U\n#EXT-X-TARGETDURATION:1\n#EXTINF:1.0,\nseg0.ts\n"),
+    });
+    let url = server.url("media.m3u8");
+
+    let args = Args::parse_from(["livestream-dl", &url, "--no-remux", "--max-retries", "0"]);
+    let (livestream, _) = Livestream::new(&url.parse().unwrap(), &args).await.unwrap();
+    let output = tempfile::tempdir().unwrap();
+    livestream.download(output.path()).await.unwrap_err();
+
+    let report = std::fs::read(output.path().join("capture-report.json")).unwrap();
+    let report: serde_json::Value = serde_json::from_slice(&report).unwrap();
+    assert_eq!(report["verdict"], "failed");
+    assert!(report["error"].as_str().unwrap().contains("404"));
+}
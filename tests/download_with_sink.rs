@@ -0,0 +1,302 @@
+//! Downloads into custom sinks with custom playlist sources and segment fetchers
+
+mod common;
+
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use clap::Parser;
+use futures::{stream, StreamExt};
+use livestream_dl::cli::Args;
+use livestream_dl::error::LivestreamDLError;
+use livestream_dl::livestream::{
+    FetchedData, FileSink, Livestream, PlaylistSource, PlaylistStream, RemoteData, SegmentFetcher,
+    Stream, StreamEnd,
+};
+use reqwest::Url;
+
+use common::NullSink;
+
+/// Live origin with a target duration of 1s, counting playlist requests
+#[derive(Debug)]
+struct LiveSource(Arc<AtomicUsize>);
+
+impl PlaylistSource for LiveSource {
+    fn playlists(&self, _: &Stream, url: &Url) -> PlaylistStream {
+        let (requests, url) = (self.0.clone(), url.clone());
+        Box::pin(stream::unfold(0, move |seq| {
+            let (requests, url) = (requests.clone(), url.clone());
+            async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                requests.fetch_add(1, Ordering::SeqCst);
+                let playlist = format!(
+                    "#EXTM3U\n#EXT-X-TARGETDURATION:1\n#EXT-X-MEDIA-SEQUENCE:{0}\n\
+                     #EXTINF:1.0,\n{0}.ts\n",
+                    seq
+                );
+                let playlist = m3u8_rs::parse_media_playlist_res(playlist.as_bytes()).unwrap();
+                Some((Ok((playlist, url)), seq + 1))
+            }
+        }))
+    }
+}
+
+/// Fetcher serving MPEG-TS segments from memory
+#[derive(Debug)]
+struct NullFetcher;
+
+#[async_trait]
+impl SegmentFetcher for NullFetcher {
+    async fn fetch(&self, data: &RemoteData) -> Result<FetchedData> {
+        Ok(FetchedData::new(vec![0x47; 188], data.url().clone()))
+    }
+}
+
+/// Source of finished playlists, serving garbage as the playlist of the "broken" rendition
+#[derive(Debug)]
+struct MemorySource;
+
+impl PlaylistSource for MemorySource {
+    fn playlists(&self, stream: &Stream, url: &Url) -> PlaylistStream {
+        if matches!(stream, Stream::Audio { name, .. } if name == "broken") {
+            let e = LivestreamDLError::ParseM3u8("<html>".into());
+            return Box::pin(stream::iter([Err(e.into())]));
+        }
+        let name = url.path().trim_start_matches('/').trim_end_matches(".m3u8");
+        let playlist = format!(
+            "#EXTM3U\n#EXT-X-TARGETDURATION:6\n#EXTINF:6.0,\n{}.ts\n#EXT-X-ENDLIST\n",
+            name
+        );
+        let playlist = m3u8_rs::parse_media_playlist_res(playlist.as_bytes()).unwrap();
+        Box::pin(stream::iter([Ok((playlist, url.clone()))]))
+    }
+}
+
+/// Fetcher recording requested URLs
+#[derive(Debug, Default)]
+struct MemoryFetcher(Arc<Mutex<Vec<String>>>);
+
+#[async_trait]
+impl SegmentFetcher for MemoryFetcher {
+    async fn fetch(&self, data: &RemoteData) -> Result<FetchedData> {
+        self.0.lock().unwrap().push(data.url().to_string());
+        Ok(FetchedData::new(vec![0x47; 188], data.url().clone()))
+    }
+}
+
+/// Source whose first playlist request fails
+#[derive(Debug, Default)]
+struct FlakySource(AtomicU32);
+
+impl PlaylistSource for FlakySource {
+    fn playlists(&self, _: &Stream, url: &Url) -> PlaylistStream {
+        if self.0.fetch_add(1, Ordering::SeqCst) == 0 {
+            return Box::pin(stream::iter([Err(anyhow::anyhow!("connection reset"))]));
+        }
+        let playlist = "#EXTM3U\n#EXT-X-TARGETDURATION:6\n#EXTINF:6.0,\n0.ts\n#EXT-X-ENDLIST\n";
+        let playlist = m3u8_rs::parse_media_playlist_res(playlist.as_bytes()).unwrap();
+        Box::pin(stream::iter([Ok((playlist, url.clone()))]))
+    }
+}
+
+/// Live source with six segments, whose output directory is renamed away while the third and
+/// fourth playlists are served
+#[derive(Debug)]
+struct FlappingSource {
+    output: PathBuf,
+    moved: PathBuf,
+}
+
+impl PlaylistSource for FlappingSource {
+    fn playlists(&self, _: &Stream, url: &Url) -> PlaylistStream {
+        let (output, moved, url) = (self.output.clone(), self.moved.clone(), url.clone());
+        Box::pin(stream::iter(0..6).then(move |seq| {
+            let (output, moved, url) = (output.clone(), moved.clone(), url.clone());
+            async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                match seq {
+                    2 => std::fs::rename(&output, &moved).unwrap(),
+                    4 => std::fs::rename(&moved, &output).unwrap(),
+                    _ => (),
+                }
+                let end = if seq == 5 { "#EXT-X-ENDLIST\n" } else { "" };
+                let playlist = format!(
+                    "#EXTM3U\n#EXT-X-TARGETDURATION:1\n#EXT-X-MEDIA-SEQUENCE:{0}\n\
+                     #EXTINF:1.0,\n{0}.ts\n{1}",
+                    seq, end
+                );
+                let playlist = m3u8_rs::parse_media_playlist_res(playlist.as_bytes()).unwrap();
+                Ok((playlist, url))
+            }
+        }))
+    }
+}
+
+fn audio(name: &str) -> Stream {
+    Stream::Audio {
+        group_id: "aac".into(),
+        name: name.into(),
+        lang: None,
+        channels: None,
+        describes_video: false,
+    }
+}
+
+#[tokio::test]
+async fn dropping_the_download_stops_playlist_requests() {
+    let url: Url = "memory://live/media.m3u8".parse().unwrap();
+    let requests = Arc::new(AtomicUsize::new(0));
+    let args = Args::parse_from(["livestream-dl", url.as_str()]);
+    let (livestream, _) = Livestream::builder(&url, &args)
+        .streams([(Stream::Main, url.clone())])
+        .playlist_source(LiveSource(requests.clone()))
+        .segment_fetcher(NullFetcher)
+        .build()
+        .await
+        .unwrap();
+
+    // Drop the download after a few playlist requests
+    let mut sink = NullSink;
+    let download = livestream.download_with_sink(&mut sink);
+    let res = tokio::time::timeout(Duration::from_millis(500), download).await;
+    assert!(res.is_err());
+
+    // No more requests within one target duration
+    let dropped_at = requests.load(Ordering::SeqCst);
+    assert!(dropped_at > 0);
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(requests.load(Ordering::SeqCst), dropped_at);
+}
+
+#[tokio::test]
+async fn broken_renditions_are_dropped() {
+    let url: Url = "memory://vod/main.m3u8".parse().unwrap();
+    let streams = [
+        (Stream::Main, url.clone()),
+        (audio("en"), url.join("en.m3u8").unwrap()),
+        (audio("broken"), url.join("broken.m3u8").unwrap()),
+    ];
+    let args = Args::parse_from(["livestream-dl", url.as_str()]);
+    let requested = Arc::new(Mutex::new(Vec::new()));
+    let (livestream, _) = Livestream::builder(&url, &args)
+        .streams(streams)
+        .playlist_source(MemorySource)
+        .segment_fetcher(MemoryFetcher(requested.clone()))
+        .build()
+        .await
+        .unwrap();
+    livestream.download_with_sink(&mut NullSink).await.unwrap();
+    let mut requested = requested.lock().unwrap().clone();
+    requested.sort();
+    assert_eq!(requested, ["memory://vod/en.ts", "memory://vod/main.ts"]);
+
+    // The dropped rendition is reported as failed
+    let statuses = livestream.stream_statuses();
+    let end = |stream: &Stream| {
+        let status = statuses.iter().find(|s| &s.stream == stream).unwrap();
+        status.end.clone()
+    };
+    assert_eq!(end(&Stream::Main), StreamEnd::Completed);
+    assert_eq!(end(&audio("en")), StreamEnd::Completed);
+    assert!(matches!(end(&audio("broken")), StreamEnd::Failed(_)));
+}
+
+#[tokio::test]
+async fn broken_renditions_fail_strict_downloads() {
+    let url: Url = "memory://vod/main.m3u8".parse().unwrap();
+    let streams = [
+        (Stream::Main, url.clone()),
+        (audio("broken"), url.join("broken.m3u8").unwrap()),
+    ];
+    let args = Args::parse_from(["livestream-dl", url.as_str(), "--strict-renditions"]);
+    let (livestream, _) = Livestream::builder(&url, &args)
+        .streams(streams)
+        .playlist_source(MemorySource)
+        .segment_fetcher(MemoryFetcher::default())
+        .build()
+        .await
+        .unwrap();
+    assert!(livestream.download_with_sink(&mut NullSink).await.is_err());
+}
+
+#[tokio::test]
+async fn failed_streams_are_restarted() {
+    let url: Url = "memory://live/main.m3u8".parse().unwrap();
+    let args = Args::parse_from([
+        "livestream-dl",
+        url.as_str(),
+        "--retry-streams",
+        "1",
+        "--retry-min-delay",
+        "0.1",
+    ]);
+    let (livestream, _) = Livestream::builder(&url, &args)
+        .streams([(Stream::Main, url.clone())])
+        .playlist_source(FlakySource::default())
+        .segment_fetcher(NullFetcher)
+        .build()
+        .await
+        .unwrap();
+    livestream.download_with_sink(&mut NullSink).await.unwrap();
+    assert_eq!(livestream.stream_statuses()[0].end, StreamEnd::Completed);
+
+    // Without --retry-streams the failure ends the download
+    let args = Args::parse_from(["livestream-dl", url.as_str()]);
+    let (livestream, _) = Livestream::builder(&url, &args)
+        .streams([(Stream::Main, url.clone())])
+        .playlist_source(FlakySource::default())
+        .segment_fetcher(NullFetcher)
+        .build()
+        .await
+        .unwrap();
+    assert!(livestream.download_with_sink(&mut NullSink).await.is_err());
+}
+
+#[tokio::test]
+async fn segments_are_kept_while_the_output_is_away() {
+    // Segments are probed before they are passed to the sink
+    if !Command::new("ffprobe")
+        .arg("-version")
+        .output()
+        .is_ok_and(|o| o.status.success())
+    {
+        eprintln!("Skipping, ffprobe is required");
+        return;
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("output");
+    std::fs::create_dir(&output).unwrap();
+    let source = FlappingSource {
+        output: output.clone(),
+        moved: dir.path().join("moved"),
+    };
+
+    let url: Url = "memory://live/main.m3u8".parse().unwrap();
+    let args = Args::parse_from(["livestream-dl", url.as_str()]);
+    let (livestream, _) = Livestream::builder(&url, &args)
+        .streams([(Stream::Main, url.clone())])
+        .playlist_source(source)
+        .segment_fetcher(NullFetcher)
+        .build()
+        .await
+        .unwrap();
+    let mut sink =
+        FileSink::new(output.join("segments")).with_output_grace(Duration::from_secs(10), None);
+    livestream.download_with_sink(&mut sink).await.unwrap();
+    sink.finish().await.unwrap();
+
+    // Segments saved while the output was away are written once it's back
+    let saved = sink.into_downloaded_segments();
+    let mut seqs: Vec<_> = saved[&Stream::Main].iter().map(|(s, _)| s.seq).collect();
+    seqs.sort();
+    assert_eq!(seqs, [0, 1, 2, 3, 4, 5]);
+    assert!(saved[&Stream::Main]
+        .iter()
+        .all(|(_, p)| p.starts_with(&output) && p.exists()));
+}
@@ -0,0 +1,132 @@
+//! Discovery of streams from playlists served over HTTP
+
+mod common;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use livestream_dl::cli::Args;
+use livestream_dl::livestream::{Livestream, Stream};
+
+use common::{MockServer, Reply};
+
+/// Error of creating a Livestream from url without retries
+async fn new_error(url: &str) -> String {
+    let args = Args::parse_from(["livestream-dl", url, "--max-retries", "0"]);
+    let err = Livestream::new(&url.parse().unwrap(), &args)
+        .await
+        .unwrap_err();
+    format!("{:#}", err)
+}
+
+#[tokio::test]
+async fn failed_playlist_request_is_retried() {
+    // Server that fails the first request, then serves a media playlist
+    let requests = AtomicUsize::new(0);
+    let server = MockServer::start(move |_| match requests.fetch_add(1, Ordering::SeqCst) {
+        0 => Reply::Full("503 Service Unavailable", Vec::new()),
+        _ => Reply::ok("#EXTM3U\n#EXT-X-TARGETDURATION:6\n#EXTINF:6.0,\nseg0.ts\n#EXT-X-ENDLIST\n"),
+    });
+    let url = server.url("media.m3u8");
+
+    let args = Args::parse_from(["livestream-dl", &url, "--retry-min-delay", "0.1"]);
+    let (livestream, _) = Livestream::new(&url.parse().unwrap(), &args).await.unwrap();
+    assert_eq!(livestream.streams().count(), 1);
+}
+
+#[tokio::test]
+async fn oversized_and_media_playlists_fail_fast() {
+    let server = MockServer::start(|path| match path {
+        "/endless.m3u8" => Reply::Endless(b"#EXTM3U\n".to_vec(), vec![b'#'; 65536]),
+        _ => {
+            let mut packet = vec![0xff; 188];
+            packet[0] = 0x47;
+            Reply::Endless(Vec::new(), packet)
+        }
+    });
+    let start = Instant::now();
+    let endless = new_error(&server.url("endless.m3u8")).await;
+    assert!(endless.contains("larger than 8 MiB"));
+    let media = new_error(&server.url("media.m3u8")).await;
+    assert!(media.contains("media file (MPEG-TS)"));
+    assert!(start.elapsed() < Duration::from_secs(10));
+}
+
+#[tokio::test]
+async fn connect_host_applies_to_any_port() {
+    let server = MockServer::start(|_| {
+        Reply::ok("#EXTM3U\n#EXT-X-TARGETDURATION:6\n#EXTINF:6.0,\nseg0.ts\n#EXT-X-ENDLIST\n")
+    });
+    // Only the host is replaced, the port of the URL is kept
+    let url = server
+        .url("media.m3u8")
+        .replace("127.0.0.1", "example.invalid");
+    let args = Args::parse_from([
+        "livestream-dl",
+        &url,
+        "--max-retries",
+        "0",
+        "--connect-host",
+        "example.invalid:127.0.0.1",
+    ]);
+    let (livestream, _) = Livestream::new(&url.parse().unwrap(), &args).await.unwrap();
+    assert_eq!(livestream.streams().count(), 1);
+}
+
+/// Streams selected from a master playlist with audio description and forced subtitles, sorted
+/// by name
+async fn selected_renditions(args: &[&str]) -> Vec<Stream> {
+    let server = MockServer::start(|_| {
+        Reply::ok(
+            r#"#EXTM3U
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID="aud",NAME="English",LANGUAGE="en",DEFAULT=YES,URI="en.m3u8"
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID="aud",NAME="English AD",LANGUAGE="en",CHARACTERISTICS="public.accessibility.describes-video",URI="ad.m3u8"
+#EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID="sub",NAME="French",LANGUAGE="fr",URI="fr.m3u8"
+#EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID="sub",NAME="English forced",LANGUAGE="en",FORCED=YES,URI="forced.m3u8"
+#EXT-X-STREAM-INF:BANDWIDTH=1000000,AUDIO="aud",SUBTITLES="sub"
+video.m3u8
+"#,
+        )
+    });
+    let url = server.url("master.m3u8");
+    let args = Args::parse_from([&["livestream-dl", url.as_str()], args].concat());
+    let (livestream, _) = Livestream::new(&url.parse().unwrap(), &args).await.unwrap();
+    let mut streams: Vec<_> = livestream.streams().map(|(s, _)| s.clone()).collect();
+    streams.sort_by_key(|s| s.to_string());
+    streams
+}
+
+fn names(streams: &[Stream]) -> Vec<String> {
+    streams
+        .iter()
+        .map(|s| s.name().unwrap_or_default())
+        .collect()
+}
+
+#[tokio::test]
+async fn audio_description_is_opt_in() {
+    let streams = selected_renditions(&[]).await;
+    assert_eq!(names(&streams), ["English", "", "English forced", "French"]);
+    assert!(matches!(streams[2], Stream::Subtitle { forced: true, .. }));
+
+    let streams = selected_renditions(&["--include-audio-description"]).await;
+    assert_eq!(
+        names(&streams),
+        ["English", "English AD", "", "English forced", "French"]
+    );
+    assert!(matches!(
+        streams[1],
+        Stream::Audio {
+            describes_video: true,
+            ..
+        }
+    ));
+}
+
+#[tokio::test]
+async fn forced_subtitles_follow_their_language() {
+    // Forced subtitles are kept with the English audio, the French subtitles are dropped
+    let streams = selected_renditions(&["--max-renditions", "1"]).await;
+    assert_eq!(names(&streams), ["English", "", "English forced"]);
+}